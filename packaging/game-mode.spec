@@ -50,14 +50,19 @@ installs files only; host provisioning is `sudo game-mode setup`.
 
 %build
 %cargo_build
+# The PAM module is a cdylib: %%cargo_install only handles bins.
+(cd pam_access_gate && %cargo_build)
 %{cargo_license_summary}
 %{cargo_license} > LICENSE.dependencies
 
 %install
 # Root crate bins: game-mode, game-mode-steam-config, game-mode-watchdog,
-# game-mode-steam-shortcut; then the verifier workspace member.
+# game-mode-steam-shortcut; then the verifier and polkit agent workspace
+# members.
 %cargo_install
 (cd verifier && %cargo_install)
+(cd polkit_agent && %cargo_install)
+install -Dpm0755 target/rpm/libpam_access_gate.so %{buildroot}%{_libdir}/security/pam_access_gate.so
 
 # Session helper scripts (referenced by the greetd session configs)
 # uwsm-start-hyprland ships as the /usr/bin/start-hyprland the greeter launcher execs
@@ -69,6 +74,9 @@ install -Dpm0755 greetd/scripts/steamos-session-select %{buildroot}%{_bindir}/st
 
 install -Dpm0644 dist/game-mode.service %{buildroot}%{_unitdir}/game-mode.service
 install -Dpm0644 dist/access-gate-verifier.service %{buildroot}%{_unitdir}/access-gate-verifier.service
+install -Dpm0644 dist/access-gate-verifier-ctrl.socket %{buildroot}%{_unitdir}/access-gate-verifier-ctrl.socket
+install -Dpm0644 dist/access-gate-verifier-web.socket %{buildroot}%{_unitdir}/access-gate-verifier-web.socket
+install -Dpm0644 dist/access-gate-polkit-agent@.service %{buildroot}%{_unitdir}/access-gate-polkit-agent@.service
 install -Dpm0644 dist/game-mode.sysusers %{buildroot}%{_sysusersdir}/game-mode.conf
 install -Dpm0644 dist/game-mode.tmpfiles %{buildroot}%{_tmpfilesdir}/game-mode.conf
 install -d -m0700 %{buildroot}%{_sharedstatedir}/access-gate
@@ -87,10 +95,10 @@ install -Dpm0644 dist/sudoers-greeter-greetd %{buildroot}%{_datadir}/game-mode/s
 # The access-gate user itself comes from the sysusers.d snippet via systemd's
 # file triggers (no scriptlet needed on current Fedora).
 %post
-%systemd_post game-mode.service access-gate-verifier.service
+%systemd_post game-mode.service access-gate-verifier.service access-gate-verifier-ctrl.socket access-gate-verifier-web.socket
 
 %preun
-%systemd_preun game-mode.service access-gate-verifier.service
+%systemd_preun game-mode.service access-gate-verifier.service access-gate-verifier-ctrl.socket access-gate-verifier-web.socket
 
 %postun
 %systemd_postun_with_restart access-gate-verifier.service
@@ -103,13 +111,18 @@ install -Dpm0644 dist/sudoers-greeter-greetd %{buildroot}%{_datadir}/game-mode/s
 %{_bindir}/game-mode-watchdog
 %{_bindir}/game-mode-steam-shortcut
 %{_bindir}/access-gate-verifier
+%{_bindir}/access-gate-polkit-agent
 %{_bindir}/start-hyprland
 %{_bindir}/game-mode-wrapper
 %{_bindir}/game-mode-discord
 %{_bindir}/game-mode-overlay
 %{_bindir}/steamos-session-select
+%{_libdir}/security/pam_access_gate.so
 %{_unitdir}/game-mode.service
 %{_unitdir}/access-gate-verifier.service
+%{_unitdir}/access-gate-verifier-ctrl.socket
+%{_unitdir}/access-gate-verifier-web.socket
+%{_unitdir}/access-gate-polkit-agent@.service
 %{_sysusersdir}/game-mode.conf
 %{_tmpfilesdir}/game-mode.conf
 %dir %attr(0700, access-gate, access-gate) %{_sharedstatedir}/access-gate
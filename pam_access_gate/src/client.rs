@@ -0,0 +1,333 @@
+//! Module options and the ctrl-socket exchange, free of any PAM types so it
+//! can be tested against a stand-in verifier.
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+const DEFAULT_SOCKET: &str = "/run/access-gate/ctrl.sock";
+const DEFAULT_GROUP: &str = "pam";
+const DEFAULT_TITLE: &str = "Allow {user} to use {service}?";
+const DEFAULT_TIMEOUT: u64 = 60;
+/// Longest PAM item passed on; a remote host name or a login name typed at
+/// an ssh prompt is not ours to trust.
+const MAX_ITEM_CHARS: usize = 64;
+const MAX_REASON_CHARS: usize = 80;
+
+/// Arguments from the PAM stack line, e.g.
+/// `auth required pam_access_gate.so group=sudo timeout=90 fail=open
+/// [title=sudo for {user} on {tty}]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub socket: PathBuf,
+    /// Request group; the verifier's `policy.groups` may restrict it.
+    pub group: String,
+    /// Placeholders: {service} {user} {ruser} {rhost} {tty}.
+    pub title: String,
+    pub timeout_secs: u64,
+    /// Verifier unreachable: skip this factor (PAM_IGNORE) instead of
+    /// failing the stack. A deny or a timeout always fails, and so does a
+    /// verifier that took the request and then went away.
+    pub fail_open: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            socket: DEFAULT_SOCKET.into(),
+            group: DEFAULT_GROUP.into(),
+            title: DEFAULT_TITLE.into(),
+            timeout_secs: DEFAULT_TIMEOUT,
+            fail_open: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<'a>(args: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        let mut opts = Options::default();
+        for arg in args {
+            let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+            match key {
+                "socket" => opts.socket = value.into(),
+                "group" => opts.group = value.into(),
+                "title" => opts.title = value.into(),
+                "timeout" => {
+                    opts.timeout_secs = value
+                        .parse()
+                        .map_err(|_| format!("timeout={value:?} is not a number"))?
+                }
+                "fail" => {
+                    opts.fail_open = match value {
+                        "open" => true,
+                        "closed" => false,
+                        _ => return Err(format!("fail={value:?}: expected open or closed")),
+                    }
+                }
+                _ => return Err(format!("unknown option {arg:?}")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// PAM items describing who wants in, as far as the application set them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    pub service: Option<String>,
+    pub user: Option<String>,
+    pub ruser: Option<String>,
+    pub rhost: Option<String>,
+    pub tty: Option<String>,
+}
+
+impl Context {
+    pub fn render(&self, template: &str) -> String {
+        let item = |v: &Option<String>| v.as_deref().map_or("?".to_string(), clean);
+        template
+            .replace("{service}", &item(&self.service))
+            .replace("{user}", &item(&self.user))
+            .replace("{ruser}", &item(&self.ruser))
+            .replace("{rhost}", &item(&self.rhost))
+            .replace("{tty}", &item(&self.tty))
+    }
+
+    /// The request line for the verifier. `exe` is the PAM service, so a
+    /// verifier grant for "sudo" does not cover "sshd".
+    pub fn request(&self, opts: &Options) -> Value {
+        let mut path = self.render("{user}");
+        if let Some(rhost) = &self.rhost {
+            path += &format!(" from {}", clean(rhost));
+        } else if let Some(tty) = &self.tty {
+            path += &format!(" on {}", clean(tty));
+        }
+        json!({
+            "exe": self.render("{service}"),
+            "path": path,
+            "group": opts.group,
+            "title": self.render(&opts.title),
+            "timeout_secs": opts.timeout_secs,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Approved,
+    /// Denied on the phone or refused by the verifier's policy.
+    Denied(Option<String>),
+    /// No decision on an acknowledged request: timed out, cancelled, or the
+    /// verifier went away or answered nonsense while it was waiting.
+    TimedOut,
+    /// No verifier, or no answer to the request: the `fail=` option decides.
+    Unavailable(String),
+}
+
+/// Send one request and block on the decision. `sent` is called once the
+/// verifier has acknowledged it (the phone is being notified).
+pub fn ask(opts: &Options, ctx: &Context, sent: &dyn Fn(&str)) -> Decision {
+    let unavailable = |what: &str, e: &dyn std::fmt::Display| {
+        Decision::Unavailable(format!("{what} {}: {e}", opts.socket.display()))
+    };
+    let mut stream = match UnixStream::connect(&opts.socket) {
+        Ok(s) => s,
+        Err(e) => return unavailable("connect", &e),
+    };
+    // The verifier times the request out itself; pad so we hear it say so.
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(opts.timeout_secs + 10)));
+    let line = format!("{}\n", ctx.request(opts));
+    if let Err(e) = stream.write_all(line.as_bytes()) {
+        return unavailable("write", &e);
+    }
+    let mut reader = BufReader::new(stream);
+    let mut next = || -> Option<Value> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        serde_json::from_str(line.trim()).ok()
+    };
+
+    let Some(ack) = next() else {
+        return Decision::Unavailable("no answer from the verifier".into());
+    };
+    if let Some(error) = ack["error"].as_str() {
+        return Decision::Denied(Some(clean_reason(error)));
+    }
+    let rid = ack["id"].as_str().unwrap_or("?");
+    sent(rid);
+    // From here on the request exists: whatever stops the verifier from
+    // answering must not make it fail open.
+    let decision = next().unwrap_or_default();
+    match decision["status"].as_str() {
+        Some("approved") => Decision::Approved,
+        Some("denied") => Decision::Denied(decision["reason"].as_str().map(clean_reason)),
+        _ => Decision::TimedOut,
+    }
+}
+
+/// Printable, single-line, bounded: PAM items and reasons end up on a
+/// terminal (the ssh or sudo prompt) and in syslog.
+fn clean(raw: &str) -> String {
+    access_gate_text::clean_text(raw, MAX_ITEM_CHARS).unwrap_or_default()
+}
+
+fn clean_reason(raw: &str) -> String {
+    access_gate_text::clean_text(raw, MAX_REASON_CHARS).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    #[test]
+    fn parses_options() {
+        let opts = Options::parse([
+            "group=sudo",
+            "timeout=90",
+            "fail=open",
+            "title=sudo for {user} on {tty}",
+        ])
+        .unwrap();
+        assert_eq!(opts.group, "sudo");
+        assert_eq!(opts.timeout_secs, 90);
+        assert!(opts.fail_open);
+        assert_eq!(opts.title, "sudo for {user} on {tty}");
+        assert_eq!(opts.socket, PathBuf::from(DEFAULT_SOCKET));
+        assert_eq!(Options::parse([]).unwrap(), Options::default());
+        assert!(Options::parse(["fail=maybe"]).is_err());
+        assert!(Options::parse(["timeout=soon"]).is_err());
+        assert!(Options::parse(["grup=sudo"]).is_err());
+    }
+
+    #[test]
+    fn renders_request_with_untrusted_items_cleaned() {
+        let ctx = Context {
+            service: Some("sshd".into()),
+            user: Some("root\u{1b}[2J".into()),
+            rhost: Some("evil.example\r\nInjected: yes".into()),
+            tty: Some("ssh".into()),
+            ..Default::default()
+        };
+        let opts = Options {
+            group: "ssh".into(),
+            title: "{user}@{rhost} via {service} ({ruser})".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            ctx.request(&opts),
+            json!({
+                "exe": "sshd",
+                "path": "root[2J from evil.example Injected: yes",
+                "group": "ssh",
+                "title": "root[2J@evil.example Injected: yes via sshd (?)",
+                "timeout_secs": DEFAULT_TIMEOUT,
+            })
+        );
+    }
+
+    /// A one-shot verifier: checks the request line, then plays `replies`.
+    fn stand_in(
+        tag: &str,
+        replies: &'static [&'static str],
+    ) -> (Options, thread::JoinHandle<Value>) {
+        let dir = std::env::temp_dir().join(format!("pam-ag-{tag}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("ctrl.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            for reply in replies {
+                (&stream)
+                    .write_all(format!("{reply}\n").as_bytes())
+                    .unwrap();
+            }
+            std::fs::remove_dir_all(&dir).unwrap();
+            serde_json::from_str(&line).unwrap()
+        });
+        let opts = Options {
+            socket,
+            timeout_secs: 1,
+            ..Default::default()
+        };
+        (opts, server)
+    }
+
+    fn ctx() -> Context {
+        Context {
+            service: Some("sudo".into()),
+            user: Some("alice".into()),
+            tty: Some("pts/3".into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn approved_after_ack() {
+        let (opts, server) = stand_in("ok", &[r#"{"id":"r1"}"#, r#"{"status":"approved"}"#]);
+        let acked = std::cell::RefCell::new(None);
+        let decision = ask(&opts, &ctx(), &|rid| {
+            *acked.borrow_mut() = Some(rid.to_string())
+        });
+        assert_eq!(decision, Decision::Approved);
+        assert_eq!(acked.into_inner().as_deref(), Some("r1"));
+        let request = server.join().unwrap();
+        assert_eq!(request["exe"], "sudo");
+        assert_eq!(request["path"], "alice on pts/3");
+        assert_eq!(request["title"], "Allow alice to use sudo?");
+    }
+
+    #[test]
+    fn denied_timed_out_refused_and_unreachable() {
+        let (opts, server) = stand_in(
+            "deny",
+            &[
+                r#"{"id":"r2"}"#,
+                r#"{"status":"denied","reason":"Homework\u001b first"}"#,
+            ],
+        );
+        assert_eq!(
+            ask(&opts, &ctx(), &|_| {}),
+            Decision::Denied(Some("Homework first".into()))
+        );
+        server.join().unwrap();
+
+        let (opts, server) = stand_in("timeout", &[r#"{"id":"r3"}"#, r#"{"status":"timeout"}"#]);
+        assert_eq!(ask(&opts, &ctx(), &|_| {}), Decision::TimedOut);
+        server.join().unwrap();
+
+        let (opts, server) = stand_in("policy", &[r#"{"error":"group \"pam\" not permitted"}"#]);
+        assert_eq!(
+            ask(&opts, &ctx(), &|_| {}),
+            Decision::Denied(Some("group \"pam\" not permitted".into()))
+        );
+        server.join().unwrap();
+
+        // Killed or restarted after the ack: no way around the factor.
+        let (opts, server) = stand_in("hangup", &[r#"{"id":"r4"}"#]);
+        assert_eq!(ask(&opts, &ctx(), &|_| {}), Decision::TimedOut);
+        server.join().unwrap();
+
+        let (opts, server) = stand_in("noack", &[]);
+        assert!(matches!(
+            ask(&opts, &ctx(), &|_| {}),
+            Decision::Unavailable(_)
+        ));
+        server.join().unwrap();
+
+        let opts = Options {
+            socket: "/nonexistent/ctrl.sock".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            ask(&opts, &ctx(), &|_| {}),
+            Decision::Unavailable(_)
+        ));
+    }
+}
@@ -0,0 +1,280 @@
+//! pam_access_gate: require an access-gate phone approval as a PAM
+//! authentication factor.
+//!
+//! The module speaks the verifier's ctrl protocol like the game-mode
+//! daemon does — one request line, `{"id"}`, then `{"status"}` — so sudo,
+//! sshd or a greeter can demand the same passkey tap:
+//!
+//! ```text
+//! auth required pam_access_gate.so group=sudo timeout=60 [title=sudo for {user} on {tty}]
+//! ```
+//!
+//! The calling user must be able to connect to the ctrl socket (it is
+//! 0660 access-gate:greeter by default; sudo and sshd run as root).
+//!
+//! libpam's functions are looked up at runtime (dlsym) rather than linked:
+//! the module only ever runs inside a process that already has libpam
+//! loaded, and the tests can then drive the entry points without it.
+
+mod client;
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+pub use client::{ask, Context, Decision, Options};
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_SERVICE_ERR: c_int = 3;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_IGNORE: c_int = 25;
+
+const PAM_SERVICE: c_int = 1;
+const PAM_USER: c_int = 2;
+const PAM_TTY: c_int = 3;
+const PAM_RHOST: c_int = 4;
+const PAM_CONV: c_int = 5;
+const PAM_RUSER: c_int = 8;
+
+const PAM_SILENT: c_int = 0x8000;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = unsafe extern "C" fn(
+    c_int,
+    *mut *const PamMessage,
+    *mut *mut PamResponse,
+    *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: Option<ConvFn>,
+    appdata_ptr: *mut c_void,
+}
+
+type GetItemFn = unsafe extern "C" fn(*const c_void, c_int, *mut *const c_void) -> c_int;
+type SyslogFn = unsafe extern "C" fn(*const c_void, c_int, *const c_char, ...);
+
+/// A libpam function from the host process, if libpam is loaded.
+unsafe fn libpam<T: Copy>(name: &CStr) -> Option<T> {
+    let p = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+    (!p.is_null()).then(|| std::mem::transmute_copy(&p))
+}
+
+struct Handle {
+    pamh: *const c_void,
+    silent: bool,
+}
+
+impl Handle {
+    fn item(&self, kind: c_int) -> Option<String> {
+        let get_item: GetItemFn = unsafe { libpam(c"pam_get_item") }?;
+        let mut value: *const c_void = std::ptr::null();
+        if self.pamh.is_null() || unsafe { get_item(self.pamh, kind, &mut value) } != PAM_SUCCESS {
+            return None;
+        }
+        (!value.is_null()).then(|| {
+            unsafe { CStr::from_ptr(value.cast()) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            service: self.item(PAM_SERVICE),
+            user: self.item(PAM_USER),
+            ruser: self.item(PAM_RUSER),
+            rhost: self.item(PAM_RHOST),
+            tty: self.item(PAM_TTY),
+        }
+    }
+
+    /// Show `text` to the user through the application's conversation
+    /// function (the sudo or ssh prompt).
+    fn say(&self, style: c_int, text: &str) {
+        let Some(get_item): Option<GetItemFn> = (unsafe { libpam(c"pam_get_item") }) else {
+            return;
+        };
+        let Ok(text) = CString::new(text) else { return };
+        let mut conv: *const c_void = std::ptr::null();
+        if self.silent
+            || self.pamh.is_null()
+            || unsafe { get_item(self.pamh, PAM_CONV, &mut conv) } != PAM_SUCCESS
+            || conv.is_null()
+        {
+            return;
+        }
+        let conv = unsafe { &*(conv as *const PamConv) };
+        let Some(f) = conv.conv else { return };
+        let msg = PamMessage {
+            msg_style: style,
+            msg: text.as_ptr(),
+        };
+        let mut msgs = [&msg as *const PamMessage];
+        let mut resp: *mut PamResponse = std::ptr::null_mut();
+        unsafe {
+            f(1, msgs.as_mut_ptr(), &mut resp, conv.appdata_ptr);
+            if !resp.is_null() {
+                libc::free((*resp).resp.cast());
+                libc::free(resp.cast());
+            }
+        }
+    }
+
+    fn log(&self, priority: c_int, text: &str) {
+        let Ok(text) = CString::new(text) else { return };
+        match unsafe { libpam::<SyslogFn>(c"pam_syslog") } {
+            Some(syslog) if !self.pamh.is_null() => unsafe {
+                syslog(self.pamh, priority, c"%s".as_ptr(), text.as_ptr())
+            },
+            _ => eprintln!("pam_access_gate: {}", text.to_string_lossy()),
+        }
+    }
+}
+
+fn args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a str> {
+    if argv.is_null() {
+        return Vec::new();
+    }
+    (0..argc.max(0) as usize)
+        .filter_map(|i| unsafe {
+            let arg = *argv.add(i);
+            (!arg.is_null()).then(|| CStr::from_ptr(arg).to_str().ok())?
+        })
+        .collect()
+}
+
+/// `f`'s result, or `PAM_AUTH_ERR` if it panics: unwinding out of an
+/// `extern "C"` entry point would take sudo or sshd down with it.
+fn guarded(f: impl FnOnce() -> c_int) -> c_int {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(PAM_AUTH_ERR)
+}
+
+/// # Safety
+/// Called by libpam with its handle and the stack line's arguments.
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut c_void,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    guarded(|| authenticate(pamh, flags, argc, argv))
+}
+
+unsafe fn authenticate(
+    pamh: *mut c_void,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    let handle = Handle {
+        pamh,
+        silent: flags & PAM_SILENT != 0,
+    };
+    let opts = match Options::parse(args(argc, argv)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            handle.log(libc::LOG_ERR, &format!("bad module arguments: {e}"));
+            return PAM_SERVICE_ERR;
+        }
+    };
+    let ctx = handle.context();
+    let decision = ask(&opts, &ctx, &|rid| {
+        handle.log(libc::LOG_INFO, &format!("approval request {rid} sent"));
+        handle.say(
+            PAM_TEXT_INFO,
+            &format!(
+                "Approval sent to your phone (waiting up to {}s)…",
+                opts.timeout_secs
+            ),
+        );
+    });
+    match decision {
+        Decision::Approved => {
+            handle.log(libc::LOG_NOTICE, "approved");
+            PAM_SUCCESS
+        }
+        Decision::Denied(reason) => {
+            let reason = reason.map(|r| format!(": {r}")).unwrap_or_default();
+            handle.log(libc::LOG_NOTICE, &format!("denied{reason}"));
+            handle.say(PAM_ERROR_MSG, &format!("Denied on the phone{reason}"));
+            PAM_AUTH_ERR
+        }
+        Decision::TimedOut => {
+            handle.log(libc::LOG_NOTICE, "no decision in time");
+            handle.say(PAM_ERROR_MSG, "Phone approval timed out");
+            PAM_AUTH_ERR
+        }
+        Decision::Unavailable(e) if opts.fail_open => {
+            handle.log(libc::LOG_WARNING, &format!("{e}; skipped (fail=open)"));
+            PAM_IGNORE
+        }
+        Decision::Unavailable(e) => {
+            handle.log(libc::LOG_ERR, &e);
+            handle.say(PAM_ERROR_MSG, "Phone approval service unavailable");
+            PAM_AUTHINFO_UNAVAIL
+        }
+    }
+}
+
+/// # Safety
+/// Called by libpam; nothing to set.
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_setcred(
+    _pamh: *mut c_void,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(args: &[&str]) -> c_int {
+        let owned: Vec<CString> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
+        let argv: Vec<*const c_char> = owned.iter().map(|a| a.as_ptr()).collect();
+        unsafe {
+            pam_sm_authenticate(
+                std::ptr::null_mut(),
+                PAM_SILENT,
+                argv.len() as c_int,
+                argv.as_ptr(),
+            )
+        }
+    }
+
+    #[test]
+    fn entry_point_maps_outcomes_to_pam_codes() {
+        let sock = "socket=/nonexistent/ctrl.sock";
+        assert_eq!(call(&[sock]), PAM_AUTHINFO_UNAVAIL);
+        assert_eq!(call(&[sock, "fail=open"]), PAM_IGNORE);
+        assert_eq!(call(&["frobnicate"]), PAM_SERVICE_ERR);
+        assert_eq!(
+            unsafe { pam_sm_setcred(std::ptr::null_mut(), 0, 0, std::ptr::null()) },
+            PAM_SUCCESS
+        );
+    }
+
+    #[test]
+    fn a_panic_fails_the_factor_instead_of_unwinding() {
+        assert_eq!(guarded(|| PAM_IGNORE), PAM_IGNORE);
+        assert_eq!(guarded(|| panic!("poisoned")), PAM_AUTH_ERR);
+    }
+}
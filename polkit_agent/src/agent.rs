@@ -0,0 +1,609 @@
+//! The `AuthenticationAgent` object polkitd calls, and the ctrl-socket
+//! exchange behind it.
+//!
+//! Each `BeginAuthentication` becomes one verifier request: `group` is the
+//! action id (so `policy.groups` and timed grants work per action), `title`
+//! the action's message and `path` the message plus its details. On an
+//! approval the agent vouches for the first offered identity with
+//! `AuthenticationAgentResponse2` — polkitd only takes that from root, which
+//! is why the agent runs as a system service for the session.
+//!
+//! The object sits on the system bus, where anyone can call it: only the
+//! current owner of `org.freedesktop.PolicyKit1` may begin or cancel an
+//! authentication, so nobody else can put a title of their choosing in
+//! front of the phone.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tracing::{info, warn};
+use zbus::fdo::DBusProxy;
+use zbus::message::Header;
+use zbus::names::WellKnownName;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value as Variant};
+use zbus::{interface, Connection};
+
+pub const AUTHORITY: &str = "org.freedesktop.PolicyKit1";
+pub const AUTHORITY_PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+pub const AUTHORITY_IFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+pub const AGENT_PATH: &str = "/org/gamemode/AccessGate/PolkitAgent";
+
+/// What polkit requests describe and the verifier never renders: keep
+/// them single-line and short.
+const MAX_TITLE_CHARS: usize = 120;
+const MAX_PATH_CHARS: usize = 300;
+const MAX_REASON_CHARS: usize = 80;
+
+/// A polkit identity or subject: `("unix-user", {"uid": <u32>})`.
+pub type Identity = (String, HashMap<String, OwnedValue>);
+
+/// Errors returned to polkitd (it shows `Cancelled` differently from a
+/// failed authentication).
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.freedesktop.PolicyKit1.Error")]
+pub enum Error {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Failed(String),
+    Cancelled(String),
+    NotAuthorized(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub socket: PathBuf,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Approved,
+    Denied(Option<String>),
+    TimedOut,
+    /// polkitd called `CancelAuthentication` (the dialog went away).
+    Cancelled,
+    Unavailable(String),
+}
+
+pub struct Agent {
+    opts: Arc<Options>,
+    /// Connections of requests in flight, by polkit cookie: cancelling
+    /// shuts one down, which the verifier records as cancelled.
+    pending: Arc<Mutex<HashMap<String, UnixStream>>>,
+}
+
+impl Agent {
+    pub fn new(opts: Options) -> Self {
+        Agent {
+            opts: Arc::new(opts),
+            pending: Arc::default(),
+        }
+    }
+}
+
+/// Refuse a call that does not come from polkitd: on a bus, the sender must
+/// be the unique name that owns `AUTHORITY` right now. A peer-to-peer
+/// connection has nobody on it but its peer.
+async fn from_authority(conn: &Connection, header: &Header<'_>) -> Result<(), Error> {
+    if conn.unique_name().is_none() {
+        return Ok(());
+    }
+    let owner = DBusProxy::new(conn)
+        .await?
+        .get_name_owner(WellKnownName::from_static_str_unchecked(AUTHORITY).into())
+        .await
+        .ok();
+    match (header.sender(), owner) {
+        (Some(sender), Some(owner)) if *sender == *owner => Ok(()),
+        (sender, _) => {
+            warn!("refusing a call from {sender:?}: not polkitd");
+            Err(Error::NotAuthorized(
+                "only polkitd may call the agent".into(),
+            ))
+        }
+    }
+}
+
+#[interface(name = "org.freedesktop.PolicyKit1.AuthenticationAgent")]
+impl Agent {
+    #[allow(clippy::too_many_arguments)]
+    async fn begin_authentication(
+        &self,
+        action_id: String,
+        message: String,
+        _icon_name: String,
+        details: HashMap<String, String>,
+        cookie: String,
+        identities: Vec<Identity>,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> Result<(), Error> {
+        from_authority(conn, &header).await?;
+        let identity = pick_identity(identities)
+            .ok_or_else(|| Error::Failed("no identity to authenticate as".into()))?;
+        let request = request(&action_id, &message, &details, self.opts.timeout_secs);
+        let (opts, pending, key) = (self.opts.clone(), self.pending.clone(), cookie.clone());
+        let decision = blocking::unblock(move || ask(&opts, &pending, &key, &request)).await;
+        info!("{action_id}: {decision:?}");
+        match decision {
+            Decision::Approved => {
+                let uid = unsafe { libc::getuid() };
+                conn.call_method(
+                    Some(AUTHORITY),
+                    AUTHORITY_PATH,
+                    Some(AUTHORITY_IFACE),
+                    "AuthenticationAgentResponse2",
+                    &(uid, &cookie, &identity),
+                )
+                .await?;
+                Ok(())
+            }
+            Decision::Denied(reason) => Err(Error::Failed(match reason {
+                Some(reason) => format!("denied on the phone: {reason}"),
+                None => "denied on the phone".into(),
+            })),
+            Decision::TimedOut => Err(Error::Failed("phone approval timed out".into())),
+            Decision::Cancelled => Err(Error::Cancelled("cancelled".into())),
+            Decision::Unavailable(e) => {
+                warn!("{action_id}: {e}");
+                Err(Error::Failed("phone approval service unavailable".into()))
+            }
+        }
+    }
+
+    async fn cancel_authentication(
+        &self,
+        cookie: String,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] header: Header<'_>,
+    ) -> Result<(), Error> {
+        from_authority(conn, &header).await?;
+        if let Some(stream) = self.pending.lock().unwrap().remove(&cookie) {
+            info!("authentication {cookie} cancelled");
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+}
+
+/// Register `AGENT_PATH` on `conn` as the agent for logind session
+/// `session`.
+pub fn register(
+    conn: &zbus::blocking::Connection,
+    session: &str,
+    locale: &str,
+) -> zbus::Result<()> {
+    let subject = (
+        "unix-session",
+        HashMap::from([("session-id", Variant::from(session))]),
+    );
+    conn.call_method(
+        Some(AUTHORITY),
+        AUTHORITY_PATH,
+        Some(AUTHORITY_IFACE),
+        "RegisterAuthenticationAgent",
+        &(
+            subject,
+            locale,
+            ObjectPath::from_static_str_unchecked(AGENT_PATH),
+        ),
+    )?;
+    Ok(())
+}
+
+/// The identity to vouch for: polkit lists who may authenticate (the user
+/// itself, or the admins); prefer a user over a group.
+fn pick_identity(identities: Vec<Identity>) -> Option<Identity> {
+    let first_user = identities.iter().position(|(kind, _)| kind == "unix-user");
+    identities.into_iter().nth(first_user.unwrap_or(0))
+}
+
+fn request(
+    action_id: &str,
+    message: &str,
+    details: &HashMap<String, String>,
+    timeout_secs: u64,
+) -> Value {
+    let mut details: Vec<String> = details.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    details.sort();
+    let mut path = message.to_string();
+    if !details.is_empty() {
+        path += &format!(" ({})", details.join(", "));
+    }
+    json!({
+        "exe": "polkit",
+        "path": clean(&path, MAX_PATH_CHARS),
+        "group": action_id,
+        "title": clean(message, MAX_TITLE_CHARS),
+        "timeout_secs": timeout_secs,
+    })
+}
+
+/// Send one request and block on its decision line, unless
+/// `CancelAuthentication` shuts the connection down first.
+fn ask(
+    opts: &Options,
+    pending: &Mutex<HashMap<String, UnixStream>>,
+    cookie: &str,
+    request: &Value,
+) -> Decision {
+    let unavailable = |what: &str, e: &dyn std::fmt::Display| {
+        Decision::Unavailable(format!("{what} {}: {e}", opts.socket.display()))
+    };
+    let mut stream = match UnixStream::connect(&opts.socket) {
+        Ok(s) => s,
+        Err(e) => return unavailable("connect", &e),
+    };
+    // The verifier times the request out itself; pad so we hear it say so.
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(opts.timeout_secs + 10)));
+    match stream.try_clone() {
+        Ok(clone) => pending.lock().unwrap().insert(cookie.to_string(), clone),
+        Err(e) => return unavailable("clone", &e),
+    };
+    let decision = exchange(&mut stream, request);
+    // Gone from `pending` already: CancelAuthentication took it.
+    if pending.lock().unwrap().remove(cookie).is_none() {
+        return Decision::Cancelled;
+    }
+    decision
+}
+
+fn exchange(stream: &mut UnixStream, request: &Value) -> Decision {
+    if let Err(e) = stream.write_all(format!("{request}\n").as_bytes()) {
+        return Decision::Unavailable(format!("write: {e}"));
+    }
+    let mut reader = BufReader::new(stream);
+    let mut next = || -> Option<Value> {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        serde_json::from_str(line.trim()).ok()
+    };
+    let Some(ack) = next() else {
+        return Decision::Unavailable("no answer from the verifier".into());
+    };
+    if let Some(error) = ack["error"].as_str() {
+        return Decision::Denied(Some(clean(error, MAX_REASON_CHARS)));
+    }
+    let rid = ack["id"].as_str().unwrap_or("?").to_string();
+    info!("approval request {rid} sent");
+    let Some(decision) = next() else {
+        return Decision::Unavailable(format!("verifier went away while waiting on {rid}"));
+    };
+    match decision["status"].as_str() {
+        Some("approved") => Decision::Approved,
+        Some("denied") => Decision::Denied(
+            decision["reason"]
+                .as_str()
+                .map(|r| clean(r, MAX_REASON_CHARS)),
+        ),
+        Some("timeout" | "cancelled" | "unknown") => Decision::TimedOut,
+        other => Decision::Unavailable(format!("unexpected status {other:?}")),
+    }
+}
+
+/// Printable, single-line, bounded: messages and details come from the
+/// action and the caller (pkexec puts its command line there).
+fn clean(raw: &str, max: usize) -> String {
+    access_gate_text::clean_text(raw, max).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use zbus::blocking::connection::Builder;
+    use zbus::Guid;
+
+    /// polkitd as far as the agent sees it: records registrations and
+    /// responses.
+    #[derive(Default, Clone)]
+    struct Authority {
+        registered: Arc<Mutex<Vec<(Identity, String, String)>>>,
+        responses: Arc<Mutex<Vec<(u32, String, Identity)>>>,
+    }
+
+    #[interface(name = "org.freedesktop.PolicyKit1.Authority")]
+    impl Authority {
+        fn register_authentication_agent(
+            &self,
+            subject: Identity,
+            locale: String,
+            object_path: ObjectPath<'_>,
+        ) {
+            let path = object_path.to_string();
+            self.registered
+                .lock()
+                .unwrap()
+                .push((subject, locale, path));
+        }
+
+        fn authentication_agent_response2(&self, uid: u32, cookie: String, identity: Identity) {
+            self.responses.lock().unwrap().push((uid, cookie, identity));
+        }
+    }
+
+    /// A private bus: polkitd's end serves `Authority`, the agent's end
+    /// serves `Agent`.
+    fn bus(
+        authority: &Authority,
+        opts: Options,
+    ) -> (zbus::blocking::Connection, zbus::blocking::Connection) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let served = authority.clone();
+        let polkitd = thread::spawn(move || {
+            Builder::async_io_unix_stream(a)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at(AUTHORITY_PATH, served)
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let agent = Builder::async_io_unix_stream(b)
+            .p2p()
+            .serve_at(AGENT_PATH, Agent::new(opts))
+            .unwrap()
+            .build()
+            .unwrap();
+        (polkitd.join().unwrap(), agent)
+    }
+
+    /// A private dbus-daemon and its address, where one is installed.
+    fn bus_daemon() -> Option<(Daemon, String)> {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(child.stdout.take()?)
+            .read_line(&mut address)
+            .ok()?;
+        let daemon = Daemon(child);
+        let address = address.trim();
+        (!address.is_empty()).then(|| (daemon, address.to_string()))
+    }
+
+    struct Daemon(Child);
+
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// A one-shot verifier: hands back the request line, then plays
+    /// `replies` and reports whether the agent hung up afterwards.
+    fn stand_in(
+        tag: &str,
+        replies: &'static [&'static str],
+    ) -> (Options, thread::JoinHandle<(Value, bool)>) {
+        let dir = std::env::temp_dir().join(format!("ag-polkit-{tag}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("ctrl.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            for reply in replies {
+                (&stream)
+                    .write_all(format!("{reply}\n").as_bytes())
+                    .unwrap();
+            }
+            let mut rest = String::new();
+            let hung_up = matches!(reader.read_line(&mut rest), Ok(0));
+            std::fs::remove_dir_all(&dir).unwrap();
+            (serde_json::from_str(&line).unwrap(), hung_up)
+        });
+        let opts = Options {
+            socket,
+            timeout_secs: 5,
+        };
+        (opts, server)
+    }
+
+    fn identity(kind: &str, key: &str, id: u32) -> Identity {
+        (
+            kind.into(),
+            HashMap::from([(key.into(), OwnedValue::from(id))]),
+        )
+    }
+
+    /// `BeginAuthentication` as polkitd sends it, to `agent` on a bus (its
+    /// unique name) or to the peer (`None`).
+    fn begin(
+        polkitd: &zbus::blocking::Connection,
+        agent: Option<&str>,
+        cookie: &str,
+    ) -> zbus::Result<()> {
+        let details = HashMap::from([("program", "/usr/bin/pacman\u{1b}[2J")]);
+        let identities = vec![
+            identity("unix-group", "gid", 10),
+            identity("unix-user", "uid", 0),
+        ];
+        polkitd.call_method(
+            agent,
+            AGENT_PATH,
+            Some("org.freedesktop.PolicyKit1.AuthenticationAgent"),
+            "BeginAuthentication",
+            &(
+                "org.freedesktop.packagekit.package-install",
+                "Install signed package",
+                "package-x-generic",
+                details,
+                cookie,
+                identities,
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn error_name(result: zbus::Result<()>) -> String {
+        match result {
+            Err(zbus::Error::MethodError(name, _, _)) => name.to_string(),
+            other => panic!("expected a polkit error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn registers_for_the_session() {
+        let authority = Authority::default();
+        let (_polkitd, agent) = bus(
+            &authority,
+            Options {
+                socket: "/nonexistent".into(),
+                timeout_secs: 5,
+            },
+        );
+        register(&agent, "c2", "de_DE.UTF-8").unwrap();
+        let registered = authority.registered.lock().unwrap();
+        let ((kind, subject), locale, path) = &registered[0];
+        assert_eq!(kind, "unix-session");
+        assert_eq!(
+            subject["session-id"],
+            OwnedValue::try_from(Variant::from("c2")).unwrap()
+        );
+        assert_eq!(locale, "de_DE.UTF-8");
+        assert_eq!(path, AGENT_PATH);
+    }
+
+    #[test]
+    fn approval_vouches_for_the_user_identity() {
+        let authority = Authority::default();
+        let (opts, verifier) = stand_in("ok", &[r#"{"id":"r1"}"#, r#"{"status":"approved"}"#]);
+        let (polkitd, _agent) = bus(&authority, opts);
+        begin(&polkitd, None, "cookie-1").unwrap();
+
+        let (request, _) = verifier.join().unwrap();
+        assert_eq!(
+            request,
+            json!({
+                "exe": "polkit",
+                "path": "Install signed package (program=/usr/bin/pacman[2J)",
+                "group": "org.freedesktop.packagekit.package-install",
+                "title": "Install signed package",
+                "timeout_secs": 5,
+            })
+        );
+        let responses = authority.responses.lock().unwrap();
+        assert_eq!(responses.len(), 1);
+        let (uid, cookie, identity) = &responses[0];
+        assert_eq!(*uid, unsafe { libc::getuid() });
+        assert_eq!(cookie, "cookie-1");
+        assert_eq!(identity.0, "unix-user");
+    }
+
+    #[test]
+    fn deny_timeout_and_outage_fail_the_authentication() {
+        let authority = Authority::default();
+        let (opts, verifier) = stand_in(
+            "deny",
+            &[
+                r#"{"id":"r2"}"#,
+                r#"{"status":"denied","reason":"Bedtime"}"#,
+            ],
+        );
+        let (polkitd, _agent) = bus(&authority, opts);
+        let failed = "org.freedesktop.PolicyKit1.Error.Failed";
+        assert_eq!(error_name(begin(&polkitd, None, "c")), failed);
+        verifier.join().unwrap();
+
+        let (opts, verifier) = stand_in("timeout", &[r#"{"id":"r3"}"#, r#"{"status":"timeout"}"#]);
+        let (polkitd, _agent) = bus(&authority, opts);
+        assert_eq!(error_name(begin(&polkitd, None, "c")), failed);
+        verifier.join().unwrap();
+
+        let opts = Options {
+            socket: "/nonexistent/ctrl.sock".into(),
+            timeout_secs: 5,
+        };
+        let (polkitd, _agent) = bus(&authority, opts);
+        assert_eq!(error_name(begin(&polkitd, None, "c")), failed);
+        assert!(authority.responses.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancel_hangs_up_on_the_verifier() {
+        let authority = Authority::default();
+        // Acknowledges, then never decides.
+        let (opts, verifier) = stand_in("cancel", &[r#"{"id":"r4"}"#]);
+        let (polkitd, _agent) = bus(&authority, opts);
+        let waiting = {
+            let polkitd = polkitd.clone();
+            thread::spawn(move || begin(&polkitd, None, "cookie-4"))
+        };
+        // The verifier has the request line once the pending entry exists.
+        thread::sleep(Duration::from_millis(300));
+        polkitd
+            .call_method(
+                None::<&str>,
+                AGENT_PATH,
+                Some("org.freedesktop.PolicyKit1.AuthenticationAgent"),
+                "CancelAuthentication",
+                &("cookie-4",),
+            )
+            .unwrap();
+        assert_eq!(
+            error_name(waiting.join().unwrap()),
+            "org.freedesktop.PolicyKit1.Error.Cancelled"
+        );
+        let (_, hung_up) = verifier.join().unwrap();
+        assert!(hung_up, "the verifier should see the request cancelled");
+        assert!(authority.responses.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_polkitd_may_call_the_agent_on_a_bus() {
+        // Needs a real bus for senders and name owners.
+        let Some((_daemon, address)) = bus_daemon() else {
+            return;
+        };
+        let connect = || Builder::address(address.as_str()).unwrap();
+        let authority = Authority::default();
+        let (opts, verifier) = stand_in("bus", &[r#"{"id":"r5"}"#, r#"{"status":"approved"}"#]);
+        let polkitd = connect()
+            .serve_at(AUTHORITY_PATH, authority.clone())
+            .unwrap()
+            .name(AUTHORITY)
+            .unwrap()
+            .build()
+            .unwrap();
+        let agent = connect()
+            .serve_at(AGENT_PATH, Agent::new(opts))
+            .unwrap()
+            .build()
+            .unwrap();
+        let agent_name = agent.unique_name().unwrap().to_string();
+
+        // Anyone else on the bus: refused before the verifier hears of it.
+        let intruder = connect().build().unwrap();
+        assert_eq!(
+            error_name(begin(&intruder, Some(&agent_name), "cookie-5")),
+            "org.freedesktop.PolicyKit1.Error.NotAuthorized"
+        );
+
+        begin(&polkitd, Some(&agent_name), "cookie-6").unwrap();
+        let (request, _) = verifier.join().unwrap();
+        assert_eq!(
+            request["group"],
+            "org.freedesktop.packagekit.package-install"
+        );
+        let responses = authority.responses.lock().unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].1, "cookie-6");
+    }
+}
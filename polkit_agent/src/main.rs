@@ -0,0 +1,70 @@
+//! access-gate-polkit-agent: a polkit authentication agent that asks the
+//! phone instead of prompting for a password, so installs, mounts and other
+//! privileged desktop actions in the game session need a passkey tap.
+//!
+//! ```text
+//! access-gate-polkit-agent [--session ID] [--socket PATH] [--timeout SECS]
+//! ```
+//!
+//! `--session` defaults to `$XDG_SESSION_ID`, `--socket` to
+//! `$AG_CTRL_SOCKET` or the verifier's default path. It must run as root
+//! (`access-gate-polkit-agent@<session>.service`).
+
+mod agent;
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+use agent::{Agent, Options, AGENT_PATH};
+
+const DEFAULT_SOCKET: &str = "/run/access-gate/ctrl.sock";
+const DEFAULT_TIMEOUT: u64 = 60;
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .without_time()
+        .init();
+
+    let mut session = std::env::var("XDG_SESSION_ID").ok();
+    let mut opts = Options {
+        socket: std::env::var("AG_CTRL_SOCKET")
+            .unwrap_or_else(|_| DEFAULT_SOCKET.into())
+            .into(),
+        timeout_secs: DEFAULT_TIMEOUT,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--session" => session = Some(value()?),
+            "--socket" => opts.socket = PathBuf::from(value()?),
+            "--timeout" => {
+                opts.timeout_secs = value()?
+                    .parse()
+                    .with_context(|| format!("{arg}: not a number"))?
+            }
+            _ => bail!("unknown argument {arg:?}"),
+        }
+    }
+    let Some(session) = session else {
+        bail!("no session: pass --session or set XDG_SESSION_ID");
+    };
+    let locale = std::env::var("LANG").unwrap_or_else(|_| "C".into());
+
+    let conn = zbus::blocking::connection::Builder::system()?
+        .serve_at(AGENT_PATH, Agent::new(opts))?
+        .build()
+        .context("connect to the system bus")?;
+    agent::register(&conn, &session, &locale)
+        .with_context(|| format!("register as the polkit agent for session {session}"))?;
+    info!("polkit agent registered for session {session}");
+    // polkitd drops the registration when this connection closes.
+    loop {
+        std::thread::park();
+    }
+}
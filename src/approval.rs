@@ -6,16 +6,56 @@
 //! `{"status":..}` once the phone decides — no polling, no TCP. Progress is
 //! logged (the cage greeter has no banner channel). Fail-closed: every error
 //! path keeps us at the greeter.
+//!
+//! A deny may carry the parent's reason ("homework first"); it is cleaned of
+//! anything a terminal would interpret before it is logged or shown. Every
+//! outcome also lands in a JSONL audit log (`AG_AUDIT_LOG`, default
+//! `<greetd dir>/logs/approval-audit.jsonl`).
+//!
+//! The verifier journals pending requests, so a connection that drops while
+//! waiting (verifier restart) is re-established with `{"resume":"<id>"}`
+//! until the wait would have ended anyway.
+//!
+//! With the local verifier down or not answering (`unreachable`), a
+//! button-sequence PIN on the pad can stand in for the phone when one is
+//! configured (see `fallback`); each try is its own audit line. A request
+//! the verifier took but could not deliver (tailscale or push down) just
+//! times out, and one it took and then never answered (resumes included)
+//! is refused as `no-decision`, without the PIN.
+//!
+//! Each request says where it comes from (see `RequestContext`): the box,
+//! the profile, the pad that pressed Guide, the VT. The verifier shows it
+//! on the approve page and in the notification.
+//!
+//! Decisions are signed. Each request carries a fresh nonce, and the
+//! verifier signs `(id, nonce, status, expiry)` with its decision key; the
+//! public half is pinned in approval.env (`AG_DECISION_PUBKEY`, written by
+//! `game-mode setup`). An unsigned, expired or mismatched answer, or no
+//! pinned key at all, refuses entry as `unverified`: whatever is listening
+//! on the socket path cannot approve by itself.
 
 use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::net::UnixStream;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use gilrs::Button;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand::RngCore;
+use serde::Serialize;
 use serde_json::Value;
 use tracing::{info, warn};
 
+use crate::fallback::{self, Attempts, Outcome, PinHash};
+
 const ENV_FILE: &str = "/etc/game-mode/approval.env";
 const DEFAULT_SOCKET: &str = "/run/access-gate/ctrl.sock";
 
@@ -23,6 +63,33 @@ struct Cfg {
     socket: String,
     timeout_secs: u64,
     disabled: bool,
+    audit_log: PathBuf,
+    /// The verifier's decision key (SEC1 point, base64url).
+    decision_key: Option<String>,
+}
+
+/// What the phone is shown about this entry besides "Enter game mode?".
+/// Fields we can't tell are left out; the verifier caps and escapes the
+/// rest, so nothing here needs cleaning.
+#[derive(Debug, Default, Serialize)]
+pub struct RequestContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// The account the game session logs in as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The pad that pressed Guide.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+    /// That pad's battery in percent; none when wired or unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vt: Option<u32>,
+    /// Seconds since this daemon last saw a user session on the VT; none
+    /// before it has seen one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_last_session: Option<u64>,
 }
 
 /// AG_* settings: process environment wins (systemd EnvironmentFile), with
@@ -51,9 +118,21 @@ fn load_cfg() -> Cfg {
         disabled: get("AG_DISABLED")
             .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
             .unwrap_or(false),
+        audit_log: get("AG_AUDIT_LOG")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_audit_log),
+        decision_key: get("AG_DECISION_PUBKEY").filter(|v| !v.is_empty()),
     }
 }
 
+fn default_audit_log() -> PathBuf {
+    crate::config::Config::load()
+        .map(|c| c.get_greetd_dir())
+        .unwrap_or_else(|_| PathBuf::from("/etc/greetd"))
+        .join("logs")
+        .join("approval-audit.jsonl")
+}
+
 /// Approval progress feedback. The cage/regreet greeter has no banner
 /// channel (the retired Hyprland greeter took hyprctl notify), so this is
 /// log-only; the call sites stay so a future greeter can surface them again.
@@ -67,20 +146,169 @@ fn read_json_line(reader: &mut BufReader<UnixStream>) -> Option<Value> {
     serde_json::from_str(line.trim()).ok()
 }
 
+/// Same bytes as the verifier's `decision::message`.
+fn decision_message(rid: &str, nonce: &str, status: &str, expires: u64, reason: &str) -> String {
+    format!("access-gate-decision-v2\n{rid}\n{nonce}\n{status}\n{expires}\n{reason}")
+}
+
+fn decision_key(cfg: &Cfg) -> Option<VerifyingKey> {
+    let point = URL_SAFE_NO_PAD.decode(cfg.decision_key.as_deref()?).ok()?;
+    VerifyingKey::from_sec1_bytes(&point).ok()
+}
+
+fn new_nonce() -> String {
+    let mut bytes = [0u8; 18];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Is `decision` the verifier's answer to our request `rid` with `nonce`?
+/// The nonce is fresh per request, so a line saved from an earlier one
+/// does not verify. The signature covers the reason too: it is shown and
+/// audited as the verifier's.
+fn verify_decision(
+    key: &VerifyingKey,
+    rid: &str,
+    nonce: &str,
+    decision: &Value,
+    now: u64,
+) -> Result<(), &'static str> {
+    let status = decision["status"].as_str().ok_or("no status")?;
+    let reason = decision["reason"].as_str().unwrap_or("");
+    let expires = decision["expires"].as_u64().ok_or("unsigned")?;
+    let sig = decision["sig"].as_str().ok_or("unsigned")?;
+    let sig = URL_SAFE_NO_PAD
+        .decode(sig)
+        .ok()
+        .and_then(|s| Signature::from_slice(&s).ok())
+        .ok_or("malformed signature")?;
+    if now > expires {
+        return Err("expired");
+    }
+    key.verify(
+        decision_message(rid, nonce, status, expires, reason).as_bytes(),
+        &sig,
+    )
+    .map_err(|_| "bad signature")
+}
+
+/// The decision line for `rid`, reconnecting with `resume` whenever the
+/// connection drops before `deadline` (the verifier restarted mid-wait).
+fn read_decision(
+    cfg: &Cfg,
+    mut reader: BufReader<UnixStream>,
+    rid: &str,
+    nonce: &str,
+    deadline: Instant,
+) -> Option<Value> {
+    loop {
+        if let Some(decision) = read_json_line(&mut reader) {
+            return Some(decision);
+        }
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())?;
+        warn!("verifier connection lost while waiting on {rid}; resuming");
+        thread::sleep(Duration::from_secs(1).min(remaining));
+        let Ok(mut stream) = UnixStream::connect(&cfg.socket) else {
+            continue;
+        };
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())?;
+        let _ = stream.set_read_timeout(Some(remaining));
+        let resume = serde_json::json!({ "resume": rid, "nonce": nonce });
+        if stream
+            .write_all(format!("{resume}\n").as_bytes())
+            .and_then(|()| stream.flush())
+            .is_err()
+        {
+            continue;
+        }
+        reader = BufReader::new(stream);
+    }
+}
+
+/// Wrong fallback PINs, kept for the life of the daemon.
+static FALLBACK_ATTEMPTS: Mutex<Attempts> = Mutex::new(Attempts::new());
+
 /// Block on a phone passkey approval before entering game mode. Returns true
-/// only on an approved decision; deny/timeout/verifier-down all return false
-/// (fail-closed: stay at the greeter).
-pub fn require_approval() -> bool {
+/// only on an approved decision, or an accepted fallback PIN read through
+/// `pad` while the verifier is unreachable (no socket, no ack);
+/// deny/timeout/no decision after the ack all return false (fail-closed:
+/// stay at the greeter). Every outcome is audited.
+pub fn require_approval(
+    context: &RequestContext,
+    pad: Option<&mut dyn FnMut(Instant) -> Option<Button>>,
+) -> bool {
     let cfg = load_cfg();
+    let (rid, outcome, reason) = ask(&cfg, context);
+    audit(&cfg, rid.as_deref(), outcome, reason.as_deref());
+    if outcome == "unreachable" {
+        if let (Some(pad), Some(pin)) = (pad, PinHash::load()) {
+            let outcome = offline_fallback(&pin, pad);
+            audit(&cfg, None, outcome, None);
+            return outcome == "fallback";
+        }
+    }
+    matches!(outcome, "approved" | "disabled")
+}
+
+/// One PIN try on the pad; the audit outcome.
+fn offline_fallback(pin: &PinHash, pad: &mut dyn FnMut(Instant) -> Option<Button>) -> &'static str {
+    warn!("verifier unreachable; offering the fallback PIN (Start submits, Select cancels)");
+    notify(
+        1,
+        30_000,
+        "Phone approval unavailable — enter the fallback PIN, then Start",
+    );
+    let mut attempts = FALLBACK_ATTEMPTS.lock().unwrap();
+    match fallback::attempt(pin, &mut attempts, pad, Instant::now()) {
+        Outcome::Accepted => {
+            warn!("game-mode entry approved by the fallback PIN");
+            notify(5, 3000, "PIN accepted — entering game mode");
+            "fallback"
+        }
+        Outcome::Rejected => {
+            warn!("wrong fallback PIN");
+            notify(3, 6000, "Wrong PIN");
+            "fallback-rejected"
+        }
+        Outcome::LockedOut => {
+            warn!("fallback PIN locked after repeated wrong entries");
+            notify(3, 8000, "PIN locked — try again later");
+            "fallback-locked"
+        }
+        Outcome::Abandoned => {
+            info!("fallback PIN entry abandoned");
+            "fallback-abandoned"
+        }
+    }
+}
 
+/// The gate itself: (request id, outcome, reason).
+fn ask(cfg: &Cfg, context: &RequestContext) -> (Option<String>, &'static str, Option<String>) {
     // Explicit opt-out (AG_DISABLED=1 in approval.env): skip the phone push
     // entirely. This is the ONLY path that grants entry without the phone —
     // every failure below still refuses (fail-closed).
     if cfg.disabled {
         warn!("approval gate DISABLED (AG_DISABLED) — entering game mode without phone approval");
-        return true;
+        return (None, "disabled", None);
     }
 
+    let Some(key) = decision_key(cfg) else {
+        warn!("no valid AG_DECISION_PUBKEY pinned (run game-mode setup); refusing game-mode entry");
+        notify(3, 8000, "Game mode: approval service not set up");
+        return (None, "unverified", None);
+    };
+    let nonce = new_nonce();
+
     info!("requesting phone approval to enter game mode...");
     let Ok(mut stream) = UnixStream::connect(&cfg.socket) else {
         warn!(
@@ -88,10 +316,11 @@ pub fn require_approval() -> bool {
             cfg.socket
         );
         notify(3, 8000, "Game mode: approval service unreachable");
-        return false;
+        return (None, "unreachable", None);
     };
     // The verifier answers the final status itself after at most timeout_secs;
     // pad the read timeout so we always get its answer rather than racing it.
+    let deadline = Instant::now() + Duration::from_secs(cfg.timeout_secs + 10);
     let _ = stream.set_read_timeout(Some(Duration::from_secs(cfg.timeout_secs + 10)));
 
     let request = serde_json::json!({
@@ -100,6 +329,8 @@ pub fn require_approval() -> bool {
         "group": "login",
         "title": "Enter game mode?",
         "timeout_secs": cfg.timeout_secs,
+        "nonce": nonce,
+        "context": context,
     });
     if stream
         .write_all(format!("{request}\n").as_bytes())
@@ -108,7 +339,7 @@ pub fn require_approval() -> bool {
     {
         warn!("failed to send approval request; refusing game-mode entry");
         notify(3, 8000, "Game mode: approval service unreachable");
-        return false;
+        return (None, "unreachable", None);
     }
 
     let mut reader = BufReader::new(stream);
@@ -116,33 +347,52 @@ pub fn require_approval() -> bool {
     let Some(ack) = read_json_line(&mut reader) else {
         warn!("no ack from verifier; refusing game-mode entry");
         notify(3, 8000, "Game mode: approval service unreachable");
-        return false;
+        return (None, "unreachable", None);
     };
-    info!(
-        "approval request {} created; awaiting the phone",
-        ack["id"].as_str().unwrap_or("?")
-    );
+    let rid = ack["id"].as_str().unwrap_or("?").to_string();
+    info!("approval request {rid} created; awaiting the phone");
     notify(
         1,
         cfg.timeout_secs * 1000,
         "Approval sent to your phone — confirm with fingerprint",
     );
 
-    let Some(decision) = read_json_line(&mut reader) else {
+    // Acknowledged: the request exists, so losing the verifier from here on
+    // is not "unreachable" and does not open the fallback PIN.
+    let Some(decision) = read_decision(cfg, reader, &rid, &nonce, deadline) else {
         warn!("no decision from verifier; refusing game-mode entry");
-        notify(3, 8000, "Game mode: approval service unreachable");
-        return false;
+        notify(3, 8000, "Game mode: no answer from the approval service");
+        return (Some(rid), "no-decision", None);
     };
-    match decision["status"].as_str().unwrap_or("") {
+    if let Err(why) = verify_decision(&key, &rid, &nonce, &decision, unix_now()) {
+        warn!("decision for {rid} failed verification ({why}); refusing game-mode entry");
+        notify(3, 8000, "Game mode: approval could not be verified");
+        return (Some(rid), "unverified", None);
+    }
+    // Typed on the phone: never trusted as-is on the VT or in the logs.
+    let reason = decision["reason"].as_str().and_then(clean_reason);
+    let outcome = match decision["status"].as_str().unwrap_or("") {
         "approved" => {
-            info!("game-mode entry approved");
+            match &reason {
+                // A standing "approve for N minutes" grant, not a fresh tap.
+                Some(reason) => info!("game-mode entry approved ({reason})"),
+                None => info!("game-mode entry approved"),
+            }
             notify(5, 3000, "Approved — entering game mode");
-            true
+            "approved"
         }
         "denied" => {
-            warn!("game-mode entry denied");
-            notify(3, 6000, "Game mode entry denied");
-            false
+            match &reason {
+                Some(reason) => {
+                    warn!("game-mode entry denied: {reason}");
+                    notify(3, 8000, &format!("Game mode entry denied: {reason}"));
+                }
+                None => {
+                    warn!("game-mode entry denied");
+                    notify(3, 6000, "Game mode entry denied");
+                }
+            }
+            "denied"
         }
         "timeout" | "expired" | "unknown" => {
             warn!("approval timed out");
@@ -151,12 +401,220 @@ pub fn require_approval() -> bool {
                 6000,
                 "Approval timed out — press the Guide button to retry",
             );
-            false
+            "timeout"
         }
         other => {
             warn!("unexpected approval status {other:?}");
             notify(3, 6000, "Game mode: unexpected approval response");
-            false
+            "error"
         }
+    };
+    (Some(rid), outcome, reason)
+}
+
+/// Longest reason shown or logged, in characters.
+const MAX_REASON_CHARS: usize = 80;
+
+/// A reason from the verifier as it may reach the VT and the logs, cleaned
+/// with `access_gate_text::clean_text`. The verifier does the same; this
+/// side does not rely on it.
+fn clean_reason(raw: &str) -> Option<String> {
+    access_gate_text::clean_text(raw, MAX_REASON_CHARS)
+}
+
+/// Append one JSON line per gate outcome to the audit log (next to the
+/// daemon log by default). The verifier's history says who decided; this
+/// says what the TV side did with it. Best-effort: a full disk must not
+/// change the decision.
+fn audit(cfg: &Cfg, rid: Option<&str>, outcome: &str, reason: Option<&str>) {
+    let ts = unix_now();
+    let line = serde_json::json!({
+        "ts": ts,
+        "rid": rid,
+        "outcome": outcome,
+        "reason": reason,
+    });
+    let written = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(&cfg.audit_log)
+        .and_then(|mut f| f.write_all(format!("{line}\n").as_bytes()));
+    if let Err(e) = written {
+        warn!("audit log {}: {e}", cfg.audit_log.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::tests::tmp_dir;
+
+    #[test]
+    fn reasons_are_safe_for_the_vt() {
+        assert_eq!(
+            clean_reason("\u{1b}]0;pwned\u{7}homework\r\nfirst").as_deref(),
+            Some("]0;pwnedhomework first")
+        );
+        assert_eq!(clean_reason("\u{2066}x\u{2069}").as_deref(), Some("x"));
+        assert_eq!(clean_reason("\u{9b}\u{0}"), None);
+        assert_eq!(
+            clean_reason(&"n".repeat(500)).unwrap().len(),
+            MAX_REASON_CHARS
+        );
+    }
+
+    #[test]
+    fn decisions_must_be_signed_for_this_request() {
+        use p256::ecdsa::signature::Signer;
+        use p256::ecdsa::SigningKey;
+
+        let signing = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let point = signing.verifying_key().to_encoded_point(false);
+        let cfg = Cfg {
+            socket: String::new(),
+            timeout_secs: 1,
+            disabled: false,
+            audit_log: PathBuf::new(),
+            decision_key: Some(URL_SAFE_NO_PAD.encode(point.as_bytes())),
+        };
+        let key = decision_key(&cfg).unwrap();
+        let signed = |rid: &str, nonce: &str, status: &str, expires: u64| {
+            let sig: Signature =
+                signing.sign(decision_message(rid, nonce, status, expires, "").as_bytes());
+            serde_json::json!({
+                "status": status,
+                "expires": expires,
+                "sig": URL_SAFE_NO_PAD.encode(sig.to_bytes()),
+            })
+        };
+        let nonce = "AAAAAAAAAAAAAAAAAAAAAAAA";
+        let good = signed("rid1", nonce, "approved", 1060);
+        assert_eq!(verify_decision(&key, "rid1", nonce, &good, 1000), Ok(()));
+        // Replayed into another request, or another request's answer.
+        assert!(verify_decision(&key, "rid1", "BBBBBBBBBBBBBBBBBBBBBBBB", &good, 1000).is_err());
+        assert!(verify_decision(&key, "rid2", nonce, &good, 1000).is_err());
+        assert_eq!(
+            verify_decision(&key, "rid1", nonce, &good, 1061),
+            Err("expired")
+        );
+        let mut tampered = signed("rid1", nonce, "denied", 1060);
+        tampered["status"] = "approved".into();
+        assert_eq!(
+            verify_decision(&key, "rid1", nonce, &tampered, 1000),
+            Err("bad signature")
+        );
+        // A reason slipped into a signed line, or changed, is not the
+        // verifier's.
+        let mut annotated = signed("rid1", nonce, "denied", 1060);
+        annotated["reason"] = "Go ahead, just this once".into();
+        assert_eq!(
+            verify_decision(&key, "rid1", nonce, &annotated, 1000),
+            Err("bad signature")
+        );
+        let sig: Signature = signing
+            .sign(decision_message("rid1", nonce, "denied", 1060, "Homework first").as_bytes());
+        let mut with_reason = serde_json::json!({
+            "status": "denied",
+            "reason": "Homework first",
+            "expires": 1060,
+            "sig": URL_SAFE_NO_PAD.encode(sig.to_bytes()),
+        });
+        assert_eq!(
+            verify_decision(&key, "rid1", nonce, &with_reason, 1000),
+            Ok(())
+        );
+        with_reason["reason"] = "Bedtime".into();
+        assert!(verify_decision(&key, "rid1", nonce, &with_reason, 1000).is_err());
+
+        let mut extended = good.clone();
+        extended["expires"] = 9999.into();
+        assert!(verify_decision(&key, "rid1", nonce, &extended, 1000).is_err());
+        let unsigned = serde_json::json!({ "status": "approved" });
+        assert_eq!(
+            verify_decision(&key, "rid1", nonce, &unsigned, 1000),
+            Err("unsigned")
+        );
+
+        assert!(decision_key(&Cfg {
+            decision_key: Some("nope".into()),
+            ..cfg
+        })
+        .is_none());
+        // Within what the verifier accepts (base64url, 16-64 chars).
+        assert_eq!(new_nonce().len(), 24);
+    }
+
+    #[test]
+    fn context_leaves_out_what_is_unknown() {
+        let context = RequestContext {
+            hostname: Some("den-pc".into()),
+            vt: Some(1),
+            ..RequestContext::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&context).unwrap(),
+            serde_json::json!({ "hostname": "den-pc", "vt": 1 })
+        );
+    }
+
+    #[test]
+    fn silence_after_the_ack_is_no_decision_not_unreachable() {
+        use p256::ecdsa::SigningKey;
+        use std::os::unix::net::UnixListener;
+
+        let dir = tmp_dir("no-decision");
+        let socket = dir.join("ctrl.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        // Takes the request, acks it, then goes away for good.
+        let stand_in = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            (&stream).write_all(b"{\"id\":\"r1\"}\n").unwrap();
+        });
+        let point = SigningKey::from_slice(&[7u8; 32])
+            .unwrap()
+            .verifying_key()
+            .to_encoded_point(false);
+        let cfg = Cfg {
+            socket: socket.display().to_string(),
+            timeout_secs: 0,
+            disabled: false,
+            audit_log: dir.join("audit.jsonl"),
+            decision_key: Some(URL_SAFE_NO_PAD.encode(point.as_bytes())),
+        };
+        let (rid, outcome, reason) = ask(&cfg, &RequestContext::default());
+        stand_in.join().unwrap();
+        assert_eq!(rid.as_deref(), Some("r1"));
+        // Not "unreachable": that would offer the fallback PIN.
+        assert_eq!(outcome, "no-decision");
+        assert_eq!(reason, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn audit_appends_json_lines() {
+        let dir = tmp_dir("audit");
+        let cfg = Cfg {
+            socket: String::new(),
+            timeout_secs: 1,
+            disabled: false,
+            audit_log: dir.join("audit.jsonl"),
+            decision_key: None,
+        };
+        audit(&cfg, Some("abc"), "denied", Some("Bedtime"));
+        audit(&cfg, None, "unreachable", None);
+        let text = fs::read_to_string(&cfg.audit_log).unwrap();
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["rid"], "abc");
+        assert_eq!(lines[0]["reason"], "Bedtime");
+        assert_eq!(lines[1]["outcome"], "unreachable");
+        assert!(lines[1]["reason"].is_null());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -153,10 +153,18 @@ impl Config {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::io::Write;
 
+    /// A fresh scratch directory for one test, shared by the daemon's tests.
+    pub(crate) fn tmp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("game-mode-{tag}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
     #[test]
     fn defaults_when_file_missing() {
         let config = Config::load_from("/nonexistent/game-mode-config.toml").unwrap();
@@ -168,8 +176,7 @@ mod tests {
 
     #[test]
     fn file_overrides_defaults() {
-        let dir = std::env::temp_dir().join(format!("game-mode-cfg-test-{}", std::process::id()));
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = tmp_dir("cfg-test");
         let path = dir.join("config.toml");
         let mut f = std::fs::File::create(&path).unwrap();
         writeln!(
@@ -188,8 +195,7 @@ mod tests {
 
     #[test]
     fn partial_file_keeps_defaults() {
-        let dir = std::env::temp_dir().join(format!("game-mode-cfg-part-{}", std::process::id()));
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = tmp_dir("cfg-part");
         let path = dir.join("config.toml");
         std::fs::write(&path, "[session]\nuser = \"couch\"\n").unwrap();
 
@@ -202,8 +208,7 @@ mod tests {
 
     #[test]
     fn garbage_file_is_an_error() {
-        let dir = std::env::temp_dir().join(format!("game-mode-cfg-bad-{}", std::process::id()));
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = tmp_dir("cfg-bad");
         let path = dir.join("config.toml");
         std::fs::write(&path, "not [ valid toml").unwrap();
         assert!(Config::load_from(path.to_str().unwrap()).is_err());
@@ -0,0 +1,339 @@
+//! Offline fallback for the approval gate: a button-sequence PIN entered on
+//! the pad, accepted only when the local verifier is down or not answering
+//! (no control socket, or no ack to the request). It does not cover
+//! tailscale or the push service being down: the verifier still takes the
+//! request, and it stays pending until it times out. `AG_DISABLED` drops the
+//! gate altogether.
+//!
+//! The PIN is kept as a salted PBKDF2-HMAC-SHA256 hash in a root-only file
+//! (`/etc/game-mode/fallback-pin`, written by `game-mode setup --fallback-pin`).
+//! The daemon runs as greeter, so systemd hands it over as a credential
+//! (`LoadCredential=` in game-mode.service); a manual run as root reads the
+//! file itself. No file, no fallback.
+//!
+//! Entry: face buttons, d-pad and shoulders make up the sequence, Start
+//! submits, Select or Guide gives up. `MAX_FAILURES` wrong sequences lock
+//! the fallback for `LOCKOUT`; the count lives in the daemon, which the
+//! pad cannot restart.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use gilrs::Button;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const PIN_FILE: &str = "/etc/game-mode/fallback-pin";
+/// The credential id in game-mode.service.
+const PIN_CREDENTIAL: &str = "fallback-pin";
+const ITERATIONS: u32 = 600_000;
+/// Shortest sequence `game-mode setup` accepts.
+pub const MIN_LEN: usize = 4;
+/// Longest sequence read from the pad; anything longer is a wrong PIN.
+const MAX_LEN: usize = 16;
+/// Time to key in one sequence.
+pub const ENTRY_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_FAILURES: u32 = 3;
+pub const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Pad buttons that may appear in a PIN, with the names setup takes.
+const KEYS: &[(&str, Button)] = &[
+    ("A", Button::South),
+    ("B", Button::East),
+    ("X", Button::West),
+    ("Y", Button::North),
+    ("Up", Button::DPadUp),
+    ("Down", Button::DPadDown),
+    ("Left", Button::DPadLeft),
+    ("Right", Button::DPadRight),
+    ("LB", Button::LeftTrigger),
+    ("RB", Button::RightTrigger),
+    ("LT", Button::LeftTrigger2),
+    ("RT", Button::RightTrigger2),
+    ("L3", Button::LeftThumb),
+    ("R3", Button::RightThumb),
+];
+
+fn key_name(button: Button) -> Option<&'static str> {
+    KEYS.iter().find(|(_, b)| *b == button).map(|(n, _)| *n)
+}
+
+/// A sequence as typed for setup ("A A Up X"), case-insensitive.
+pub fn parse_sequence(text: &str) -> Result<Vec<Button>> {
+    let buttons = text
+        .split_whitespace()
+        .map(|word| {
+            KEYS.iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(word))
+                .map(|(_, b)| *b)
+                .with_context(|| {
+                    let names: Vec<_> = KEYS.iter().map(|(n, _)| *n).collect();
+                    format!("unknown button {word:?} (use {})", names.join(" "))
+                })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !(MIN_LEN..=MAX_LEN).contains(&buttons.len()) {
+        bail!("a PIN is {MIN_LEN} to {MAX_LEN} buttons");
+    }
+    Ok(buttons)
+}
+
+/// The stored form: `pbkdf2-sha256$<iterations>$<salt>$<hash>` (base64).
+pub struct PinHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl PinHash {
+    pub fn new(buttons: &[Button]) -> Self {
+        let mut salt = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        Self::with(buttons, salt, ITERATIONS)
+    }
+
+    fn with(buttons: &[Button], salt: Vec<u8>, iterations: u32) -> Self {
+        let hash = pbkdf2_sha256(sequence_bytes(buttons).as_bytes(), &salt, iterations);
+        PinHash {
+            iterations,
+            salt,
+            hash,
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.trim().split('$');
+        if parts.next()? != "pbkdf2-sha256" {
+            return None;
+        }
+        let iterations = parts.next()?.parse().ok().filter(|&n| n > 0)?;
+        let salt = STANDARD_NO_PAD.decode(parts.next()?).ok()?;
+        let hash = STANDARD_NO_PAD
+            .decode(parts.next()?)
+            .ok()?
+            .try_into()
+            .ok()?;
+        parts.next().is_none().then_some(PinHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    pub fn to_line(&self) -> String {
+        format!(
+            "pbkdf2-sha256${}${}${}",
+            self.iterations,
+            STANDARD_NO_PAD.encode(&self.salt),
+            STANDARD_NO_PAD.encode(self.hash)
+        )
+    }
+
+    pub fn matches(&self, buttons: &[Button]) -> bool {
+        let hash = pbkdf2_sha256(
+            sequence_bytes(buttons).as_bytes(),
+            &self.salt,
+            self.iterations,
+        );
+        hash.iter()
+            .zip(self.hash)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+
+    /// The configured PIN: the systemd credential, else the file itself.
+    /// An empty credential (the unit's default) means none is set.
+    pub fn load() -> Option<Self> {
+        let path = std::env::var_os("CREDENTIALS_DIRECTORY")
+            .map(|dir| PathBuf::from(dir).join(PIN_CREDENTIAL))
+            .unwrap_or_else(|| PathBuf::from(PIN_FILE));
+        let text = fs::read_to_string(path).ok()?;
+        if text.trim().is_empty() {
+            return None;
+        }
+        let pin = PinHash::parse(&text);
+        if pin.is_none() {
+            tracing::warn!("fallback PIN is unreadable; re-run game-mode setup --fallback-pin");
+        }
+        pin
+    }
+}
+
+fn sequence_bytes(buttons: &[Button]) -> String {
+    buttons
+        .iter()
+        .map(|&b| key_name(b).unwrap_or("?"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// PBKDF2-HMAC-SHA256 (RFC 8018), one output block.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let prf = Hmac::<Sha256>::new_from_slice(password).expect("HMAC takes any key length");
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+    let mut out = u;
+    for _ in 1..iterations {
+        let mut mac = prf.clone();
+        mac.update(&u);
+        u = mac.finalize().into_bytes().into();
+        out.iter_mut().zip(u).for_each(|(o, x)| *o ^= x);
+    }
+    out
+}
+
+/// Wrong tries so far and the lockout they earned.
+pub struct Attempts {
+    failures: u32,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    pub const fn new() -> Self {
+        Attempts {
+            failures: 0,
+            locked_until: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+    /// Too many wrong sequences; nothing was read.
+    LockedOut,
+    /// Select/Guide, or nothing submitted in time.
+    Abandoned,
+}
+
+/// Read one sequence with `next_button` (None once `deadline` passes) and
+/// check it against `pin`.
+pub fn attempt(
+    pin: &PinHash,
+    attempts: &mut Attempts,
+    next_button: &mut dyn FnMut(Instant) -> Option<Button>,
+    now: Instant,
+) -> Outcome {
+    if attempts.locked_until.is_some_and(|t| now < t) {
+        return Outcome::LockedOut;
+    }
+    let deadline = now + ENTRY_TIMEOUT;
+    let mut entered = Vec::new();
+    loop {
+        match next_button(deadline) {
+            None | Some(Button::Select | Button::Mode) => return Outcome::Abandoned,
+            Some(Button::Start) => break,
+            Some(button) if entered.len() <= MAX_LEN => entered.push(button),
+            Some(_) => {}
+        }
+    }
+    if pin.matches(&entered) {
+        *attempts = Attempts::new();
+        return Outcome::Accepted;
+    }
+    attempts.failures += 1;
+    if attempts.failures >= MAX_FAILURES {
+        attempts.failures = 0;
+        attempts.locked_until = Some(now + LOCKOUT);
+    }
+    Outcome::Rejected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Button::*;
+
+    fn scripted(buttons: &[Button]) -> impl FnMut(Instant) -> Option<Button> + '_ {
+        let mut it = buttons.iter().copied();
+        move |_| it.next()
+    }
+
+    #[test]
+    fn pbkdf2_matches_the_rfc_7914_vector() {
+        let hex: String = pbkdf2_sha256(b"passwd", b"salt", 1)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        // The first 32 of the RFC's 64 bytes: one block.
+        assert_eq!(
+            hex,
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+    }
+
+    #[test]
+    fn stored_form_round_trips() {
+        let pin = PinHash::with(&[South, South, DPadUp, West], b"salty".to_vec(), 10);
+        let line = pin.to_line();
+        let back = PinHash::parse(&line).unwrap();
+        assert!(back.matches(&[South, South, DPadUp, West]));
+        assert!(!back.matches(&[South, South, DPadUp]));
+        assert!(PinHash::parse("pbkdf2-sha256$0$AA$AA").is_none());
+        assert!(PinHash::parse(&format!("{line}$extra")).is_none());
+        assert_eq!(
+            parse_sequence("a a up X").unwrap(),
+            [South, South, DPadUp, West]
+        );
+        assert!(parse_sequence("A B").is_err());
+        assert!(parse_sequence("A B Start X").is_err());
+    }
+
+    #[test]
+    fn scripted_entry_and_lockout() {
+        let pin = PinHash::with(&[South, East, DPadLeft, LeftTrigger], b"s".to_vec(), 10);
+        let mut attempts = Attempts::new();
+        let t0 = Instant::now();
+        let right = [South, East, DPadLeft, LeftTrigger, Start];
+        let wrong = [South, East, DPadLeft, RightTrigger, Start];
+
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut scripted(&right), t0),
+            Outcome::Accepted
+        );
+        // Giving up or running out of time costs nothing.
+        let mut quit = scripted(&[South, Select]);
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut quit, t0),
+            Outcome::Abandoned
+        );
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut scripted(&[South]), t0),
+            Outcome::Abandoned
+        );
+
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(
+                attempt(&pin, &mut attempts, &mut scripted(&wrong), t0),
+                Outcome::Rejected
+            );
+        }
+        // Locked: even the right PIN is not read.
+        let later = t0 + LOCKOUT - Duration::from_secs(1);
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut scripted(&right), later),
+            Outcome::LockedOut
+        );
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut scripted(&right), t0 + LOCKOUT),
+            Outcome::Accepted
+        );
+
+        // Overlong entries are wrong, not truncated into a match.
+        let mut long = vec![South, East, DPadLeft, LeftTrigger];
+        long.extend([North; MAX_LEN]);
+        long.push(Start);
+        assert_eq!(
+            attempt(&pin, &mut attempts, &mut scripted(&long), t0 + LOCKOUT),
+            Outcome::Rejected
+        );
+    }
+}
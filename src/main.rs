@@ -1,5 +1,6 @@
 mod approval;
 mod config;
+mod fallback;
 mod game_mode_switch;
 mod paths;
 mod setup;
@@ -206,6 +207,47 @@ fn get_active_tty() -> Result<String> {
     Ok(tty)
 }
 
+/// The next button pressed on any pad, or None once `deadline` passes.
+fn next_button(gilrs: &mut Gilrs, deadline: Instant) -> Option<Button> {
+    loop {
+        while let Some(Event { event, .. }) = gilrs.next_event() {
+            if let gilrs::EventType::ButtonPressed(button, _) = event {
+                return Some(button);
+            }
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// What the phone is told about this entry (see `approval::RequestContext`):
+/// the box, the session account, the pad that pressed Guide (if any), the VT,
+/// and how long ago a user session was last seen there.
+fn request_context(
+    config: &Config,
+    pad: Option<gilrs::Gamepad<'_>>,
+    last_session: Option<Instant>,
+) -> approval::RequestContext {
+    let battery = pad.as_ref().and_then(|p| match p.power_info() {
+        gilrs::PowerInfo::Discharging(level) | gilrs::PowerInfo::Charging(level) => Some(level),
+        gilrs::PowerInfo::Charged => Some(100),
+        gilrs::PowerInfo::Wired | gilrs::PowerInfo::Unknown => None,
+    });
+    approval::RequestContext {
+        hostname: fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty()),
+        profile: Some(config.session.user.clone()),
+        pad: pad.as_ref().map(|p| p.name().to_string()),
+        battery,
+        vt: Some(config.terminal.vt),
+        since_last_session: last_session.map(|at| at.elapsed().as_secs()),
+    }
+}
+
 fn run_game_mode() -> Result<()> {
     // Logging is already initialized in main()
     info!("Starting game mode service");
@@ -241,16 +283,14 @@ fn run_game_mode() -> Result<()> {
     let greetd_tty = format!("tty{}", config.terminal.vt);
     let greetd_vt = config.terminal.vt.to_string();
     info!("Greetd running on {}", greetd_tty);
+    // When a user session on the VT was last seen, for the request context.
+    // Only this run's sightings: the daemon restarts with greetd.
+    let mut last_session: Option<Instant> = None;
 
     // Main event loop
     while running.load(Ordering::SeqCst) {
         // Process gamepad events
-        while let Some(Event {
-            id: _,
-            event,
-            time: _,
-        }) = gilrs.next_event()
-        {
+        while let Some(Event { id, event, time: _ }) = gilrs.next_event() {
             debug!("Gamepad event: {:?}", event);
 
             // Check if greetd TTY is active
@@ -275,6 +315,7 @@ fn run_game_mode() -> Result<()> {
             // Check if we're in the greeter session
             if !is_greeter_active()? {
                 debug!("Greeter is not active, ignoring gamepad events");
+                last_session = Some(Instant::now());
                 std::thread::sleep(Duration::from_millis(1000));
                 continue;
             }
@@ -282,21 +323,25 @@ fn run_game_mode() -> Result<()> {
             // Check if any non-greeter user is logged in
             if is_user_logged_in_on_tty(&greetd_tty)? {
                 debug!("Non-greeter user logged in, ignoring gamepad events");
+                last_session = Some(Instant::now());
                 std::thread::sleep(Duration::from_millis(1000));
                 continue;
             }
 
             match event {
-                gilrs::EventType::ButtonPressed(Button::Mode, _) => {
-                    if !menu_pressed.load(Ordering::SeqCst) {
-                        menu_pressed.store(true, Ordering::SeqCst);
-                        info!("Menu button pressed");
-                    }
+                gilrs::EventType::ButtonPressed(Button::Mode, _)
+                    if !menu_pressed.load(Ordering::SeqCst) =>
+                {
+                    menu_pressed.store(true, Ordering::SeqCst);
+                    info!("Menu button pressed");
                 }
                 gilrs::EventType::ButtonReleased(Button::Mode, _) => {
                     menu_pressed.store(false, Ordering::SeqCst);
                     // Gate entry on a phone passkey approval (fail-closed).
-                    if approval::require_approval() {
+                    let context =
+                        request_context(&config, gilrs.connected_gamepad(id), last_session);
+                    let mut pad = |deadline| next_button(&mut gilrs, deadline);
+                    if approval::require_approval(&context, Some(&mut pad)) {
                         game_mode_switch::switch_to_game_mode()?;
                     } else {
                         info!("game-mode entry not approved; staying at greeter");
@@ -316,7 +361,7 @@ fn main() -> Result<()> {
     // stdout/stderr, no tracing/log-dir setup — it may run on a box where
     // /etc/greetd doesn't have its final permissions yet.
     if env::args().nth(1).as_deref() == Some("setup") {
-        return setup::run();
+        return setup::run(&env::args().skip(2).collect::<Vec<_>>());
     }
 
     // Initialize logging first thing
@@ -327,7 +372,8 @@ fn main() -> Result<()> {
 
     // Exercise the approval gate without a gamepad (run as the greeter user).
     if env::args().any(|a| a == "--test-approval") {
-        let ok = approval::require_approval();
+        let context = request_context(&Config::load()?, None, None);
+        let ok = approval::require_approval(&context, None);
         println!(
             "approval result: {}",
             if ok { "APPROVED" } else { "NOT APPROVED" }
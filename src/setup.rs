@@ -13,23 +13,32 @@
 //!     into /etc/greetd (greetd itself needs concrete values on disk) and
 //!     swaps the config.toml symlink to the greeter config
 //!   - renders and installs the sudoers grant for the greeter user
-//!   - checks tailscale and writes /etc/game-mode/approval.env (the WebAuthn
-//!     verifier's RP ID / origin come from the tailnet FQDN)
+//!   - checks tailscale and writes /etc/game-mode/verifier.toml (the WebAuthn
+//!     verifier's RP ID / origin come from the tailnet FQDN; an older
+//!     approval.env is migrated) plus the daemon's approval.env — or, with
+//!     `--approval-origin https://host[:port]`, skips tailscale and has the
+//!     verifier terminate TLS itself (`--tls-cert` / `--tls-key`, default
+//!     /etc/game-mode/tls/{cert,key}.pem) for LAN-only boxes
+//!   - pins the verifier's decision-signing key in approval.env
 //!   - enables the systemd units
 //!
+//! `game-mode setup --fallback-pin` only sets the offline fallback PIN
+//! (`--fallback-pin off` removes it); see `fallback`.
+//!
 //! Idempotent: re-run it after upgrades or to reconfigure (existing answers
 //! in config.toml become the new defaults).
 
 use anyhow::{bail, Context, Result};
-use dialoguer::{Confirm, Input};
+use dialoguer::{Confirm, Input, Password};
 use serde_json::Value;
 use std::fs;
-use std::io::IsTerminal;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{IsTerminal, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::Path;
 use std::process::Command;
 
 use crate::config::{self, Config};
+use crate::fallback::{self, PinHash};
 use crate::game_mode_switch;
 
 const SHARE_GREETD: &str = "/usr/share/game-mode/greetd";
@@ -37,16 +46,104 @@ const SUDOERS_TEMPLATE: &str = "/usr/share/game-mode/sudoers/greeter-greetd";
 const SUDOERS_PATH: &str = "/etc/sudoers.d/greeter-greetd";
 const ETC_DIR: &str = "/etc/game-mode";
 const APPROVAL_ENV: &str = "/etc/game-mode/approval.env";
+const VERIFIER_TOML: &str = "/etc/game-mode/verifier.toml";
+const DEFAULT_TLS_CERT: &str = "/etc/game-mode/tls/cert.pem";
+const DEFAULT_TLS_KEY: &str = "/etc/game-mode/tls/key.pem";
+/// approval.env keys the daemon reads; everything else in an old env file
+/// belonged to the verifier and moves to verifier.toml on migration.
+const DAEMON_ENV_KEYS: &[&str] = &[
+    "AG_CTRL_SOCKET",
+    "AG_TIMEOUT",
+    "AG_DISABLED",
+    "AG_DECISION_PUBKEY",
+];
 
 /// Files copied verbatim from /usr/share/game-mode/greetd to /etc/greetd.
 const STATIC_FILES: &[&str] = &["bg.png", "environments"];
 /// Files rendered ({{vt}}, {{games_user}}) into /etc/greetd.
 const TEMPLATE_FILES: &[&str] = &["config_default.toml", "game_mode_login.toml"];
 
-pub fn run() -> Result<()> {
+/// Where the approval pages are served from.
+enum ApprovalOrigin {
+    /// `tailscale serve` in front of the plain web port; origin = tailnet FQDN.
+    Tailscale,
+    /// The verifier's own TLS listener (no tailscale).
+    Direct {
+        origin: String,
+        host: String,
+        cert: String,
+        key: String,
+    },
+}
+
+impl ApprovalOrigin {
+    /// `setup [--approval-origin URL [--tls-cert PATH] [--tls-key PATH]]`
+    fn from_args(args: &[String]) -> Result<Self> {
+        let mut origin = None;
+        let mut cert = DEFAULT_TLS_CERT.to_string();
+        let mut key = DEFAULT_TLS_KEY.to_string();
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let mut value = || {
+                it.next()
+                    .cloned()
+                    .with_context(|| format!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--approval-origin" => origin = Some(value()?),
+                "--tls-cert" => cert = value()?,
+                "--tls-key" => key = value()?,
+                other => bail!("unknown setup option {other:?}"),
+            }
+        }
+        let Some(origin) = origin else {
+            return Ok(ApprovalOrigin::Tailscale);
+        };
+        let origin = origin.trim_end_matches('/').to_string();
+        let host = origin_host(&origin)?;
+        for path in [&cert, &key] {
+            if !Path::new(path).is_file() {
+                bail!("{path} not found (pass --tls-cert / --tls-key)");
+            }
+        }
+        Ok(ApprovalOrigin::Direct {
+            origin,
+            host,
+            cert,
+            key,
+        })
+    }
+}
+
+/// Host part of an `https://host[:port]` origin (the WebAuthn RP ID). It
+/// must be a domain name: browsers refuse WebAuthn for an IP address.
+fn origin_host(origin: &str) -> Result<String> {
+    let rest = origin
+        .strip_prefix("https://")
+        .context("--approval-origin must be an https:// URL (WebAuthn requires TLS)")?;
+    if rest.contains('/') {
+        bail!("--approval-origin must be scheme://host[:port] without a path");
+    }
+    if rest.starts_with('[') {
+        bail!("--approval-origin {origin:?} is an IPv6 address; WebAuthn needs a domain name");
+    }
+    match rest.split(':').next() {
+        Some(h) if h.parse::<std::net::Ipv4Addr>().is_ok() => {
+            bail!("--approval-origin {origin:?} is an IP address; WebAuthn needs a domain name")
+        }
+        Some(h) if !h.is_empty() => Ok(h.to_string()),
+        _ => bail!("--approval-origin {origin:?} has no host"),
+    }
+}
+
+pub fn run(args: &[String]) -> Result<()> {
     if unsafe { libc::geteuid() } != 0 {
         bail!("game-mode setup must run as root: sudo game-mode setup");
     }
+    if let Some(i) = args.iter().position(|a| a == "--fallback-pin") {
+        return set_fallback_pin(args.get(i + 1).map(String::as_str) == Some("off"));
+    }
+    let approval = ApprovalOrigin::from_args(args)?;
     let interactive = std::io::stdin().is_terminal();
 
     // Existing config (or defaults) seeds the prompts, so re-runs are quiet
@@ -86,9 +183,29 @@ pub fn run() -> Result<()> {
     // Land on the greeter config (atomic symlink swap, same code path the
     // daemon uses to reset after a game session).
     game_mode_switch::switch_to_desktop_mode()?;
-    tailscale_approval_env()?;
+    match &approval {
+        ApprovalOrigin::Tailscale => tailscale_approval_env()?,
+        ApprovalOrigin::Direct {
+            origin,
+            host,
+            cert,
+            key,
+        } => {
+            write_verifier_config(host, origin, Some((cert, key)))?;
+            write_approval_env()?;
+            // The verifier runs as access-gate and must read the key.
+            run_checked("chgrp", &["access-gate", key])?;
+            fs::set_permissions(key, fs::Permissions::from_mode(0o640))?;
+        }
+    }
+    if Path::new(APPROVAL_ENV).exists() {
+        pin_decision_key()?;
+    }
     enable_services(interactive)?;
-    print_next_steps();
+    print_next_steps(match &approval {
+        ApprovalOrigin::Tailscale => "https://<tailnet-fqdn>",
+        ApprovalOrigin::Direct { origin, .. } => origin,
+    });
     Ok(())
 }
 
@@ -261,27 +378,12 @@ fn tailscale_approval_env() -> Result<()> {
         .as_str()
         .map(|s| s.trim_end_matches('.'))
     else {
-        println!("WARN: could not determine the tailnet FQDN; skipping approval.env");
+        println!("WARN: could not determine the tailnet FQDN; skipping the verifier config");
         return Ok(());
     };
 
-    if Path::new(APPROVAL_ENV).exists() {
-        println!("{APPROVAL_ENV} already exists; leaving it untouched");
-    } else {
-        fs::create_dir_all(ETC_DIR)?;
-        let text = format!(
-            "AG_RP_ID={fqdn}\n\
-             AG_ORIGIN=https://{fqdn}\n\
-             AG_DATA_DIR=/var/lib/access-gate\n\
-             AG_CTRL_SOCKET=/run/access-gate/ctrl.sock\n\
-             AG_APPROVE_BASE=https://{fqdn}/approve\n\
-             AG_TIMEOUT=90\n\
-             AG_VAPID_SUB=access-gate@{fqdn}\n"
-        );
-        fs::write(APPROVAL_ENV, text)?;
-        fs::set_permissions(APPROVAL_ENV, fs::Permissions::from_mode(0o644))?;
-        println!("Wrote {APPROVAL_ENV} (RP ID {fqdn})");
-    }
+    write_verifier_config(fqdn, &format!("https://{fqdn}"), None)?;
+    write_approval_env()?;
 
     // WebAuthn needs a real TLS origin; serve the verifier over the tailnet.
     let served = Command::new("tailscale")
@@ -295,14 +397,211 @@ fn tailscale_approval_env() -> Result<()> {
     Ok(())
 }
 
+/// The daemon's side of the approval config.
+fn write_approval_env() -> Result<()> {
+    if Path::new(APPROVAL_ENV).exists() {
+        println!("{APPROVAL_ENV} already exists; leaving it untouched");
+        return Ok(());
+    }
+    fs::create_dir_all(ETC_DIR)?;
+    let text = "AG_CTRL_SOCKET=/run/access-gate/ctrl.sock\n\
+                AG_TIMEOUT=90\n";
+    fs::write(APPROVAL_ENV, text)?;
+    fs::set_permissions(APPROVAL_ENV, fs::Permissions::from_mode(0o644))?;
+    println!("Wrote {APPROVAL_ENV}");
+    Ok(())
+}
+
+/// Prompt for the offline fallback PIN and store its hash root-only, or
+/// remove it. The daemon gets it as a systemd credential at start.
+fn set_fallback_pin(remove: bool) -> Result<()> {
+    if remove {
+        match fs::remove_file(fallback::PIN_FILE) {
+            Ok(()) => println!("Removed {}", fallback::PIN_FILE),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                println!("No fallback PIN was set")
+            }
+            Err(e) => return Err(e).context(format!("failed to remove {}", fallback::PIN_FILE)),
+        }
+    } else {
+        if !std::io::stdin().is_terminal() {
+            bail!("--fallback-pin prompts for the sequence; run it from a terminal");
+        }
+        println!(
+            "Fallback PIN: {} or more pad buttons from A B X Y Up Down Left Right LB RB LT RT L3 R3,",
+            fallback::MIN_LEN
+        );
+        println!("separated by spaces (entered on the pad, then Start).");
+        let text = Password::new()
+            .with_prompt("  sequence")
+            .with_confirmation("  again", "the sequences differ")
+            .interact()?;
+        let line = PinHash::new(&fallback::parse_sequence(&text)?).to_line();
+        fs::create_dir_all(ETC_DIR)?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(fallback::PIN_FILE)
+            .with_context(|| format!("failed to write {}", fallback::PIN_FILE))?;
+        // An older file keeps its mode through open(); tighten it anyway.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(format!("{line}\n").as_bytes())?;
+        println!("Wrote {}", fallback::PIN_FILE);
+    }
+    println!("Apply with: sudo systemctl restart game-mode.service");
+    Ok(())
+}
+
+/// Pin the verifier's decision-signing key (made on first use, as the
+/// verifier's user so it owns the file) in approval.env; the daemon refuses
+/// every decision until this is done. Re-running after a key change re-pins.
+fn pin_decision_key() -> Result<()> {
+    let out = Command::new("runuser")
+        .args([
+            "-u",
+            "access-gate",
+            "--",
+            "access-gate-verifier",
+            "decision-key",
+        ])
+        .env("AG_CONFIG", VERIFIER_TOML)
+        .output();
+    let key = match out {
+        Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        _ => {
+            println!("WARN: could not read the verifier's decision key; approvals stay refused.");
+            println!("      Re-run once access-gate-verifier is installed: sudo game-mode setup");
+            return Ok(());
+        }
+    };
+    let text = fs::read_to_string(APPROVAL_ENV)?;
+    fs::write(APPROVAL_ENV, set_env_key(&text, "AG_DECISION_PUBKEY", &key))?;
+    println!("Pinned the verifier's decision key in {APPROVAL_ENV}");
+    Ok(())
+}
+
+/// `text` (KEY=value lines) with `key` set to `value`, replacing any
+/// existing line for it.
+fn set_env_key(text: &str, key: &str, value: &str) -> String {
+    let mut out: String = text
+        .lines()
+        .filter(|line| line.split_once('=').is_none_or(|(k, _)| k.trim() != key))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    out.push_str(&format!("{key}={value}\n"));
+    out
+}
+
+/// Create or update verifier.toml. An existing approval.env from before the
+/// config file existed is migrated first (by the verifier itself, which owns
+/// the key mapping) and then pruned to the daemon's keys, so stale AG_*
+/// values in the EnvironmentFile can't override the config. On every run
+/// the WebAuthn identity is refreshed; other keys are left as the operator
+/// set them. `[tls]` follows the chosen mode: written for a direct origin,
+/// removed for tailscale (whose `serve` already holds :443).
+fn write_verifier_config(rp_id: &str, origin: &str, tls: Option<(&str, &str)>) -> Result<()> {
+    fs::create_dir_all(ETC_DIR)?;
+    if !Path::new(VERIFIER_TOML).exists() && Path::new(APPROVAL_ENV).exists() {
+        let migrated = Command::new("access-gate-verifier")
+            .args(["--migrate-env", APPROVAL_ENV])
+            .env("AG_CONFIG", VERIFIER_TOML)
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        if migrated {
+            prune_approval_env()?;
+        } else {
+            println!("WARN: could not migrate {APPROVAL_ENV}; writing a fresh {VERIFIER_TOML}");
+        }
+    }
+
+    let mut table: toml::Table = match fs::read_to_string(VERIFIER_TOML) {
+        Ok(text) => text
+            .parse()
+            .with_context(|| format!("failed to parse {VERIFIER_TOML}"))?,
+        Err(_) => toml::Table::new(),
+    };
+    let section = |table: &mut toml::Table, name: &str| -> Result<toml::Table> {
+        match table.remove(name) {
+            Some(toml::Value::Table(t)) => Ok(t),
+            Some(_) => bail!("{VERIFIER_TOML}: [{name}] is not a table"),
+            None => Ok(toml::Table::new()),
+        }
+    };
+    let mut webauthn = section(&mut table, "webauthn")?;
+    webauthn.insert("rp_id".into(), rp_id.into());
+    webauthn.insert("origin".into(), origin.into());
+    table.insert("webauthn".into(), webauthn.into());
+    let mut notify = section(&mut table, "notify")?;
+    notify
+        .entry("vapid_sub")
+        .or_insert_with(|| format!("access-gate@{rp_id}").into());
+    table.insert("notify".into(), notify.into());
+    match tls {
+        Some((cert, key)) => {
+            let mut tls = section(&mut table, "tls")?;
+            tls.insert("cert".into(), cert.into());
+            tls.insert("key".into(), key.into());
+            table.insert("tls".into(), tls.into());
+        }
+        None => {
+            table.remove("tls");
+        }
+    }
+
+    let text = format!(
+        "# access-gate verifier configuration — [webauthn] and [tls] are\n\
+         # refreshed by `game-mode setup`; everything else is yours to edit.\n\
+         # AG_* environment variables override individual keys.\n\
+         \n{}",
+        toml::to_string_pretty(&table).context("failed to serialize verifier config")?
+    );
+    fs::write(VERIFIER_TOML, text).with_context(|| format!("failed to write {VERIFIER_TOML}"))?;
+    // May hold notifier tokens: readable by the verifier's user only.
+    fs::set_permissions(VERIFIER_TOML, fs::Permissions::from_mode(0o640))?;
+    run_checked("chgrp", &["access-gate", VERIFIER_TOML])?;
+    println!("Wrote {VERIFIER_TOML} (RP ID {rp_id})");
+    Ok(())
+}
+
+/// Drop the verifier's keys from approval.env after migration.
+fn prune_approval_env() -> Result<()> {
+    let text = fs::read_to_string(APPROVAL_ENV)?;
+    let kept: String = text
+        .lines()
+        .filter(|line| {
+            let key = line.split_once('=').map_or("", |(k, _)| k.trim());
+            line.trim_start().starts_with('#') || key.is_empty() || DAEMON_ENV_KEYS.contains(&key)
+        })
+        .map(|line| format!("{line}\n"))
+        .collect();
+    fs::write(APPROVAL_ENV, kept)?;
+    println!("Migrated verifier settings from {APPROVAL_ENV} to {VERIFIER_TOML}");
+    Ok(())
+}
+
 fn enable_services(interactive: bool) -> Result<()> {
     run_checked("systemctl", &["daemon-reload"])?;
     run_checked("systemctl", &["enable", "game-mode.service"])?;
     run_checked("systemctl", &["enable", "access-gate-verifier.service"])?;
-    if Path::new(APPROVAL_ENV).exists() {
+    // Listeners held by systemd, so requests queue across verifier restarts.
+    // A verifier from before socket activation holds the addresses itself.
+    run_checked("systemctl", &["stop", "access-gate-verifier.service"])?;
+    run_checked(
+        "systemctl",
+        &[
+            "enable",
+            "--now",
+            "access-gate-verifier-ctrl.socket",
+            "access-gate-verifier-web.socket",
+        ],
+    )?;
+    if Path::new(VERIFIER_TOML).exists() {
         run_checked("systemctl", &["restart", "access-gate-verifier.service"])?;
     } else {
-        println!("access-gate-verifier not started (no {APPROVAL_ENV} yet)");
+        println!("access-gate-verifier not started (no {VERIFIER_TOML} yet)");
     }
 
     // Restarting greetd respawns the greeter on its VT (active desktop
@@ -320,15 +619,42 @@ fn enable_services(interactive: bool) -> Result<()> {
     Ok(())
 }
 
-fn print_next_steps() {
+fn print_next_steps(origin: &str) {
     println!();
     println!("Setup complete. Remaining one-time steps:");
     println!("  1. Phone passkey enrollment (only while no key is enrolled):");
-    println!("       sudo -u access-gate touch /var/lib/access-gate/enroll-open");
-    println!("     then open https://<tailnet-fqdn>/enroll on the phone");
-    println!("     (and https://<tailnet-fqdn>/setup for push notifications).");
+    println!("       sudo -u access-gate access-gate-verifier enroll-token");
+    println!("     and scan the QR code with the phone; then the same with");
+    println!("     `enroll-token push` for notifications ({origin}/setup).");
     println!("  2. \"Discord\" non-Steam shortcut (with Steam closed, as the games user):");
     println!("       game-mode-steam-shortcut --name Discord --exe /usr/bin/game-mode-discord");
     println!("  3. Test the approval gate without a gamepad:");
     println!("       sudo -u greeter game-mode --test-approval");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approval_origin_host() {
+        assert_eq!(origin_host("https://box.lan").unwrap(), "box.lan");
+        assert_eq!(origin_host("https://box.lan:8443").unwrap(), "box.lan");
+        assert!(origin_host("https://[fd00::20]:8443").is_err());
+        assert!(origin_host("https://192.168.1.20:8443").is_err());
+        assert!(origin_host("http://box.lan").is_err());
+        assert!(origin_host("https://box.lan/approve").is_err());
+        assert!(origin_host("https://").is_err());
+    }
+
+    #[test]
+    fn decision_key_is_pinned_once() {
+        let text = "AG_CTRL_SOCKET=/run/access-gate/ctrl.sock\nAG_DECISION_PUBKEY=old\n# note\n";
+        let text = set_env_key(text, "AG_DECISION_PUBKEY", "new");
+        assert_eq!(
+            text,
+            "AG_CTRL_SOCKET=/run/access-gate/ctrl.sock\n# note\nAG_DECISION_PUBKEY=new\n"
+        );
+        assert_eq!(set_env_key("", "K", "v"), "K=v\n");
+    }
+}
@@ -0,0 +1,51 @@
+//! One filter for text that crosses a trust boundary: request titles and
+//! paths, PAM items, polkit messages, deny reasons. It ends up on a VT, a
+//! terminal, a phone and in logs, so every crate that shows or forwards
+//! such text cleans it here rather than with its own copy.
+
+/// `raw` made printable and single-line: control characters (escape
+/// sequences) and bidi overrides dropped, whitespace collapsed, capped at
+/// `max_chars` characters. `None` if nothing is left.
+pub fn clean_text(raw: &str, max_chars: usize) -> Option<String> {
+    let text: String = raw
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .filter(|c| {
+            !c.is_control() && !matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+        })
+        .collect();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text: String = text.chars().take(max_chars).collect();
+    let text = text.trim_end();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_and_bidi_characters_are_dropped() {
+        assert_eq!(
+            clean_text("  homework\n first\u{1b}[2J ", 80).as_deref(),
+            Some("homework first[2J")
+        );
+        assert_eq!(
+            clean_text("\u{1b}]0;pwned\u{7}x", 80).as_deref(),
+            Some("]0;pwnedx")
+        );
+        assert_eq!(
+            clean_text("\u{202e}lol\u{2066}", 80).as_deref(),
+            Some("lol")
+        );
+        assert_eq!(clean_text(" \t\r\n\u{9b}\u{0}", 80), None);
+    }
+
+    #[test]
+    fn length_is_capped_in_characters() {
+        let long = clean_text(&"ä".repeat(200), 80).unwrap();
+        assert_eq!(long.chars().count(), 80);
+        // No trailing space left by the cut.
+        assert_eq!(clean_text("ab cd", 3).as_deref(), Some("ab"));
+    }
+}
@@ -0,0 +1,83 @@
+//! systemd socket activation (sd_listen_fds(3) without libsystemd).
+//!
+//! With the shipped `.socket` units, systemd owns the control socket and the
+//! web port across verifier restarts: a daemon that connects while the
+//! verifier is down sits in the listen backlog and is answered once it is
+//! back, instead of failing closed on "unreachable". Listeners are matched
+//! by `FileDescriptorName=` — `ctrl`, `web`, `https`, `metrics` — and
+//! unnamed ones by family (unix = ctrl, TCP = web).
+
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+use tracing::{info, warn};
+
+/// First passed descriptor (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Pre-opened listeners handed over by systemd; `None` = bind it ourselves.
+#[derive(Default)]
+pub struct Listeners {
+    pub ctrl: Option<UnixListener>,
+    pub web: Option<TcpListener>,
+    pub https: Option<TcpListener>,
+    pub metrics: Option<TcpListener>,
+}
+
+impl Listeners {
+    /// Take the descriptors named in LISTEN_FDS / LISTEN_FDNAMES, if they are
+    /// meant for this process (LISTEN_PID), and unset the variables so
+    /// children don't inherit them.
+    pub fn from_env() -> Self {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        if pid.and_then(|p| p.parse::<u32>().ok()) != Some(std::process::id()) {
+            return Listeners::default();
+        }
+        let count: RawFd = count.and_then(|n| n.parse().ok()).unwrap_or(0);
+        let names: Vec<&str> = names.split(':').collect();
+
+        let mut out = Listeners::default();
+        for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let family = socket_family(fd);
+            let name = match names.get(i).copied().unwrap_or("") {
+                n @ ("ctrl" | "web" | "https" | "metrics") => n,
+                _ if family == Some(libc::AF_UNIX) => "ctrl",
+                _ if matches!(family, Some(libc::AF_INET | libc::AF_INET6)) => "web",
+                other => {
+                    warn!("socket activation: ignoring fd {fd} ({other:?})");
+                    unsafe { libc::close(fd) };
+                    continue;
+                }
+            };
+            let unix = family == Some(libc::AF_UNIX);
+            if (name == "ctrl") != unix {
+                warn!("socket activation: fd {fd} named {name:?} has the wrong family");
+                unsafe { libc::close(fd) };
+                continue;
+            }
+            info!("socket activation: {name} on fd {fd}");
+            // SAFETY: systemd passed us this descriptor and nothing else owns it.
+            match name {
+                "ctrl" => out.ctrl = Some(unsafe { UnixListener::from_raw_fd(fd) }),
+                "web" => out.web = Some(unsafe { TcpListener::from_raw_fd(fd) }),
+                "https" => out.https = Some(unsafe { TcpListener::from_raw_fd(fd) }),
+                _ => out.metrics = Some(unsafe { TcpListener::from_raw_fd(fd) }),
+            }
+        }
+        out
+    }
+}
+
+fn socket_family(fd: RawFd) -> Option<libc::c_int> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let rc = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    (rc == 0).then_some(addr.ss_family as libc::c_int)
+}
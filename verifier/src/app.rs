@@ -0,0 +1,346 @@
+//! Shared verifier state: the pending requests and everything the two
+//! planes (`ctrl`, `web`) decide them with.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use url::Url;
+use webauthn_rs::prelude::*;
+
+use crate::config::{Cfg, NotifyBackend};
+use crate::context::RequestContext;
+use crate::decision::DecisionKey;
+use crate::history::{Peer, Record};
+use crate::journal::{now_unix, Journal};
+use crate::pages::Pages;
+use crate::store::Store;
+use crate::token::{self, Purpose};
+use crate::totp::TotpCred;
+use crate::vapid::Vapid;
+use crate::{metrics, notify, ratelimit, store, tls};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ApprovalRequest {
+    pub(crate) exe: String,
+    pub(crate) path: String,
+    pub(crate) group: String,
+    #[serde(default)]
+    pub(crate) title: String,
+    /// The control client (SO_PEERCRED).
+    #[serde(default)]
+    pub(crate) peer: Option<Peer>,
+    /// See `history::Record::exe_mismatch`.
+    #[serde(default)]
+    pub(crate) exe_mismatch: bool,
+    /// The control client's nonce, echoed in the signed decision.
+    #[serde(default)]
+    pub(crate) nonce: Option<String>,
+    pub(crate) status: String, // pending | approved | denied | timeout | cancelled
+    /// Unix seconds; the request expires `request_ttl` after this.
+    pub(crate) created_at: u64,
+    /// Unix seconds until which the control client waits for a decision.
+    pub(crate) wait_until: u64,
+    pub(crate) auth: Option<PasskeyAuthentication>,
+    /// Failed assertions so far; at `max_failures` the request is denied.
+    #[serde(default)]
+    pub(crate) failures: u64,
+    /// Why it was denied, as typed or picked on the approve page.
+    #[serde(default)]
+    pub(crate) reason: Option<String>,
+    /// Where the request comes from, as the client tells it.
+    #[serde(default)]
+    pub(crate) context: Option<RequestContext>,
+}
+
+pub struct App {
+    pub cfg: Cfg,
+    pub(crate) webauthn: Webauthn,
+    pub(crate) vapid: Arc<Vapid>,
+    pub(crate) pages: Pages,
+    pub(crate) decision: DecisionKey,
+    pub(crate) notifier: notify::Chain,
+    pub(crate) requests: Mutex<HashMap<String, ApprovalRequest>>,
+    /// Signalled (with `requests` held) whenever a request is added or
+    /// decided; the expiry scheduler and every waiter sleep on it.
+    pub(crate) decided: Condvar,
+    journal: Journal,
+    pub store: Arc<dyn Store>,
+    /// Client addresses behind the built-in TLS listener.
+    pub peers: tls::Peers,
+    /// The registration ceremony in progress and when it goes stale (unix
+    /// seconds; cleared by the expiry scheduler).
+    pub(crate) enroll_state: Mutex<Option<(u64, PasskeyRegistration)>>,
+    /// The secret `/totp` shows, until a code from it confirms the enrollment.
+    pub(crate) totp_pending: Mutex<Option<Vec<u8>>>,
+    /// Pending `/history` sign-in ceremony.
+    pub(crate) login_state: Mutex<Option<PasskeyAuthentication>>,
+    pub(crate) sessions: Mutex<HashMap<String, Session>>,
+    pub(crate) metrics: metrics::Metrics,
+    pub(crate) limiter: ratelimit::Limiter,
+}
+
+/// How long a started passkey registration may take to finish.
+pub(crate) const ENROLL_CEREMONY_SECS: u64 = 300;
+
+/// A signed-in `/history` browser.
+pub(crate) struct Session {
+    pub(crate) expires: u64,
+    pub(crate) credential: String,
+}
+
+impl App {
+    /// Everything the planes share: WebAuthn, the pages, the VAPID key and
+    /// notifier chain, the store, and the journaled requests of a previous
+    /// run. Broken page templates fail here.
+    pub fn new(cfg: Cfg) -> Result<Self> {
+        let rp_origin = Url::parse(&cfg.origin).context("webauthn.origin is not a valid URL")?;
+        let webauthn = WebauthnBuilder::new(&cfg.rp_id, &rp_origin)
+            .context("webauthn builder")?
+            .rp_name("access-gate")
+            .build()
+            .context("webauthn build")?;
+
+        let pages = Pages::load(&cfg.pages_dir, &cfg.brand)?;
+        let store = store::open(&cfg)?;
+        let vapid = Arc::new(Vapid::load_or_generate(&cfg.data_dir, store.as_ref())?);
+        let decision = DecisionKey::load_or_generate(&cfg.data_dir)?;
+        let notifier = build_notifier(&cfg, &vapid, &store);
+        info!(
+            "notification chain: {}",
+            notifier
+                .0
+                .iter()
+                .map(|b| b.name())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        );
+
+        let journal = Journal::new(&cfg.data_dir);
+        let restored: HashMap<String, ApprovalRequest> = journal.load();
+        if !restored.is_empty() {
+            info!("restored {} journaled request(s)", restored.len());
+        }
+
+        Ok(App {
+            webauthn,
+            vapid,
+            pages,
+            decision,
+            notifier,
+            requests: Mutex::new(restored),
+            decided: Condvar::new(),
+            journal,
+            store,
+            peers: tls::Peers::default(),
+            login_state: Mutex::new(None),
+            sessions: Mutex::new(HashMap::new()),
+            metrics: metrics::Metrics::default(),
+            limiter: ratelimit::Limiter::new(cfg.rate_per_min, cfg.rate_burst),
+            enroll_state: Mutex::new(None),
+            totp_pending: Mutex::new(None),
+            cfg,
+        })
+    }
+
+    /// Does `t` open the enrollment for `purpose`?
+    pub(crate) fn token_valid(&self, purpose: Purpose, t: Option<&str>) -> bool {
+        token::check(&self.cfg.data_dir, purpose, t, now_unix())
+    }
+
+    /// The enrolled passkey; a store error reads as none (logged), which
+    /// fails every ceremony closed.
+    pub(crate) fn passkey(&self) -> Option<Passkey> {
+        self.store.passkey().unwrap_or_else(|e| {
+            warn!("reading the passkey: {e:#}");
+            None
+        })
+    }
+
+    /// Keep a passkey's updated sign counter after a good assertion.
+    pub(crate) fn passkey_used(&self, mut passkey: Passkey, result: &AuthenticationResult) {
+        if passkey.update_credential(result).is_some() {
+            if let Err(e) = self.store.save_passkey(&passkey) {
+                warn!("updating the passkey counter: {e:#}");
+            }
+        }
+    }
+
+    /// Enrolled authenticator apps; a store error reads as none (logged).
+    pub(crate) fn totp_creds(&self) -> Vec<TotpCred> {
+        self.store.totp_creds().unwrap_or_else(|e| {
+            warn!("reading TOTP authenticators: {e:#}");
+            Vec::new()
+        })
+    }
+
+    /// May a request in `group` be approved with a TOTP code?
+    pub(crate) fn totp_allowed(&self, group: &str) -> bool {
+        self.cfg.totp_groups.iter().any(|g| g == group) && !self.totp_creds().is_empty()
+    }
+
+    fn subscribed(&self) -> bool {
+        matches!(self.store.subscription(), Ok(Some(_)))
+    }
+
+    pub(crate) fn enroll_allowed(&self, t: Option<&str>) -> bool {
+        self.token_valid(Purpose::Passkey, t) && self.passkey().is_none()
+    }
+
+    pub(crate) fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "service": "access-gate-verifier",
+            "rp_id": self.cfg.rp_id,
+            "enrolled": self.passkey().is_some(),
+            "push_subscribed": self.subscribed(),
+            "vapid_key_age_days": self.vapid.age(SystemTime::now()).map(|s| s / 86400),
+            "vapid_rotation_pending": self.vapid.next().is_some(),
+        })
+    }
+
+    pub(crate) fn approve_url(&self, rid: &str) -> String {
+        format!("{}/approve/{rid}", self.cfg.origin.trim_end_matches('/'))
+    }
+
+    /// Close a pending request with `outcome` and log the decision. Callers
+    /// persist the map and wake waiters.
+    pub(crate) fn finish(
+        &self,
+        rid: &str,
+        r: &mut ApprovalRequest,
+        outcome: &str,
+        credential: Option<String>,
+        client_ip: Option<String>,
+        reason: Option<String>,
+    ) {
+        r.status = outcome.into();
+        r.auth = None;
+        r.reason.clone_from(&reason);
+        self.metrics
+            .request_decided(outcome, now_unix().saturating_sub(r.created_at));
+        let record = Record {
+            rid: rid.into(),
+            exe: r.exe.clone(),
+            path: r.path.clone(),
+            group: r.group.clone(),
+            title: r.title.clone(),
+            peer: r.peer.clone(),
+            exe_mismatch: r.exe_mismatch,
+            outcome: outcome.into(),
+            credential,
+            client_ip,
+            reason,
+            created_at: r.created_at,
+            decided_at: now_unix(),
+        };
+        self.record(&record);
+    }
+
+    /// Append to the decision log; a failure is logged, never fatal.
+    pub(crate) fn record(&self, record: &Record) {
+        if let Err(e) = self.store.append_history(record) {
+            warn!("recording decision for {}: {e:#}", record.rid);
+        }
+    }
+
+    /// A failed passkey assertion: back the client off, and deny the
+    /// request once it has seen `max_failures` of them.
+    pub(crate) fn assertion_failed(&self, rid: Option<&str>, client_ip: &Option<String>) {
+        let client = client_ip.as_deref().unwrap_or_default();
+        let lockout = self.limiter.failure(client, Instant::now());
+        warn!(
+            "failed assertion from {client}; locked out for {}s",
+            lockout.as_secs()
+        );
+        let Some(rid) = rid else { return };
+        let mut requests = self.requests.lock().unwrap();
+        let Some(r) = requests.get_mut(rid).filter(|r| r.status == "pending") else {
+            return;
+        };
+        r.failures += 1;
+        if r.failures >= self.cfg.max_failures {
+            warn!(
+                "request {rid} denied after {} failed assertions",
+                r.failures
+            );
+            let reason = format!("{} failed assertions", r.failures);
+            self.finish(rid, r, "denied", None, client_ip.clone(), Some(reason));
+            self.decided.notify_all();
+        }
+        self.persist(&requests);
+    }
+
+    pub(crate) fn render_metrics(&self) -> String {
+        let pending = {
+            let requests = self.requests.lock().unwrap();
+            requests.values().filter(|r| r.status == "pending").count()
+        };
+        self.metrics.render(&metrics::Gauges {
+            pending,
+            credentials: usize::from(self.passkey().is_some()),
+            subscriptions: usize::from(self.subscribed()),
+        })
+    }
+
+    /// Wake the expiry scheduler after a new deadline outside the request
+    /// map (an enrollment ceremony). Taking the lock first means it can't
+    /// miss this between its sweep and its wait.
+    pub(crate) fn reschedule(&self) {
+        let _requests = self.requests.lock().unwrap();
+        self.decided.notify_all();
+    }
+
+    /// Journal the request map; call with the lock held after every change.
+    pub(crate) fn persist(&self, requests: &HashMap<String, ApprovalRequest>) {
+        if let Err(e) = self.journal.save(requests) {
+            warn!("journaling pending requests: {e:#}");
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Notifications (best-effort, never block a ceremony)
+// ---------------------------------------------------------------------------
+
+fn build_notifier(cfg: &Cfg, vapid: &Arc<Vapid>, store: &Arc<dyn Store>) -> notify::Chain {
+    let backends = cfg
+        .notify
+        .iter()
+        .map(|b| -> Box<dyn notify::Notifier> {
+            match b {
+                NotifyBackend::WebPush => Box::new(notify::WebPush {
+                    store: store.clone(),
+                    vapid: vapid.clone(),
+                    vapid_sub: cfg.vapid_sub.clone(),
+                }),
+                NotifyBackend::Ntfy { url, topic, token } => Box::new(notify::Ntfy {
+                    url: url.clone(),
+                    topic: topic.clone(),
+                    token: token.clone(),
+                }),
+                NotifyBackend::Gotify { url, token } => Box::new(notify::Gotify {
+                    url: url.clone(),
+                    token: token.clone(),
+                }),
+                NotifyBackend::Webhook { url, token } => Box::new(notify::Webhook {
+                    url: url.clone(),
+                    token: token.clone(),
+                }),
+            }
+        })
+        .collect();
+    notify::Chain(backends)
+}
+
+/// A short random request id, also the approve URL's path segment.
+pub(crate) fn new_request_id() -> String {
+    let mut bytes = [0u8; 9];
+    use rand::RngCore;
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,1051 @@
+//! Verifier configuration: /etc/game-mode/verifier.toml, with AG_*
+//! environment variables overriding individual keys (systemd
+//! EnvironmentFile, manual runs). Built-in defaults fill whatever neither
+//! sets; only the WebAuthn RP ID and origin are mandatory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+pub const CONFIG_TOML: &str = "/etc/game-mode/verifier.toml";
+pub const APPROVAL_ENV: &str = "/etc/game-mode/approval.env";
+
+const DEFAULT_WEB_LISTEN: &str = "127.0.0.1:8730";
+const DEFAULT_CTRL_SOCKET: &str = "/run/access-gate/ctrl.sock";
+/// tailscaled, which runs `tailscale serve`, runs as root.
+const DEFAULT_PROXY_UID: u32 = 0;
+const DEFAULT_DATA_DIR: &str = "/var/lib/access-gate";
+const DEFAULT_REQUEST_TTL: u64 = 120;
+const DEFAULT_WAIT: u64 = 90;
+const DEFAULT_RATE_PER_MIN: u64 = 60;
+const DEFAULT_RATE_BURST: u64 = 20;
+const DEFAULT_MAX_FAILURES: u64 = 3;
+const DEFAULT_GRANT_MINUTES: [u64; 2] = [30, 120];
+/// Longest grant the approve page may offer.
+const MAX_GRANT_MINUTES: u64 = 24 * 60;
+const DEFAULT_NTFY_URL: &str = "https://ntfy.sh";
+const DEFAULT_BRAND: &str = "access-gate";
+const DEFAULT_DENY_REASONS: [&str; 3] = ["Not now", "Homework first", "Bedtime"];
+/// The daemon's own claim, checked out of the box.
+const DEFAULT_EXE_PATHS: [(&str, &str); 1] = [("game-mode", "/usr/bin/game-mode")];
+const REDACTED: &str = "<redacted>";
+
+/// On-disk shape of verifier.toml. Every key is optional so a partial file
+/// falls back to env/defaults; unknown keys are ignored so old binaries
+/// tolerate newer configs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileConfig {
+    #[serde(default)]
+    webauthn: FileWebauthn,
+    #[serde(default)]
+    listen: FileListen,
+    #[serde(default)]
+    storage: FileStorage,
+    #[serde(default)]
+    requests: FileRequests,
+    #[serde(default)]
+    limits: FileLimits,
+    #[serde(default)]
+    notify: FileNotify,
+    #[serde(default)]
+    policy: FilePolicy,
+    #[serde(default)]
+    pages: FilePages,
+    #[serde(default)]
+    sandbox: FileSandbox,
+    tls: Option<FileTls>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileWebauthn {
+    rp_id: Option<String>,
+    origin: Option<String>,
+    user_name: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileListen {
+    /// host:port of the plain-HTTP web plane.
+    web: Option<String>,
+    ctrl_socket: Option<PathBuf>,
+    /// host:port serving only /metrics; unset = /metrics on the web plane.
+    metrics: Option<String>,
+    /// Owner of the reverse proxy's loopback connections, the only ones
+    /// whose X-Forwarded-For is believed.
+    proxy_uid: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileStorage {
+    data_dir: Option<PathBuf>,
+    /// "file" (JSON files in data_dir) or "sqlite" (data_dir/verifier.db).
+    backend: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileRequests {
+    /// Hard lifetime of a request, and the cap on any client's wait.
+    ttl_secs: Option<u64>,
+    /// Wait used when the client doesn't send `timeout_secs`.
+    default_wait_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileLimits {
+    /// Sustained web requests per client address.
+    per_min: Option<u64>,
+    /// Requests a client may make in a burst before `per_min` applies.
+    burst: Option<u64>,
+    /// Failed passkey assertions after which a request is denied.
+    max_failures: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileNotify {
+    /// Fallback chain, tried in order.
+    chain: Option<Vec<String>>,
+    vapid_sub: Option<String>,
+    ntfy: Option<FileNtfy>,
+    gotify: Option<FileGotify>,
+    webhook: Option<FileWebhook>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileNtfy {
+    url: Option<String>,
+    topic: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileGotify {
+    url: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileWebhook {
+    url: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FilePolicy {
+    /// Request groups accepted on the control socket; empty = any.
+    groups: Option<Vec<String>>,
+    /// "Approve for N minutes" choices on the approve page; empty = only
+    /// "approve once".
+    grant_minutes: Option<Vec<u64>>,
+    /// Request groups each uid may use on the control socket (keys are
+    /// uids); unset = every uid that can connect, any group.
+    uids: Option<BTreeMap<String, Vec<String>>>,
+    /// The binary each claimed `exe` must really be; a request whose peer
+    /// runs something else is flagged on the phone and never uses a grant.
+    exes: Option<BTreeMap<String, PathBuf>>,
+    /// Request groups the approve page also decides with a TOTP code;
+    /// empty = passkey only.
+    totp_groups: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FilePages {
+    /// Name shown in page titles.
+    brand: Option<String>,
+    /// One-tap reasons offered when denying (free text is always possible).
+    deny_reasons: Option<Vec<String>>,
+    /// Page template and message catalogue overrides (see `pages`); default
+    /// `<data_dir>/pages`.
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileSandbox {
+    /// Confine the filesystem to the data and socket dirs (default true).
+    landlock: Option<bool>,
+    /// Confine the process to the system calls it needs (default true).
+    seccomp: Option<bool>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FileTls {
+    /// PEM chain, leaf first.
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    /// host:port for HTTPS; defaults to all addresses on the origin's port.
+    listen: Option<String>,
+}
+
+/// Built-in TLS termination, when configured (see `tls.rs`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsCfg {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub listen: String,
+}
+
+/// Self-confinement at startup (see `sandbox.rs`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SandboxCfg {
+    pub landlock: bool,
+    pub seccomp: bool,
+}
+
+/// A notification backend as configured; built into a `notify::Chain` once
+/// the VAPID key is loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NotifyBackend {
+    WebPush,
+    Ntfy {
+        url: String,
+        topic: String,
+        token: Option<String>,
+    },
+    Gotify {
+        url: String,
+        token: String,
+    },
+    Webhook {
+        url: String,
+        token: Option<String>,
+    },
+}
+
+/// Where credentials, subscriptions, grants and history live (see `store`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    File,
+    Sqlite,
+}
+
+/// Effective configuration.
+#[derive(Debug)]
+pub struct Cfg {
+    pub rp_id: String,
+    pub origin: String,
+    pub user_name: String,
+    pub web_listen: String,
+    pub metrics_listen: Option<String>,
+    pub proxy_uid: u32,
+    pub ctrl_socket: PathBuf,
+    pub data_dir: PathBuf,
+    pub storage: StorageBackend,
+    pub request_ttl: u64,
+    pub default_wait: u64,
+    pub rate_per_min: u64,
+    pub rate_burst: u64,
+    pub max_failures: u64,
+    pub vapid_sub: String,
+    pub notify: Vec<NotifyBackend>,
+    pub groups: Vec<String>,
+    pub grant_minutes: Vec<u64>,
+    pub uid_groups: BTreeMap<u32, Vec<String>>,
+    pub exe_paths: BTreeMap<String, PathBuf>,
+    pub totp_groups: Vec<String>,
+    pub brand: String,
+    pub deny_reasons: Vec<String>,
+    pub pages_dir: PathBuf,
+    pub sandbox: SandboxCfg,
+    pub tls: Option<TlsCfg>,
+}
+
+/// An ntfy topic as ntfy itself accepts it (letters, digits, `-`, `_`):
+/// it becomes a URL path segment, so `/`, `?` or `#` would post elsewhere.
+fn ntfy_topic(topic: String) -> Result<String> {
+    let valid = !topic.is_empty()
+        && topic.len() <= 64
+        && topic
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("notify.ntfy.topic {topic:?}: only letters, digits, '-' and '_' (at most 64)");
+    }
+    Ok(topic)
+}
+
+impl Cfg {
+    /// Load `AG_CONFIG` (default /etc/game-mode/verifier.toml) overridden by
+    /// the process environment. A missing file is fine; a file that exists
+    /// but fails to parse is an error.
+    pub fn load() -> Result<Self> {
+        let path = std::env::var("AG_CONFIG").unwrap_or_else(|_| CONFIG_TOML.into());
+        Self::load_from(Path::new(&path), &|k| std::env::var(k).ok())
+    }
+
+    pub fn load_from(path: &Path, env: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let file: FileConfig = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(_) => FileConfig::default(),
+        };
+        Self::resolve(file, env)
+    }
+
+    /// Merge: environment, then file, then built-in default.
+    fn resolve(file: FileConfig, env: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let parse = |k: &str| -> Result<Option<u64>> {
+            env(k)
+                .map(|v| {
+                    v.parse()
+                        .with_context(|| format!("{k}={v:?} is not a number"))
+                })
+                .transpose()
+        };
+        let flag = |k: &str| -> Result<Option<bool>> {
+            env(k)
+                .map(|v| match v.trim().to_ascii_lowercase().as_str() {
+                    "1" | "true" | "yes" | "on" => Ok(true),
+                    "0" | "false" | "no" | "off" => Ok(false),
+                    _ => bail!("{k}={v:?} is not a boolean"),
+                })
+                .transpose()
+        };
+        let list = |v: String| -> Vec<String> {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        let rp_id = env("AG_RP_ID")
+            .or(file.webauthn.rp_id)
+            .context("webauthn.rp_id (AG_RP_ID) not set")?;
+        let origin = env("AG_ORIGIN")
+            .or(file.webauthn.origin)
+            .context("webauthn.origin (AG_ORIGIN) not set")?;
+        // A WebAuthn RP ID is a domain; browsers refuse every ceremony for
+        // an IP address, long after setup looked fine.
+        if rp_id
+            .trim_matches(['[', ']'])
+            .parse::<std::net::IpAddr>()
+            .is_ok()
+        {
+            bail!("webauthn.rp_id {rp_id:?} is an IP address; WebAuthn needs a domain name");
+        }
+        match url::Url::parse(&origin).map(|u| u.host().map(|h| h.to_owned())) {
+            Ok(Some(url::Host::Domain(_))) => {}
+            Ok(Some(_)) => {
+                bail!("webauthn.origin {origin:?} is an IP address; WebAuthn needs a domain name")
+            }
+            _ => bail!("webauthn.origin {origin:?} is not a URL with a host"),
+        }
+
+        let mut web_listen = env("AG_WEB_LISTEN")
+            .or(file.listen.web)
+            .unwrap_or_else(|| DEFAULT_WEB_LISTEN.into());
+        if let Some(port) = parse("AG_WEB_PORT")? {
+            let host = web_listen
+                .rsplit_once(':')
+                .map_or(web_listen.as_str(), |(h, _)| h);
+            web_listen = format!("{host}:{port}");
+        }
+
+        let ntfy = file.notify.ntfy.unwrap_or_default();
+        let gotify = file.notify.gotify.unwrap_or_default();
+        let webhook = file.notify.webhook.unwrap_or_default();
+        let chain = env("AG_NOTIFY")
+            .map(list)
+            .or(file.notify.chain)
+            .unwrap_or_else(|| vec!["webpush".into()]);
+        let mut notify = Vec::new();
+        for name in &chain {
+            notify.push(match name.as_str() {
+                "webpush" => NotifyBackend::WebPush,
+                "ntfy" => NotifyBackend::Ntfy {
+                    url: env("AG_NTFY_URL")
+                        .or(ntfy.url.clone())
+                        .unwrap_or_else(|| DEFAULT_NTFY_URL.into()),
+                    topic: ntfy_topic(env("AG_NTFY_TOPIC").or(ntfy.topic.clone()).context(
+                        "notify chain has ntfy but notify.ntfy.topic (AG_NTFY_TOPIC) not set",
+                    )?)?,
+                    token: env("AG_NTFY_TOKEN").or(ntfy.token.clone()),
+                },
+                "gotify" => NotifyBackend::Gotify {
+                    url: env("AG_GOTIFY_URL").or(gotify.url.clone()).context(
+                        "notify chain has gotify but notify.gotify.url (AG_GOTIFY_URL) not set",
+                    )?,
+                    token: env("AG_GOTIFY_TOKEN").or(gotify.token.clone()).context(
+                        "notify chain has gotify but notify.gotify.token (AG_GOTIFY_TOKEN) not set",
+                    )?,
+                },
+                "webhook" => NotifyBackend::Webhook {
+                    url: env("AG_WEBHOOK_URL").or(webhook.url.clone()).context(
+                        "notify chain has webhook but notify.webhook.url (AG_WEBHOOK_URL) not set",
+                    )?,
+                    token: env("AG_WEBHOOK_TOKEN").or(webhook.token.clone()),
+                },
+                other => bail!("notify chain: unknown backend {other:?}"),
+            });
+        }
+
+        let file_tls = file.tls.unwrap_or_default();
+        let tls = match (
+            env("AG_TLS_CERT").map(PathBuf::from).or(file_tls.cert),
+            env("AG_TLS_KEY").map(PathBuf::from).or(file_tls.key),
+        ) {
+            (Some(cert), Some(key)) => {
+                let listen = match env("AG_TLS_LISTEN").or(file_tls.listen) {
+                    Some(listen) => listen,
+                    None => {
+                        let url = url::Url::parse(&origin)
+                            .with_context(|| format!("origin {origin:?} is not a URL"))?;
+                        format!("0.0.0.0:{}", url.port_or_known_default().unwrap_or(443))
+                    }
+                };
+                Some(TlsCfg { cert, key, listen })
+            }
+            (None, None) => None,
+            _ => bail!("tls.cert (AG_TLS_CERT) and tls.key (AG_TLS_KEY) must be set together"),
+        };
+
+        let grant_minutes = match env("AG_GRANT_MINUTES") {
+            Some(v) => list(v)
+                .iter()
+                .map(|m| {
+                    m.parse()
+                        .with_context(|| format!("AG_GRANT_MINUTES: {m:?} is not a number"))
+                })
+                .collect::<Result<Vec<u64>>>()?,
+            None => file
+                .policy
+                .grant_minutes
+                .unwrap_or_else(|| DEFAULT_GRANT_MINUTES.to_vec()),
+        };
+        if let Some(bad) = grant_minutes
+            .iter()
+            .find(|&&m| m == 0 || m > MAX_GRANT_MINUTES)
+        {
+            bail!("policy.grant_minutes: {bad} is outside 1..={MAX_GRANT_MINUTES}");
+        }
+
+        // AG_UID_GROUPS="0=sudo:polkit,1000=login"
+        let uids = match env("AG_UID_GROUPS") {
+            Some(v) => list(v)
+                .iter()
+                .map(|entry| {
+                    let (uid, groups) = entry.split_once('=').with_context(|| {
+                        format!("AG_UID_GROUPS: {entry:?} is not uid=group:group")
+                    })?;
+                    Ok((
+                        uid.to_string(),
+                        groups.split(':').map(String::from).collect(),
+                    ))
+                })
+                .collect::<Result<BTreeMap<String, Vec<String>>>>()?,
+            None => file.policy.uids.unwrap_or_default(),
+        };
+        let uid_groups = uids
+            .into_iter()
+            .map(|(uid, groups)| {
+                let uid = uid
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("policy.uids: {uid:?} is not a uid"))?;
+                Ok((uid, groups))
+            })
+            .collect::<Result<_>>()?;
+        // AG_EXE_PATHS="game-mode=/usr/bin/game-mode"
+        let exe_paths = match env("AG_EXE_PATHS") {
+            Some(v) => list(v)
+                .iter()
+                .map(|entry| {
+                    let (exe, path) = entry
+                        .split_once('=')
+                        .with_context(|| format!("AG_EXE_PATHS: {entry:?} is not exe=/path"))?;
+                    Ok((exe.to_string(), PathBuf::from(path)))
+                })
+                .collect::<Result<_>>()?,
+            None => file.policy.exes.unwrap_or_else(|| {
+                DEFAULT_EXE_PATHS
+                    .iter()
+                    .map(|(exe, path)| (exe.to_string(), PathBuf::from(path)))
+                    .collect()
+            }),
+        };
+
+        let data_dir: PathBuf = env("AG_DATA_DIR")
+            .map(PathBuf::from)
+            .or(file.storage.data_dir)
+            .unwrap_or_else(|| DEFAULT_DATA_DIR.into());
+        let storage = match env("AG_STORAGE")
+            .or(file.storage.backend)
+            .as_deref()
+            .unwrap_or("file")
+        {
+            "file" => StorageBackend::File,
+            "sqlite" => StorageBackend::Sqlite,
+            other => bail!("storage.backend: unknown backend {other:?} (file or sqlite)"),
+        };
+
+        let request_ttl = parse("AG_REQUEST_TTL")?
+            .or(file.requests.ttl_secs)
+            .unwrap_or(DEFAULT_REQUEST_TTL);
+        Ok(Cfg {
+            rp_id,
+            origin,
+            user_name: env("AG_USER_NAME")
+                .or(file.webauthn.user_name)
+                .unwrap_or_else(|| "game-mode".into()),
+            web_listen,
+            metrics_listen: env("AG_METRICS_LISTEN").or(file.listen.metrics),
+            proxy_uid: match parse("AG_PROXY_UID")? {
+                Some(uid) => u32::try_from(uid).context("AG_PROXY_UID is not a uid")?,
+                None => file.listen.proxy_uid.unwrap_or(DEFAULT_PROXY_UID),
+            },
+            ctrl_socket: env("AG_CTRL_SOCKET")
+                .map(PathBuf::from)
+                .or(file.listen.ctrl_socket)
+                .unwrap_or_else(|| DEFAULT_CTRL_SOCKET.into()),
+            pages_dir: env("AG_PAGES_DIR")
+                .map(PathBuf::from)
+                .or(file.pages.dir)
+                .unwrap_or_else(|| data_dir.join("pages")),
+            data_dir,
+            storage,
+            request_ttl,
+            default_wait: parse("AG_DEFAULT_WAIT")?
+                .or(file.requests.default_wait_secs)
+                .unwrap_or(DEFAULT_WAIT)
+                .min(request_ttl),
+            rate_per_min: parse("AG_RATE_PER_MIN")?
+                .or(file.limits.per_min)
+                .unwrap_or(DEFAULT_RATE_PER_MIN),
+            rate_burst: parse("AG_RATE_BURST")?
+                .or(file.limits.burst)
+                .unwrap_or(DEFAULT_RATE_BURST),
+            max_failures: parse("AG_MAX_FAILURES")?
+                .or(file.limits.max_failures)
+                .unwrap_or(DEFAULT_MAX_FAILURES)
+                .max(1),
+            vapid_sub: env("AG_VAPID_SUB")
+                .or(file.notify.vapid_sub)
+                .unwrap_or_else(|| "access-gate@localhost".into()),
+            notify,
+            groups: env("AG_GROUPS")
+                .map(list)
+                .or(file.policy.groups)
+                .unwrap_or_default(),
+            grant_minutes,
+            uid_groups,
+            exe_paths,
+            totp_groups: env("AG_TOTP_GROUPS")
+                .map(list)
+                .or(file.policy.totp_groups)
+                .unwrap_or_default(),
+            brand: env("AG_BRAND")
+                .or(file.pages.brand)
+                .unwrap_or_else(|| DEFAULT_BRAND.into()),
+            deny_reasons: env("AG_DENY_REASONS")
+                .map(list)
+                .or(file.pages.deny_reasons)
+                .unwrap_or_else(|| DEFAULT_DENY_REASONS.map(String::from).to_vec())
+                .iter()
+                .filter_map(|r| crate::history::clean_reason(r))
+                .collect(),
+            sandbox: SandboxCfg {
+                landlock: flag("AG_SANDBOX_LANDLOCK")?
+                    .or(file.sandbox.landlock)
+                    .unwrap_or(true),
+                seccomp: flag("AG_SANDBOX_SECCOMP")?
+                    .or(file.sandbox.seccomp)
+                    .unwrap_or(true),
+            },
+            tls,
+        })
+    }
+
+    /// The effective config in file form (what `--print-config` shows and
+    /// `--migrate-env` writes). Secrets are replaced when `redact` is set.
+    pub fn to_file(&self, redact: bool) -> FileConfig {
+        let secret = |s: &str| {
+            if redact {
+                REDACTED.to_string()
+            } else {
+                s.to_string()
+            }
+        };
+        let mut notify = FileNotify {
+            vapid_sub: Some(self.vapid_sub.clone()),
+            ..Default::default()
+        };
+        let mut chain = Vec::new();
+        for backend in &self.notify {
+            match backend {
+                NotifyBackend::WebPush => chain.push("webpush".into()),
+                NotifyBackend::Ntfy { url, topic, token } => {
+                    chain.push("ntfy".into());
+                    notify.ntfy = Some(FileNtfy {
+                        url: Some(url.clone()),
+                        topic: Some(topic.clone()),
+                        token: token.as_deref().map(secret),
+                    });
+                }
+                NotifyBackend::Gotify { url, token } => {
+                    chain.push("gotify".into());
+                    notify.gotify = Some(FileGotify {
+                        url: Some(url.clone()),
+                        token: Some(secret(token)),
+                    });
+                }
+                NotifyBackend::Webhook { url, token } => {
+                    chain.push("webhook".into());
+                    notify.webhook = Some(FileWebhook {
+                        url: Some(url.clone()),
+                        token: token.as_deref().map(secret),
+                    });
+                }
+            }
+        }
+        notify.chain = Some(chain);
+        FileConfig {
+            webauthn: FileWebauthn {
+                rp_id: Some(self.rp_id.clone()),
+                origin: Some(self.origin.clone()),
+                user_name: Some(self.user_name.clone()),
+            },
+            listen: FileListen {
+                web: Some(self.web_listen.clone()),
+                ctrl_socket: Some(self.ctrl_socket.clone()),
+                metrics: self.metrics_listen.clone(),
+                proxy_uid: Some(self.proxy_uid),
+            },
+            storage: FileStorage {
+                data_dir: Some(self.data_dir.clone()),
+                backend: Some(
+                    match self.storage {
+                        StorageBackend::File => "file",
+                        StorageBackend::Sqlite => "sqlite",
+                    }
+                    .into(),
+                ),
+            },
+            requests: FileRequests {
+                ttl_secs: Some(self.request_ttl),
+                default_wait_secs: Some(self.default_wait),
+            },
+            limits: FileLimits {
+                per_min: Some(self.rate_per_min),
+                burst: Some(self.rate_burst),
+                max_failures: Some(self.max_failures),
+            },
+            notify,
+            policy: FilePolicy {
+                groups: Some(self.groups.clone()),
+                grant_minutes: Some(self.grant_minutes.clone()),
+                uids: Some(
+                    self.uid_groups
+                        .iter()
+                        .map(|(uid, groups)| (uid.to_string(), groups.clone()))
+                        .collect(),
+                ),
+                exes: Some(self.exe_paths.clone()),
+                totp_groups: Some(self.totp_groups.clone()),
+            },
+            pages: FilePages {
+                brand: Some(self.brand.clone()),
+                deny_reasons: Some(self.deny_reasons.clone()),
+                dir: Some(self.pages_dir.clone()),
+            },
+            sandbox: FileSandbox {
+                landlock: Some(self.sandbox.landlock),
+                seccomp: Some(self.sandbox.seccomp),
+            },
+            tls: self.tls.as_ref().map(|t| FileTls {
+                cert: Some(t.cert.clone()),
+                key: Some(t.key.clone()),
+                listen: Some(t.listen.clone()),
+            }),
+        }
+    }
+
+    pub fn to_toml(&self, redact: bool) -> Result<String> {
+        toml::to_string_pretty(&self.to_file(redact)).context("serialize config")
+    }
+}
+
+/// KEY=VALUE lines of an env file (the systemd EnvironmentFile subset the
+/// setup command writes: no quoting, `#` comments).
+pub fn read_env_file(path: &Path) -> Result<HashMap<String, String>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect())
+}
+
+/// `--migrate-env`: turn an existing approval.env into verifier.toml.
+/// Refuses to overwrite an existing config; the env file is left alone (the
+/// daemon still reads its own keys from it).
+pub fn migrate_env(env_file: &Path, out: &Path) -> Result<()> {
+    if out.exists() {
+        bail!("{} already exists; not overwriting", out.display());
+    }
+    let vars = read_env_file(env_file)?;
+    let cfg = Cfg::resolve(FileConfig::default(), &|k| vars.get(k).cloned())?;
+    let text = format!(
+        "# access-gate verifier configuration, migrated from {}.\n\
+         # AG_* environment variables override individual keys.\n\n{}",
+        env_file.display(),
+        cfg.to_toml(false)?
+    );
+    if let Some(dir) = out.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // May hold notifier tokens: not world-readable.
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o640)
+        .open(out)
+        .with_context(|| format!("failed to create {}", out.display()))?;
+    use std::io::Write;
+    f.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn defaults_with_required_keys_from_env() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("box.example.ts.net".to_string()),
+            "AG_ORIGIN" => Some("https://box.example.ts.net".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(Path::new("/nonexistent/verifier.toml"), &env).unwrap();
+        assert_eq!(cfg.web_listen, DEFAULT_WEB_LISTEN);
+        assert_eq!(cfg.metrics_listen, None);
+        assert_eq!(cfg.proxy_uid, DEFAULT_PROXY_UID);
+        assert_eq!(cfg.ctrl_socket, PathBuf::from(DEFAULT_CTRL_SOCKET));
+        assert_eq!(cfg.data_dir, PathBuf::from(DEFAULT_DATA_DIR));
+        assert_eq!(cfg.pages_dir, PathBuf::from(DEFAULT_DATA_DIR).join("pages"));
+        assert!(cfg.sandbox.landlock && cfg.sandbox.seccomp);
+        assert_eq!(cfg.storage, StorageBackend::File);
+        assert_eq!(cfg.request_ttl, DEFAULT_REQUEST_TTL);
+        assert_eq!(cfg.default_wait, DEFAULT_WAIT);
+        assert_eq!(cfg.rate_per_min, DEFAULT_RATE_PER_MIN);
+        assert_eq!(cfg.max_failures, DEFAULT_MAX_FAILURES);
+        assert_eq!(cfg.notify, vec![NotifyBackend::WebPush]);
+        assert!(cfg.groups.is_empty());
+        assert_eq!(cfg.grant_minutes, DEFAULT_GRANT_MINUTES);
+        assert_eq!(cfg.deny_reasons, DEFAULT_DENY_REASONS);
+        assert!(cfg.uid_groups.is_empty());
+        assert_eq!(
+            cfg.exe_paths["game-mode"],
+            PathBuf::from("/usr/bin/game-mode")
+        );
+    }
+
+    #[test]
+    fn missing_rp_id_is_an_error() {
+        assert!(Cfg::load_from(Path::new("/nonexistent/verifier.toml"), &no_env).is_err());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let dir = tmp_dir("cfg-override");
+        let path = dir.join("verifier.toml");
+        fs::write(
+            &path,
+            r#"
+[webauthn]
+rp_id = "file.example"
+origin = "https://file.example"
+
+[listen]
+web = "0.0.0.0:9000"
+metrics = "100.64.0.2:9731"
+proxy_uid = 970
+
+[storage]
+backend = "sqlite"
+
+[requests]
+ttl_secs = 300
+
+[limits]
+burst = 5
+max_failures = 2
+
+[notify]
+chain = ["ntfy", "webpush"]
+
+[notify.ntfy]
+topic = "from-file"
+
+[sandbox]
+seccomp = false
+"#,
+        )
+        .unwrap();
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("env.example".to_string()),
+            "AG_WEB_PORT" => Some("9100".to_string()),
+            "AG_NTFY_TOPIC" => Some("from-env".to_string()),
+            "AG_SANDBOX_LANDLOCK" => Some("off".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(&path, &env).unwrap();
+        assert_eq!(cfg.rp_id, "env.example");
+        assert_eq!(cfg.origin, "https://file.example");
+        assert_eq!(cfg.web_listen, "0.0.0.0:9100");
+        assert_eq!(cfg.metrics_listen.as_deref(), Some("100.64.0.2:9731"));
+        assert_eq!(cfg.proxy_uid, 970);
+        assert_eq!(cfg.storage, StorageBackend::Sqlite);
+        assert_eq!(cfg.request_ttl, 300);
+        assert_eq!((cfg.rate_burst, cfg.max_failures), (5, 2));
+        assert_eq!(
+            cfg.sandbox,
+            SandboxCfg {
+                landlock: false,
+                seccomp: false
+            }
+        );
+        assert_eq!(
+            cfg.notify,
+            vec![
+                NotifyBackend::Ntfy {
+                    url: DEFAULT_NTFY_URL.into(),
+                    topic: "from-env".into(),
+                    token: None,
+                },
+                NotifyBackend::WebPush,
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wait_is_capped_by_ttl() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("a".to_string()),
+            "AG_ORIGIN" => Some("https://a".to_string()),
+            "AG_REQUEST_TTL" => Some("30".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(Path::new("/nonexistent"), &env).unwrap();
+        assert_eq!(cfg.default_wait, 30);
+    }
+
+    #[test]
+    fn grant_minutes_are_bounded() {
+        let env = |minutes: &'static str| {
+            move |k: &str| match k {
+                "AG_RP_ID" => Some("a".to_string()),
+                "AG_ORIGIN" => Some("https://a".to_string()),
+                "AG_GRANT_MINUTES" => Some(minutes.to_string()),
+                _ => None,
+            }
+        };
+        let cfg = Cfg::load_from(Path::new("/nonexistent"), &env("15, 60")).unwrap();
+        assert_eq!(cfg.grant_minutes, [15, 60]);
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env(""))
+            .unwrap()
+            .grant_minutes
+            .is_empty());
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env("0")).is_err());
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env("2000")).is_err());
+    }
+
+    #[test]
+    fn chain_backend_without_settings_is_an_error() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("a".to_string()),
+            "AG_ORIGIN" => Some("https://a".to_string()),
+            "AG_NOTIFY" => Some("webpush,gotify".to_string()),
+            _ => None,
+        };
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env).is_err());
+    }
+
+    #[test]
+    fn ip_literal_origins_are_refused() {
+        let env = |rp_id: &'static str, origin: &'static str| {
+            move |k: &str| match k {
+                "AG_RP_ID" => Some(rp_id.to_string()),
+                "AG_ORIGIN" => Some(origin.to_string()),
+                _ => None,
+            }
+        };
+        let load = |rp_id, origin| Cfg::load_from(Path::new("/nonexistent"), &env(rp_id, origin));
+        assert!(load("box.lan", "https://box.lan:8443").is_ok());
+        assert!(load("192.168.1.20", "https://192.168.1.20").is_err());
+        assert!(load("fd00::20", "https://[fd00::20]:8443").is_err());
+        assert!(load("box.lan", "https://10.0.0.2").is_err());
+        assert!(load("box.lan", "not a url").is_err());
+    }
+
+    #[test]
+    fn ntfy_topic_must_be_one_path_segment() {
+        let env = |topic: &'static str| {
+            move |k: &str| match k {
+                "AG_RP_ID" => Some("a".to_string()),
+                "AG_ORIGIN" => Some("https://a".to_string()),
+                "AG_NOTIFY" => Some("ntfy".to_string()),
+                "AG_NTFY_TOPIC" => Some(topic.to_string()),
+                _ => None,
+            }
+        };
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env("gate_tv-1")).is_ok());
+        for bad in ["a/b", "gate?x=1", "gate#x", "", "spa ce"] {
+            assert!(
+                Cfg::load_from(Path::new("/nonexistent"), &env(bad)).is_err(),
+                "{bad:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn peer_policy_from_file_and_env() {
+        let dir = tmp_dir("cfg-peers");
+        let path = dir.join("verifier.toml");
+        fs::write(
+            &path,
+            r#"
+[webauthn]
+rp_id = "a"
+origin = "https://a"
+
+[policy]
+totp_groups = ["login"]
+
+[policy.uids]
+0 = ["sudo", "polkit"]
+1000 = ["login"]
+
+[policy.exes]
+game-mode = "/opt/game-mode/bin/game-mode"
+"#,
+        )
+        .unwrap();
+        let cfg = Cfg::load_from(&path, &no_env).unwrap();
+        assert_eq!(cfg.uid_groups[&0], ["sudo", "polkit"]);
+        assert_eq!(cfg.uid_groups[&1000], ["login"]);
+        assert_eq!(cfg.exe_paths.len(), 1);
+        assert_eq!(cfg.totp_groups, ["login"]);
+
+        let env = |k: &str| match k {
+            "AG_UID_GROUPS" => Some("971=login:sudo".to_string()),
+            "AG_TOTP_GROUPS" => Some(String::new()),
+            "AG_EXE_PATHS" => Some("sshd=/usr/sbin/sshd".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(&path, &env).unwrap();
+        assert_eq!(
+            cfg.uid_groups,
+            BTreeMap::from([(971, vec!["login".to_string(), "sudo".to_string()])])
+        );
+        assert_eq!(cfg.exe_paths["sshd"], PathBuf::from("/usr/sbin/sshd"));
+        assert!(cfg.totp_groups.is_empty());
+
+        let bad = |k: &str| match k {
+            "AG_UID_GROUPS" => Some("greeter=login".to_string()),
+            _ => None,
+        };
+        assert!(Cfg::load_from(&path, &bad).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_storage_backend_is_an_error() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("a".to_string()),
+            "AG_ORIGIN" => Some("https://a".to_string()),
+            "AG_STORAGE" => Some("tape".to_string()),
+            _ => None,
+        };
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &env).is_err());
+    }
+
+    #[test]
+    fn garbage_file_is_an_error() {
+        let dir = tmp_dir("cfg-bad");
+        let path = dir.join("verifier.toml");
+        fs::write(&path, "not [ valid toml").unwrap();
+        assert!(Cfg::load_from(&path, &no_env).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn printed_config_round_trips_and_redacts() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("a".to_string()),
+            "AG_ORIGIN" => Some("https://a".to_string()),
+            "AG_NOTIFY" => Some("gotify".to_string()),
+            "AG_GOTIFY_URL" => Some("https://gotify.lan".to_string()),
+            "AG_GOTIFY_TOKEN" => Some("s3cret".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(Path::new("/nonexistent"), &env).unwrap();
+        let printed = cfg.to_toml(true).unwrap();
+        assert!(!printed.contains("s3cret"), "{printed}");
+        let again = Cfg::resolve(
+            toml::from_str(&cfg.to_toml(false).unwrap()).unwrap(),
+            &no_env,
+        )
+        .unwrap();
+        assert_eq!(again.notify, cfg.notify);
+        assert_eq!(again.web_listen, cfg.web_listen);
+        assert_eq!(again.exe_paths, cfg.exe_paths);
+    }
+
+    #[test]
+    fn tls_listen_defaults_to_origin_port() {
+        let env = |k: &str| match k {
+            "AG_RP_ID" => Some("box.lan".to_string()),
+            "AG_ORIGIN" => Some("https://box.lan:8443".to_string()),
+            "AG_TLS_CERT" => Some("/etc/game-mode/tls/cert.pem".to_string()),
+            "AG_TLS_KEY" => Some("/etc/game-mode/tls/key.pem".to_string()),
+            _ => None,
+        };
+        let cfg = Cfg::load_from(Path::new("/nonexistent"), &env).unwrap();
+        assert_eq!(cfg.tls.unwrap().listen, "0.0.0.0:8443");
+
+        let half = |k: &str| match k {
+            "AG_TLS_KEY" => None,
+            k => env(k),
+        };
+        assert!(Cfg::load_from(Path::new("/nonexistent"), &half).is_err());
+    }
+
+    #[test]
+    fn migrates_approval_env() {
+        let dir = tmp_dir("cfg-migrate");
+        let env_file = dir.join("approval.env");
+        let out = dir.join("verifier.toml");
+        fs::write(
+            &env_file,
+            "AG_RP_ID=box.example.ts.net\n\
+             AG_ORIGIN=https://box.example.ts.net\n\
+             # comment\n\
+             AG_DATA_DIR=/var/lib/access-gate\n\
+             AG_CTRL_SOCKET=/run/access-gate/ctrl.sock\n\
+             AG_APPROVE_BASE=https://box.example.ts.net/approve\n\
+             AG_TIMEOUT=90\n\
+             AG_VAPID_SUB=access-gate@box.example.ts.net\n",
+        )
+        .unwrap();
+        migrate_env(&env_file, &out).unwrap();
+        let cfg = Cfg::load_from(&out, &no_env).unwrap();
+        assert_eq!(cfg.rp_id, "box.example.ts.net");
+        assert_eq!(cfg.origin, "https://box.example.ts.net");
+        assert_eq!(cfg.vapid_sub, "access-gate@box.example.ts.net");
+        assert!(migrate_env(&env_file, &out).is_err(), "must not overwrite");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
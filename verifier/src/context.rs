@@ -0,0 +1,181 @@
+//! What a request says about where it comes from, beyond `exe` and `path`:
+//! the box, the profile, the pad and its battery, the VT, and how long ago
+//! the last session there was. The daemon sends it as the request's
+//! `context` object; it is shown on the approve page and in the
+//! notification body.
+//!
+//! It is the client's word, like `exe`: every text is cleaned (see
+//! `access_gate_text::clean_text`) and capped here, and HTML-escaped again
+//! where a page renders it.
+
+use access_gate_text::clean_text;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Longest text field kept, in characters.
+pub const MAX_FIELD_CHARS: usize = 48;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequestContext {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The pad's name as the daemon's input layer reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pad: Option<String>,
+    /// Pad battery in percent; absent for a wired or unknown pad.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vt: Option<u32>,
+    /// Seconds since the daemon last saw a session on its VT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since_last_session: Option<u64>,
+}
+
+/// A duration as the catalogue message that shows it (`minutes`, `hours`,
+/// `days`) and its `{n}`. Rounded down, never below one minute.
+pub fn span(secs: u64) -> (&'static str, u64) {
+    match secs {
+        s if s < 3600 => ("minutes", (s / 60).max(1)),
+        s if s < 2 * 86400 => ("hours", s / 3600),
+        s => ("days", s / 86400),
+    }
+}
+
+impl RequestContext {
+    /// The `context` object of a request, cleaned; `None` when it is
+    /// missing or nothing in it is usable.
+    pub fn from_request(v: &Value) -> Option<Self> {
+        let text = |key: &str| v[key].as_str().and_then(|s| clean_text(s, MAX_FIELD_CHARS));
+        let ctx = RequestContext {
+            hostname: text("hostname"),
+            profile: text("profile"),
+            pad: text("pad"),
+            battery: v["battery"].as_u64().filter(|b| *b <= 100).map(|b| b as u8),
+            vt: v["vt"].as_u64().and_then(|n| u32::try_from(n).ok()),
+            since_last_session: v["since_last_session"].as_u64(),
+        };
+        (ctx != RequestContext::default()).then_some(ctx)
+    }
+
+    /// The pad with its battery, as one line.
+    fn pad_line(&self) -> Option<String> {
+        let pad = self.pad.as_deref()?;
+        Some(match self.battery {
+            Some(b) => format!("{pad} ({b}%)"),
+            None => pad.to_string(),
+        })
+    }
+
+    /// (catalogue label key, value) for each field that is set, in display
+    /// order. Values are plain text; `last` renders the session span.
+    pub fn rows(&self, last: impl Fn(u64) -> String) -> Vec<(&'static str, String)> {
+        let mut rows = Vec::new();
+        if let Some(h) = &self.hostname {
+            rows.push(("context_host", h.clone()));
+        }
+        if let Some(p) = &self.profile {
+            rows.push(("context_profile", p.clone()));
+        }
+        if let Some(pad) = self.pad_line() {
+            rows.push(("context_pad", pad));
+        }
+        if let Some(vt) = self.vt {
+            rows.push(("context_vt", vt.to_string()));
+        }
+        if let Some(secs) = self.since_last_session {
+            rows.push(("context_last_session", last(secs)));
+        }
+        rows
+    }
+
+    /// One line for a notification body (not localised, like the rest of
+    /// it): "den-pc · Xbox Controller (40%) · games · VT 1 · last session
+    /// 3 h ago".
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = Vec::new();
+        parts.extend(self.hostname.clone());
+        parts.extend(self.pad_line());
+        parts.extend(self.profile.clone());
+        parts.extend(self.vt.map(|vt| format!("VT {vt}")));
+        parts.extend(self.since_last_session.map(|secs| {
+            let (unit, n) = span(secs);
+            let unit = match unit {
+                "minutes" => "min",
+                "hours" => "h",
+                _ => "d",
+            };
+            format!("last session {n} {unit} ago")
+        }));
+        parts.join(" · ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn request_context_is_cleaned_and_capped() {
+        let ctx = RequestContext::from_request(&json!({
+            "hostname": "den\u{1b}[2J-pc",
+            "profile": "  games\n",
+            "pad": "p".repeat(500),
+            "battery": 40,
+            "vt": 1,
+            "since_last_session": 7300,
+        }))
+        .unwrap();
+        assert_eq!(ctx.hostname.as_deref(), Some("den[2J-pc"));
+        assert_eq!(ctx.profile.as_deref(), Some("games"));
+        assert_eq!(ctx.pad.as_ref().unwrap().chars().count(), MAX_FIELD_CHARS);
+        assert_eq!(ctx.battery, Some(40));
+
+        assert!(
+            RequestContext::from_request(&json!({"battery": 250, "vt": -1, "pad": 7})).is_none()
+        );
+        assert!(RequestContext::from_request(&Value::Null).is_none());
+        assert!(RequestContext::from_request(&json!({"hostname": " \t"})).is_none());
+    }
+
+    #[test]
+    fn rows_and_summary_skip_missing_fields() {
+        let ctx = RequestContext {
+            hostname: Some("den-pc".into()),
+            pad: Some("Xbox Controller".into()),
+            battery: Some(40),
+            vt: Some(1),
+            since_last_session: Some(3 * 3600 + 59),
+            ..RequestContext::default()
+        };
+        assert_eq!(
+            ctx.summary(),
+            "den-pc · Xbox Controller (40%) · VT 1 · last session 3 h ago"
+        );
+        let rows = ctx.rows(|secs| format!("{secs}s"));
+        let keys: Vec<&str> = rows.iter().map(|(k, _)| *k).collect();
+        assert_eq!(
+            keys,
+            [
+                "context_host",
+                "context_pad",
+                "context_vt",
+                "context_last_session"
+            ]
+        );
+        assert_eq!(rows[3].1, "10859s");
+        assert_eq!(RequestContext::default().summary(), "");
+    }
+
+    #[test]
+    fn spans_round_down() {
+        assert_eq!(span(5), ("minutes", 1));
+        assert_eq!(span(3599), ("minutes", 59));
+        assert_eq!(span(3600), ("hours", 1));
+        assert_eq!(span(47 * 3600), ("hours", 47));
+        assert_eq!(span(9 * 86400 + 5), ("days", 9));
+    }
+}
@@ -0,0 +1,405 @@
+//! Control plane: the unix socket the daemon (and the PAM module, the
+//! polkit agent) create requests on. Blocking request/response, one
+//! request per connection.
+//!
+//! Newline-delimited JSON: the client writes one request line; the
+//! verifier answers `{"id":..}` at once and `{"status":..}` when the phone
+//! decides or the wait times out. No polling, and unlike a localhost TCP
+//! port the socket's permissions limit who can create requests at all. A
+//! client whose connection dropped sends `{"resume":"<id>"}` and gets the
+//! decision line for that request.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::app::{new_request_id, App, ApprovalRequest};
+use crate::config::Cfg;
+use crate::context::RequestContext;
+use crate::decision::valid_nonce;
+use crate::history::{Peer, Record};
+use crate::journal::now_unix;
+use crate::notify::Notification;
+use crate::sandbox;
+
+/// The process on the other end of a unix socket: its credentials as of
+/// connect(), and the binary that pid runs now.
+fn peer_cred(stream: &UnixStream) -> Option<Peer> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return None;
+    }
+    let exe = sandbox::peer_exe(cred.pid);
+    Some(Peer {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: cred.pid,
+        exe,
+    })
+}
+
+/// Why `policy.uids` keeps `peer` from requesting `group`, if it does.
+fn uid_refusal(cfg: &Cfg, peer: Option<&Peer>, group: &str) -> Option<String> {
+    if cfg.uid_groups.is_empty() {
+        return None;
+    }
+    let Some(peer) = peer else {
+        return Some("peer credentials unavailable".into());
+    };
+    match cfg.uid_groups.get(&peer.uid) {
+        Some(groups) if groups.iter().any(|g| g == group) => None,
+        _ => Some(format!("uid {} may not request group {group:?}", peer.uid)),
+    }
+}
+
+/// Does the request claim an `exe` with a configured binary (`policy.exes`)
+/// while the peer runs something else? An unreadable peer binary counts:
+/// the claim can't be backed. A binary replaced on disk since the process
+/// started (an upgrade) shows as "<path> (deleted)" and still matches.
+fn exe_mismatch(cfg: &Cfg, claimed: &str, peer: Option<&Peer>) -> bool {
+    let Some(expected) = cfg.exe_paths.get(claimed) else {
+        return false;
+    };
+    let actual = peer.and_then(|p| p.exe.as_deref());
+    actual.map(|a| a.trim_end_matches(" (deleted)")) != expected.to_str()
+}
+
+fn refuse(writer: &mut UnixStream, why: &str) -> Result<()> {
+    writer.write_all(format!("{}\n", json!({ "error": why })).as_bytes())?;
+    Ok(())
+}
+
+/// Write the `{"status":..}` line for `rid`, signed when the client asked
+/// for it with a nonce.
+fn send_status(
+    app: &App,
+    writer: &mut UnixStream,
+    rid: &str,
+    nonce: Option<&str>,
+    mut line: Value,
+) -> Result<()> {
+    info!("request {rid}: {line}");
+    if let Some(nonce) = nonce {
+        app.decision.sign(rid, nonce, &mut line, now_unix());
+    }
+    writer.write_all(format!("{line}\n").as_bytes())?;
+    Ok(())
+}
+
+fn handle_ctrl(stream: UnixStream, app: Arc<App>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let req: Value = serde_json::from_str(line.trim()).context("bad request json")?;
+    let mut writer = stream;
+    let peer = peer_cred(&writer);
+    // Echoed into every signed status line (see `decision`).
+    let nonce = match req["nonce"].as_str() {
+        Some(n) if !valid_nonce(n) => return refuse(&mut writer, "bad nonce"),
+        n => n.map(String::from),
+    };
+
+    // Reconnect after a dropped connection (verifier restart): no new
+    // request, just the decision line for the old one.
+    if let Some(rid) = req["resume"].as_str() {
+        // Only the uid that made the request hears its decision.
+        let known = app
+            .requests
+            .lock()
+            .unwrap()
+            .get(rid)
+            .filter(|r| r.peer.as_ref().map(|p| p.uid) == peer.as_ref().map(|p| p.uid))
+            .map(|r| r.nonce.clone());
+        let (decision, nonce) = match known {
+            Some(stored) => {
+                info!("request {rid}: control client resumed");
+                let decision = wait_decision(&app, rid, &writer);
+                (decision, stored.or(nonce))
+            }
+            None => (json!({ "status": "unknown" }), nonce),
+        };
+        return send_status(&app, &mut writer, rid, nonce.as_deref(), decision);
+    }
+
+    let rid = new_request_id();
+    let exe = req["exe"].as_str().unwrap_or("?").to_string();
+    let path = req["path"].as_str().unwrap_or("?").to_string();
+    let group = req["group"].as_str().unwrap_or("?").to_string();
+    let title = req["title"].as_str().unwrap_or("").to_string();
+    let context = RequestContext::from_request(&req["context"]);
+    let wait_secs = req["timeout_secs"]
+        .as_u64()
+        .unwrap_or(app.cfg.default_wait)
+        .min(app.cfg.request_ttl);
+
+    if !app.cfg.groups.is_empty() && !app.cfg.groups.contains(&group) {
+        warn!("request for group {group:?} refused by policy (exe={exe})");
+        return refuse(&mut writer, &format!("group {group:?} not permitted"));
+    }
+    if let Some(why) = uid_refusal(&app.cfg, peer.as_ref(), &group) {
+        warn!("request refused by policy: {why} (exe={exe})");
+        return refuse(&mut writer, &why);
+    }
+    let exe_mismatch = exe_mismatch(&app.cfg, &exe, peer.as_ref());
+    if exe_mismatch {
+        warn!(
+            "request claims exe={exe} but comes from {}",
+            peer.as_ref()
+                .map_or("an unknown peer".into(), Peer::describe)
+        );
+    }
+
+    let now = now_unix();
+    // A grant is for the real binary: an impostor always asks the phone.
+    let grant = match exe_mismatch {
+        true => None,
+        false => app
+            .store
+            .matching_grant(&group, &exe, now)
+            .unwrap_or_else(|e| {
+                warn!("reading grants: {e:#}");
+                None
+            }),
+    };
+    if let Some(grant) = grant {
+        info!(
+            "request {rid} approved by grant {} (exe={exe}, {}s left)",
+            grant.id,
+            grant.expires_at - now
+        );
+        app.metrics.request_created();
+        app.metrics.request_decided("granted", 0);
+        let record = Record {
+            rid: rid.clone(),
+            exe,
+            path,
+            group,
+            title,
+            peer,
+            exe_mismatch,
+            outcome: "approved".into(),
+            credential: grant.credential,
+            client_ip: None,
+            reason: Some(format!("grant {}", grant.id)),
+            created_at: now,
+            decided_at: now,
+        };
+        app.record(&record);
+        writer.write_all(format!("{}\n", json!({ "id": rid })).as_bytes())?;
+        let answer = json!({ "status": "approved", "reason": "grant" });
+        return send_status(&app, &mut writer, &rid, nonce.as_deref(), answer);
+    }
+
+    {
+        let mut requests = app.requests.lock().unwrap();
+        requests.insert(
+            rid.clone(),
+            ApprovalRequest {
+                exe: exe.clone(),
+                path: path.clone(),
+                group: group.clone(),
+                title: title.clone(),
+                peer,
+                exe_mismatch,
+                nonce: nonce.clone(),
+                status: "pending".into(),
+                created_at: now,
+                wait_until: now + wait_secs,
+                auth: None,
+                failures: 0,
+                reason: None,
+                context: context.clone(),
+            },
+        );
+        app.persist(&requests);
+        // A new deadline for the expiry scheduler.
+        app.decided.notify_all();
+    }
+    app.metrics.request_created();
+    info!("request {rid} created (exe={exe})");
+
+    {
+        let app = app.clone();
+        let n = Notification {
+            url: app.approve_url(&rid),
+            rid: rid.clone(),
+            title,
+            exe: exe.clone(),
+            path: path.clone(),
+            context,
+            ttl: app.cfg.request_ttl as u32,
+        };
+        thread::spawn(move || {
+            app.notifier.send(&n, &|backend, delivered| {
+                app.metrics.notification(backend, delivered)
+            });
+        });
+    }
+
+    writer.write_all(format!("{}\n", json!({ "id": rid })).as_bytes())?;
+    writer.flush()?;
+
+    let decision = wait_decision(&app, &rid, &writer);
+    send_status(&app, &mut writer, &rid, nonce.as_deref(), decision)
+}
+
+/// `{"status":..}` for a decided request, with the deny reason if any.
+fn decision_line(r: &ApprovalRequest) -> Value {
+    match &r.reason {
+        Some(reason) => json!({ "status": r.status, "reason": reason }),
+        None => json!({ "status": r.status }),
+    }
+}
+
+/// Has the control client hung up? (EOF or an error pending on its socket.)
+fn client_gone(stream: &UnixStream) -> bool {
+    let mut byte = 0u8;
+    let n = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    n == 0 || (n < 0 && std::io::Error::last_os_error().kind() != std::io::ErrorKind::WouldBlock)
+}
+
+/// How often a waiting control connection is checked for a hang-up.
+const HANGUP_POLL: Duration = Duration::from_secs(1);
+
+/// Block until `rid` is decided (on the web plane, or timed out at its
+/// `wait_until` by the expiry scheduler) or the control client hangs up
+/// (cancelled at the TV). The outcome is recorded on the request, which
+/// stays around (for the approve page and a repeated `resume`) until the
+/// scheduler drops it.
+fn wait_decision(app: &App, rid: &str, client: &UnixStream) -> Value {
+    let mut requests = app.requests.lock().unwrap();
+    loop {
+        let Some(r) = requests.get_mut(rid) else {
+            return json!({ "status": "unknown" });
+        };
+        if r.status != "pending" {
+            return decision_line(r);
+        }
+        if client_gone(client) {
+            app.finish(rid, r, "cancelled", None, None, None);
+            app.persist(&requests);
+            app.decided.notify_all();
+            return json!({ "status": "cancelled" });
+        }
+        let (guard, _) = app.decided.wait_timeout(requests, HANGUP_POLL).unwrap();
+        requests = guard;
+    }
+}
+
+/// The control socket: systemd's, or bound at `ctrl_socket`.
+pub fn listen(cfg: &Cfg, activated: Option<UnixListener>) -> Result<UnixListener> {
+    let sock = &cfg.ctrl_socket;
+    Ok(match activated {
+        // Path, mode and ownership come from the .socket unit.
+        Some(listener) => listener,
+        None => {
+            if let Some(dir) = sock.parent() {
+                fs::create_dir_all(dir).ok();
+            }
+            let _ = fs::remove_file(sock);
+            let listener = UnixListener::bind(sock).with_context(|| format!("bind {sock:?}"))?;
+            // group-rw: the service's group (e.g. greeter) may create requests
+            fs::set_permissions(sock, fs::Permissions::from_mode(0o660))?;
+            info!("control listening on {sock:?}");
+            listener
+        }
+    })
+}
+
+pub fn run_ctrl(app: Arc<App>, listener: UnixListener) -> Result<()> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                let app = app.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_ctrl(s, app) {
+                        warn!("ctrl connection error: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("ctrl accept error: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn cfg(uid_groups: &'static str) -> Cfg {
+        let env = move |k: &str| match k {
+            "AG_RP_ID" => Some("a".to_string()),
+            "AG_ORIGIN" => Some("https://a".to_string()),
+            "AG_UID_GROUPS" => Some(uid_groups.to_string()),
+            _ => None,
+        };
+        Cfg::load_from(Path::new("/nonexistent"), &env).unwrap()
+    }
+
+    fn peer(uid: u32, exe: Option<&str>) -> Peer {
+        Peer {
+            uid,
+            gid: uid,
+            pid: 42,
+            exe: exe.map(String::from),
+        }
+    }
+
+    #[test]
+    fn uid_allowlist() {
+        let open = cfg("");
+        assert_eq!(uid_refusal(&open, None, "sudo"), None);
+
+        let cfg = cfg("0=sudo:polkit,1000=login");
+        assert_eq!(uid_refusal(&cfg, Some(&peer(0, None)), "sudo"), None);
+        assert_eq!(uid_refusal(&cfg, Some(&peer(1000, None)), "login"), None);
+        assert!(uid_refusal(&cfg, Some(&peer(1000, None)), "sudo").is_some());
+        assert!(uid_refusal(&cfg, Some(&peer(1001, None)), "login").is_some());
+        assert!(uid_refusal(&cfg, None, "login").is_some());
+    }
+
+    #[test]
+    fn claimed_exe_is_checked_against_the_binary() {
+        let cfg = cfg("");
+        let daemon = peer(0, Some("/usr/bin/game-mode"));
+        assert!(!exe_mismatch(&cfg, "game-mode", Some(&daemon)));
+        let upgraded = peer(0, Some("/usr/bin/game-mode (deleted)"));
+        assert!(!exe_mismatch(&cfg, "game-mode", Some(&upgraded)));
+
+        let impostor = peer(1000, Some("/usr/bin/python3"));
+        assert!(exe_mismatch(&cfg, "game-mode", Some(&impostor)));
+        assert!(exe_mismatch(&cfg, "game-mode", Some(&peer(0, None))));
+        assert!(exe_mismatch(&cfg, "game-mode", None));
+        // Claims without a configured binary are only shown, not judged.
+        assert!(!exe_mismatch(&cfg, "sudo", Some(&impostor)));
+    }
+}
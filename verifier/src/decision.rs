@@ -0,0 +1,162 @@
+//! Signed decisions. A control client that sends a `nonce` with its request
+//! gets every status line for it signed (ECDSA P-256), so the daemon can
+//! tell the verifier's answer from anything else that managed to listen on
+//! the socket path. The key is `decision_key.pem` in the data dir, made on
+//! first use; `access-gate-verifier decision-key` prints the public half
+//! for the daemon to pin (`game-mode setup` does that).
+//!
+//! The signed message, one field per line (the daemon builds the same
+//! bytes in its `approval.rs`):
+//!
+//! ```text
+//! access-gate-decision-v2
+//! <rid>
+//! <nonce>
+//! <status>
+//! <expires, unix seconds>
+//! <reason, empty when the line has none>
+//! ```
+//!
+//! The reason is shown on the TV and audited as the verifier's word, so it
+//! is signed too. It comes last: cleaned text has no line breaks, but even
+//! one could not shift the fields before it.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::SecretKey;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::journal::write_atomic;
+
+/// How long a signed line stays acceptable; the daemon reads it at once.
+pub const DECISION_TTL: u64 = 60;
+
+/// The bytes behind `sig`.
+pub fn message(rid: &str, nonce: &str, status: &str, expires: u64, reason: &str) -> String {
+    format!("access-gate-decision-v2\n{rid}\n{nonce}\n{status}\n{expires}\n{reason}")
+}
+
+/// A client nonce goes into the signed message verbatim, so it must not be
+/// able to forge a line break: base64url, 16 to 64 characters.
+pub fn valid_nonce(nonce: &str) -> bool {
+    (16..=64).contains(&nonce.len())
+        && nonce
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+pub struct DecisionKey(SigningKey);
+
+impl DecisionKey {
+    /// The data dir's key, generated when missing. Unlike the VAPID key an
+    /// unparsable one is an error: replacing it would break the daemon's pin
+    /// without anyone noticing.
+    pub fn load_or_generate(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("decision_key.pem");
+        if let Ok(pem) = fs::read_to_string(&path) {
+            let key = SecretKey::from_sec1_pem(&pem)
+                .map_err(|e| anyhow!("{} is unusable: {e}", path.display()))?;
+            return Ok(DecisionKey(key.into()));
+        }
+        let key = SecretKey::random(&mut OsRng);
+        let pem = key
+            .to_sec1_pem(Default::default())
+            .map_err(|e| anyhow!("PEM encode: {e}"))?;
+        write_atomic(&path, pem.as_bytes())?;
+        info!("generated new decision signing key");
+        Ok(DecisionKey(key.into()))
+    }
+
+    /// Uncompressed SEC1 point, base64url: what the daemon pins.
+    pub fn public_b64u(&self) -> String {
+        let point = self.0.verifying_key().to_encoded_point(false);
+        URL_SAFE_NO_PAD.encode(point.as_bytes())
+    }
+
+    /// Add `expires` and `sig` to a `{"status":..}` line for `rid`, covering
+    /// its `reason` as well.
+    pub fn sign(&self, rid: &str, nonce: &str, line: &mut Value, now: u64) {
+        let Some(status) = line["status"].as_str() else {
+            warn!("not signing a line without a status: {line}");
+            return;
+        };
+        let reason = line["reason"].as_str().unwrap_or("");
+        let expires = now + DECISION_TTL;
+        let msg = message(rid, nonce, status, expires, reason);
+        let sig: Signature = self.0.sign(msg.as_bytes());
+        line["expires"] = expires.into();
+        line["sig"] = URL_SAFE_NO_PAD.encode(sig.to_bytes()).into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use p256::ecdsa::signature::Verifier;
+    use p256::ecdsa::VerifyingKey;
+    use serde_json::json;
+
+    #[test]
+    fn signs_what_the_daemon_checks() {
+        let dir = tmp_dir("decision");
+        let key = DecisionKey::load_or_generate(&dir).unwrap();
+        // Stable across restarts: the pin must keep working.
+        let again = DecisionKey::load_or_generate(&dir).unwrap();
+        assert_eq!(key.public_b64u(), again.public_b64u());
+
+        let mut line = json!({"status": "approved"});
+        key.sign("rid1", "n0nce-n0nce-n0nce", &mut line, 1000);
+        assert_eq!(line["expires"], 1000 + DECISION_TTL);
+
+        let public = URL_SAFE_NO_PAD.decode(key.public_b64u()).unwrap();
+        let verifying = VerifyingKey::from_sec1_bytes(&public).unwrap();
+        let sig = URL_SAFE_NO_PAD
+            .decode(line["sig"].as_str().unwrap())
+            .unwrap();
+        let sig = Signature::from_slice(&sig).unwrap();
+        let expires = 1000 + DECISION_TTL;
+        let signed = message("rid1", "n0nce-n0nce-n0nce", "approved", expires, "");
+        assert!(verifying.verify(signed.as_bytes(), &sig).is_ok());
+        let other = message("rid1", "n0nce-n0nce-n0nce", "denied", expires, "");
+        assert!(verifying.verify(other.as_bytes(), &sig).is_err());
+
+        // The reason is part of what is signed.
+        let mut denied = json!({"status": "denied", "reason": "Homework first"});
+        key.sign("rid1", "n0nce-n0nce-n0nce", &mut denied, 1000);
+        let sig = URL_SAFE_NO_PAD
+            .decode(denied["sig"].as_str().unwrap())
+            .unwrap();
+        let sig = Signature::from_slice(&sig).unwrap();
+        let signed = message(
+            "rid1",
+            "n0nce-n0nce-n0nce",
+            "denied",
+            expires,
+            "Homework first",
+        );
+        assert!(verifying.verify(signed.as_bytes(), &sig).is_ok());
+        let swapped = message("rid1", "n0nce-n0nce-n0nce", "denied", expires, "Go ahead");
+        assert!(verifying.verify(swapped.as_bytes(), &sig).is_err());
+
+        fs::write(dir.join("decision_key.pem"), "garbage").unwrap();
+        assert!(DecisionKey::load_or_generate(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nonces_cannot_break_the_message() {
+        assert!(valid_nonce("AAAAAAAAAAAAAAAAAAAAAAAA"));
+        assert!(!valid_nonce("short"));
+        assert!(!valid_nonce("AAAAAAAAAAAAAAAA\napproved"));
+        assert!(!valid_nonce(&"A".repeat(65)));
+    }
+}
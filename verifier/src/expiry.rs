@@ -0,0 +1,206 @@
+//! Request lifecycles: one scheduler thread owns every deadline. A pending
+//! request times out at its `wait_until`, a finished one is dropped twice
+//! the request TTL after it was made, and a registration ceremony nobody
+//! finished is cleared. Waiters (the control client, the approve page's
+//! event stream) only wait on `App::decided`, which the scheduler signals.
+//!
+//! Time comes from a `Clock`, so tests can move it by hand.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::info;
+
+use crate::app::{App, ApprovalRequest};
+
+/// Wall-clock time since the Unix epoch.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// Everything due at `now` (unix seconds). Returns the next deadline, if
+/// anything is left to expire.
+pub(crate) fn sweep(
+    app: &App,
+    requests: &mut HashMap<String, ApprovalRequest>,
+    now: u64,
+) -> Option<u64> {
+    let ttl = app.cfg.request_ttl;
+    let before = requests.len();
+    requests.retain(|_, r| r.status == "pending" || now < r.created_at + 2 * ttl);
+    let mut expired = false;
+    for (rid, r) in requests.iter_mut() {
+        if r.status == "pending" && now >= r.wait_until {
+            info!("request {rid} timed out");
+            app.finish(rid, r, "timeout", None, None, None);
+            expired = true;
+        }
+    }
+    if expired || requests.len() != before {
+        app.persist(requests);
+    }
+    if expired {
+        app.decided.notify_all();
+    }
+
+    let mut enroll = app.enroll_state.lock().unwrap();
+    if enroll.as_ref().is_some_and(|(until, _)| now >= *until) {
+        info!("unfinished passkey registration cleared");
+        *enroll = None;
+    }
+    let enroll_until = enroll.as_ref().map(|(until, _)| *until);
+
+    requests
+        .values()
+        .map(|r| match r.status.as_str() {
+            "pending" => r.wait_until,
+            _ => r.created_at + 2 * ttl,
+        })
+        .chain(enroll_until)
+        .min()
+}
+
+/// The scheduler thread: sweep, then sleep until the next deadline or
+/// until the requests change (`App::reschedule`). Never returns.
+pub fn run(app: &App, clock: &dyn Clock) {
+    let mut requests = app.requests.lock().unwrap();
+    loop {
+        let now = clock.now();
+        requests = match sweep(app, &mut requests, now.as_secs()) {
+            Some(next) => {
+                let wait = Duration::from_secs(next).saturating_sub(now);
+                app.decided.wait_timeout(requests, wait).unwrap().0
+            }
+            None => app.decided.wait(requests).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Cfg;
+    use crate::store::tests::tmp_dir;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+    use uuid::Uuid;
+
+    struct FakeClock(Mutex<Duration>);
+
+    impl FakeClock {
+        fn set(&self, secs: u64) {
+            *self.0.lock().unwrap() = Duration::from_secs(secs);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn app(tag: &str) -> App {
+        let dir = tmp_dir(tag).display().to_string();
+        let env = move |k: &str| match k {
+            "AG_RP_ID" => Some("localhost".to_string()),
+            "AG_ORIGIN" => Some("https://localhost".to_string()),
+            "AG_DATA_DIR" => Some(dir.clone()),
+            "AG_REQUEST_TTL" => Some("100".to_string()),
+            _ => None,
+        };
+        App::new(Cfg::load_from(Path::new("/nonexistent"), &env).unwrap()).unwrap()
+    }
+
+    fn pending(created_at: u64, wait_until: u64) -> ApprovalRequest {
+        serde_json::from_value(serde_json::json!({
+            "exe": "game-mode", "path": "p", "group": "login", "status": "pending",
+            "created_at": created_at, "wait_until": wait_until, "auth": null,
+        }))
+        .unwrap()
+    }
+
+    fn status(app: &App, rid: &str) -> Option<String> {
+        let requests = app.requests.lock().unwrap();
+        requests.get(rid).map(|r| r.status.clone())
+    }
+
+    #[test]
+    fn requests_time_out_at_the_deadline_and_are_dropped_later() {
+        let app = app("expiry-sweep");
+        let mut requests = HashMap::from([
+            ("a".to_string(), pending(1000, 1030)),
+            ("b".to_string(), pending(1000, 1090)),
+        ]);
+        assert_eq!(sweep(&app, &mut requests, 1029), Some(1030));
+        assert_eq!(requests["a"].status, "pending");
+        assert_eq!(sweep(&app, &mut requests, 1030), Some(1090));
+        assert_eq!(requests["a"].status, "timeout");
+        assert_eq!(sweep(&app, &mut requests, 1090), Some(1200));
+        assert_eq!(requests["b"].status, "timeout");
+        // Finished requests stay for the page and `resume`, then go.
+        assert_eq!(sweep(&app, &mut requests, 1199), Some(1200));
+        assert_eq!(requests.len(), 2);
+        assert_eq!(sweep(&app, &mut requests, 1200), None);
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn stale_registration_is_cleared() {
+        let app = app("expiry-enroll");
+        let (_, state) = app
+            .webauthn
+            .start_passkey_registration(Uuid::new_v4(), "u", "u", None)
+            .unwrap();
+        *app.enroll_state.lock().unwrap() = Some((500, state));
+        let mut requests = HashMap::new();
+        assert_eq!(sweep(&app, &mut requests, 499), Some(500));
+        assert!(app.enroll_state.lock().unwrap().is_some());
+        assert_eq!(sweep(&app, &mut requests, 500), None);
+        assert!(app.enroll_state.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn scheduler_thread_follows_the_clock() {
+        let app = Arc::new(app("expiry-thread"));
+        let clock = Arc::new(FakeClock(Mutex::new(Duration::from_secs(1000))));
+        app.requests
+            .lock()
+            .unwrap()
+            .insert("r".into(), pending(1000, 1060));
+        {
+            let (app, clock) = (app.clone(), clock.clone());
+            thread::spawn(move || run(&app, clock.as_ref()));
+        }
+        // Woken by the change; still a minute short of the deadline by the
+        // clock, whatever the real time.
+        app.reschedule();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(status(&app, "r").as_deref(), Some("pending"));
+
+        clock.set(1060);
+        app.reschedule();
+        let started = Instant::now();
+        let mut requests = app.requests.lock().unwrap();
+        while requests["r"].status == "pending" {
+            assert!(started.elapsed() < Duration::from_secs(5), "never expired");
+            requests = app
+                .decided
+                .wait_timeout(requests, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        assert_eq!(requests["r"].status, "timeout");
+    }
+}
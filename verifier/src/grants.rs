@@ -0,0 +1,142 @@
+//! Time-boxed approvals ("trusted for 2 hours"): approving a request for N
+//! minutes records a grant for its `group` + `exe`, and matching requests
+//! inside the window are approved without asking the phone again.
+//!
+//! Kept in `grants.json` under the data dir and re-read on every lookup,
+//! so a revoke from the CLI (a separate process) takes effect at once and
+//! grants survive restarts.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Grant {
+    pub id: String,
+    pub group: String,
+    pub exe: String,
+    /// Base64url id of the passkey that approved it.
+    pub credential: Option<String>,
+    pub client_ip: Option<String>,
+    /// Unix seconds.
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+pub struct Grants {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl Grants {
+    pub fn new(data_dir: &Path) -> Self {
+        Grants {
+            dir: data_dir.to_path_buf(),
+            path: data_dir.join("grants.json"),
+        }
+    }
+
+    /// Grants still in force, soonest to expire first.
+    pub fn active(&self, now: u64) -> Vec<Grant> {
+        let mut grants: Vec<Grant> = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        grants.retain(|g| g.expires_at > now);
+        grants.sort_by_key(|g| g.expires_at);
+        grants
+    }
+
+    pub fn matching(&self, group: &str, exe: &str, now: u64) -> Option<Grant> {
+        self.active(now)
+            .into_iter()
+            .find(|g| g.group == group && g.exe == exe)
+    }
+
+    /// Record `grant`, replacing any other for the same group + exe.
+    pub fn add(&self, grant: Grant) -> Result<()> {
+        let mut grants = self.active(grant.created_at);
+        grants.retain(|g| !(g.group == grant.group && g.exe == grant.exe));
+        grants.push(grant);
+        self.save(&grants)
+    }
+
+    /// Revoke one grant by id, or all of them for `None`. Returns how many
+    /// were dropped.
+    pub fn revoke(&self, id: Option<&str>, now: u64) -> Result<usize> {
+        let mut grants = self.active(now);
+        let before = grants.len();
+        grants.retain(|g| id.is_some_and(|id| g.id != id));
+        self.save(&grants)?;
+        Ok(before - grants.len())
+    }
+
+    fn save(&self, grants: &[Grant]) -> Result<()> {
+        crate::journal::write_atomic(&self.path, serde_json::to_string(grants)?.as_bytes())?;
+        // `grants revoke` may run as root: hand the file back to the
+        // service user, or the verifier could no longer read it.
+        if let Ok(meta) = fs::metadata(&self.dir) {
+            let _ = std::os::unix::fs::chown(&self.path, Some(meta.uid()), Some(meta.gid()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    fn grant(id: &str, exe: &str, expires_at: u64) -> Grant {
+        Grant {
+            id: id.into(),
+            group: "login".into(),
+            exe: exe.into(),
+            credential: Some("Y3JlZA".into()),
+            client_ip: None,
+            created_at: 1000,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn matches_group_and_exe_until_expiry() {
+        let dir = tmp_dir("grants-match");
+        let grants = Grants::new(&dir);
+        assert!(grants.matching("login", "game-mode", 1000).is_none());
+        grants.add(grant("a", "game-mode", 2000)).unwrap();
+        grants.add(grant("b", "steam", 1500)).unwrap();
+
+        assert_eq!(grants.matching("login", "game-mode", 1999).unwrap().id, "a");
+        assert!(grants.matching("login", "game-mode", 2000).is_none());
+        assert!(grants.matching("sudo", "game-mode", 1999).is_none());
+        let ids: Vec<String> = grants.active(1000).into_iter().map(|g| g.id).collect();
+        assert_eq!(ids, ["b", "a"]);
+
+        // A second grant for the same scope replaces the first.
+        grants.add(grant("c", "game-mode", 3000)).unwrap();
+        assert_eq!(grants.active(1000).len(), 2);
+        assert_eq!(grants.matching("login", "game-mode", 2500).unwrap().id, "c");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revoke_one_or_all() {
+        let dir = tmp_dir("grants-revoke");
+        let grants = Grants::new(&dir);
+        grants.add(grant("a", "game-mode", 2000)).unwrap();
+        grants.add(grant("b", "steam", 2000)).unwrap();
+        assert_eq!(grants.revoke(Some("nope"), 1000).unwrap(), 0);
+        assert_eq!(grants.revoke(Some("a"), 1000).unwrap(), 1);
+        assert!(grants.matching("login", "game-mode", 1000).is_none());
+        assert_eq!(grants.revoke(None, 1000).unwrap(), 1);
+        assert!(grants.active(1000).is_empty());
+
+        fs::write(dir.join("grants.json"), "[ torn").unwrap();
+        assert!(grants.active(1000).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+//! Decision log: one JSON line per finished request in `history.jsonl`
+//! under the data dir. Append-only; once it passes `MAX_BYTES` the oldest
+//! half is dropped, so a kiosk that approves a dozen times a day never needs
+//! logrotate.
+
+use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+const MAX_BYTES: u64 = 1 << 20;
+/// Longest deny reason kept, in characters.
+pub const MAX_REASON_CHARS: usize = 80;
+
+/// Who opened the control connection: SO_PEERCRED, plus the binary the
+/// kernel says that pid runs. Unlike the request's `exe` text, nothing here
+/// is up to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Peer {
+    pub uid: u32,
+    #[serde(default)]
+    pub gid: u32,
+    pub pid: i32,
+    /// `/proc/<pid>/exe` at connect time; `None` when it can't be read
+    /// (the process is gone, or the verifier lacks CAP_SYS_PTRACE for
+    /// another user's process).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exe: Option<String>,
+}
+
+impl Peer {
+    /// One line for the approve page and logs.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} (uid {}, gid {}, pid {})",
+            self.exe.as_deref().unwrap_or("unknown binary"),
+            self.uid,
+            self.gid,
+            self.pid
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub rid: String,
+    pub exe: String,
+    pub path: String,
+    pub group: String,
+    pub title: String,
+    pub peer: Option<Peer>,
+    /// The claimed `exe` has a configured binary and the peer runs another.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exe_mismatch: bool,
+    /// approved | denied | timeout | cancelled
+    pub outcome: String,
+    /// Base64url id of the passkey that approved (or denied from /history).
+    pub credential: Option<String>,
+    /// Browser that decided, as seen through the proxy.
+    pub client_ip: Option<String>,
+    /// Why, when not a plain decision on the approve page (e.g. the grant
+    /// that approved it).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Unix seconds.
+    pub created_at: u64,
+    pub decided_at: u64,
+}
+
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(data_dir: &Path) -> Self {
+        History {
+            path: data_dir.join("history.jsonl"),
+        }
+    }
+
+    pub fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        let mut f = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        f.write_all(line.as_bytes())?;
+        if f.metadata()?.len() > MAX_BYTES {
+            self.truncate_oldest()?;
+        }
+        Ok(())
+    }
+
+    /// Up to `n` most recent records, newest first. Lines that don't parse
+    /// (a torn write, a future format) are skipped.
+    pub fn recent(&self, n: usize) -> Vec<Record> {
+        let Ok(text) = fs::read_to_string(&self.path) else {
+            return Vec::new();
+        };
+        text.lines()
+            .rev()
+            .filter_map(|l| serde_json::from_str(l).ok())
+            .take(n)
+            .collect()
+    }
+
+    fn truncate_oldest(&self) -> Result<()> {
+        let text = fs::read_to_string(&self.path)?;
+        let lines: Vec<&str> = text.lines().collect();
+        let kept = lines[lines.len() / 2..].join("\n") + "\n";
+        crate::journal::write_atomic(&self.path, kept.as_bytes())
+    }
+}
+
+/// A free-text deny reason as it may be stored and passed on: it ends up on
+/// the TV's VT and in logs, so control and bidi-override characters are
+/// dropped, whitespace is collapsed and the length capped. `None` if
+/// nothing is left.
+pub fn clean_reason(raw: &str) -> Option<String> {
+    access_gate_text::clean_text(raw, MAX_REASON_CHARS)
+}
+
+/// Client address for the log and the rate limiter. Connections from the
+/// built-in TLS listener are mapped back to the real peer (`proxied`).
+/// `forwarded_for` is the X-Forwarded-For of a `tailscale serve` connection
+/// (see `socket_owner`), `None` for anyone else; only its last entry, the
+/// one the proxy appended, is trusted: anything before it is whatever the
+/// browser sent. Everybody else is keyed on their own address.
+pub fn client_ip(
+    remote: Option<SocketAddr>,
+    proxied: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+) -> Option<String> {
+    if let Some(ip) = proxied {
+        return Some(ip.to_string());
+    }
+    if let Some(hop) = forwarded_for
+        .and_then(|v| v.rsplit(',').next())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        return Some(hop.to_string());
+    }
+    remote.map(|r| r.ip().to_string())
+}
+
+/// Owner uid of the local socket at `addr`, the far end of a loopback
+/// connection, as `/proc/net/tcp{,6}` lists it. Any local process can
+/// connect and send X-Forwarded-For; only the proxy's own uid can't be
+/// faked.
+pub fn socket_owner(addr: SocketAddr) -> Option<u32> {
+    // The kernel prints addresses as 32-bit words in host byte order.
+    let words = |octets: &[u8]| -> String {
+        octets
+            .chunks(4)
+            .map(|w| format!("{:08X}", u32::from_ne_bytes(w.try_into().unwrap())))
+            .collect()
+    };
+    let port = addr.port();
+    let (v4, v6) = match addr.ip() {
+        IpAddr::V4(ip) => (
+            Some(format!("{}:{port:04X}", words(&ip.octets()))),
+            format!("{}:{port:04X}", words(&ip.to_ipv6_mapped().octets())),
+        ),
+        IpAddr::V6(ip) => (None, format!("{}:{port:04X}", words(&ip.octets()))),
+    };
+    [("/proc/net/tcp", v4), ("/proc/net/tcp6", Some(v6))]
+        .into_iter()
+        .filter_map(|(table, local)| Some((fs::read_to_string(table).ok()?, local?)))
+        .find_map(|(table, local)| {
+            table.lines().skip(1).find_map(|line| {
+                // sl local_address rem_address st tx:rx tr:when retrnsmt uid
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                (fields.get(1) == Some(&local.as_str()))
+                    .then(|| fields.get(7)?.parse().ok())
+                    .flatten()
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    fn record(rid: &str) -> Record {
+        Record {
+            rid: rid.into(),
+            exe: "game-mode".into(),
+            path: "switch".into(),
+            group: "login".into(),
+            title: "Enter game mode?".into(),
+            peer: Some(Peer {
+                uid: 971,
+                gid: 971,
+                pid: 42,
+                exe: Some("/usr/bin/game-mode".into()),
+            }),
+            exe_mismatch: false,
+            outcome: "approved".into(),
+            credential: Some("Y3JlZA".into()),
+            client_ip: Some("100.64.0.7".into()),
+            reason: None,
+            created_at: 1_700_000_000,
+            decided_at: 1_700_000_009,
+        }
+    }
+
+    #[test]
+    fn newest_first_and_bounded() {
+        let dir = tmp_dir("history-recent");
+        let history = History::new(&dir);
+        assert!(history.recent(10).is_empty());
+        for i in 0..5 {
+            history.append(&record(&format!("r{i}"))).unwrap();
+        }
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("history.jsonl"))
+            .unwrap()
+            .write_all(b"{ torn\n")
+            .unwrap();
+        let recent = history.recent(3);
+        let ids: Vec<&str> = recent.iter().map(|r| r.rid.as_str()).collect();
+        assert_eq!(ids, ["r4", "r3", "r2"]);
+        assert_eq!(recent[0], record("r4"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_log_drops_oldest_half() {
+        let dir = tmp_dir("history-rotate");
+        let history = History::new(&dir);
+        let mut big = record("x");
+        big.path = "p".repeat(4096);
+        let n = (MAX_BYTES / 4096) as usize + 2;
+        for i in 0..n {
+            big.rid = format!("r{i}");
+            history.append(&big).unwrap();
+        }
+        let len = fs::metadata(dir.join("history.jsonl")).unwrap().len();
+        assert!(len <= MAX_BYTES, "{len}");
+        assert_eq!(history.recent(1)[0].rid, format!("r{}", n - 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reasons_are_cleaned_and_capped() {
+        assert_eq!(
+            clean_reason("  homework\n first\u{1b}[2J ").as_deref(),
+            Some("homework first[2J")
+        );
+        assert_eq!(clean_reason("\u{202e}lol").as_deref(), Some("lol"));
+        assert_eq!(clean_reason(" \t\r\n"), None);
+        let long = clean_reason(&"ä".repeat(200)).unwrap();
+        assert_eq!(long.chars().count(), MAX_REASON_CHARS);
+    }
+
+    #[test]
+    fn only_the_last_forwarded_hop_is_trusted() {
+        let lo = Some("127.0.0.1:5000".parse().unwrap());
+        let tls_peer = Some("192.168.1.20".parse().unwrap());
+        assert_eq!(
+            client_ip(lo, None, Some("6.6.6.6, 100.64.0.7")).as_deref(),
+            Some("100.64.0.7")
+        );
+        assert_eq!(client_ip(lo, None, None).as_deref(), Some("127.0.0.1"));
+        assert_eq!(client_ip(lo, None, Some(" ")).as_deref(), Some("127.0.0.1"));
+        assert_eq!(
+            client_ip(lo, tls_peer, Some("6.6.6.6")).as_deref(),
+            Some("192.168.1.20")
+        );
+    }
+
+    #[test]
+    fn socket_owner_finds_the_connecting_process() {
+        let me = unsafe { libc::geteuid() };
+        let v4 = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(v4.local_addr().unwrap()).unwrap();
+        assert_eq!(socket_owner(client.local_addr().unwrap()), Some(me));
+        if let Ok(v6) = std::net::TcpListener::bind("[::1]:0") {
+            let client = std::net::TcpStream::connect(v6.local_addr().unwrap()).unwrap();
+            assert_eq!(socket_owner(client.local_addr().unwrap()), Some(me));
+        }
+        assert_eq!(socket_owner("127.0.0.1:1".parse().unwrap()), None);
+    }
+}
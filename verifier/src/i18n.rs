@@ -0,0 +1,211 @@
+//! Message catalogues for the pages, picked by the phone's Accept-Language.
+//!
+//! English and German are built in (`src/i18n/*.toml`, flat `key = "text"`).
+//! A `<lang>.toml` in the pages directory adds a language or overrides
+//! texts of a built-in one; keys it leaves out fall back to English. A key
+//! English doesn't have, or a `{placeholder}` its English text doesn't
+//! have, is an error at startup rather than a blank on the phone.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+pub const DEFAULT: &str = "en";
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("en", include_str!("i18n/en.toml")),
+    ("de", include_str!("i18n/de.toml")),
+];
+
+pub type Catalogue = BTreeMap<String, String>;
+
+pub struct Catalogues {
+    langs: BTreeMap<String, Catalogue>,
+}
+
+fn parse(text: &str) -> Result<Catalogue> {
+    Ok(toml::from_str(text)?)
+}
+
+/// The `{name}` parts of a message.
+fn placeholders(text: &str) -> Vec<&str> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Is `name` a language tag we'd serve (`de`, `pt-br`)?
+fn valid_lang(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 16
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// `text` over `lang` (English, for a new language), checked against
+/// English.
+fn merge(
+    english: &Catalogue,
+    langs: &mut BTreeMap<String, Catalogue>,
+    lang: &str,
+    text: &str,
+) -> Result<()> {
+    let texts = parse(text)?;
+    for (key, text) in &texts {
+        let Some(en) = english.get(key) else {
+            bail!("unknown message {key:?}");
+        };
+        let known = placeholders(en);
+        if let Some(p) = placeholders(text).iter().find(|p| !known.contains(p)) {
+            bail!("message {key:?}: unknown placeholder {{{p}}}");
+        }
+    }
+    langs
+        .entry(lang.to_string())
+        .or_insert_with(|| english.clone())
+        .extend(texts);
+    Ok(())
+}
+
+impl Catalogues {
+    /// The built-in languages, then whatever `dir` adds.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let english = parse(BUILTIN[0].1).context("built-in en catalogue")?;
+        let mut langs = BTreeMap::from([(DEFAULT.to_string(), english.clone())]);
+        for (lang, text) in &BUILTIN[1..] {
+            merge(&english, &mut langs, lang, text)
+                .with_context(|| format!("built-in {lang} catalogue"))?;
+        }
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Catalogues { langs }),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+        };
+        let mut files: Vec<_> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+        files.sort();
+        for path in files {
+            if path.extension().and_then(|e| e.to_str()) != Some("toml") {
+                continue;
+            }
+            let lang = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if !valid_lang(&lang) {
+                bail!("{}: not a language tag", path.display());
+            }
+            let text =
+                fs::read_to_string(&path).with_context(|| format!("reading {}", path.display()))?;
+            merge(&english, &mut langs, &lang, &text)
+                .with_context(|| path.display().to_string())?;
+        }
+        Ok(Catalogues { langs })
+    }
+
+    pub fn langs(&self) -> impl Iterator<Item = (&str, &Catalogue)> {
+        self.langs.iter().map(|(lang, c)| (lang.as_str(), c))
+    }
+
+    pub fn has_key(&self, key: &str) -> bool {
+        self.langs[DEFAULT].contains_key(key)
+    }
+
+    /// `key` in `lang` (falling back to English); `key` itself if unknown.
+    pub fn get<'a>(&'a self, lang: &str, key: &'a str) -> &'a str {
+        self.langs
+            .get(lang)
+            .unwrap_or(&self.langs[DEFAULT])
+            .get(key)
+            .map_or(key, String::as_str)
+    }
+
+    /// The best language we have for an Accept-Language header: highest
+    /// q first, an exact tag before its primary subtag (`de-AT` → `de`).
+    pub fn negotiate(&self, accept_language: Option<&str>) -> &str {
+        let mut wanted: Vec<(f32, usize, String)> = accept_language
+            .unwrap_or_default()
+            .split(',')
+            .enumerate()
+            .filter_map(|(i, item)| {
+                let mut parts = item.split(';');
+                let tag = parts.next()?.trim().to_ascii_lowercase();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+                (!tag.is_empty() && q > 0.0).then_some((q, i, tag))
+            })
+            .collect();
+        // Stable on the header's own order for equal q.
+        wanted.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        for (_, _, tag) in &wanted {
+            let primary = tag.split('-').next().unwrap_or_default();
+            for candidate in [tag.as_str(), primary] {
+                if let Some((lang, _)) = self.langs.get_key_value(candidate) {
+                    return lang;
+                }
+            }
+        }
+        DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    #[test]
+    fn builtin_catalogues_are_complete() {
+        let c = Catalogues::load(Path::new("/nonexistent")).unwrap();
+        let en = parse(BUILTIN[0].1).unwrap();
+        for (lang, text) in BUILTIN {
+            let own = parse(text).unwrap();
+            let missing: Vec<_> = en.keys().filter(|k| !own.contains_key(*k)).collect();
+            assert!(missing.is_empty(), "{lang} lacks {missing:?}");
+        }
+        assert_eq!(c.get("de", "deny"), "Ablehnen");
+        assert_eq!(c.get("xx", "deny"), "Deny");
+    }
+
+    #[test]
+    fn negotiation_prefers_q_then_order() {
+        let c = Catalogues::load(Path::new("/nonexistent")).unwrap();
+        assert_eq!(c.negotiate(None), "en");
+        assert_eq!(c.negotiate(Some("de-AT,de;q=0.9,en;q=0.8")), "de");
+        assert_eq!(c.negotiate(Some("fr-FR, en;q=0.5, de;q=0.7")), "de");
+        assert_eq!(c.negotiate(Some("fr, *;q=0.1")), "en");
+        assert_eq!(c.negotiate(Some("de;q=0, en")), "en");
+        assert_eq!(c.negotiate(Some("garbage;q=x")), "en");
+    }
+
+    #[test]
+    fn directory_adds_and_overrides_languages() {
+        let dir = tmp_dir("i18n");
+        fs::write(dir.join("nl.toml"), "deny = \"Weigeren\"\n").unwrap();
+        fs::write(dir.join("de.toml"), "deny = \"Nein\"\n").unwrap();
+        let c = Catalogues::load(&dir).unwrap();
+        assert_eq!(c.get("nl", "deny"), "Weigeren");
+        assert_eq!(c.get("nl", "approve"), "Approve");
+        assert_eq!(c.get("de", "deny"), "Nein");
+        assert_eq!(c.get("de", "approve"), "Freigeben");
+        assert_eq!(c.negotiate(Some("nl-BE")), "nl");
+
+        fs::write(dir.join("nl.toml"), "denny = \"Weigeren\"\n").unwrap();
+        let err = Catalogues::load(&dir).err().unwrap();
+        assert!(
+            format!("{err:#}").contains("unknown message \"denny\""),
+            "{err:#}"
+        );
+        fs::write(dir.join("nl.toml"), "approve_for = \"{duur} goedkeuren\"\n").unwrap();
+        assert!(Catalogues::load(&dir).is_err());
+        fs::write(dir.join("nl.toml"), "deny = ").unwrap();
+        assert!(Catalogues::load(&dir).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
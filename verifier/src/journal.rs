@@ -0,0 +1,92 @@
+//! Crash-safe journal of in-flight approval requests.
+//!
+//! The whole pending map (including the half-finished passkey ceremony) is
+//! rewritten on every change: write a temp file, fsync, rename over the old
+//! one. A handful of requests at most are ever in flight, so there is no
+//! point in anything incremental. Deadlines inside are wall-clock seconds,
+//! since an `Instant` means nothing to the next process.
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(data_dir: &Path) -> Self {
+        Journal {
+            path: data_dir.join("pending.json"),
+        }
+    }
+
+    /// The last saved state; missing or unreadable = nothing pending (a
+    /// corrupt journal must not keep the verifier from starting).
+    pub fn load<T: DeserializeOwned + Default>(&self) -> T {
+        let Ok(text) = fs::read_to_string(&self.path) else {
+            return T::default();
+        };
+        serde_json::from_str(&text).unwrap_or_else(|e| {
+            tracing::warn!("{}: unreadable, starting empty: {e}", self.path.display());
+            T::default()
+        })
+    }
+
+    pub fn save<T: Serialize>(&self, state: &T) -> Result<()> {
+        write_atomic(&self.path, serde_json::to_string(state)?.as_bytes())
+    }
+}
+
+/// Replace `path` with `data` so readers (and a crash) see either the old or
+/// the new content, never a torn file. Mode 0600.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .with_context(|| format!("failed to create {}", tmp.display()))?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use std::collections::HashMap;
+
+    #[test]
+    fn round_trips_and_tolerates_garbage() {
+        let dir = tmp_dir("journal-rt");
+        let journal = Journal::new(&dir);
+        let empty: HashMap<String, u64> = journal.load();
+        assert!(empty.is_empty());
+
+        let state = HashMap::from([("abc".to_string(), 42u64)]);
+        journal.save(&state).unwrap();
+        assert_eq!(journal.load::<HashMap<String, u64>>(), state);
+        assert!(!dir.join("pending.tmp").exists());
+
+        fs::write(dir.join("pending.json"), "{ torn").unwrap();
+        assert!(journal.load::<HashMap<String, u64>>().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
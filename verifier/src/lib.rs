@@ -0,0 +1,38 @@
+//! access-gate verifier: WebAuthn relying party for phone-as-identity
+//! approvals of game-mode entry, plus the notifications that nudge the
+//! phone.
+//!
+//! Two planes share one request table (`app`):
+//!
+//! - the control plane (`ctrl`), a unix socket where the daemon, the PAM
+//!   module and the polkit agent create requests and block on the decision;
+//! - the web plane (`web`), plain HTTP behind a TLS terminator, where the
+//!   phone enrolls and approves or denies with its passkey.
+//!
+//! Trust is the single enrolled passkey, with user verification on every
+//! assertion; a notification carries no authority. Each module's own doc
+//! covers its part.
+
+pub mod activation;
+pub mod app;
+pub mod config;
+pub mod context;
+pub mod ctrl;
+pub mod decision;
+pub mod expiry;
+pub mod grants;
+pub mod history;
+pub mod i18n;
+pub mod journal;
+pub mod metrics;
+pub mod notify;
+pub mod pages;
+pub mod qr;
+pub mod ratelimit;
+pub mod sandbox;
+pub mod store;
+pub mod tls;
+pub mod token;
+pub mod totp;
+pub mod vapid;
+pub mod web;
@@ -0,0 +1,215 @@
+//! Prometheus text exposition for `/metrics`, hand-rolled: a few counters,
+//! one histogram and gauges read at scrape time don't justify a client
+//! library.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds (seconds) of the approval latency buckets. Request times
+/// are whole seconds, so finer buckets would be noise.
+const LATENCY_BUCKETS: [u64; 9] = [5, 10, 15, 20, 30, 45, 60, 90, 120];
+
+#[derive(Default)]
+pub struct Metrics {
+    created: AtomicU64,
+    decided: Mutex<BTreeMap<String, u64>>,
+    /// (backend, delivered) -> attempts
+    notifications: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    latency: Mutex<Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Per bucket, non-cumulative; the last slot is +Inf.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: u64,
+}
+
+/// Values read from current state when scraped.
+pub struct Gauges {
+    pub pending: usize,
+    pub credentials: usize,
+    pub subscriptions: usize,
+}
+
+impl Metrics {
+    pub fn request_created(&self) {
+        self.created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A request left `pending`; `secs` since it was created.
+    pub fn request_decided(&self, status: &str, secs: u64) {
+        *self
+            .decided
+            .lock()
+            .unwrap()
+            .entry(status.to_string())
+            .or_default() += 1;
+        if status == "approved" {
+            let mut h = self.latency.lock().unwrap();
+            let slot = LATENCY_BUCKETS
+                .iter()
+                .position(|&le| secs <= le)
+                .unwrap_or(LATENCY_BUCKETS.len());
+            h.counts[slot] += 1;
+            h.sum += secs;
+        }
+    }
+
+    pub fn notification(&self, backend: &'static str, delivered: bool) {
+        *self
+            .notifications
+            .lock()
+            .unwrap()
+            .entry((backend, delivered))
+            .or_default() += 1;
+    }
+
+    pub fn render(&self, g: &Gauges) -> String {
+        let mut out = String::new();
+        let o = &mut out;
+        let _ = writeln!(
+            o,
+            "# HELP ag_requests_created_total Approval requests created."
+        );
+        let _ = writeln!(o, "# TYPE ag_requests_created_total counter");
+        let _ = writeln!(
+            o,
+            "ag_requests_created_total {}",
+            self.created.load(Ordering::Relaxed)
+        );
+
+        let _ = writeln!(
+            o,
+            "# HELP ag_requests_decided_total Requests finished, by outcome."
+        );
+        let _ = writeln!(o, "# TYPE ag_requests_decided_total counter");
+        for (status, n) in self.decided.lock().unwrap().iter() {
+            let _ = writeln!(o, "ag_requests_decided_total{{status=\"{status}\"}} {n}");
+        }
+
+        let _ = writeln!(
+            o,
+            "# HELP ag_notifications_total Notification attempts, by backend and result."
+        );
+        let _ = writeln!(o, "# TYPE ag_notifications_total counter");
+        for ((backend, delivered), n) in self.notifications.lock().unwrap().iter() {
+            let result = if *delivered { "success" } else { "failure" };
+            let _ = writeln!(
+                o,
+                "ag_notifications_total{{backend=\"{backend}\",result=\"{result}\"}} {n}"
+            );
+        }
+
+        let _ = writeln!(
+            o,
+            "# HELP ag_approval_latency_seconds Time from request to passkey approval."
+        );
+        let _ = writeln!(o, "# TYPE ag_approval_latency_seconds histogram");
+        let h = self.latency.lock().unwrap();
+        let mut cumulative = 0;
+        for (le, n) in LATENCY_BUCKETS.iter().zip(h.counts) {
+            cumulative += n;
+            let _ = writeln!(
+                o,
+                "ag_approval_latency_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        cumulative += h.counts[LATENCY_BUCKETS.len()];
+        let _ = writeln!(
+            o,
+            "ag_approval_latency_seconds_bucket{{le=\"+Inf\"}} {cumulative}"
+        );
+        let _ = writeln!(o, "ag_approval_latency_seconds_sum {}", h.sum);
+        let _ = writeln!(o, "ag_approval_latency_seconds_count {cumulative}");
+        drop(h);
+
+        for (name, help, value) in [
+            (
+                "ag_pending_requests",
+                "Requests awaiting a decision.",
+                g.pending,
+            ),
+            (
+                "ag_enrolled_credentials",
+                "Enrolled passkeys.",
+                g.credentials,
+            ),
+            (
+                "ag_push_subscriptions",
+                "Web Push subscriptions.",
+                g.subscriptions,
+            ),
+        ] {
+            let _ = writeln!(o, "# HELP {name} {help}");
+            let _ = writeln!(o, "# TYPE {name} gauge");
+            let _ = writeln!(o, "{name} {value}");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauges() -> Gauges {
+        Gauges {
+            pending: 2,
+            credentials: 1,
+            subscriptions: 0,
+        }
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let m = Metrics::default();
+        m.request_created();
+        m.request_created();
+        m.request_decided("denied", 3);
+        m.notification("webpush", false);
+        m.notification("ntfy", true);
+        let text = m.render(&gauges());
+        for line in [
+            "ag_requests_created_total 2",
+            "ag_requests_decided_total{status=\"denied\"} 1",
+            "ag_notifications_total{backend=\"webpush\",result=\"failure\"} 1",
+            "ag_notifications_total{backend=\"ntfy\",result=\"success\"} 1",
+            "ag_pending_requests 2",
+            "ag_enrolled_credentials 1",
+            "ag_push_subscriptions 0",
+            "# TYPE ag_approval_latency_seconds histogram",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn latency_buckets_are_cumulative() {
+        let m = Metrics::default();
+        for secs in [4, 12, 12, 500] {
+            m.request_decided("approved", secs);
+        }
+        m.request_decided("timeout", 90);
+        let text = m.render(&gauges());
+        for line in [
+            "ag_approval_latency_seconds_bucket{le=\"5\"} 1",
+            "ag_approval_latency_seconds_bucket{le=\"10\"} 1",
+            "ag_approval_latency_seconds_bucket{le=\"15\"} 3",
+            "ag_approval_latency_seconds_bucket{le=\"120\"} 3",
+            "ag_approval_latency_seconds_bucket{le=\"+Inf\"} 4",
+            "ag_approval_latency_seconds_sum 528",
+            "ag_approval_latency_seconds_count 4",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! Approval notifications: pluggable backends that nudge the phone.
+//!
+//! A notification carries no authority — it only delivers the
+//! `/approve/<rid>` deep link; the passkey assertion on that page decides.
+//! Backends are tried in configured order and the first one that succeeds
+//! ends the chain, so a broken browser push service falls through to ntfy,
+//! Gotify or a webhook instead of silently timing the request out.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::context::RequestContext;
+use crate::store::Store;
+use crate::vapid::Vapid;
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::json;
+use tracing::{info, warn};
+use web_push::{ContentEncoding, SubscriptionInfo, VapidSignatureBuilder, WebPushMessageBuilder};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One approval prompt, rendered by each backend in its own format.
+pub struct Notification {
+    pub rid: String,
+    pub title: String,
+    pub exe: String,
+    pub path: String,
+    /// Which box, profile and pad the request comes from, if it says.
+    pub context: Option<RequestContext>,
+    /// Deep link to the approve page (`<origin>/approve/<rid>`).
+    pub url: String,
+    /// Seconds after which the prompt is useless (the request has expired).
+    pub ttl: u32,
+}
+
+impl Notification {
+    fn title_or_default(&self) -> &str {
+        if self.title.is_empty() {
+            "Access approval needed"
+        } else {
+            &self.title
+        }
+    }
+
+    fn body(&self) -> String {
+        match self.context_line() {
+            Some(line) => format!("{} → {}\n{line}", self.exe, self.path),
+            None => format!("{} → {}", self.exe, self.path),
+        }
+    }
+
+    fn context_line(&self) -> Option<String> {
+        self.context
+            .as_ref()
+            .map(RequestContext::summary)
+            .filter(|s| !s.is_empty())
+    }
+}
+
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn send(&self, n: &Notification) -> Result<()>;
+}
+
+/// Map a ureq result to "delivered or not"; any non-2xx is a failure so the
+/// chain moves on to the next backend.
+fn check_status(backend: &str, resp: Result<ureq::Response, ureq::Error>) -> Result<()> {
+    match resp {
+        Ok(r) => {
+            info!("{backend} notification sent ({})", r.status());
+            Ok(())
+        }
+        Err(ureq::Error::Status(code, _)) => bail!("{backend} rejected ({code})"),
+        Err(e) => bail!("{backend} transport: {e}"),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Web Push
+// ---------------------------------------------------------------------------
+
+pub struct WebPush {
+    pub store: Arc<dyn Store>,
+    pub vapid: Arc<Vapid>,
+    pub vapid_sub: String,
+}
+
+impl WebPush {
+    /// Ask the service worker to subscribe again under `next_key` and post
+    /// the subscription to `url` (see `vapid`).
+    pub fn resubscribe(&self, next_key: &str, url: &str, ttl: u32) -> Result<()> {
+        let body = json!({"type": "resubscribe", "key": next_key, "url": url});
+        self.push(&body.to_string(), ttl)
+    }
+
+    /// Encrypt `body` to the subscription and deliver it, signed with the
+    /// current VAPID key.
+    fn push(&self, body: &str, ttl: u32) -> Result<()> {
+        let sub_json = self
+            .store
+            .subscription()?
+            .ok_or_else(|| anyhow!("no push subscription"))?;
+        let sub: SubscriptionInfo = serde_json::from_value(sub_json)
+            .map_err(|e| anyhow!("push subscription unreadable: {e}"))?;
+
+        let mut sig = VapidSignatureBuilder::from_base64(
+            &URL_SAFE_NO_PAD.encode(self.vapid.current().to_bytes()),
+            web_push::URL_SAFE_NO_PAD,
+            &sub,
+        )
+        .map_err(|e| anyhow!("vapid: {e:?}"))?;
+        sig.add_claim("sub", format!("mailto:{}", self.vapid_sub));
+        let signature = sig.build().map_err(|e| anyhow!("vapid build: {e:?}"))?;
+
+        let mut msg = WebPushMessageBuilder::new(&sub);
+        msg.set_payload(ContentEncoding::Aes128Gcm, body.as_bytes());
+        msg.set_vapid_signature(signature);
+        msg.set_ttl(ttl);
+        let msg = msg.build().map_err(|e| anyhow!("message build: {e:?}"))?;
+
+        // Send manually (instead of the crate's async clients) so the request
+        // carries `Urgency: high` — Android defers normal-priority pushes on
+        // a dozing phone, and approvals must wake it.
+        let mut req = ureq::post(&msg.endpoint.to_string())
+            .timeout(SEND_TIMEOUT)
+            .set("TTL", &msg.ttl.to_string())
+            .set("Urgency", "high");
+        let resp = match msg.payload {
+            Some(p) => {
+                for (k, v) in &p.crypto_headers {
+                    req = req.set(k, v);
+                }
+                req.set("Content-Encoding", "aes128gcm")
+                    .send_bytes(&p.content)
+            }
+            None => req.call(),
+        };
+        if let Err(ureq::Error::Status(code @ (404 | 410), _)) = resp {
+            warn!("push endpoint gone ({code}); dropping subscription");
+            if let Err(e) = self.store.set_subscription(None) {
+                warn!("dropping push subscription: {e:#}");
+            }
+            bail!("push endpoint gone ({code})");
+        }
+        check_status("web push", resp)
+    }
+}
+
+impl Notifier for WebPush {
+    fn name(&self) -> &'static str {
+        "webpush"
+    }
+
+    fn send(&self, n: &Notification) -> Result<()> {
+        // The service worker renders title/body itself and opens `url`.
+        let body = json!({
+            "rid": n.rid, "exe": n.exe, "path": n.path, "title": n.title, "url": n.url,
+            "context": n.context_line(),
+        });
+        self.push(&body.to_string(), n.ttl)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// ntfy (plain HTTP publish)
+// ---------------------------------------------------------------------------
+
+pub struct Ntfy {
+    /// Server base URL, e.g. `https://ntfy.sh`.
+    pub url: String,
+    pub topic: String,
+    pub token: Option<String>,
+}
+
+impl Notifier for Ntfy {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn send(&self, n: &Notification) -> Result<()> {
+        let endpoint = format!("{}/{}", self.url.trim_end_matches('/'), self.topic);
+        let mut req = ureq::post(&endpoint)
+            .timeout(SEND_TIMEOUT)
+            .set("Title", n.title_or_default())
+            .set("Priority", "high")
+            .set("Tags", "lock")
+            .set("Click", &n.url);
+        if let Some(token) = &self.token {
+            req = req.set("Authorization", &format!("Bearer {token}"));
+        }
+        check_status("ntfy", req.send_string(&n.body()))
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Gotify
+// ---------------------------------------------------------------------------
+
+pub struct Gotify {
+    /// Server base URL, e.g. `https://gotify.example.net`.
+    pub url: String,
+    /// Application token.
+    pub token: String,
+}
+
+impl Notifier for Gotify {
+    fn name(&self) -> &'static str {
+        "gotify"
+    }
+
+    fn send(&self, n: &Notification) -> Result<()> {
+        let endpoint = format!("{}/message", self.url.trim_end_matches('/'));
+        let req = ureq::post(&endpoint)
+            .timeout(SEND_TIMEOUT)
+            .set("X-Gotify-Key", &self.token);
+        check_status(
+            "gotify",
+            req.send_json(json!({
+                "title": n.title_or_default(),
+                "message": format!("{}\n{}", n.body(), n.url),
+                "priority": 8,
+                "extras": {
+                    "client::notification": { "click": { "url": n.url } },
+                },
+            })),
+        )
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Generic JSON webhook
+// ---------------------------------------------------------------------------
+
+pub struct Webhook {
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl Notifier for Webhook {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(&self, n: &Notification) -> Result<()> {
+        let mut req = ureq::post(&self.url).timeout(SEND_TIMEOUT);
+        if let Some(token) = &self.token {
+            req = req.set("Authorization", &format!("Bearer {token}"));
+        }
+        check_status(
+            "webhook",
+            req.send_json(json!({
+                "rid": n.rid,
+                "title": n.title_or_default(),
+                "exe": n.exe,
+                "path": n.path,
+                "context": n.context,
+                "url": n.url,
+                "ttl": n.ttl,
+            })),
+        )
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Fallback chain
+// ---------------------------------------------------------------------------
+
+/// Backends in configured order; `send` stops at the first success.
+pub struct Chain(pub Vec<Box<dyn Notifier>>);
+
+impl Chain {
+    /// Best-effort: never blocks a ceremony on failure, only logs. Every
+    /// attempt is reported to `attempt` as (backend, delivered) for metrics.
+    /// Returns the backend that delivered, if any.
+    pub fn send(
+        &self,
+        n: &Notification,
+        attempt: &dyn Fn(&'static str, bool),
+    ) -> Option<&'static str> {
+        for backend in &self.0 {
+            let result = backend.send(n);
+            attempt(backend.name(), result.is_ok());
+            match result {
+                Ok(()) => return Some(backend.name()),
+                Err(e) => warn!("{} notification failed: {e}", backend.name()),
+            }
+        }
+        warn!("request {}: no notification backend delivered", n.rid);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use crate::store::FileStore;
+    use p256::SecretKey;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::thread;
+    use tiny_http::{Response, Server};
+
+    struct Captured {
+        url: String,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    /// Stand-in HTTP server answering each request with the next status in
+    /// `statuses`; captured requests arrive on the returned channel.
+    fn stand_in(statuses: Vec<u16>) -> (String, mpsc::Receiver<Captured>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let Ok(mut req) = server.recv() else { return };
+                let mut body = Vec::new();
+                let _ = req.as_reader().read_to_end(&mut body);
+                let headers = req
+                    .headers()
+                    .iter()
+                    .map(|h| (h.field.to_string().to_lowercase(), h.value.to_string()))
+                    .collect();
+                let _ = tx.send(Captured {
+                    url: req.url().to_string(),
+                    headers,
+                    body,
+                });
+                let _ = req.respond(Response::empty(status));
+            }
+        });
+        (base, rx)
+    }
+
+    fn random_key() -> SecretKey {
+        SecretKey::random(&mut p256::elliptic_curve::rand_core::OsRng)
+    }
+
+    /// Write a subscription for `endpoint` (with a browser-side key pair, as
+    /// a real subscription carries) and return a sender for it.
+    fn subscribed(store: Arc<dyn Store>, endpoint: &str) -> WebPush {
+        use p256::elliptic_curve::sec1::ToEncodedPoint;
+        let ua_key = random_key();
+        let p256dh = URL_SAFE_NO_PAD.encode(ua_key.public_key().to_encoded_point(false));
+        store
+            .set_subscription(Some(&json!({
+                "endpoint": endpoint,
+                "keys": { "p256dh": p256dh, "auth": URL_SAFE_NO_PAD.encode([7u8; 16]) },
+            })))
+            .unwrap();
+        WebPush {
+            store,
+            vapid: Arc::new(Vapid::with_key("/nonexistent".as_ref(), random_key())),
+            vapid_sub: "access-gate@localhost".into(),
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            rid: "abc123".into(),
+            title: "Enter game mode?".into(),
+            exe: "game-mode".into(),
+            path: "switch this PC into Steam game mode".into(),
+            context: None,
+            url: "https://box.example.ts.net/approve/abc123".into(),
+            ttl: 90,
+        }
+    }
+
+    #[test]
+    fn ntfy_publishes_with_click_link() {
+        let (base, rx) = stand_in(vec![200]);
+        let ntfy = Ntfy {
+            url: format!("{base}/"),
+            topic: "gate".into(),
+            token: Some("tk_secret".into()),
+        };
+        ntfy.send(&notification()).unwrap();
+        let got = rx.recv().unwrap();
+        assert_eq!(got.url, "/gate");
+        assert_eq!(
+            got.headers["click"],
+            "https://box.example.ts.net/approve/abc123"
+        );
+        assert_eq!(got.headers["title"], "Enter game mode?");
+        assert_eq!(got.headers["authorization"], "Bearer tk_secret");
+        assert_eq!(
+            String::from_utf8(got.body).unwrap(),
+            "game-mode → switch this PC into Steam game mode"
+        );
+    }
+
+    #[test]
+    fn gotify_posts_message_with_click_extra() {
+        let (base, rx) = stand_in(vec![200]);
+        let gotify = Gotify {
+            url: base,
+            token: "app-token".into(),
+        };
+        gotify.send(&notification()).unwrap();
+        let got = rx.recv().unwrap();
+        assert_eq!(got.url, "/message");
+        assert_eq!(got.headers["x-gotify-key"], "app-token");
+        let v: serde_json::Value = serde_json::from_slice(&got.body).unwrap();
+        assert_eq!(
+            v["extras"]["client::notification"]["click"]["url"],
+            "https://box.example.ts.net/approve/abc123"
+        );
+        assert_eq!(v["title"], "Enter game mode?");
+    }
+
+    #[test]
+    fn webhook_posts_json() {
+        let (base, rx) = stand_in(vec![204]);
+        let hook = Webhook {
+            url: format!("{base}/hook"),
+            token: None,
+        };
+        let n = Notification {
+            context: Some(RequestContext {
+                hostname: Some("den-pc".into()),
+                battery: Some(40),
+                ..RequestContext::default()
+            }),
+            ..notification()
+        };
+        hook.send(&n).unwrap();
+        let got = rx.recv().unwrap();
+        assert_eq!(got.url, "/hook");
+        assert!(!got.headers.contains_key("authorization"));
+        let v: serde_json::Value = serde_json::from_slice(&got.body).unwrap();
+        assert_eq!(v["rid"], "abc123");
+        assert_eq!(v["context"], json!({"hostname": "den-pc", "battery": 40}));
+        assert_eq!(v["url"], "https://box.example.ts.net/approve/abc123");
+    }
+
+    #[test]
+    fn webpush_encrypts_to_subscription() {
+        let (base, rx) = stand_in(vec![201]);
+        let dir = tmp_dir("notify-wp");
+        let wp = subscribed(Arc::new(FileStore::new(&dir)), &format!("{base}/push/xyz"));
+        wp.send(&notification()).unwrap();
+        let got = rx.recv().unwrap();
+        assert_eq!(got.url, "/push/xyz");
+        assert_eq!(got.headers["urgency"], "high");
+        assert_eq!(got.headers["ttl"], "90");
+        assert_eq!(got.headers["content-encoding"], "aes128gcm");
+        assert!(got.headers["authorization"].starts_with("vapid "));
+        assert!(!got.body.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn webpush_gone_drops_subscription() {
+        let (base, _rx) = stand_in(vec![410]);
+        let dir = tmp_dir("notify-gone");
+        let store: Arc<dyn Store> = Arc::new(FileStore::new(&dir));
+        let wp = subscribed(store.clone(), &format!("{base}/push/xyz"));
+        assert!(wp.send(&notification()).is_err());
+        assert!(store.subscription().unwrap().is_none());
+        assert!(!dir.join("push_subscription.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chain_falls_back_and_stops_at_first_success() {
+        let (broken, broken_rx) = stand_in(vec![500]);
+        let (ok, ok_rx) = stand_in(vec![200]);
+        let (unused, unused_rx) = stand_in(vec![200]);
+        let chain = Chain(vec![
+            Box::new(WebPush {
+                store: Arc::new(FileStore::new("/nonexistent".as_ref())),
+                vapid: Arc::new(Vapid::with_key("/nonexistent".as_ref(), random_key())),
+                vapid_sub: "access-gate@localhost".into(),
+            }),
+            Box::new(Webhook {
+                url: broken,
+                token: None,
+            }),
+            Box::new(Ntfy {
+                url: ok,
+                topic: "gate".into(),
+                token: None,
+            }),
+            Box::new(Webhook {
+                url: unused,
+                token: None,
+            }),
+        ]);
+        let attempts = std::sync::Mutex::new(Vec::new());
+        let delivered = chain.send(&notification(), &|backend, ok| {
+            attempts.lock().unwrap().push((backend, ok))
+        });
+        assert_eq!(delivered, Some("ntfy"));
+        assert_eq!(
+            *attempts.lock().unwrap(),
+            [("webpush", false), ("webhook", false), ("ntfy", true)]
+        );
+        assert!(broken_rx.recv().is_ok());
+        assert!(ok_rx.recv().is_ok());
+        assert!(unused_rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn chain_reports_total_failure() {
+        let chain = Chain(vec![Box::new(Webhook {
+            url: "http://127.0.0.1:1/hook".into(),
+            token: None,
+        })]);
+        assert_eq!(chain.send(&notification(), &|_, _| {}), None);
+    }
+}
@@ -0,0 +1,606 @@
+//! The pages (inline, no external assets) and the push service worker.
+//!
+//! Each page is a template: `__NAME__` is a variable the handler fills in
+//! (see `Page::vars`), `__t.key__` a message from the phone's language (see
+//! `i18n`), and `//HELPERS//` the shared script helpers. A file named after
+//! the page (`approve.html`, ...) in the pages directory replaces the
+//! built-in one. Every template is parsed at startup: an unknown variable
+//! or message, or a missing required variable, stops the verifier there
+//! instead of breaking the page on the phone.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+
+use crate::i18n::Catalogues;
+
+const JS_HELPERS: &str = r#"
+function b64uToBuf(s){s=s.replace(/-/g,'+').replace(/_/g,'/');const p=s.length%4;if(p)s+='='.repeat(4-p);
+const b=atob(s),a=new Uint8Array(b.length);for(let i=0;i<b.length;i++)a[i]=b.charCodeAt(i);return a.buffer;}
+function bufToB64u(b){const a=new Uint8Array(b);let s='';for(let i=0;i<a.length;i++)s+=String.fromCharCode(a[i]);
+return btoa(s).replace(/\+/g,'-').replace(/\//g,'_').replace(/=+$/,'');}
+function fmt(s,o){return s.replace(/\{(\w+)\}/g,(x,k)=>k in o?o[k]:x);}
+"#;
+
+const PAGE_ENROLL_TMPL: &str = r#"<!doctype html><html lang=__LANG__><meta name=viewport content="width=device-width,initial-scale=1">
+<title>__BRAND__ __t.enroll_title__</title><body style="font-family:sans-serif;max-width:30em;margin:3em auto;padding:0 1em">
+<h2>__t.enroll_heading__</h2>
+<button id=go style="font-size:1.2em;padding:.6em 1.2em">__t.enroll_button__</button>
+<p id=msg></p><script>//HELPERS//
+const T='?t=__TOKEN__',M=__MESSAGES__;
+document.getElementById('go').onclick=async()=>{
+ const m=document.getElementById('msg');m.textContent='...';
+ try{
+  const j=await (await fetch('/enroll/options'+T,{method:'POST'})).json();
+  const o=j.publicKey;
+  o.challenge=b64uToBuf(o.challenge);o.user.id=b64uToBuf(o.user.id);
+  if(o.excludeCredentials)o.excludeCredentials.forEach(c=>c.id=b64uToBuf(c.id));
+  const cred=await navigator.credentials.create({publicKey:o});
+  const r=cred.response;
+  const body={id:cred.id,rawId:bufToB64u(cred.rawId),type:cred.type,extensions:{},response:{
+   attestationObject:bufToB64u(r.attestationObject),clientDataJSON:bufToB64u(r.clientDataJSON)}};
+  const res=await fetch('/enroll/verify'+T,{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify(body)});
+  m.textContent=res.ok?M.enroll_done:M.verify_failed+await res.text();
+ }catch(e){m.textContent=M.error+e;}
+};</script></body></html>"#;
+
+const PAGE_SETUP_TMPL: &str = r#"<!doctype html><html lang=__LANG__><meta name=viewport content="width=device-width,initial-scale=1">
+<title>__BRAND__ __t.setup_title__</title><body style="font-family:sans-serif;max-width:30em;margin:3em auto;padding:0 1em">
+<h2>__t.setup_heading__</h2>
+<p>__t.setup_intro__</p>
+<button id=go style="font-size:1.2em;padding:.6em 1.2em">__t.setup_button__</button>
+<p id=msg></p><script>//HELPERS//
+const T='?t=__TOKEN__',M=__MESSAGES__;
+document.getElementById('go').onclick=async()=>{
+ const m=document.getElementById('msg');m.textContent='...';
+ try{
+  const reg=await navigator.serviceWorker.register('/sw.js');
+  await navigator.serviceWorker.ready;
+  if(await Notification.requestPermission()!=='granted'){m.textContent=M.setup_denied;return;}
+  const sub=await reg.pushManager.subscribe({userVisibleOnly:true,
+   applicationServerKey:b64uToBuf('__VAPID_PUB__')});
+  const res=await fetch('/push/subscribe'+T,{method:'POST',
+   headers:{'content-type':'application/json'},body:JSON.stringify(sub.toJSON())});
+  m.textContent=res.ok?M.setup_done:M.setup_failed+await res.text();
+ }catch(e){m.textContent=M.error+e;}
+};</script></body></html>"#;
+
+const PAGE_APPROVE_TMPL: &str = r#"<!doctype html><html lang=__LANG__><meta name=viewport content="width=device-width,initial-scale=1">
+<title>__BRAND__ __t.approve_title__</title><body style="font-family:sans-serif;max-width:30em;margin:3em auto;padding:0 1em">
+<h2 id=hd>__t.approve_heading__</h2>
+<p><b>__t.process__</b> <code>__EXE__</code><br><b>__t.path__</b> <code>__PATH__</code><br><b>__t.group__</b> __GROUP__</p>
+__CONTEXT__
+<p style="color:#666"><b>__t.verified_caller__</b> <code>__PEER__</code></p>
+__FLAG__
+<p id=msg style="font-size:1.2em"></p>
+<p id=left style="color:#666"></p>
+<button id=ok style="font-size:1.2em;padding:.6em 1.2em;margin-right:1em;display:none">__t.approve__</button>
+<button id=no style="font-size:1.2em;padding:.6em 1.2em">__t.deny__</button>
+<p id=for></p>
+<div id=code style="display:none"><p>__t.code_prompt__</p>
+<p><input id=otp inputmode=numeric autocomplete=one-time-code maxlength=6 placeholder="123456"
+ style="font-size:1.4em;width:7em;letter-spacing:.2em"></p>
+<button id=otpgo style="font-size:1.2em;padding:.6em 1.2em">__t.approve__</button></div>
+<div id=why style="display:none"><p>__t.why_prompt__</p><p id=presets></p>
+<p><input id=txt maxlength=80 placeholder="__t.why_placeholder__" style="font-size:1em;width:100%;box-sizing:border-box"></p>
+<button id=send style="font-size:1.2em;padding:.6em 1.2em">__t.deny__</button></div>
+<script>//HELPERS//
+const RID='__RID__',GRANTS=__GRANTS__,REASONS=__REASONS__,TOTP=__TOTP__,M=__MESSAGES__,m=document.getElementById('msg'),
+ ok=document.getElementById('ok'),no=document.getElementById('no'),hd=document.getElementById('hd'),left=document.getElementById('left'),
+ fr=document.getElementById('for'),why=document.getElementById('why'),txt=document.getElementById('txt'),
+ code=document.getElementById('code'),otp=document.getElementById('otp');
+let over=false,deadline=0,tick=null,es=null;const ac=new AbortController();
+function done(){setTimeout(()=>window.close(),1500);}
+// Final state, from this page's own action or from the event stream.
+function finish(title,msg){if(over)return;over=true;hd.textContent=title;m.textContent=msg||'';
+ left.textContent='';clearInterval(tick);if(es)es.close();ac.abort();no.style.display=ok.style.display='none';
+ fr.replaceChildren();why.style.display=code.style.display='none';done();}
+function span(min){return min%60?fmt(M.minutes,{n:min}):fmt(M.hours,{n:min/60});}
+function because(reason){return fmt(M.reason,{reason});}
+const ELSEWHERE={approved:[M.approved_elsewhere_title,M.approved_elsewhere],
+ denied:[M.denied_elsewhere_title,M.denied_elsewhere],
+ timeout:[M.expired_title,M.timed_out],cancelled:[M.cancelled_title,M.cancelled],
+ unknown:[M.expired_title,M.not_pending]};
+function countdown(){const s=Math.max(0,Math.round((deadline-Date.now())/1000));
+ left.textContent=fmt(M.expires_in,{time:Math.floor(s/60)+':'+String(s%60).padStart(2,'0')});}
+if(window.EventSource){es=new EventSource('/approve/'+RID+'/events');
+ es.onmessage=e=>{const d=JSON.parse(e.data);
+  if(d.status==='pending'){deadline=Date.now()+d.remaining*1000;countdown();if(!tick)tick=setInterval(countdown,1000);}
+  else{const f=ELSEWHERE[d.status]||ELSEWHERE.unknown;finish(f[0],d.reason?because(d.reason):f[1]);}};}
+async function deny(reason){
+ await fetch('/approve/'+RID+'/deny',{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify({reason})});
+ finish(M.denied,reason?because(reason):'');}
+// Deny asks why first: a preset in one tap, or free text.
+no.onclick=()=>{no.style.display=ok.style.display=code.style.display='none';fr.replaceChildren();why.style.display='block';
+ const p=document.getElementById('presets');p.replaceChildren();
+ REASONS.forEach(r=>{const b=document.createElement('button');b.textContent=r;
+  b.style.cssText='font-size:1em;padding:.5em 1em;margin:0 .5em .5em 0';b.onclick=()=>deny(r);p.append(b);});};
+document.getElementById('send').onclick=()=>deny(txt.value.trim());
+async function approve(min){
+ m.textContent=M.confirm_fingerprint;
+ try{
+  const opt=await fetch('/approve/'+RID+'/options',{method:'POST'});
+  if(!opt.ok){m.textContent=M.not_pending;return;}
+  const j=await opt.json();
+  const o=j.publicKey;
+  o.challenge=b64uToBuf(o.challenge);
+  if(o.allowCredentials)o.allowCredentials.forEach(c=>c.id=b64uToBuf(c.id));
+  const cred=await navigator.credentials.get({publicKey:o,signal:ac.signal});
+  const r=cred.response;
+  const body={id:cred.id,rawId:bufToB64u(cred.rawId),type:cred.type,extensions:{},response:{
+   authenticatorData:bufToB64u(r.authenticatorData),clientDataJSON:bufToB64u(r.clientDataJSON),
+   signature:bufToB64u(r.signature),userHandle:r.userHandle?bufToB64u(r.userHandle):null}};
+  const res=await fetch('/approve/'+RID+'/verify'+(min?'?grant='+min:''),
+   {method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify(body)});
+  if(res.ok){finish(M.approved,min?fmt(M.trusted_for,{span:span(min)}):'');}
+  else if(res.status===404){m.textContent=M.not_pending;}
+  else{m.textContent=M.verify_failed+await res.text();}
+ }catch(e){
+  if(over)return;
+  // Auto-fire blocked or dismissed: fall back to explicit buttons.
+  m.textContent='';offer();
+ }
+}
+// Approve once, or for one of the configured spans (no auto-fire then, so
+// the choice is made before the fingerprint prompt).
+function offer(){ok.style.display='inline-block';fr.replaceChildren();
+ const pick=min=>{ok.style.display='none';fr.replaceChildren();approve(min);};
+ ok.textContent=GRANTS.length?M.approve_once:M.approve;ok.onclick=()=>pick(0);
+ GRANTS.forEach(min=>{const b=document.createElement('button');b.textContent=fmt(M.approve_for,{span:span(min)});
+  b.style.cssText='font-size:1em;padding:.5em 1em;margin:.5em .5em 0 0';b.onclick=()=>pick(min);fr.append(b);});
+ // A code approves once; grants need the passkey.
+ if(TOTP){const b=document.createElement('button');b.textContent=M.use_code;
+  b.style.cssText='font-size:1em;padding:.5em 1em;margin:.5em .5em 0 0';
+  b.onclick=()=>{ok.style.display='none';fr.replaceChildren();code.style.display='block';otp.focus();};fr.append(b);}}
+document.getElementById('otpgo').onclick=async()=>{
+ const res=await fetch('/approve/'+RID+'/totp',{method:'POST',headers:{'content-type':'application/json'},
+  body:JSON.stringify({code:otp.value.trim()})});
+ if(res.ok)finish(M.approved,'');
+ else if(res.status===404)m.textContent=M.not_pending;
+ else{m.textContent=M.wrong_code;otp.value='';}};
+// With a code on offer, let the user pick rather than firing the passkey prompt.
+if(GRANTS.length||TOTP)offer();else approve(0);
+</script></body></html>"#;
+
+const PAGE_TOTP_TMPL: &str = r#"<!doctype html><html lang=__LANG__><meta name=viewport content="width=device-width,initial-scale=1">
+<title>__BRAND__ __t.totp_title__</title><body style="font-family:sans-serif;max-width:30em;margin:3em auto;padding:0 1em">
+<h2>__t.totp_heading__</h2>
+<p>__t.totp_intro__</p>
+__QR__
+<p><code style="word-break:break-all">__SECRET__</code></p>
+<p><input id=label maxlength=40 placeholder="__t.totp_label_placeholder__"
+ style="font-size:1em;width:100%;box-sizing:border-box"></p>
+<p><input id=otp inputmode=numeric autocomplete=one-time-code maxlength=6 placeholder="123456"
+ style="font-size:1.4em;width:7em;letter-spacing:.2em"></p>
+<button id=go style="font-size:1.2em;padding:.6em 1.2em">__t.totp_add__</button>
+<p id=msg></p><script>
+const M=__MESSAGES__;
+document.getElementById('go').onclick=async()=>{
+ const m=document.getElementById('msg');m.textContent='...';
+ const res=await fetch('/totp/verify?t=__TOKEN__',{method:'POST',headers:{'content-type':'application/json'},
+  body:JSON.stringify({label:document.getElementById('label').value.trim(),code:document.getElementById('otp').value.trim()})});
+ m.textContent=res.ok?M.totp_done:M.failed+await res.text();
+};</script></body></html>"#;
+
+const PAGE_HISTORY_TMPL: &str = r#"<!doctype html><html lang=__LANG__><meta name=viewport content="width=device-width,initial-scale=1">
+<title>__BRAND__ __t.history_title__</title><body style="font-family:sans-serif;max-width:40em;margin:2em auto;padding:0 1em">
+<h2>__t.history_heading__</h2>
+<p id=msg></p>
+<button id=login style="font-size:1.2em;padding:.6em 1.2em">__t.sign_in__</button>
+<div id=data style="display:none">
+<h3>__t.pending__</h3><table id=pending></table>
+<h3>__t.recent__</h3><table id=recent></table>
+</div>
+<style>td{padding:.2em .6em;border-bottom:1px solid #ddd;vertical-align:top}</style>
+<script>//HELPERS//
+const M=__MESSAGES__,m=document.getElementById('msg'),login=document.getElementById('login');
+function when(t){return new Date(t*1000).toLocaleString(document.documentElement.lang);}
+function peer(p){return p?(p.exe||'?')+' (uid '+p.uid+', pid '+p.pid+')':'';}
+function row(table,cells,btn){const tr=table.insertRow();
+ cells.forEach(c=>{tr.insertCell().textContent=c==null?'':String(c);});
+ if(btn)tr.insertCell().appendChild(btn);}
+async function load(){
+ const res=await fetch('/history/data');
+ if(res.status===401){login.style.display='inline-block';document.getElementById('data').style.display='none';return;}
+ const d=await res.json();login.style.display='none';document.getElementById('data').style.display='block';
+ const p=document.getElementById('pending'),r=document.getElementById('recent');p.replaceChildren();r.replaceChildren();
+ if(!d.pending.length)row(p,[M.none]);
+ d.pending.forEach(x=>{const b=document.createElement('button');b.textContent=M.deny;
+  b.onclick=async()=>{await fetch('/history/deny/'+encodeURIComponent(x.rid),{method:'POST'});load();};
+  row(p,[when(x.created_at),(x.exe_mismatch?'⚠ ':'')+(x.title||x.exe),x.path,x.group,peer(x.peer)],b);});
+ if(!d.recent.length)row(r,[M.none]);
+ d.recent.forEach(x=>row(r,[when(x.decided_at),x.outcome,(x.exe_mismatch?'⚠ ':'')+(x.title||x.exe),x.path,peer(x.peer),x.client_ip,
+  x.credential?x.credential.slice(0,8)+'…':'']));}
+login.onclick=async()=>{m.textContent=M.confirm_fingerprint;
+ try{
+  const j=await (await fetch('/history/login/options',{method:'POST'})).json();
+  const o=j.publicKey;
+  o.challenge=b64uToBuf(o.challenge);
+  if(o.allowCredentials)o.allowCredentials.forEach(c=>c.id=b64uToBuf(c.id));
+  const cred=await navigator.credentials.get({publicKey:o});
+  const r=cred.response;
+  const body={id:cred.id,rawId:bufToB64u(cred.rawId),type:cred.type,extensions:{},response:{
+   authenticatorData:bufToB64u(r.authenticatorData),clientDataJSON:bufToB64u(r.clientDataJSON),
+   signature:bufToB64u(r.signature),userHandle:r.userHandle?bufToB64u(r.userHandle):null}};
+  const res=await fetch('/history/login/verify',{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify(body)});
+  m.textContent=res.ok?'':M.sign_in_failed+await res.text();
+  if(res.ok)load();
+ }catch(e){m.textContent=M.sign_in_cancelled;}
+};
+login.style.display='none';load();setInterval(load,10000);
+</script></body></html>"#;
+
+const SW_JS_TMPL: &str = r#"//HELPERS//
+self.addEventListener('install', () => self.skipWaiting());
+self.addEventListener('activate', e => e.waitUntil(clients.claim()));
+// VAPID rotation: subscribe again under the next key and hand the new
+// subscription to the one-time link; the old key is retired after that.
+async function resubscribe(d) {
+  const old = await self.registration.pushManager.getSubscription();
+  if (old) await old.unsubscribe();
+  const sub = await self.registration.pushManager.subscribe({ userVisibleOnly: true,
+    applicationServerKey: b64uToBuf(d.key) });
+  await fetch(d.url, { method: 'POST', headers: { 'content-type': 'application/json' },
+    body: JSON.stringify(sub.toJSON()) });
+  // Every push must show something.
+  await self.registration.showNotification('Approval notifications renewed',
+    { tag: 'access-gate-resubscribe', silent: true });
+}
+self.addEventListener('push', e => {
+  let d = {};
+  try { d = e.data.json(); } catch (_) {}
+  if (d.type === 'resubscribe') return e.waitUntil(resubscribe(d));
+  e.waitUntil(self.registration.showNotification(d.title || 'Access approval needed', {
+    body: (d.exe || '?') + ' → ' + (d.path || '?') + (d.context ? '\n' + d.context : ''),
+    tag: d.rid || 'access-gate',
+    requireInteraction: true,
+    data: { url: d.url || '/approve/' + (d.rid || '') },
+  }));
+});
+self.addEventListener('notificationclick', e => {
+  e.notification.close();
+  if (!e.notification.data) return;
+  e.waitUntil((async () => {
+    const wins = await clients.matchAll({ type: 'window', includeUncontrolled: true });
+    const old = wins.find(w => new URL(w.url).pathname.startsWith('/approve/'));
+    if (old) {
+      try {
+        await old.navigate(e.notification.data.url);
+        return old.focus();
+      } catch (_) { /* uncontrolled client; fall through */ }
+    }
+    return clients.openWindow(e.notification.data.url);
+  })());
+});
+"#;
+
+/// Variables every page gets: the configured brand (HTML-escaped), the
+/// language served, and that language's catalogue as a script object.
+pub const COMMON_VARS: &[&str] = &["BRAND", "LANG", "MESSAGES"];
+
+/// A page the verifier serves from a template.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Page {
+    Enroll,
+    Setup,
+    Approve,
+    Totp,
+    History,
+}
+
+impl Page {
+    pub const ALL: [Page; 5] = [
+        Page::Enroll,
+        Page::Setup,
+        Page::Approve,
+        Page::Totp,
+        Page::History,
+    ];
+
+    /// The file in the pages directory that replaces the built-in template.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Page::Enroll => "enroll.html",
+            Page::Setup => "setup.html",
+            Page::Approve => "approve.html",
+            Page::Totp => "totp.html",
+            Page::History => "history.html",
+        }
+    }
+
+    pub fn builtin(self) -> &'static str {
+        match self {
+            Page::Enroll => PAGE_ENROLL_TMPL,
+            Page::Setup => PAGE_SETUP_TMPL,
+            Page::Approve => PAGE_APPROVE_TMPL,
+            Page::Totp => PAGE_TOTP_TMPL,
+            Page::History => PAGE_HISTORY_TMPL,
+        }
+    }
+
+    /// The page's own variables (besides `COMMON_VARS`). Text is
+    /// HTML-escaped; `GRANTS`, `REASONS` and `TOTP` are script literals,
+    /// `QR`, `CONTEXT` and `FLAG` markup.
+    pub fn vars(self) -> &'static [&'static str] {
+        match self {
+            Page::Enroll => &["TOKEN"],
+            Page::Setup => &["TOKEN", "VAPID_PUB"],
+            Page::Approve => &[
+                "RID", "GRANTS", "REASONS", "TOTP", "EXE", "PATH", "GROUP", "CONTEXT", "PEER",
+                "FLAG",
+            ],
+            Page::Totp => &["TOKEN", "QR", "SECRET"],
+            Page::History => &[],
+        }
+    }
+
+    /// The variables the page can't work without.
+    pub fn required(self) -> &'static [&'static str] {
+        match self {
+            Page::Enroll => &["TOKEN"],
+            Page::Setup => &["TOKEN", "VAPID_PUB"],
+            Page::Approve => &["RID"],
+            Page::Totp => &["TOKEN"],
+            Page::History => &[],
+        }
+    }
+}
+
+/// Text for HTML content and quoted attributes.
+pub(crate) fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// JSON for inlining into a page's <script>: no `</script>` breakout.
+pub(crate) fn script_json(v: &Value) -> String {
+    v.to_string().replace('<', "\\u003c")
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Var(String),
+    Msg(String),
+}
+
+fn is_var(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Split `tmpl` into text, `__VAR__`s and `__t.key__`s. Other `__`s (as in
+/// `__proto__`) are text.
+fn parse(tmpl: &str) -> Result<Vec<Segment>> {
+    let mut out = Vec::new();
+    let mut text = String::new();
+    let mut rest = tmpl;
+    while let Some(at) = rest.find("__") {
+        text.push_str(&rest[..at]);
+        let after = &rest[at + 2..];
+        let name = after.find("__").map(|end| &after[..end]);
+        if let Some(key) = after.strip_prefix("t.") {
+            let Some(key) = name.and_then(|n| n.strip_prefix("t.")) else {
+                bail!("unterminated message near {:?}", &key[..key.len().min(20)]);
+            };
+            if !key.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+                bail!("malformed message name {key:?}");
+            }
+            out.push(Segment::Text(std::mem::take(&mut text)));
+            out.push(Segment::Msg(key.to_string()));
+        } else if let Some(name) = name.filter(|n| is_var(n)) {
+            out.push(Segment::Text(std::mem::take(&mut text)));
+            out.push(Segment::Var(name.to_string()));
+        } else {
+            text.push_str("__");
+            rest = after;
+            continue;
+        }
+        rest = &after[name.unwrap_or_default().len() + 2..];
+    }
+    text.push_str(rest);
+    out.push(Segment::Text(text));
+    out.retain(|s| *s != Segment::Text(String::new()));
+    Ok(out)
+}
+
+/// `tmpl` for `page`, checked against its variables and the catalogues.
+fn compile(page: Page, tmpl: &str, catalogues: &Catalogues) -> Result<Vec<Segment>> {
+    let segments = parse(&tmpl.replace("//HELPERS//", JS_HELPERS))?;
+    for s in &segments {
+        match s {
+            Segment::Var(v)
+                if !COMMON_VARS.contains(&v.as_str()) && !page.vars().contains(&v.as_str()) =>
+            {
+                bail!("unknown variable __{v}__ (this page has {:?})", page.vars())
+            }
+            Segment::Msg(key) if !catalogues.has_key(key) => bail!("unknown message __t.{key}__"),
+            _ => {}
+        }
+    }
+    if let Some(missing) = page
+        .required()
+        .iter()
+        .find(|r| !segments.contains(&Segment::Var(r.to_string())))
+    {
+        bail!("the page needs __{missing}__");
+    }
+    Ok(segments)
+}
+
+/// The compiled templates and the catalogues they draw on.
+pub struct Pages {
+    templates: Vec<Vec<Segment>>,
+    catalogues: Catalogues,
+    /// Each language's catalogue as a script object.
+    messages: BTreeMap<String, String>,
+    brand: String,
+}
+
+impl Pages {
+    /// Templates and catalogues from `dir` over the built-in ones.
+    pub fn load(dir: &Path, brand: &str) -> Result<Self> {
+        let catalogues = Catalogues::load(dir)?;
+        let mut templates = Vec::new();
+        for page in Page::ALL {
+            let path = dir.join(page.file_name());
+            let compiled = match fs::read_to_string(&path) {
+                Ok(tmpl) => compile(page, &tmpl, &catalogues)
+                    .with_context(|| format!("template {}", path.display()))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    compile(page, page.builtin(), &catalogues)
+                        .with_context(|| format!("built-in {}", page.file_name()))?
+                }
+                Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+            };
+            templates.push(compiled);
+        }
+        let messages = catalogues
+            .langs()
+            .map(|(lang, texts)| (lang.to_string(), script_json(&json!(texts))))
+            .collect();
+        Ok(Pages {
+            templates,
+            catalogues,
+            messages,
+            brand: html_escape(brand),
+        })
+    }
+
+    /// The language to answer an Accept-Language header in.
+    pub fn negotiate(&self, accept_language: Option<&str>) -> &str {
+        self.catalogues.negotiate(accept_language)
+    }
+
+    /// Message `key` in `lang`, unescaped.
+    pub fn message<'a>(&'a self, lang: &str, key: &'a str) -> &'a str {
+        self.catalogues.get(lang, key)
+    }
+
+    /// `page` in `lang`. `vars` are inserted as given (escaped by the
+    /// caller), in one pass, so a value is never scanned for variables.
+    pub fn render(&self, page: Page, lang: &str, vars: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        for s in &self.templates[Page::ALL.iter().position(|p| *p == page).unwrap()] {
+            match s {
+                Segment::Text(t) => out.push_str(t),
+                Segment::Msg(key) => out.push_str(&html_escape(self.message(lang, key))),
+                Segment::Var(v) => match v.as_str() {
+                    "BRAND" => out.push_str(&self.brand),
+                    "LANG" => out.push_str(lang),
+                    "MESSAGES" => out.push_str(
+                        self.messages
+                            .get(lang)
+                            .unwrap_or(&self.messages[crate::i18n::DEFAULT]),
+                    ),
+                    name => out.push_str(
+                        vars.iter()
+                            .find(|(k, _)| *k == name)
+                            .map_or("", |(_, val)| val),
+                    ),
+                },
+            }
+        }
+        out
+    }
+}
+
+/// The service worker, with the helpers spliced in once, at first use.
+pub(crate) static SW_JS: once_cell_lite::Lazy<String> =
+    once_cell_lite::Lazy::new(|| SW_JS_TMPL.replace("//HELPERS//", JS_HELPERS));
+
+/// Minimal Lazy<T> (std-only) so we don't pull once_cell just for a few pages.
+mod once_cell_lite {
+    use std::sync::OnceLock;
+
+    pub struct Lazy<T> {
+        cell: OnceLock<T>,
+        init: fn() -> T,
+    }
+
+    impl<T> Lazy<T> {
+        pub const fn new(init: fn() -> T) -> Self {
+            Self {
+                cell: OnceLock::new(),
+                init,
+            }
+        }
+    }
+
+    impl<T> std::ops::Deref for Lazy<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            self.cell.get_or_init(self.init)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    #[test]
+    fn parsing_splits_variables_and_messages() {
+        assert_eq!(
+            parse("<b>__t.deny__</b> __RID__ x.__proto__ a__b").unwrap(),
+            vec![
+                Segment::Text("<b>".into()),
+                Segment::Msg("deny".into()),
+                Segment::Text("</b> ".into()),
+                Segment::Var("RID".into()),
+                Segment::Text(" x.__proto__ a__b".into()),
+            ]
+        );
+        assert!(parse("__t.deny").is_err());
+        assert!(parse("__t.Deny__").is_err());
+    }
+
+    #[test]
+    fn builtin_pages_render_in_both_languages() {
+        let pages = Pages::load(Path::new("/nonexistent"), "<TV>").unwrap();
+        let lang = pages.negotiate(Some("de-DE,de;q=0.9"));
+        let html = pages.render(Page::Approve, lang, &[("RID", "abc"), ("EXE", "game-mode")]);
+        assert!(html.contains("<html lang=de>"), "{html}");
+        assert!(html.contains("&lt;TV&gt; Freigabe"));
+        assert!(html.contains("const RID='abc'"));
+        assert!(html.contains("\"deny\":\"Ablehnen\""));
+        assert!(!html.contains("__"), "unfilled: {html}");
+        let html = pages.render(Page::Enroll, "en", &[("TOKEN", "t0k")]);
+        assert!(html.contains("Create passkey") && html.contains("?t=t0k"));
+    }
+
+    #[test]
+    fn overrides_are_checked_at_load() {
+        let dir = tmp_dir("pages");
+        fs::write(
+            dir.join("approve.html"),
+            "<h1>__t.approve_heading__ __RID__ __BRAND__</h1>",
+        )
+        .unwrap();
+        let pages = Pages::load(&dir, "TV").unwrap();
+        assert_eq!(
+            pages.render(Page::Approve, "en", &[("RID", "__EXE__")]),
+            "<h1>Access request __EXE__ TV</h1>"
+        );
+
+        let broken = |tmpl: &str, expect: &str| {
+            fs::write(dir.join("approve.html"), tmpl).unwrap();
+            let err = format!("{:#}", Pages::load(&dir, "TV").err().unwrap());
+            assert!(err.contains(expect), "{err}");
+        };
+        broken("__RID__ __TOKEN__", "unknown variable __TOKEN__");
+        broken("__RID__ __t.nope__", "unknown message __t.nope__");
+        broken("__EXE__", "needs __RID__");
+        broken("__RID__ __t.deny", "unterminated message");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,82 @@
+//! QR codes, so enrolling needs no typing on the phone: inline SVG for
+//! pages, block characters for the terminal.
+
+use anyhow::{anyhow, Result};
+use qrcodegen::{QrCode, QrCodeEcc};
+
+/// Quiet zone, in modules (the spec's minimum).
+const BORDER: i32 = 4;
+
+fn encode(text: &str) -> Result<QrCode> {
+    QrCode::encode_text(text, QrCodeEcc::Medium).map_err(|e| anyhow!("QR encode: {e}"))
+}
+
+/// `text` as a scalable SVG, one path for all dark modules.
+pub fn svg(text: &str) -> Result<String> {
+    let qr = encode(text)?;
+    let size = qr.size() + 2 * BORDER;
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + BORDER, y + BORDER));
+            }
+        }
+    }
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" \
+         shape-rendering=\"crispEdges\" style=\"width:100%;max-width:16em\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\
+         <path d=\"{path}\" fill=\"#000\"/></svg>"
+    ))
+}
+
+/// `text` for a terminal, two rows of modules per line (half blocks).
+/// Light modules are drawn, so it scans on a dark background.
+pub fn terminal(text: &str) -> Result<String> {
+    let qr = encode(text)?;
+    let light = |x: i32, y: i32| !qr.get_module(x, y);
+    let range = -BORDER..qr.size() + BORDER;
+    let mut out = String::new();
+    for y in range.clone().step_by(2) {
+        for x in range.clone() {
+            // Past the last row counts as quiet zone.
+            let lower = y + 1 >= qr.size() + BORDER || light(x, y + 1);
+            out.push(match (light(x, y), lower) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_renderings_have_the_finder_patterns() {
+        let text = "otpauth://totp/access-gate:kid?secret=JBSWY3DPEHPK3PXP&issuer=access-gate";
+        let image = svg(text).unwrap();
+        assert!(image.starts_with("<svg"));
+        // Top-left finder: its corner module and the ring's inner gap.
+        assert!(image.contains("M4,4h1v1h-1z"));
+        assert!(!image.contains("M5,5h1v1h-1z"));
+        assert!(image.contains("M6,6h1v1h-1z"));
+        assert!(svg(&"x".repeat(4000)).is_err());
+
+        let lines: Vec<Vec<char>> = terminal(text)
+            .unwrap()
+            .lines()
+            .map(|l| l.chars().collect())
+            .collect();
+        // Quiet zone on top, then the finder's dark corner two modules high.
+        assert!(lines[0].iter().all(|&c| c == '█'));
+        assert_eq!(lines[2][BORDER as usize], ' ');
+        assert_eq!(lines.len(), lines[0].len().div_ceil(2));
+    }
+}
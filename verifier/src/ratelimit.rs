@@ -0,0 +1,174 @@
+//! Per-client throttling for the web plane: a token bucket per client
+//! address, plus an exponential lockout after failed passkey assertions.
+//! Everything on the tailnet can reach these endpoints, and a WebAuthn
+//! ceremony is cheap to start, so nothing stops a script from hammering
+//! them otherwise. Time is passed in so the tests can drive it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Lockout after the first failed assertion; doubles with each further one.
+const BACKOFF_BASE: Duration = Duration::from_secs(2);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// Past this many tracked clients, idle ones are forgotten.
+const MAX_TRACKED: usize = 4096;
+
+pub struct Limiter {
+    per_sec: f64,
+    burst: f64,
+    clients: Mutex<HashMap<String, Client>>,
+}
+
+struct Client {
+    tokens: f64,
+    refilled: Instant,
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Client {
+    fn refill(&mut self, now: Instant, per_sec: f64, burst: f64) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(burst);
+        self.refilled = now;
+        // Failures age out, so one typo a week doesn't escalate forever.
+        if self.failures > 0 && now.saturating_duration_since(self.last_failure) > BACKOFF_MAX {
+            self.failures = 0;
+        }
+    }
+
+    fn idle(&self, now: Instant, burst: f64) -> bool {
+        self.tokens >= burst && self.failures == 0 && self.locked_until.is_none_or(|t| t <= now)
+    }
+}
+
+impl Limiter {
+    pub fn new(per_min: u64, burst: u64) -> Self {
+        Limiter {
+            per_sec: per_min as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spend a token for `client`. `Err` carries how long to wait: the
+    /// lockout if one is running, else until the next token.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED {
+            clients.retain(|_, c| {
+                c.refill(now, self.per_sec, self.burst);
+                !c.idle(now, self.burst)
+            });
+        }
+        let c = clients.entry(client.to_string()).or_insert(Client {
+            tokens: self.burst,
+            refilled: now,
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        c.refill(now, self.per_sec, self.burst);
+        if let Some(until) = c.locked_until.filter(|&t| t > now) {
+            return Err(until - now);
+        }
+        if c.tokens < 1.0 {
+            let wait = if self.per_sec > 0.0 {
+                Duration::from_secs_f64((1.0 - c.tokens) / self.per_sec)
+            } else {
+                BACKOFF_MAX
+            };
+            return Err(wait);
+        }
+        c.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Record a failed assertion and return the lockout it earned.
+    pub fn failure(&self, client: &str, now: Instant) -> Duration {
+        let mut clients = self.clients.lock().unwrap();
+        let Some(c) = clients.get_mut(client) else {
+            return Duration::ZERO;
+        };
+        c.refill(now, self.per_sec, self.burst);
+        c.failures += 1;
+        c.last_failure = now;
+        let lockout = BACKOFF_BASE
+            .saturating_mul(1 << (c.failures - 1).min(16))
+            .min(BACKOFF_MAX);
+        c.locked_until = Some(now + lockout);
+        lockout
+    }
+
+    /// A good assertion clears the client's failure record.
+    pub fn success(&self, client: &str) {
+        if let Some(c) = self.clients.lock().unwrap().get_mut(client) {
+            c.failures = 0;
+            c.locked_until = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limiter = Limiter::new(60, 3);
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", t0), Ok(()));
+        }
+        let wait = limiter.check("a", t0).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= SEC, "{wait:?}");
+        // Other clients have their own bucket.
+        assert_eq!(limiter.check("b", t0), Ok(()));
+        assert_eq!(limiter.check("a", t0 + SEC), Ok(()));
+        assert!(limiter.check("a", t0 + SEC).is_err());
+        // Never refills past the burst.
+        let later = t0 + 3600 * SEC;
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", later), Ok(()));
+        }
+        assert!(limiter.check("a", later).is_err());
+    }
+
+    #[test]
+    fn failures_back_off_exponentially_until_success() {
+        let limiter = Limiter::new(600, 100);
+        let t0 = Instant::now();
+        limiter.check("a", t0).unwrap();
+        assert_eq!(limiter.failure("a", t0), 2 * SEC);
+        assert_eq!(limiter.check("a", t0 + SEC), Err(SEC));
+        assert_eq!(limiter.check("a", t0 + 2 * SEC), Ok(()));
+        assert_eq!(limiter.failure("a", t0 + 2 * SEC), 4 * SEC);
+        assert_eq!(limiter.failure("a", t0 + 2 * SEC), 8 * SEC);
+        for _ in 0..10 {
+            limiter.failure("a", t0 + 2 * SEC);
+        }
+        assert_eq!(limiter.check("a", t0 + 2 * SEC), Err(BACKOFF_MAX));
+        // The lockout is per client.
+        assert_eq!(limiter.check("b", t0 + 2 * SEC), Ok(()));
+
+        limiter.success("a");
+        assert_eq!(limiter.check("a", t0 + 3 * SEC), Ok(()));
+        assert_eq!(limiter.failure("a", t0 + 3 * SEC), 2 * SEC);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let limiter = Limiter::new(600, 100);
+        let t0 = Instant::now();
+        limiter.check("a", t0).unwrap();
+        limiter.failure("a", t0);
+        limiter.failure("a", t0);
+        let much_later = t0 + BACKOFF_MAX + 10 * SEC;
+        assert_eq!(limiter.check("a", much_later), Ok(()));
+        assert_eq!(limiter.failure("a", much_later), 2 * SEC);
+    }
+}
@@ -0,0 +1,548 @@
+//! Self-confinement once the listeners are bound: the verifier parses
+//! untrusted HTTP and WebAuthn JSON while holding the passkey store.
+//!
+//! Landlock limits the filesystem to the data dir and the control socket's
+//! dir, plus read-only system paths (name resolution, time zones) and the
+//! directories of the TLS certificate and key. A seccomp allowlist limits
+//! the process to the system calls tiny_http, the push client and the
+//! control socket make; anything else fails with EPERM rather than killing
+//! the verifier mid-approval. Both apply to the calling thread and the
+//! threads it spawns afterwards, so `apply` runs before the first one. Both
+//! are on by default (`[sandbox]` turns either off); a kernel without them
+//! runs unconfined, with a warning.
+//!
+//! Reading `/proc/<pid>/exe` of a control client that runs as another user
+//! takes `CAP_SYS_PTRACE`, and Landlock refuses it whatever the
+//! capabilities. A helper forked before either comes down answers just
+//! that (see `peer_exe`); it alone keeps the capability, and the verifier
+//! drops it (`split_exe_helper`).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use tracing::{info, warn};
+
+use crate::config::Cfg;
+
+/// Read-only system paths: the resolver's files and NSS modules, time zones.
+const SYSTEM_RO: &[&str] = &["/etc", "/usr", "/lib", "/lib64", "/proc", "/dev/urandom"];
+
+/// The exe helper's end of the line, once it is forked.
+static EXE_HELPER: OnceLock<Mutex<UnixStream>> = OnceLock::new();
+
+/// `capability.h`: the `capget`/`capset` ABI and the one capability dropped.
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+const CAP_SYS_PTRACE: u32 = 19;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Fork the exe helper, then drop `CAP_SYS_PTRACE` from the verifier: from
+/// here on only the helper can look into other users' processes. Call
+/// before `apply` and before spawning any thread, as capabilities are
+/// per thread.
+pub fn split_exe_helper() -> Result<()> {
+    spawn_exe_helper()?;
+    drop_capability(CAP_SYS_PTRACE)
+}
+
+/// Remove `cap` from the calling thread's ambient, effective, permitted and
+/// inheritable sets, for good (no privileges to take it back with).
+fn drop_capability(cap: u32) -> Result<()> {
+    // Fails only on a kernel without ambient capabilities, which then has
+    // none to lower.
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_LOWER,
+            cap as libc::c_ulong,
+            0,
+            0,
+        )
+    };
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).context("capget");
+    }
+    let (word, bit) = ((cap / 32) as usize, 1u32 << (cap % 32));
+    data[word].effective &= !bit;
+    data[word].permitted &= !bit;
+    data[word].inheritable &= !bit;
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).context("capset");
+    }
+    Ok(())
+}
+
+/// Confine the process per `cfg.sandbox`. Call before spawning any thread.
+pub fn apply(cfg: &Cfg) -> Result<()> {
+    if cfg.sandbox.landlock {
+        let mut rw = vec![cfg.data_dir.clone(), PathBuf::from("/dev/null")];
+        rw.extend(cfg.ctrl_socket.parent().map(Path::to_path_buf));
+        let mut ro: Vec<PathBuf> = SYSTEM_RO.iter().map(PathBuf::from).collect();
+        if let Some(tls) = &cfg.tls {
+            // Re-read on SIGHUP.
+            ro.extend(tls_dirs(&[&tls.cert, &tls.key]));
+        }
+        landlock(&rw, &ro)?;
+    } else {
+        warn!("sandbox: Landlock turned off (sandbox.landlock)");
+    }
+    if cfg.sandbox.seccomp {
+        seccomp()?;
+    } else {
+        warn!("sandbox: seccomp turned off (sandbox.seccomp)");
+    }
+    Ok(())
+}
+
+/// Where the certificate and key may be read from after a renewal: the
+/// directories holding them and their symlink targets, not the files, which
+/// a renewal replaces (a rule on a file holds its old inode).
+fn tls_dirs(files: &[&Path]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for file in files {
+        let target = fs::canonicalize(file).ok();
+        for path in [Some(file.to_path_buf()), target].into_iter().flatten() {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                if !dirs.iter().any(|d| d == dir) {
+                    dirs.push(dir.to_path_buf());
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// Restrict the filesystem to `rw` (anything) and `ro` (reading) for the
+/// calling thread and its future threads. Whether it is enforced: false on
+/// a kernel without Landlock.
+pub fn landlock(rw: &[PathBuf], ro: &[PathBuf]) -> Result<bool> {
+    let abi = ABI::V5;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(rw, AccessFs::from_all(abi)))?
+        .add_rules(path_beneath_rules(ro, AccessFs::from_read(abi)))?
+        .restrict_self()
+        .context("Landlock")?;
+    Ok(match status.ruleset {
+        RulesetStatus::FullyEnforced => {
+            info!("sandbox: filesystem limited to {rw:?} (+ read-only system paths)");
+            true
+        }
+        RulesetStatus::PartiallyEnforced => {
+            info!("sandbox: filesystem limited to {rw:?} (older Landlock ABI, partially)");
+            true
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("sandbox: kernel without Landlock; filesystem access not confined");
+            false
+        }
+    })
+}
+
+/// The binary process `pid` runs, as `/proc/<pid>/exe` reads: asked of the
+/// helper once `split_exe_helper` forked it, read directly before that.
+pub fn peer_exe(pid: i32) -> Option<String> {
+    let Some(helper) = EXE_HELPER.get() else {
+        return fs::read_link(format!("/proc/{pid}/exe"))
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned());
+    };
+    let mut sock = helper.lock().unwrap();
+    let ask = |sock: &mut UnixStream| -> io::Result<Option<String>> {
+        sock.write_all(&pid.to_le_bytes())?;
+        let mut len = [0u8; 4];
+        sock.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len == u32::MAX {
+            return Ok(None);
+        }
+        let mut path = vec![0u8; len as usize];
+        sock.read_exact(&mut path)?;
+        Ok(Some(String::from_utf8_lossy(&path).into_owned()))
+    };
+    ask(&mut sock).unwrap_or_else(|e| {
+        warn!("exe helper: {e}");
+        None
+    })
+}
+
+/// Fork the helper `peer_exe` asks. Single-threaded callers only.
+fn spawn_exe_helper() -> Result<()> {
+    let (ours, theirs) = UnixStream::pair().context("exe helper socket")?;
+    let parent = unsafe { libc::getpid() };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("forking the exe helper"),
+        0 => {
+            drop(ours);
+            exe_helper(theirs, parent)
+        }
+        _ => {
+            drop(theirs);
+            let _ = EXE_HELPER.set(Mutex::new(ours));
+            Ok(())
+        }
+    }
+}
+
+/// The helper: a pid in, its binary's path out (length-prefixed, `u32::MAX`
+/// for none), until the verifier goes away.
+fn exe_helper(mut sock: UnixStream, parent: libc::pid_t) -> ! {
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        if libc::getppid() != parent {
+            libc::_exit(0);
+        }
+        // Nothing of the verifier's but this line: no listeners, no store.
+        let fd = sock.as_raw_fd() as libc::c_uint;
+        libc::syscall(libc::SYS_close_range, 3, fd - 1, 0);
+        libc::syscall(libc::SYS_close_range, fd + 1, libc::c_uint::MAX, 0);
+    }
+    let mut pid = [0u8; 4];
+    while sock.read_exact(&mut pid).is_ok() {
+        let pid = i32::from_le_bytes(pid);
+        let reply = match fs::read_link(format!("/proc/{pid}/exe")) {
+            Ok(path) => {
+                let bytes = path.as_os_str().as_bytes();
+                [&(bytes.len() as u32).to_le_bytes()[..], bytes].concat()
+            }
+            Err(_) => u32::MAX.to_le_bytes().to_vec(),
+        };
+        if sock.write_all(&reply).is_err() {
+            break;
+        }
+    }
+    unsafe { libc::_exit(0) }
+}
+
+/// The system calls the verifier makes once serving.
+fn allowed_syscalls() -> Vec<i64> {
+    let mut calls = vec![
+        // I/O and files (the data dir; Landlock decides which).
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_openat,
+        libc::SYS_close,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_lseek,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_mkdirat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_ftruncate,
+        libc::SYS_fallocate,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_faccessat,
+        libc::SYS_faccessat2,
+        libc::SYS_utimensat,
+        libc::SYS_flock,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_eventfd2,
+        libc::SYS_getcwd,
+        libc::SYS_umask,
+        // Sockets: the listeners, the control socket's peers, outgoing pushes.
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_shutdown,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        // Memory, threads, signals, time.
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mprotect,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_futex,
+        libc::SYS_clone,
+        libc::SYS_clone3,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_set_tid_address,
+        libc::SYS_membarrier,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_restart_syscall,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_sigaltstack,
+        libc::SYS_tgkill,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_prctl,
+        libc::SYS_prlimit64,
+        libc::SYS_getrusage,
+        libc::SYS_sysinfo,
+        libc::SYS_uname,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_getresuid,
+        libc::SYS_getresgid,
+    ];
+    // The older calls aarch64 never had.
+    #[cfg(target_arch = "x86_64")]
+    calls.extend([
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_readlink,
+        libc::SYS_mkdir,
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_chmod,
+        libc::SYS_getdents,
+        libc::SYS_dup2,
+        libc::SYS_pipe,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_wait,
+        libc::SYS_epoll_create,
+        libc::SYS_arch_prctl,
+        libc::SYS_time,
+    ]);
+    calls
+}
+
+/// The allowlist as a BPF program for this machine.
+fn filter() -> Result<BpfProgram> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH)
+        .map_err(|e| anyhow!("seccomp on {}: {e}", std::env::consts::ARCH))?;
+    let rules: BTreeMap<i64, Vec<_>> = allowed_syscalls()
+        .into_iter()
+        .map(|nr| (nr, Vec::new()))
+        .collect();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        arch,
+    )?;
+    Ok(filter.try_into()?)
+}
+
+/// Apply the system call allowlist to every thread. Whether it is
+/// enforced: false on a kernel or architecture without seccomp.
+pub fn seccomp() -> Result<bool> {
+    let program = match filter() {
+        Ok(program) => program,
+        Err(e) => {
+            warn!("sandbox: {e:#}; system calls not confined");
+            return Ok(false);
+        }
+    };
+    match seccompiler::apply_filter_all_threads(&program) {
+        Ok(()) => {
+            info!("sandbox: system calls limited to the verifier's allowlist");
+            Ok(true)
+        }
+        Err(seccompiler::Error::Seccomp(e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+            warn!("sandbox: kernel without seccomp filters; system calls not confined");
+            Ok(false)
+        }
+        Err(e) => Err(e).context("seccomp"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use std::fs;
+    use std::sync::mpsc;
+
+    // Both only ever confine the thread they run on (and its children), so
+    // each test confines a thread of its own.
+
+    #[test]
+    fn landlock_keeps_writes_inside_the_data_dir() {
+        let base = tmp_dir("landlock");
+        let data = base.join("data");
+        fs::create_dir_all(&data).unwrap();
+        let outside = base.join("outside");
+        std::thread::spawn(move || {
+            let enforced = landlock(std::slice::from_ref(&data), &[]).unwrap();
+            fs::write(data.join("credential.json"), "{}").unwrap();
+            let write = fs::write(&outside, "x");
+            if enforced {
+                let err = write.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+                assert!(fs::read_to_string("/etc/passwd").is_err());
+            } else {
+                // No Landlock here: nothing confined, and nothing failed.
+                write.unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn renewed_certificate_stays_readable() {
+        let base = fs::canonicalize(tmp_dir("landlock-tls")).unwrap();
+        let (live, archive) = (base.join("live"), base.join("archive"));
+        fs::create_dir_all(&live).unwrap();
+        fs::create_dir_all(&archive).unwrap();
+        fs::write(archive.join("cert1.pem"), "old").unwrap();
+        std::os::unix::fs::symlink(archive.join("cert1.pem"), live.join("cert.pem")).unwrap();
+        fs::write(base.join("key.pem"), "old").unwrap();
+        let (cert, key) = (live.join("cert.pem"), base.join("key.pem"));
+
+        let dirs = tls_dirs(&[&cert, &key]);
+        assert_eq!(dirs, [live.clone(), archive.clone(), base.clone()]);
+
+        // A renewal replaces both files while the confined thread waits.
+        let (renew, renewed) = (mpsc::channel(), mpsc::channel());
+        let reader = {
+            let (cert, key) = (cert.clone(), key.clone());
+            std::thread::spawn(move || {
+                let enforced = landlock(&[], &dirs).unwrap();
+                assert_eq!(fs::read_to_string(&cert).unwrap(), "old");
+                renew.0.send(()).unwrap();
+                renewed.1.recv().unwrap();
+                assert_eq!(fs::read_to_string(&cert).unwrap(), "new");
+                assert_eq!(fs::read_to_string(&key).unwrap(), "new");
+                if enforced {
+                    assert!(fs::read_to_string("/etc/passwd").is_err());
+                }
+            })
+        };
+        renew.1.recv().unwrap();
+        fs::write(archive.join("cert2.pem"), "new").unwrap();
+        std::os::unix::fs::symlink(archive.join("cert2.pem"), live.join("cert.tmp")).unwrap();
+        fs::rename(live.join("cert.tmp"), &cert).unwrap();
+        fs::write(base.join("key.tmp"), "new").unwrap();
+        fs::rename(base.join("key.tmp"), &key).unwrap();
+        renewed.0.send(()).unwrap();
+        reader.join().unwrap();
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn dropped_capability_is_gone_from_every_set() {
+        std::thread::spawn(|| {
+            drop_capability(CAP_SYS_PTRACE).unwrap();
+            let mut header = CapHeader {
+                version: LINUX_CAPABILITY_VERSION_3,
+                pid: 0,
+            };
+            let mut data = [CapData::default(); 2];
+            let rc = unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) };
+            assert_eq!(rc, 0);
+            let bit = 1 << CAP_SYS_PTRACE;
+            assert_eq!(data[0].effective & bit, 0);
+            assert_eq!(data[0].permitted & bit, 0);
+            assert_eq!(data[0].inheritable & bit, 0);
+            let ambient = unsafe {
+                libc::prctl(
+                    libc::PR_CAP_AMBIENT,
+                    libc::PR_CAP_AMBIENT_IS_SET,
+                    CAP_SYS_PTRACE as libc::c_ulong,
+                    0,
+                    0,
+                )
+            };
+            assert!(ambient <= 0);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn seccomp_refuses_calls_off_the_list() {
+        let dir = tmp_dir("seccomp");
+        std::thread::spawn(move || {
+            let program = filter().unwrap();
+            // This thread only (no TSYNC): the test harness stays unconfined.
+            seccompiler::apply_filter(&program).unwrap();
+            fs::write(dir.join("ok"), "fine").unwrap();
+            let rc = unsafe { libc::syscall(libc::SYS_getpriority, libc::PRIO_PROCESS, 0) };
+            assert_eq!(rc, -1);
+            assert_eq!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EPERM)
+            );
+            let _ = fs::remove_dir_all(&dir);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+//! Persistent state behind one interface: the enrolled passkey, the Web
+//! Push subscription, TOTP authenticators, timed grants and the decision
+//! log.
+//!
+//! Two backends, picked by `storage.backend`:
+//!
+//! - `file` (default): `credential.json`, `push_subscription.json`,
+//!   `totp.json`, `grants.json` and `history.jsonl` in the data dir, each
+//!   replaced atomically (history is append-only).
+//! - `sqlite` (cargo feature `sqlite`): one `verifier.db` in the data dir,
+//!   every update a transaction; see `store::sqlite`.
+//!
+//! Pending requests stay in the journal with either backend (rewritten on
+//! every change, gone after a TTL), and the enrollment tokens stay files:
+//! the desktop side mints them while the verifier runs (see `token`).
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde_json::Value;
+use webauthn_rs::prelude::Passkey;
+
+use crate::config::{Cfg, StorageBackend};
+use crate::grants::{Grant, Grants};
+use crate::history::{History, Record};
+use crate::journal::write_atomic;
+use crate::totp::TotpCred;
+
+pub trait Store: Send + Sync {
+    /// The enrolled passkey, if any.
+    fn passkey(&self) -> Result<Option<Passkey>>;
+    /// Enroll `passkey`, or keep its updated sign counter after a use.
+    fn save_passkey(&self, passkey: &Passkey) -> Result<()>;
+
+    /// The browser's push subscription (`{"endpoint", "keys"}`), if any.
+    fn subscription(&self) -> Result<Option<Value>>;
+    /// Replace the subscription; `None` drops it, which re-opens /setup.
+    fn set_subscription(&self, subscription: Option<&Value>) -> Result<()>;
+
+    /// Enrolled TOTP authenticators, oldest first.
+    fn totp_creds(&self) -> Result<Vec<TotpCred>>;
+    fn add_totp(&self, cred: &TotpCred) -> Result<()>;
+    /// Whether `id` existed (and is gone now).
+    fn remove_totp(&self, id: &str) -> Result<bool>;
+    /// Record that `id` approved with the code for `step`. False when that
+    /// step or a later one was used already: a replayed code.
+    fn use_totp(&self, id: &str, step: u64) -> Result<bool>;
+
+    /// Grants still in force at `now`, soonest to expire first.
+    fn grants(&self, now: u64) -> Result<Vec<Grant>>;
+    /// Record `grant`, replacing any other for the same group + exe.
+    fn add_grant(&self, grant: Grant) -> Result<()>;
+    /// Revoke one grant by id, or all of them for `None`. Returns how many
+    /// were dropped.
+    fn revoke_grants(&self, id: Option<&str>, now: u64) -> Result<usize>;
+
+    fn append_history(&self, record: &Record) -> Result<()>;
+    /// Up to `n` most recent decisions, newest first.
+    fn recent_history(&self, n: usize) -> Result<Vec<Record>>;
+
+    fn matching_grant(&self, group: &str, exe: &str, now: u64) -> Result<Option<Grant>> {
+        Ok(self
+            .grants(now)?
+            .into_iter()
+            .find(|g| g.group == group && g.exe == exe))
+    }
+}
+
+/// The configured backend, migrated and ready.
+pub fn open(cfg: &Cfg) -> Result<Arc<dyn Store>> {
+    match cfg.storage {
+        StorageBackend::File => Ok(Arc::new(FileStore::new(&cfg.data_dir))),
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => Ok(Arc::new(sqlite::SqliteStore::open(&cfg.data_dir)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => {
+            anyhow::bail!("storage.backend = \"sqlite\", but this build lacks the sqlite feature")
+        }
+    }
+}
+
+pub struct FileStore {
+    dir: PathBuf,
+    credential: PathBuf,
+    subscription: PathBuf,
+    totp: PathBuf,
+    /// Held across read-modify-write of `totp.json`.
+    totp_lock: Mutex<()>,
+    grants: Grants,
+    history: History,
+}
+
+impl FileStore {
+    pub fn new(data_dir: &Path) -> Self {
+        FileStore {
+            dir: data_dir.to_path_buf(),
+            credential: data_dir.join("credential.json"),
+            subscription: data_dir.join("push_subscription.json"),
+            totp: data_dir.join("totp.json"),
+            totp_lock: Mutex::new(()),
+            grants: Grants::new(data_dir),
+            history: History::new(data_dir),
+        }
+    }
+}
+
+impl FileStore {
+    fn save_totp(&self, creds: &[TotpCred]) -> Result<()> {
+        write_atomic(&self.totp, serde_json::to_string(creds)?.as_bytes())?;
+        // `totp remove` may run as root: hand the file back to the service
+        // user, as for grants.
+        if let Ok(meta) = fs::metadata(&self.dir) {
+            let _ = std::os::unix::fs::chown(&self.totp, Some(meta.uid()), Some(meta.gid()));
+        }
+        Ok(())
+    }
+}
+
+/// A JSON file's content; missing or unparsable reads as absent, as it
+/// always has (a torn credential means re-enrolling, not a dead verifier).
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+    let text = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+impl Store for FileStore {
+    fn passkey(&self) -> Result<Option<Passkey>> {
+        Ok(read_json(&self.credential))
+    }
+
+    fn save_passkey(&self, passkey: &Passkey) -> Result<()> {
+        write_atomic(&self.credential, serde_json::to_string(passkey)?.as_bytes())
+    }
+
+    fn subscription(&self) -> Result<Option<Value>> {
+        Ok(read_json(&self.subscription))
+    }
+
+    fn set_subscription(&self, subscription: Option<&Value>) -> Result<()> {
+        match subscription {
+            Some(sub) => write_atomic(&self.subscription, sub.to_string().as_bytes()),
+            None => match fs::remove_file(&self.subscription) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    fn totp_creds(&self) -> Result<Vec<TotpCred>> {
+        Ok(read_json(&self.totp).unwrap_or_default())
+    }
+
+    fn add_totp(&self, cred: &TotpCred) -> Result<()> {
+        let _lock = self.totp_lock.lock().unwrap();
+        let mut creds = self.totp_creds()?;
+        creds.push(cred.clone());
+        self.save_totp(&creds)
+    }
+
+    fn remove_totp(&self, id: &str) -> Result<bool> {
+        let _lock = self.totp_lock.lock().unwrap();
+        let mut creds = self.totp_creds()?;
+        let before = creds.len();
+        creds.retain(|c| c.id != id);
+        if creds.len() == before {
+            return Ok(false);
+        }
+        self.save_totp(&creds)?;
+        Ok(true)
+    }
+
+    fn use_totp(&self, id: &str, step: u64) -> Result<bool> {
+        let _lock = self.totp_lock.lock().unwrap();
+        let mut creds = self.totp_creds()?;
+        match creds.iter_mut().find(|c| c.id == id) {
+            Some(c) if c.last_step < step => c.last_step = step,
+            _ => return Ok(false),
+        }
+        self.save_totp(&creds)?;
+        Ok(true)
+    }
+
+    fn grants(&self, now: u64) -> Result<Vec<Grant>> {
+        Ok(self.grants.active(now))
+    }
+
+    fn add_grant(&self, grant: Grant) -> Result<()> {
+        self.grants.add(grant)
+    }
+
+    fn revoke_grants(&self, id: Option<&str>, now: u64) -> Result<usize> {
+        self.grants.revoke(id, now)
+    }
+
+    fn append_history(&self, record: &Record) -> Result<()> {
+        self.history.append(record)
+    }
+
+    fn recent_history(&self, n: usize) -> Result<Vec<Record>> {
+        Ok(self.history.recent(n))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    pub(crate) fn tmp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ag-store-{tag}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A real passkey, as `finish_passkey_registration` would produce.
+    pub(crate) fn passkey() -> Passkey {
+        serde_json::from_value(json!({"cred": {
+            "cred_id": "AAECAwQFBgcICQoLDA0ODw",
+            "cred": {"type_": "ES256", "key": {"EC_EC2": {
+                "curve": "SECP256R1",
+                "x": "1xSSgPeeoKnQeEf5hPFV3LddOPKrwJbYkOTDVBnMxjg",
+                "y": "pNfDqrcIcFVHx1zVAl94wL1oETiAAR0mcwEqSsmx0y4"
+            }}},
+            "counter": 0,
+            "transports": null,
+            "user_verified": true,
+            "backup_eligible": false,
+            "backup_state": false,
+            "registration_policy": "required",
+            "extensions": {},
+            "attestation": {"data": "None", "metadata": "None"},
+            "attestation_format": "none"
+        }}))
+        .unwrap()
+    }
+
+    pub(crate) fn totp(id: &str, label: &str) -> TotpCred {
+        TotpCred {
+            id: id.into(),
+            label: label.into(),
+            secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".into(),
+            created_at: 1000,
+            last_step: 0,
+        }
+    }
+
+    pub(crate) fn grant(id: &str, exe: &str, expires_at: u64) -> Grant {
+        Grant {
+            id: id.into(),
+            group: "login".into(),
+            exe: exe.into(),
+            credential: Some("Y3JlZA".into()),
+            client_ip: None,
+            created_at: 1000,
+            expires_at,
+        }
+    }
+
+    pub(crate) fn record(rid: &str, decided_at: u64) -> Record {
+        Record {
+            rid: rid.into(),
+            exe: "game-mode".into(),
+            path: "switch".into(),
+            group: "login".into(),
+            title: String::new(),
+            peer: None,
+            exe_mismatch: false,
+            outcome: "approved".into(),
+            credential: None,
+            client_ip: None,
+            reason: None,
+            created_at: decided_at - 5,
+            decided_at,
+        }
+    }
+
+    /// What every backend must do; run against each.
+    pub(crate) fn exercise(store: &dyn Store) {
+        assert!(store.passkey().unwrap().is_none());
+        let pk = passkey();
+        store.save_passkey(&pk).unwrap();
+        store.save_passkey(&pk).unwrap();
+        assert_eq!(store.passkey().unwrap().unwrap().cred_id(), pk.cred_id());
+
+        assert!(store.subscription().unwrap().is_none());
+        store.set_subscription(None).unwrap();
+        let sub = json!({"endpoint": "https://push.example/a", "keys": {"auth": "x"}});
+        store.set_subscription(Some(&sub)).unwrap();
+        let newer = json!({"endpoint": "https://push.example/b", "keys": {"auth": "y"}});
+        store.set_subscription(Some(&newer)).unwrap();
+        assert_eq!(store.subscription().unwrap(), Some(newer));
+        store.set_subscription(None).unwrap();
+        assert!(store.subscription().unwrap().is_none());
+
+        assert!(store.totp_creds().unwrap().is_empty());
+        store.add_totp(&totp("t1", "Kid")).unwrap();
+        store.add_totp(&totp("t2", "Gran")).unwrap();
+        assert!(store.use_totp("t1", 100).unwrap());
+        // Replayed (same or older step) or unknown: refused.
+        assert!(!store.use_totp("t1", 100).unwrap());
+        assert!(!store.use_totp("t1", 99).unwrap());
+        assert!(!store.use_totp("nope", 100).unwrap());
+        assert!(store.use_totp("t2", 100).unwrap());
+        assert!(store.use_totp("t1", 101).unwrap());
+        let creds = store.totp_creds().unwrap();
+        assert_eq!(
+            creds.iter().map(|c| c.last_step).collect::<Vec<_>>(),
+            [101, 100]
+        );
+        assert!(store.remove_totp("t1").unwrap());
+        assert!(!store.remove_totp("t1").unwrap());
+        assert_eq!(
+            store.totp_creds().unwrap(),
+            [TotpCred {
+                last_step: 100,
+                ..totp("t2", "Gran")
+            }]
+        );
+
+        store.add_grant(grant("a", "game-mode", 2000)).unwrap();
+        store.add_grant(grant("b", "steam", 1500)).unwrap();
+        let ids = |now| -> Vec<String> {
+            store
+                .grants(now)
+                .unwrap()
+                .into_iter()
+                .map(|g| g.id)
+                .collect()
+        };
+        assert_eq!(ids(1000), ["b", "a"]);
+        assert_eq!(ids(1500), ["a"]);
+        // Same scope: replaced.
+        store.add_grant(grant("c", "game-mode", 3000)).unwrap();
+        assert_eq!(ids(1000), ["b", "c"]);
+        let found = store.matching_grant("login", "game-mode", 2500).unwrap();
+        assert_eq!(found.unwrap().id, "c");
+        assert!(store
+            .matching_grant("sudo", "game-mode", 1000)
+            .unwrap()
+            .is_none());
+        assert_eq!(store.revoke_grants(Some("nope"), 1000).unwrap(), 0);
+        assert_eq!(store.revoke_grants(Some("c"), 1000).unwrap(), 1);
+        assert_eq!(store.revoke_grants(None, 1000).unwrap(), 1);
+        assert!(store.grants(1000).unwrap().is_empty());
+
+        assert!(store.recent_history(10).unwrap().is_empty());
+        for (i, rid) in ["r1", "r2", "r3"].iter().enumerate() {
+            store.append_history(&record(rid, 100 + i as u64)).unwrap();
+        }
+        let recent = store.recent_history(2).unwrap();
+        assert_eq!(recent, [record("r3", 102), record("r2", 101)]);
+    }
+
+    #[test]
+    fn file_backend() {
+        let dir = tmp_dir("file");
+        exercise(&FileStore::new(&dir));
+        // Whole-file replacements leave no temp files behind.
+        assert!(!dir.join("credential.tmp").exists());
+        assert!(!dir.join("push_subscription.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
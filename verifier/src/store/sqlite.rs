@@ -0,0 +1,416 @@
+//! SQLite backend: `verifier.db` in the data dir. Every update is one
+//! transaction, so a crash or a concurrent `grants revoke` never leaves a
+//! half-written state.
+//!
+//! The schema is versioned with `PRAGMA user_version`; `MIGRATIONS` run in
+//! order on open, each in its own transaction. The first one that creates
+//! the tables also imports what the file backend left in the data dir, so
+//! switching `storage.backend` keeps the passkey, subscription, grants and
+//! history (the files stay put, for switching back); TOTP authenticators
+//! came later and are imported by their own migration.
+
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde_json::Value;
+use webauthn_rs::prelude::Passkey;
+
+use super::{FileStore, Store};
+use crate::grants::Grant;
+use crate::history::Record;
+use crate::totp::TotpCred;
+
+/// Decisions kept; older ones are dropped on append.
+const MAX_HISTORY: i64 = 10_000;
+
+type Migration = fn(&Transaction, &Path) -> Result<()>;
+
+/// Schema versions 1.., in order. Append only: an installed database has
+/// already run the earlier ones.
+const MIGRATIONS: &[Migration] = &[create_tables, import_files, create_totp];
+
+fn create_tables(tx: &Transaction, _data_dir: &Path) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE credentials (
+             id TEXT PRIMARY KEY,
+             passkey TEXT NOT NULL
+         );
+         CREATE TABLE subscriptions (
+             endpoint TEXT PRIMARY KEY,
+             subscription TEXT NOT NULL
+         );
+         CREATE TABLE grants (
+             id TEXT PRIMARY KEY,
+             grp TEXT NOT NULL,
+             exe TEXT NOT NULL,
+             credential TEXT,
+             client_ip TEXT,
+             created_at INTEGER NOT NULL,
+             expires_at INTEGER NOT NULL
+         );
+         CREATE INDEX grants_scope ON grants (grp, exe);
+         CREATE TABLE history (
+             seq INTEGER PRIMARY KEY AUTOINCREMENT,
+             rid TEXT NOT NULL,
+             outcome TEXT NOT NULL,
+             decided_at INTEGER NOT NULL,
+             record TEXT NOT NULL
+         );",
+    )?;
+    Ok(())
+}
+
+fn import_files(tx: &Transaction, data_dir: &Path) -> Result<()> {
+    let files = FileStore::new(data_dir);
+    if let Some(passkey) = files.passkey()? {
+        put_passkey(tx, &passkey)?;
+    }
+    if let Some(sub) = files.subscription()? {
+        put_subscription(tx, Some(&sub))?;
+    }
+    for grant in files.grants(crate::journal::now_unix())? {
+        put_grant(tx, &grant)?;
+    }
+    let mut history = files.recent_history(MAX_HISTORY as usize)?;
+    history.reverse();
+    for record in &history {
+        put_record(tx, record)?;
+    }
+    Ok(())
+}
+
+fn create_totp(tx: &Transaction, data_dir: &Path) -> Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE totp (
+             seq INTEGER PRIMARY KEY AUTOINCREMENT,
+             id TEXT NOT NULL UNIQUE,
+             label TEXT NOT NULL,
+             secret TEXT NOT NULL,
+             created_at INTEGER NOT NULL,
+             last_step INTEGER NOT NULL
+         );",
+    )?;
+    for cred in FileStore::new(data_dir).totp_creds()? {
+        put_totp(tx, &cred)?;
+    }
+    Ok(())
+}
+
+fn put_totp(tx: &Transaction, c: &TotpCred) -> Result<()> {
+    tx.execute(
+        "INSERT INTO totp (id, label, secret, created_at, last_step)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            c.id,
+            c.label,
+            c.secret,
+            c.created_at as i64,
+            c.last_step as i64
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_passkey(tx: &Transaction, passkey: &Passkey) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO credentials (id, passkey) VALUES (?1, ?2)",
+        params![
+            URL_SAFE_NO_PAD.encode(passkey.cred_id()),
+            serde_json::to_string(passkey)?
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_subscription(tx: &Transaction, sub: Option<&Value>) -> Result<()> {
+    tx.execute("DELETE FROM subscriptions", [])?;
+    if let Some(sub) = sub {
+        tx.execute(
+            "INSERT INTO subscriptions (endpoint, subscription) VALUES (?1, ?2)",
+            params![
+                sub["endpoint"].as_str().unwrap_or_default(),
+                sub.to_string()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn put_grant(tx: &Transaction, g: &Grant) -> Result<()> {
+    tx.execute(
+        "DELETE FROM grants WHERE grp = ?1 AND exe = ?2",
+        params![g.group, g.exe],
+    )?;
+    tx.execute(
+        "INSERT INTO grants (id, grp, exe, credential, client_ip, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            g.id,
+            g.group,
+            g.exe,
+            g.credential,
+            g.client_ip,
+            g.created_at as i64,
+            g.expires_at as i64
+        ],
+    )?;
+    Ok(())
+}
+
+fn put_record(tx: &Transaction, r: &Record) -> Result<()> {
+    tx.execute(
+        "INSERT INTO history (rid, outcome, decided_at, record) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            r.rid,
+            r.outcome,
+            r.decided_at as i64,
+            serde_json::to_string(r)?
+        ],
+    )?;
+    Ok(())
+}
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join("verifier.db");
+        let mut conn = Connection::open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        // The `grants` CLI opens it alongside the running verifier.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        migrate(&mut conn, data_dir)?;
+        // Secrets inside; and if the CLI (as root) created it, hand it back
+        // to the service user.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        if let Ok(meta) = std::fs::metadata(data_dir) {
+            let _ = std::os::unix::fs::chown(&path, Some(meta.uid()), Some(meta.gid()));
+        }
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Run `f` in one transaction.
+    fn write<T>(&self, f: impl FnOnce(&Transaction) -> Result<T>) -> Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let out = f(&tx)?;
+        tx.commit()?;
+        Ok(out)
+    }
+}
+
+fn migrate(conn: &mut Connection, data_dir: &Path) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx, data_dir).with_context(|| format!("schema migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        tracing::info!("verifier.db: migrated to schema {}", i + 1);
+    }
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn passkey(&self) -> Result<Option<Passkey>> {
+        let conn = self.conn.lock().unwrap();
+        let text: Option<String> = conn
+            .query_row(
+                "SELECT passkey FROM credentials ORDER BY rowid LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(text.and_then(|t| serde_json::from_str(&t).ok()))
+    }
+
+    fn save_passkey(&self, passkey: &Passkey) -> Result<()> {
+        self.write(|tx| put_passkey(tx, passkey))
+    }
+
+    fn subscription(&self) -> Result<Option<Value>> {
+        let conn = self.conn.lock().unwrap();
+        let text: Option<String> = conn
+            .query_row(
+                "SELECT subscription FROM subscriptions LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(text.and_then(|t| serde_json::from_str(&t).ok()))
+    }
+
+    fn set_subscription(&self, subscription: Option<&Value>) -> Result<()> {
+        self.write(|tx| put_subscription(tx, subscription))
+    }
+
+    fn totp_creds(&self) -> Result<Vec<TotpCred>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT id, label, secret, created_at, last_step FROM totp ORDER BY seq")?;
+        let creds = stmt
+            .query_map([], |row| {
+                Ok(TotpCred {
+                    id: row.get(0)?,
+                    label: row.get(1)?,
+                    secret: row.get(2)?,
+                    created_at: row.get::<_, i64>(3)? as u64,
+                    last_step: row.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(creds)
+    }
+
+    fn add_totp(&self, cred: &TotpCred) -> Result<()> {
+        self.write(|tx| put_totp(tx, cred))
+    }
+
+    fn remove_totp(&self, id: &str) -> Result<bool> {
+        self.write(|tx| Ok(tx.execute("DELETE FROM totp WHERE id = ?1", [id])? > 0))
+    }
+
+    fn use_totp(&self, id: &str, step: u64) -> Result<bool> {
+        // One statement: two racing approvals cannot both advance it.
+        self.write(|tx| {
+            Ok(tx.execute(
+                "UPDATE totp SET last_step = ?2 WHERE id = ?1 AND last_step < ?2",
+                params![id, step as i64],
+            )? > 0)
+        })
+    }
+
+    fn grants(&self, now: u64) -> Result<Vec<Grant>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, grp, exe, credential, client_ip, created_at, expires_at
+             FROM grants WHERE expires_at > ?1 ORDER BY expires_at",
+        )?;
+        let grants = stmt
+            .query_map([now as i64], |row| {
+                Ok(Grant {
+                    id: row.get(0)?,
+                    group: row.get(1)?,
+                    exe: row.get(2)?,
+                    credential: row.get(3)?,
+                    client_ip: row.get(4)?,
+                    created_at: row.get::<_, i64>(5)? as u64,
+                    expires_at: row.get::<_, i64>(6)? as u64,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(grants)
+    }
+
+    fn add_grant(&self, grant: Grant) -> Result<()> {
+        self.write(|tx| {
+            tx.execute(
+                "DELETE FROM grants WHERE expires_at <= ?1",
+                [grant.created_at as i64],
+            )?;
+            put_grant(tx, &grant)
+        })
+    }
+
+    fn revoke_grants(&self, id: Option<&str>, now: u64) -> Result<usize> {
+        self.write(|tx| {
+            tx.execute("DELETE FROM grants WHERE expires_at <= ?1", [now as i64])?;
+            Ok(match id {
+                Some(id) => tx.execute("DELETE FROM grants WHERE id = ?1", [id])?,
+                None => tx.execute("DELETE FROM grants", [])?,
+            })
+        })
+    }
+
+    fn append_history(&self, record: &Record) -> Result<()> {
+        self.write(|tx| {
+            put_record(tx, record)?;
+            tx.execute(
+                "DELETE FROM history WHERE seq <= (SELECT MAX(seq) FROM history) - ?1",
+                [MAX_HISTORY],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn recent_history(&self, n: usize) -> Result<Vec<Record>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT record FROM history ORDER BY seq DESC LIMIT ?1")?;
+        let rows = stmt
+            .query_map([n.min(i64::MAX as usize) as i64], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        // Like the file backend: a record that no longer parses is skipped.
+        Ok(rows
+            .iter()
+            .filter_map(|t| serde_json::from_str(t).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{exercise, grant, passkey, record, tmp_dir, totp};
+    use serde_json::json;
+
+    #[test]
+    fn sqlite_backend() {
+        let dir = tmp_dir("sqlite");
+        exercise(&SqliteStore::open(&dir).unwrap());
+        let mode = std::fs::metadata(dir.join("verifier.db")).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imports_file_backend_once() {
+        let dir = tmp_dir("import");
+        let files = FileStore::new(&dir);
+        files.save_passkey(&passkey()).unwrap();
+        let sub = json!({"endpoint": "https://push.example/a", "keys": {}});
+        files.set_subscription(Some(&sub)).unwrap();
+        files.add_totp(&totp("t1", "Kid")).unwrap();
+        let now = crate::journal::now_unix();
+        files
+            .add_grant(grant("g1", "game-mode", now + 600))
+            .unwrap();
+        files.append_history(&record("r1", 100)).unwrap();
+        files.append_history(&record("r2", 101)).unwrap();
+
+        let db = SqliteStore::open(&dir).unwrap();
+        assert!(db.passkey().unwrap().is_some());
+        assert_eq!(db.subscription().unwrap(), Some(sub));
+        assert_eq!(db.totp_creds().unwrap(), [totp("t1", "Kid")]);
+        assert_eq!(db.grants(now).unwrap()[0].id, "g1");
+        assert_eq!(
+            db.recent_history(10).unwrap(),
+            [record("r2", 101), record("r1", 100)]
+        );
+        drop(db);
+
+        // Reopening runs nothing again: no duplicated history.
+        files.append_history(&record("r3", 102)).unwrap();
+        let db = SqliteStore::open(&dir).unwrap();
+        assert_eq!(db.recent_history(10).unwrap().len(), 2);
+        let version: usize = db
+            .conn
+            .lock()
+            .unwrap()
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
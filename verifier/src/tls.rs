@@ -0,0 +1,437 @@
+//! Built-in TLS for hosts without `tailscale serve` (LAN-only boxes with an
+//! internal CA). WebAuthn needs an HTTPS origin, so when `[tls]` is
+//! configured the verifier terminates TLS itself: a rustls listener in
+//! front of the unchanged plain-HTTP web plane, one pump thread per
+//! connection. The certificate is re-read on SIGHUP (`systemctl reload`)
+//! and must cover the configured origin's host — checked at startup and on
+//! every reload, so a renewal for the wrong name keeps the old certificate
+//! instead of breaking every ceremony.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection};
+use tracing::{info, warn};
+use url::{Host, Url};
+use x509_parser::extensions::GeneralName;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Both directions silent this long: drop the connection.
+const IDLE_TIMEOUT_MS: i32 = 5 * 60 * 1000;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Does a SAN dNSName `pattern` cover `host`? Case-insensitive; a leading
+/// `*.` wildcard matches exactly one label (RFC 6125).
+fn name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == host,
+    }
+}
+
+/// The origin's host must appear in the leaf certificate's SANs, or every
+/// browser would reject the page before WebAuthn ever ran.
+fn check_san(leaf: &CertificateDer<'_>, origin: &Url) -> Result<()> {
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf.as_ref())
+        .map_err(|e| anyhow!("certificate unparsable: {e}"))?;
+    let sans = cert
+        .subject_alternative_name()
+        .map_err(|e| anyhow!("certificate SAN extension unparsable: {e}"))?
+        .map(|ext| ext.value.general_names.clone())
+        .unwrap_or_default();
+    // `Cfg` only takes a domain origin: a WebAuthn RP ID can't be an IP.
+    let Some(Host::Domain(host)) = origin.host() else {
+        bail!("origin {origin} has no domain name");
+    };
+    let covered = sans
+        .iter()
+        .any(|n| matches!(n, GeneralName::DNSName(p) if name_matches(p, host)));
+    if !covered {
+        let names: Vec<String> = sans
+            .iter()
+            .map(|n| match n {
+                GeneralName::DNSName(d) => d.to_string(),
+                other => format!("{other:?}"),
+            })
+            .collect();
+        bail!(
+            "certificate SANs [{}] do not cover the origin {origin}",
+            names.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The serving certificate, swappable at runtime.
+pub struct CertStore {
+    cert: PathBuf,
+    key: PathBuf,
+    origin: Url,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl std::fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertStore")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl CertStore {
+    pub fn load(cert: &Path, key: &Path, origin: &Url) -> Result<Arc<Self>> {
+        let provider = provider();
+        let ck = read_certified_key(cert, key, origin, &provider)?;
+        info!("TLS certificate loaded from {}", cert.display());
+        Ok(Arc::new(CertStore {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            origin: origin.clone(),
+            provider,
+            current: RwLock::new(Arc::new(ck)),
+        }))
+    }
+
+    /// Re-read certificate and key; on any error the old pair stays live.
+    pub fn reload(&self) -> Result<()> {
+        let ck = read_certified_key(&self.cert, &self.key, &self.origin, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(ck);
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> Result<Arc<ServerConfig>> {
+        let mut cfg = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .context("TLS protocol versions")?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(cfg))
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, _hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn read_certified_key(
+    cert: &Path,
+    key: &Path,
+    origin: &Url,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey> {
+    let pem = fs::read(cert).with_context(|| format!("read {}", cert.display()))?;
+    let chain = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("{}: {e}", cert.display()))?;
+    let Some(leaf) = chain.first() else {
+        bail!("{}: no certificate found", cert.display());
+    };
+    check_san(leaf, origin).with_context(|| cert.display().to_string())?;
+    let key_der =
+        PrivateKeyDer::from_pem_file(key).map_err(|e| anyhow!("{}: {e}", key.display()))?;
+    CertifiedKey::from_der(chain, key_der, provider)
+        .map_err(|e| anyhow!("{} / {}: {e}", cert.display(), key.display()))
+}
+
+/// Real client address of each proxied connection, keyed by the local end
+/// of its backend connection (what the web plane sees as the remote).
+#[derive(Clone, Default)]
+pub struct Peers(Arc<Mutex<HashMap<SocketAddr, IpAddr>>>);
+
+impl Peers {
+    pub fn lookup(&self, backend_remote: SocketAddr) -> Option<IpAddr> {
+        self.0.lock().unwrap().get(&backend_remote).copied()
+    }
+}
+
+/// Removes a connection's `Peers` entry when its pump ends.
+struct PeerEntry(Peers, SocketAddr);
+
+impl Drop for PeerEntry {
+    fn drop(&mut self) {
+        self.0 .0.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Accept TLS connections and pump each one to the plain web plane at
+/// `backend`. Never returns.
+pub fn serve(listener: TcpListener, config: Arc<ServerConfig>, backend: SocketAddr, peers: Peers) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(tcp) => {
+                let config = config.clone();
+                let peers = peers.clone();
+                thread::spawn(move || {
+                    let peer = tcp.peer_addr().ok();
+                    if let Err(e) = pump(tcp, config, backend, peers) {
+                        tracing::debug!("tls connection {peer:?}: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("tls accept error: {e}"),
+        }
+    }
+}
+
+fn poll_in(fds: &mut [libc::pollfd], timeout_ms: i32) -> std::io::Result<i32> {
+    loop {
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if n >= 0 {
+            return Ok(n);
+        }
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Shuttle bytes between one TLS client and a fresh backend connection.
+/// Single thread per connection: poll(2) on both sockets, so the rustls
+/// state is never shared across threads.
+fn pump(
+    mut tcp: TcpStream,
+    config: Arc<ServerConfig>,
+    backend: SocketAddr,
+    peers: Peers,
+) -> Result<()> {
+    let mut conn = ServerConnection::new(config)?;
+    // Handshake before touching the backend, so a scanner never opens a
+    // backend connection.
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut tcp)?;
+    }
+    tcp.set_read_timeout(None)?;
+
+    let mut up = TcpStream::connect(backend)?;
+    let _entry = match (up.local_addr(), tcp.peer_addr()) {
+        (Ok(local), Ok(client)) => {
+            peers.0.lock().unwrap().insert(local, client.ip());
+            Some(PeerEntry(peers, local))
+        }
+        _ => None,
+    };
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut client_open = true;
+    loop {
+        while conn.wants_write() {
+            conn.write_tls(&mut tcp)?;
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: tcp.as_raw_fd(),
+                events: if client_open { libc::POLLIN } else { 0 },
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: up.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if poll_in(&mut fds, IDLE_TIMEOUT_MS)? == 0 {
+            return Ok(()); // idle
+        }
+        let ready = libc::POLLIN | libc::POLLHUP | libc::POLLERR;
+
+        if client_open && fds[0].revents & ready != 0 {
+            if conn.read_tls(&mut tcp)? == 0 {
+                return Ok(()); // client vanished without close_notify
+            }
+            let state = match conn.process_new_packets() {
+                Ok(state) => state,
+                Err(e) => {
+                    let _ = conn.write_tls(&mut tcp); // the alert
+                    return Err(e.into());
+                }
+            };
+            let n = state.plaintext_bytes_to_read();
+            if n > 0 {
+                let mut plain = vec![0u8; n];
+                conn.reader().read_exact(&mut plain)?;
+                up.write_all(&plain)?;
+            }
+            if state.peer_has_closed() {
+                // Half-close: the backend may still be answering.
+                client_open = false;
+                let _ = up.shutdown(Shutdown::Write);
+            }
+        }
+
+        if fds[1].revents & ready != 0 {
+            let n = up.read(&mut buf)?;
+            if n == 0 {
+                conn.send_close_notify();
+                while conn.wants_write() {
+                    conn.write_tls(&mut tcp)?;
+                }
+                return Ok(());
+            }
+            conn.writer().write_all(&buf[..n])?;
+        }
+    }
+}
+
+/// Block SIGHUP in the calling thread and every thread spawned after it, so
+/// the reload thread's sigwait(2) is the only receiver (its default action
+/// would otherwise terminate the verifier). Call before spawning threads.
+pub fn block_sighup() {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+/// Reload the certificate on every SIGHUP (requires `block_sighup` first).
+pub fn reload_on_sighup(store: Arc<CertStore>) {
+    thread::spawn(move || loop {
+        let mut sig = 0;
+        let rc = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGHUP);
+            libc::sigwait(&set, &mut sig)
+        };
+        if rc != 0 || sig != libc::SIGHUP {
+            continue;
+        }
+        match store.reload() {
+            Ok(()) => info!("SIGHUP: TLS certificate reloaded"),
+            Err(e) => warn!("SIGHUP: TLS reload failed, keeping the old certificate: {e:#}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use tiny_http::{Response, Server};
+
+    /// Self-signed certificate for `names`, written as cert.pem/key.pem.
+    fn write_cert(dir: &Path, names: &[&str]) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let names: Vec<String> = names.iter().map(|s| s.to_string()).collect();
+        let ck = rcgen::generate_simple_self_signed(names).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, ck.cert.pem()).unwrap();
+        fs::write(&key, ck.key_pair.serialize_pem()).unwrap();
+        (cert, key, ck.cert.der().clone())
+    }
+
+    fn origin(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn wildcard_covers_exactly_one_label() {
+        assert!(name_matches("box.lan", "BOX.lan"));
+        assert!(name_matches("*.home.lan", "gaming.home.lan"));
+        assert!(!name_matches("*.home.lan", "home.lan"));
+        assert!(!name_matches("*.home.lan", "a.gaming.home.lan"));
+        assert!(!name_matches("box.lan", "evil-box.lan"));
+    }
+
+    #[test]
+    fn origin_must_be_in_sans() {
+        let dir = tmp_dir("tls-san");
+        let (cert, key, _) = write_cert(&dir, &["box.lan", "192.168.1.20"]);
+        assert!(CertStore::load(&cert, &key, &origin("https://box.lan:8443")).is_ok());
+        // An IP SAN is no use: the origin is never an address.
+        assert!(CertStore::load(&cert, &key, &origin("https://192.168.1.20")).is_err());
+        let err = CertStore::load(&cert, &key, &origin("https://other.lan")).unwrap_err();
+        assert!(format!("{err:#}").contains("do not cover"), "{err:#}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reload_with_wrong_name_keeps_old_certificate() {
+        let dir = tmp_dir("tls-badreload");
+        let (cert, key, first) = write_cert(&dir, &["box.lan"]);
+        let store = CertStore::load(&cert, &key, &origin("https://box.lan")).unwrap();
+        write_cert(&dir, &["elsewhere.lan"]);
+        assert!(store.reload().is_err());
+        assert_eq!(store.current.read().unwrap().cert[0], first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// GET / through the terminator; returns the body and the certificate
+    /// the server presented.
+    fn get(addr: SocketAddr, trust: &CertificateDer<'static>) -> (String, CertificateDer<'static>) {
+        let mut roots = RootCertStore::empty();
+        roots.add(trust.clone()).unwrap();
+        let cfg = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn =
+            ClientConnection::new(Arc::new(cfg), ServerName::try_from("box.lan").unwrap()).unwrap();
+        let mut tls = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        tls.write_all(b"GET / HTTP/1.1\r\nHost: box.lan\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut out = String::new();
+        let _ = tls.read_to_string(&mut out);
+        let presented = tls.conn.peer_certificates().unwrap()[0].clone();
+        (out, presented)
+    }
+
+    #[test]
+    fn terminates_tls_and_reloads() {
+        let backend = Server::http("127.0.0.1:0").unwrap();
+        let backend_addr = backend.server_addr().to_ip().unwrap();
+        thread::spawn(move || {
+            for req in backend.incoming_requests() {
+                let _ = req.respond(Response::from_string("hello from the web plane"));
+            }
+        });
+
+        let dir = tmp_dir("tls-e2e");
+        let (cert, key, first) = write_cert(&dir, &["box.lan"]);
+        let store = CertStore::load(&cert, &key, &origin("https://box.lan")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = store.server_config().unwrap();
+        thread::spawn(move || serve(listener, config, backend_addr, Peers::default()));
+
+        let (body, presented) = get(addr, &first);
+        assert!(body.contains("hello from the web plane"), "{body}");
+        assert_eq!(presented, first);
+
+        let (_, _, second) = write_cert(&dir, &["box.lan"]);
+        store.reload().unwrap();
+        let (body, presented) = get(addr, &second);
+        assert!(body.contains("hello from the web plane"), "{body}");
+        assert_eq!(presented, second);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Single-use enrollment tokens. `access-gate-verifier enroll-token`
+//! (run on the desktop) mints one, prints it as a terminal QR code of the
+//! page's URL, and leaves only its hash in the data dir; the page and every
+//! call behind it need the token, and finishing the enrollment spends it.
+//! Whoever else is on the tailnet during those minutes gets nowhere without
+//! the screen in front of them. A VAPID rotation hands its token to the
+//! service worker inside the (encrypted) re-subscribe push instead.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::journal::write_atomic;
+
+/// How long a minted enrollment token stays usable.
+pub const TTL: u64 = 10 * 60;
+/// How long the phone has to answer a re-subscribe push; it may be off.
+pub const RESUBSCRIBE_TTL: u64 = 7 * 24 * 3600;
+
+/// What a token opens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purpose {
+    /// `/enroll`: the passkey (only while none is enrolled).
+    Passkey,
+    /// `/setup`: the Web Push subscription.
+    Push,
+    /// `/totp`: one authenticator app.
+    Totp,
+    /// `/push/resubscribe`: the subscription under the next VAPID key.
+    Resubscribe,
+}
+
+impl Purpose {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "passkey" => Some(Purpose::Passkey),
+            "push" => Some(Purpose::Push),
+            "totp" => Some(Purpose::Totp),
+            _ => None,
+        }
+    }
+
+    /// The page the token is for.
+    pub fn page(self) -> &'static str {
+        match self {
+            Purpose::Passkey => "/enroll",
+            Purpose::Push => "/setup",
+            Purpose::Totp => "/totp",
+            Purpose::Resubscribe => "/push/resubscribe",
+        }
+    }
+
+    pub fn ttl(self) -> u64 {
+        match self {
+            Purpose::Resubscribe => RESUBSCRIBE_TTL,
+            _ => TTL,
+        }
+    }
+
+    fn path(self, data_dir: &Path) -> PathBuf {
+        data_dir.join(match self {
+            Purpose::Passkey => "enroll-token.json",
+            Purpose::Push => "push-token.json",
+            Purpose::Totp => "totp-token.json",
+            Purpose::Resubscribe => "resubscribe-token.json",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    /// SHA-256 of the token, hex.
+    hash: String,
+    expires_at: u64,
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// A fresh token for `purpose`, replacing any earlier one.
+pub fn mint(data_dir: &Path, purpose: Purpose, now: u64) -> Result<String> {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    let stored = Stored {
+        hash: hash(&token),
+        expires_at: now + purpose.ttl(),
+    };
+    let path = purpose.path(data_dir);
+    write_atomic(&path, serde_json::to_string(&stored)?.as_bytes())?;
+    // Minted as root: hand the file to the service user, as for grants.
+    if let Ok(meta) = fs::metadata(data_dir) {
+        let _ = std::os::unix::fs::chown(&path, Some(meta.uid()), Some(meta.gid()));
+    }
+    Ok(token)
+}
+
+/// Is `token` the live one for `purpose`?
+pub fn check(data_dir: &Path, purpose: Purpose, token: Option<&str>, now: u64) -> bool {
+    let Some(token) = token.filter(|t| !t.is_empty()) else {
+        return false;
+    };
+    let Some(stored) = fs::read_to_string(purpose.path(data_dir))
+        .ok()
+        .and_then(|text| serde_json::from_str::<Stored>(&text).ok())
+    else {
+        return false;
+    };
+    now < stored.expires_at && hash(token) == stored.hash
+}
+
+/// Spend `purpose`'s token once its enrollment went through.
+pub fn consume(data_dir: &Path, purpose: Purpose) {
+    let _ = fs::remove_file(purpose.path(data_dir));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+
+    #[test]
+    fn tokens_are_single_use_and_short_lived() {
+        let dir = tmp_dir("token");
+        let t0 = 1_000_000;
+        let token = mint(&dir, Purpose::Passkey, t0).unwrap();
+        assert!(check(&dir, Purpose::Passkey, Some(&token), t0));
+        assert!(!check(&dir, Purpose::Passkey, Some("nope"), t0));
+        assert!(!check(&dir, Purpose::Passkey, None, t0));
+        assert!(!check(&dir, Purpose::Push, Some(&token), t0));
+        assert!(!check(&dir, Purpose::Passkey, Some(&token), t0 + TTL));
+
+        // A new token replaces the old one.
+        let newer = mint(&dir, Purpose::Passkey, t0).unwrap();
+        assert!(!check(&dir, Purpose::Passkey, Some(&token), t0));
+        assert!(check(&dir, Purpose::Passkey, Some(&newer), t0));
+        consume(&dir, Purpose::Passkey);
+        assert!(!check(&dir, Purpose::Passkey, Some(&newer), t0));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
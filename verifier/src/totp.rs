@@ -0,0 +1,214 @@
+//! TOTP (RFC 6238) as a second way to approve, for household members
+//! without a passkey-capable phone: any authenticator app enrolls from a QR
+//! code on `/totp` (opened by `enroll-token totp`, see `token`), and the
+//! approve page takes a 6-digit code instead of the passkey for the groups
+//! in `policy.totp_groups`.
+//!
+//! A code is accepted one step (30 s) either side of now, to cover clock
+//! drift on the phone. Each enrolled secret remembers the last step it
+//! approved with, and only a later step is accepted, so a code seen over a
+//! shoulder cannot be used again.
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Seconds per code.
+pub const STEP: u64 = 30;
+/// Steps accepted either side of now.
+pub const DRIFT: u64 = 1;
+pub const DIGITS: u32 = 6;
+
+/// An enrolled authenticator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TotpCred {
+    pub id: String,
+    /// Whose it is, as typed when enrolling.
+    pub label: String,
+    /// Base32, as the authenticator app shows it.
+    pub secret: String,
+    pub created_at: u64,
+    /// The last step a code was accepted for; 0 before the first.
+    #[serde(default)]
+    pub last_step: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// A fresh 160-bit secret (RFC 4226's recommended length for SHA-1).
+pub fn new_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// HOTP (RFC 4226) over `alg`: dynamic truncation, `digits` decimal digits.
+pub fn hotp(alg: Algorithm, key: &[u8], counter: u64, digits: u32) -> u32 {
+    fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], msg: &[u8]) -> Vec<u8> {
+        let mut m =
+            <M as hmac::digest::KeyInit>::new_from_slice(key).expect("HMAC takes any key length");
+        m.update(msg);
+        m.finalize().into_bytes().to_vec()
+    }
+    let msg = counter.to_be_bytes();
+    let hash = match alg {
+        Algorithm::Sha1 => mac::<Hmac<sha1::Sha1>>(key, &msg),
+        Algorithm::Sha256 => mac::<Hmac<sha2::Sha256>>(key, &msg),
+        Algorithm::Sha512 => mac::<Hmac<sha2::Sha512>>(key, &msg),
+    };
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let bin = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    bin % 10u32.pow(digits)
+}
+
+/// The step a `code` for `secret` belongs to, looking `DRIFT` steps either
+/// side of `now`; the caller still has to check it was not used before.
+pub fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let now_step = now / STEP;
+    (now_step.saturating_sub(DRIFT)..=now_step + DRIFT).find(|&step| {
+        let expected = format!(
+            "{:0width$}",
+            hotp(Algorithm::Sha1, secret, step, DIGITS),
+            width = DIGITS as usize
+        );
+        // Every byte compared, match or not.
+        expected
+            .bytes()
+            .zip(code.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    })
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, as authenticator apps take it.
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for &b in bytes {
+        buf = (buf << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buf >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buf << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buf, mut bits) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let v = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buf = (buf << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// The `otpauth://` URI authenticator apps scan.
+pub fn otpauth_uri(issuer: &str, account: &str, secret_b32: &str) -> String {
+    let enc = |s: &str| -> String {
+        s.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{b:02X}"),
+            })
+            .collect()
+    };
+    format!(
+        "otpauth://totp/{}:{}?secret={secret_b32}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+        enc(issuer),
+        enc(account),
+        enc(issuer)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 appendix B: the seed is the ASCII digits repeated to the
+    /// hash's length, codes are 8 digits.
+    #[test]
+    fn rfc_6238_vectors() {
+        let sha1 = b"12345678901234567890".as_slice();
+        let sha256 = b"12345678901234567890123456789012".as_slice();
+        let sha512 = b"1234567890123456789012345678901234567890123456789012345678901234".as_slice();
+        let vectors: [(u64, u32, u32, u32); 6] = [
+            (59, 94287082, 46119246, 90693936),
+            (1111111109, 7081804, 68084774, 25091201),
+            (1111111111, 14050471, 67062674, 99943326),
+            (1234567890, 89005924, 91819424, 93441116),
+            (2000000000, 69279037, 90698825, 38618901),
+            (20000000000, 65353130, 77737706, 47863826),
+        ];
+        for (time, c1, c256, c512) in vectors {
+            let step = time / STEP;
+            assert_eq!(hotp(Algorithm::Sha1, sha1, step, 8), c1, "SHA1 at {time}");
+            assert_eq!(
+                hotp(Algorithm::Sha256, sha256, step, 8),
+                c256,
+                "SHA256 at {time}"
+            );
+            assert_eq!(
+                hotp(Algorithm::Sha512, sha512, step, 8),
+                c512,
+                "SHA512 at {time}"
+            );
+        }
+    }
+
+    #[test]
+    fn drift_window() {
+        let secret = b"12345678901234567890";
+        // 6-digit SHA-1 code for T = 59 (step 1).
+        let code = format!("{:06}", hotp(Algorithm::Sha1, secret, 1, 6));
+        assert_eq!(code, "287082");
+        assert_eq!(matching_step(secret, &code, 59), Some(1));
+        assert_eq!(matching_step(secret, &code, 30 + 59), Some(1));
+        assert_eq!(matching_step(secret, &code, 0), Some(1));
+        assert_eq!(matching_step(secret, &code, 60 + 59), None);
+        assert_eq!(matching_step(secret, " 287082\n", 59), Some(1));
+        assert_eq!(matching_step(secret, "28708", 59), None);
+        assert_eq!(matching_step(secret, "28708x", 59), None);
+    }
+
+    #[test]
+    fn base32_and_uri() {
+        // RFC 4648 section 10.
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+        assert_eq!(base32_decode("MZXW6YTBOI======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert!(base32_decode("MZXW1").is_none());
+        let secret = new_secret();
+        assert_eq!(base32_decode(&base32_encode(&secret)).unwrap(), secret);
+
+        assert_eq!(
+            otpauth_uri("access gate", "Kid's phone", "MZXW6YTBOI"),
+            "otpauth://totp/access%20gate:Kid%27s%20phone?secret=MZXW6YTBOI\
+             &issuer=access%20gate&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! The VAPID key that signs Web Push requests, and its rotation.
+//!
+//! A push subscription is bound to the public key it was made with, so a
+//! new key needs a new subscription. `access-gate-verifier vapid rotate`
+//! makes the next key (`vapid_next.pem`) and, signed with the current one,
+//! pushes a re-subscribe message with the next public key and a single-use
+//! link (see `token`). The service worker subscribes again under the next
+//! key and posts the new subscription to the link; only then does the next
+//! key replace the current one. Until the phone answers, pushes go out
+//! under the current key as before. Without a subscription there is nothing
+//! to carry over, and the next key takes over at once.
+//!
+//! The key files are re-read on use, so a rotation done from the command
+//! line reaches the running verifier.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::pkcs8::DecodePrivateKey;
+use p256::SecretKey;
+use tracing::{info, warn};
+
+use crate::journal::write_atomic;
+use crate::store::Store;
+
+const CURRENT: &str = "vapid_private.pem";
+const NEXT: &str = "vapid_next.pem";
+
+pub struct Vapid {
+    dir: PathBuf,
+    /// The last current key read, for when the file can't be.
+    cached: Mutex<SecretKey>,
+}
+
+fn parse_pem(pem: &str) -> Option<SecretKey> {
+    SecretKey::from_sec1_pem(pem)
+        .ok()
+        .or_else(|| SecretKey::from_pkcs8_pem(pem).ok())
+}
+
+fn read_key(path: &Path) -> Option<SecretKey> {
+    parse_pem(&fs::read_to_string(path).ok()?)
+}
+
+fn generate(path: &Path) -> Result<SecretKey> {
+    let key = SecretKey::random(&mut OsRng);
+    let pem = key
+        .to_sec1_pem(Default::default())
+        .map_err(|e| anyhow!("PEM encode: {e}"))?;
+    write_atomic(path, pem.as_bytes())?;
+    Ok(key)
+}
+
+/// Uncompressed SEC1 point, base64url: the browser's applicationServerKey.
+pub fn public_b64u(key: &SecretKey) -> String {
+    let point = key.public_key().to_encoded_point(false);
+    URL_SAFE_NO_PAD.encode(point.as_bytes())
+}
+
+impl Vapid {
+    /// Load the current key, generating a fresh one when missing or
+    /// unparsable. A fresh key invalidates any existing subscription (the
+    /// browser's applicationServerKey no longer matches), so the
+    /// subscription is dropped.
+    pub fn load_or_generate(data_dir: &Path, store: &dyn Store) -> Result<Self> {
+        let path = data_dir.join(CURRENT);
+        let key = match fs::read_to_string(&path).ok().map(|pem| parse_pem(&pem)) {
+            Some(Some(key)) => key,
+            unreadable => {
+                if unreadable.is_some() {
+                    warn!("existing VAPID key unparsable; generating a new one (push subscription dropped)");
+                    store.set_subscription(None)?;
+                }
+                let key = generate(&path)?;
+                info!("generated new VAPID key");
+                key
+            }
+        };
+        Ok(Self::with_key(data_dir, key))
+    }
+
+    /// `key` as the current key until the data dir says otherwise.
+    pub fn with_key(data_dir: &Path, key: SecretKey) -> Self {
+        Vapid {
+            dir: data_dir.to_path_buf(),
+            cached: Mutex::new(key),
+        }
+    }
+
+    pub fn current(&self) -> SecretKey {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(key) = read_key(&self.dir.join(CURRENT)) {
+            *cached = key;
+        }
+        cached.clone()
+    }
+
+    pub fn public_b64u(&self) -> String {
+        public_b64u(&self.current())
+    }
+
+    /// The key a rotation under way moves to, if any.
+    pub fn next(&self) -> Option<SecretKey> {
+        read_key(&self.dir.join(NEXT))
+    }
+
+    /// The next key, made now unless a rotation is already under way.
+    pub fn next_or_generate(&self) -> Result<SecretKey> {
+        if let Some(key) = self.next() {
+            return Ok(key);
+        }
+        let key = generate(&self.dir.join(NEXT))?;
+        info!("generated next VAPID key");
+        Ok(key)
+    }
+
+    /// Retire the current key for the next one; nothing to do without one.
+    pub fn promote(&self) -> Result<()> {
+        let Some(key) = self.next() else {
+            return Ok(());
+        };
+        fs::rename(self.dir.join(NEXT), self.dir.join(CURRENT))
+            .context("failed to replace the VAPID key")?;
+        *self.cached.lock().unwrap() = key;
+        info!("VAPID key rotated");
+        Ok(())
+    }
+
+    /// Seconds since the current key was made.
+    pub fn age(&self, now: SystemTime) -> Option<u64> {
+        let made = fs::metadata(self.dir.join(CURRENT)).ok()?.modified().ok()?;
+        Some(now.duration_since(made).unwrap_or_default().as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::tmp_dir;
+    use crate::store::FileStore;
+    use serde_json::json;
+
+    #[test]
+    fn rotation_keeps_the_current_key_until_promoted() {
+        let dir = tmp_dir("vapid");
+        let store = FileStore::new(&dir);
+        let vapid = Vapid::load_or_generate(&dir, &store).unwrap();
+        let first = vapid.public_b64u();
+        assert!(vapid.age(SystemTime::now()).unwrap() < 60);
+        assert!(vapid.next().is_none());
+        vapid.promote().unwrap();
+        assert_eq!(vapid.public_b64u(), first);
+
+        let next = public_b64u(&vapid.next_or_generate().unwrap());
+        assert_ne!(next, first);
+        assert_eq!(public_b64u(&vapid.next_or_generate().unwrap()), next);
+        assert_eq!(vapid.public_b64u(), first);
+        // A second process (the verifier) sees the same state.
+        let other = Vapid::load_or_generate(&dir, &store).unwrap();
+        assert_eq!(other.public_b64u(), first);
+
+        vapid.promote().unwrap();
+        assert_eq!(vapid.public_b64u(), next);
+        assert_eq!(other.public_b64u(), next);
+        assert!(vapid.next().is_none());
+
+        // A broken key is replaced, and the subscription with it.
+        store
+            .set_subscription(Some(&json!({"endpoint": "https://push.example/x"})))
+            .unwrap();
+        fs::write(dir.join(CURRENT), "garbage").unwrap();
+        let fresh = Vapid::load_or_generate(&dir, &store).unwrap();
+        assert_ne!(fresh.public_b64u(), next);
+        assert!(store.subscription().unwrap().is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
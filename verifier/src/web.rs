@@ -0,0 +1,865 @@
+//! Web plane: enrollment (passkey and TOTP), push setup, the approve
+//! ceremony, `/history` and `/metrics`, all over plain HTTP behind a TLS
+//! terminator (`tailscale serve` or the built-in `[tls]` listener).
+//!
+//! ```text
+//! /                      status JSON
+//! /enroll, /enroll/*     one-time passkey registration (token-gated)
+//! /setup, /sw.js, /push/subscribe
+//!                        Web Push subscription (token-gated)
+//! /push/resubscribe      the subscription under a rotated VAPID key
+//! /approve/<id>, ...     assertion ceremony deciding a request
+//! /approve/<id>/events   live status for the page (Server-Sent Events)
+//! /approve/<id>/totp     a TOTP code instead of the passkey (policy)
+//! /totp, /totp/verify    TOTP enrollment by QR code (token-gated)
+//! /history, /history/*   passkey-protected log of decisions + pending
+//!                        requests (deny from there)
+//! /metrics               Prometheus text format (or only on
+//!                        listen.metrics, when set)
+//! ```
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response, Server};
+use tracing::{info, warn};
+use uuid::Uuid;
+use webauthn_rs::prelude::*;
+
+use crate::app::{new_request_id, App, ApprovalRequest, Session, ENROLL_CEREMONY_SECS};
+use crate::context;
+use crate::grants::Grant;
+use crate::history::{self, Peer};
+use crate::journal::now_unix;
+use crate::pages::{html_escape, script_json, Page, SW_JS};
+use crate::qr;
+use crate::token::{self, Purpose};
+use crate::totp::{self, TotpCred};
+
+/// How long a `/history` sign-in lasts.
+const SESSION_SECS: u64 = 600;
+const SESSION_COOKIE: &str = "ag_history";
+
+fn header(k: &str, v: &str) -> Header {
+    Header::from_bytes(k.as_bytes(), v.as_bytes()).unwrap()
+}
+
+fn respond_json(req: tiny_http::Request, status: u16, v: Value) {
+    let resp = Response::from_string(v.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"));
+    let _ = req.respond(resp);
+}
+
+/// The language to render pages in for `req`.
+fn page_lang<'a>(app: &'a App, req: &tiny_http::Request) -> &'a str {
+    app.pages.negotiate(
+        req.headers()
+            .iter()
+            .find(|h| h.field.equiv("Accept-Language"))
+            .map(|h| h.value.as_str()),
+    )
+}
+
+/// `page` in the phone's language; `vars` are already escaped.
+fn respond_page(req: tiny_http::Request, app: &App, page: Page, vars: &[(&str, &str)]) {
+    let body = app.pages.render(page, page_lang(app, &req), vars);
+    let resp =
+        Response::from_string(body).with_header(header("Content-Type", "text/html; charset=utf-8"));
+    let _ = req.respond(resp);
+}
+
+fn respond_text(req: tiny_http::Request, status: u16, body: &str) {
+    let resp = Response::from_string(body).with_status_code(status);
+    let _ = req.respond(resp);
+}
+
+/// The optional `{"reason":..}` body of a deny, cleaned for the VT.
+fn deny_reason(req: &mut tiny_http::Request) -> Option<String> {
+    let body: Value = serde_json::from_str(&read_body(req)).ok()?;
+    history::clean_reason(body["reason"].as_str()?)
+}
+
+fn query_param<'a>(url: &'a str, key: &str) -> Option<&'a str> {
+    url.split_once('?')?
+        .1
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn describe_peer(app: &App, lang: &str, r: &ApprovalRequest) -> String {
+    r.peer.as_ref().map_or_else(
+        || app.pages.message(lang, "peer_unknown").into(),
+        Peer::describe,
+    )
+}
+
+/// The warning shown above the buttons when the claimed process isn't the
+/// binary it names.
+fn exe_flag(app: &App, lang: &str, r: &ApprovalRequest) -> String {
+    if !r.exe_mismatch {
+        return String::new();
+    }
+    format!(
+        "<p style=\"color:#b00020\"><b>{}</b> {}</p>",
+        html_escape(app.pages.message(lang, "exe_warning_label")),
+        html_escape(app.pages.message(lang, "exe_warning"))
+            .replace("{exe}", &format!("<code>{}</code>", html_escape(&r.exe)))
+    )
+}
+
+/// Where the request says it comes from, one labelled line per field.
+fn context_block(app: &App, lang: &str, r: &ApprovalRequest) -> String {
+    let Some(ctx) = &r.context else {
+        return String::new();
+    };
+    let ago = |secs| {
+        let (unit, n) = context::span(secs);
+        let span = app.pages.message(lang, unit).replace("{n}", &n.to_string());
+        app.pages.message(lang, "ago").replace("{span}", &span)
+    };
+    let lines: Vec<String> = ctx
+        .rows(ago)
+        .into_iter()
+        .map(|(label, value)| {
+            format!(
+                "<b>{}</b> {}",
+                html_escape(app.pages.message(lang, label)),
+                html_escape(&value)
+            )
+        })
+        .collect();
+    format!("<p>{}</p>", lines.join("<br>"))
+}
+
+fn read_body(req: &mut tiny_http::Request) -> String {
+    let mut body = String::new();
+    let _ = req.as_reader().take(1 << 20).read_to_string(&mut body);
+    body
+}
+
+/// How often an idle event stream sends a comment, so a phone that left is
+/// noticed (the write fails) and proxies don't time the stream out.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// `/approve/<rid>/events`: a `data:` line with the status and seconds left
+/// on every status change, until the request is decided. Written to the raw
+/// connection with our own chunked framing: tiny_http buffers streamed
+/// bodies, and the final zero-length chunk ends the response cleanly.
+fn stream_events(req: tiny_http::Request, app: &App, rid: &str) {
+    if !app.requests.lock().unwrap().contains_key(rid) {
+        respond_text(req, 404, "");
+        return;
+    }
+    let mut out = req.into_writer();
+    let head = "HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\n\
+                Transfer-Encoding: chunked\r\n\r\n";
+    if out.write_all(head.as_bytes()).is_err() {
+        return;
+    }
+    // One chunk per event; the empty chunk is the terminator.
+    let mut send = move |chunk: &str| {
+        write!(out, "{:x}\r\n{chunk}\r\n", chunk.len())
+            .and_then(|()| out.flush())
+            .is_ok()
+    };
+    let mut last: Option<String> = None;
+    loop {
+        let requests = app.requests.lock().unwrap();
+        let (status, wait_until, reason) = requests
+            .get(rid)
+            .map_or(("unknown".to_string(), 0, None), |r| {
+                (r.status.clone(), r.wait_until, r.reason.clone())
+            });
+        let remaining = wait_until.saturating_sub(now_unix());
+        if last.as_deref() == Some(status.as_str()) {
+            // Checked and waited under one lock: no decision slips between.
+            // Expiry wakes us too (see `expiry`).
+            let (requests, waited) = app.decided.wait_timeout(requests, SSE_KEEPALIVE).unwrap();
+            drop(requests);
+            if waited.timed_out() && !send(": keepalive\n\n") {
+                return;
+            }
+            continue;
+        }
+        drop(requests);
+        let mut event = json!({ "status": status, "remaining": remaining });
+        if let Some(reason) = reason {
+            event["reason"] = reason.into();
+        }
+        if !send(&format!("data: {event}\n\n")) {
+            return;
+        }
+        if status != "pending" {
+            send("");
+            return;
+        }
+        last = Some(status);
+    }
+}
+
+pub fn handle_web(mut req: tiny_http::Request, app: Arc<App>) {
+    let method = req.method().clone();
+    let url = req.url().split('?').next().unwrap_or("/").to_string();
+    let parts: Vec<String> = url
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    let parts_ref: Vec<&str> = parts.iter().map(String::as_str).collect();
+    let full_url = req.url().to_string();
+    // The single-use token on the enrollment pages and their calls.
+    let enroll_token = query_param(&full_url, "t");
+
+    let remote = req.remote_addr().copied();
+    let client_ip = history::client_ip(
+        remote,
+        remote.and_then(|a| app.peers.lookup(a)),
+        req.headers()
+            .iter()
+            .rev()
+            .find(|h| h.field.equiv("X-Forwarded-For"))
+            .map(|h| h.value.as_str())
+            .filter(|_| {
+                // Only `tailscale serve` says who it forwards for.
+                remote.is_some_and(|a| {
+                    a.ip().is_loopback() && history::socket_owner(a) == Some(app.cfg.proxy_uid)
+                })
+            }),
+    );
+    if parts_ref != ["metrics"] {
+        let client = client_ip.as_deref().unwrap_or_default();
+        if let Err(wait) = app.limiter.check(client, Instant::now()) {
+            let resp = Response::from_string("Too many requests; try again later.")
+                .with_status_code(429)
+                .with_header(header("Retry-After", &wait.as_secs().max(1).to_string()));
+            let _ = req.respond(resp);
+            return;
+        }
+    }
+
+    match (&method, parts_ref.as_slice()) {
+        (Method::Get, []) => respond_json(req, 200, app.status()),
+
+        (Method::Get, ["metrics"]) if app.cfg.metrics_listen.is_none() => {
+            respond_metrics(req, &app)
+        }
+
+        // ----- enrollment (one-time, token-gated) -----
+        (Method::Get, ["enroll"]) => {
+            if !app.enroll_allowed(enroll_token) {
+                respond_text(
+                    req,
+                    403,
+                    "Enrollment closed (a passkey is already registered, or the link is used up or expired). \
+                     New link: access-gate-verifier enroll-token on the desktop.",
+                );
+                return;
+            }
+            let token = enroll_token.unwrap_or_default();
+            respond_page(req, &app, Page::Enroll, &[("TOKEN", token)]);
+        }
+        (Method::Post, ["enroll", "options"]) => {
+            if !app.enroll_allowed(enroll_token) {
+                respond_text(req, 404, "");
+                return;
+            }
+            match app.webauthn.start_passkey_registration(
+                Uuid::new_v4(),
+                &app.cfg.user_name,
+                &app.cfg.user_name,
+                None,
+            ) {
+                Ok((ccr, state)) => {
+                    let until = now_unix() + ENROLL_CEREMONY_SECS;
+                    *app.enroll_state.lock().unwrap() = Some((until, state));
+                    app.reschedule();
+                    respond_json(req, 200, serde_json::to_value(&ccr).unwrap());
+                }
+                Err(e) => {
+                    warn!("start registration: {e}");
+                    respond_text(req, 500, "registration start failed");
+                }
+            }
+        }
+        (Method::Post, ["enroll", "verify"]) => {
+            if !app.enroll_allowed(enroll_token) {
+                respond_text(req, 404, "");
+                return;
+            }
+            let body = read_body(&mut req);
+            let Some((_, state)) = app.enroll_state.lock().unwrap().take() else {
+                respond_text(req, 400, "no enrollment in progress");
+                return;
+            };
+            let cred: RegisterPublicKeyCredential = match serde_json::from_str(&body) {
+                Ok(c) => c,
+                Err(e) => {
+                    respond_text(req, 400, &format!("bad credential: {e}"));
+                    return;
+                }
+            };
+            match app.webauthn.finish_passkey_registration(&cred, &state) {
+                Ok(passkey) => {
+                    if let Err(e) = app.store.save_passkey(&passkey) {
+                        warn!("storing passkey: {e}");
+                        respond_text(req, 500, "could not store credential");
+                        return;
+                    }
+                    token::consume(&app.cfg.data_dir, Purpose::Passkey);
+                    info!("passkey enrolled");
+                    respond_json(req, 200, json!({"ok": true}));
+                }
+                Err(e) => {
+                    warn!("finish registration: {e}");
+                    app.assertion_failed(None, &client_ip);
+                    respond_text(req, 400, &format!("verification failed: {e}"));
+                }
+            }
+        }
+
+        // ----- TOTP enrollment (token-gated, one authenticator per token) -----
+        (Method::Get, ["totp"]) => {
+            if !app.token_valid(Purpose::Totp, enroll_token) {
+                respond_text(
+                    req,
+                    403,
+                    "Authenticator enrollment closed. To add one: \
+                     access-gate-verifier enroll-token totp on the desktop.",
+                );
+                return;
+            }
+            let secret = totp::new_secret();
+            let key = totp::base32_encode(&secret);
+            let uri = totp::otpauth_uri(&app.cfg.brand, &app.cfg.user_name, &key);
+            let svg = match qr::svg(&uri) {
+                Ok(svg) => svg,
+                Err(e) => {
+                    warn!("{e:#}");
+                    respond_text(req, 500, "could not draw the QR code");
+                    return;
+                }
+            };
+            *app.totp_pending.lock().unwrap() = Some(secret);
+            let token = enroll_token.unwrap_or_default();
+            respond_page(
+                req,
+                &app,
+                Page::Totp,
+                &[("QR", &svg), ("SECRET", &key), ("TOKEN", token)],
+            );
+        }
+        (Method::Post, ["totp", "verify"]) => {
+            let body: Value = serde_json::from_str(&read_body(&mut req)).unwrap_or_default();
+            let secret = app.totp_pending.lock().unwrap().clone();
+            let (true, Some(secret)) = (app.token_valid(Purpose::Totp, enroll_token), secret)
+            else {
+                respond_text(req, 404, "");
+                return;
+            };
+            let code = body["code"].as_str().unwrap_or_default();
+            let Some(step) = totp::matching_step(&secret, code, now_unix()) else {
+                app.assertion_failed(None, &client_ip);
+                respond_text(
+                    req,
+                    400,
+                    "wrong code; check the phone's clock and try again",
+                );
+                return;
+            };
+            let cred = TotpCred {
+                id: new_request_id(),
+                label: body["label"]
+                    .as_str()
+                    .and_then(history::clean_reason)
+                    .unwrap_or_else(|| "authenticator".into()),
+                secret: totp::base32_encode(&secret),
+                created_at: now_unix(),
+                // The confirming code does not approve anything later.
+                last_step: step,
+            };
+            if let Err(e) = app.store.add_totp(&cred) {
+                warn!("storing TOTP authenticator: {e:#}");
+                respond_text(req, 500, "could not store the authenticator");
+                return;
+            }
+            app.limiter
+                .success(client_ip.as_deref().unwrap_or_default());
+            *app.totp_pending.lock().unwrap() = None;
+            token::consume(&app.cfg.data_dir, Purpose::Totp);
+            info!("TOTP authenticator {} enrolled ({})", cred.id, cred.label);
+            respond_json(req, 200, json!({"ok": true}));
+        }
+
+        // ----- push setup (token-gated) -----
+        (Method::Get, ["sw.js"]) => {
+            let resp = Response::from_string(SW_JS.as_str())
+                .with_header(header("Content-Type", "application/javascript"));
+            let _ = req.respond(resp);
+        }
+        (Method::Get, ["setup"]) => {
+            if !app.token_valid(Purpose::Push, enroll_token) {
+                respond_text(
+                    req,
+                    403,
+                    "Push setup closed (the link is used up or expired). \
+                     New link: access-gate-verifier enroll-token push on the desktop.",
+                );
+                return;
+            }
+            let token = enroll_token.unwrap_or_default();
+            respond_page(
+                req,
+                &app,
+                Page::Setup,
+                &[("VAPID_PUB", &app.vapid.public_b64u()), ("TOKEN", token)],
+            );
+        }
+        (Method::Post, ["push", "subscribe"]) => {
+            if !app.token_valid(Purpose::Push, enroll_token) {
+                respond_text(req, 404, "");
+                return;
+            }
+            let body = read_body(&mut req);
+            let v: Value = match serde_json::from_str(&body) {
+                Ok(v) => v,
+                Err(_) => {
+                    respond_text(req, 400, "bad subscription");
+                    return;
+                }
+            };
+            if v.get("endpoint").and_then(Value::as_str).is_none() {
+                respond_text(req, 400, "bad subscription");
+                return;
+            }
+            if let Err(e) = app.store.set_subscription(Some(&v)) {
+                warn!("storing subscription: {e:#}");
+                respond_text(req, 500, "could not store subscription");
+                return;
+            }
+            token::consume(&app.cfg.data_dir, Purpose::Push);
+            info!("push subscription stored");
+            respond_json(req, 200, json!({"ok": true}));
+        }
+
+        // The service worker's answer to a VAPID rotation (see `vapid`).
+        (Method::Post, ["push", "resubscribe"]) => {
+            if !app.token_valid(Purpose::Resubscribe, enroll_token) {
+                respond_text(req, 404, "");
+                return;
+            }
+            let v: Value = serde_json::from_str(&read_body(&mut req)).unwrap_or_default();
+            if v.get("endpoint").and_then(Value::as_str).is_none() {
+                respond_text(req, 400, "bad subscription");
+                return;
+            }
+            // The next key first: the subscription is only good with it.
+            if let Err(e) = app
+                .vapid
+                .promote()
+                .and_then(|()| app.store.set_subscription(Some(&v)))
+            {
+                warn!("re-subscribing: {e:#}");
+                respond_text(req, 500, "could not store subscription");
+                return;
+            }
+            token::consume(&app.cfg.data_dir, Purpose::Resubscribe);
+            info!("push subscription renewed under the new VAPID key");
+            respond_json(req, 200, json!({"ok": true}));
+        }
+
+        // ----- approval ceremony -----
+        (Method::Get, ["approve", rid, "events"]) => stream_events(req, &app, rid),
+        (Method::Get, ["approve", rid]) => {
+            let requests = app.requests.lock().unwrap();
+            let Some(r) = requests.get(*rid) else {
+                drop(requests);
+                respond_text(req, 404, "Unknown or expired request.");
+                return;
+            };
+            let lang = page_lang(&app, &req);
+            let vars = [
+                ("RID", rid.to_string()),
+                ("GRANTS", script_json(&json!(app.cfg.grant_minutes))),
+                ("REASONS", script_json(&json!(app.cfg.deny_reasons))),
+                ("TOTP", app.totp_allowed(&r.group).to_string()),
+                ("EXE", html_escape(&r.exe)),
+                ("PATH", html_escape(&r.path)),
+                ("GROUP", html_escape(&r.group)),
+                ("CONTEXT", context_block(&app, lang, r)),
+                ("PEER", html_escape(&describe_peer(&app, lang, r))),
+                ("FLAG", exe_flag(&app, lang, r)),
+            ];
+            drop(requests);
+            let vars: Vec<(&str, &str)> = vars.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            respond_page(req, &app, Page::Approve, &vars);
+        }
+        (Method::Post, ["approve", rid, "options"]) => {
+            let Some(passkey) = app.passkey() else {
+                respond_text(req, 404, "");
+                return;
+            };
+            let mut requests = app.requests.lock().unwrap();
+            let Some(r) = requests.get_mut(*rid) else {
+                drop(requests);
+                respond_text(req, 404, "");
+                return;
+            };
+            if r.status != "pending" {
+                drop(requests);
+                respond_text(req, 404, "");
+                return;
+            }
+            match app.webauthn.start_passkey_authentication(&[passkey]) {
+                Ok((rcr, state)) => {
+                    r.auth = Some(state);
+                    app.persist(&requests);
+                    drop(requests);
+                    respond_json(req, 200, serde_json::to_value(&rcr).unwrap());
+                }
+                Err(e) => {
+                    drop(requests);
+                    warn!("start authentication: {e}");
+                    respond_text(req, 500, "authentication start failed");
+                }
+            }
+        }
+        (Method::Post, ["approve", rid, "verify"]) => {
+            let grant_minutes = query_param(req.url(), "grant")
+                .and_then(|m| m.parse::<u64>().ok())
+                .filter(|m| app.cfg.grant_minutes.contains(m));
+            let body = read_body(&mut req);
+            let Some(passkey) = app.passkey() else {
+                respond_text(req, 404, "");
+                return;
+            };
+            let state = {
+                let mut requests = app.requests.lock().unwrap();
+                let state = match requests.get_mut(*rid) {
+                    Some(r) if r.status == "pending" => r.auth.take(),
+                    _ => None,
+                };
+                app.persist(&requests);
+                state
+            };
+            let Some(state) = state else {
+                respond_text(req, 404, "");
+                return;
+            };
+            let result = serde_json::from_str::<PublicKeyCredential>(&body)
+                .map_err(|e| (400, format!("bad credential: {e}")))
+                .and_then(|cred| {
+                    app.webauthn
+                        .finish_passkey_authentication(&cred, &state)
+                        .map_err(|e| (400, format!("verification failed: {e}")))
+                })
+                .and_then(|result| match result.user_verified() {
+                    true => Ok(result),
+                    false => Err((403, "user verification required".to_string())),
+                });
+            match result {
+                Ok(result) => {
+                    app.limiter
+                        .success(client_ip.as_deref().unwrap_or_default());
+                    app.passkey_used(passkey, &result);
+                    let credential = URL_SAFE_NO_PAD.encode(result.cred_id());
+                    let mut requests = app.requests.lock().unwrap();
+                    // It may have timed out or been cancelled during the
+                    // ceremony: no grant and no decision for a finished one.
+                    match requests.get_mut(*rid) {
+                        Some(r) if r.status == "pending" => {
+                            if let Some(minutes) = grant_minutes {
+                                let now = now_unix();
+                                let grant = Grant {
+                                    id: new_request_id(),
+                                    group: r.group.clone(),
+                                    exe: r.exe.clone(),
+                                    credential: Some(credential.clone()),
+                                    client_ip: client_ip.clone(),
+                                    created_at: now,
+                                    expires_at: now + minutes * 60,
+                                };
+                                info!(
+                                    "grant {} for {}/{} ({minutes} min)",
+                                    grant.id, grant.group, grant.exe
+                                );
+                                if let Err(e) = app.store.add_grant(grant) {
+                                    warn!("storing grant: {e:#}");
+                                }
+                            }
+                            app.finish(rid, r, "approved", Some(credential), client_ip, None);
+                            app.persist(&requests);
+                            app.decided.notify_all();
+                            drop(requests);
+                            respond_json(req, 200, json!({"ok": true}));
+                        }
+                        _ => {
+                            drop(requests);
+                            respond_text(req, 404, "");
+                        }
+                    }
+                }
+                Err((status, msg)) => {
+                    warn!("approval {rid}: {msg}");
+                    app.assertion_failed(Some(rid), &client_ip);
+                    respond_text(req, status, &msg);
+                }
+            }
+        }
+        (Method::Post, ["approve", rid, "totp"]) => {
+            let body: Value = serde_json::from_str(&read_body(&mut req)).unwrap_or_default();
+            let group = match app.requests.lock().unwrap().get(*rid) {
+                Some(r) if r.status == "pending" => r.group.clone(),
+                _ => String::new(),
+            };
+            if !app.totp_allowed(&group) {
+                respond_text(req, 404, "");
+                return;
+            }
+            let code = body["code"].as_str().unwrap_or_default();
+            let now = now_unix();
+            let matched = app.totp_creds().into_iter().find_map(|c| {
+                let secret = totp::base32_decode(&c.secret)?;
+                let step = totp::matching_step(&secret, code, now)?;
+                Some((c, step))
+            });
+            let accepted = matched.filter(|(c, step)| match app.store.use_totp(&c.id, *step) {
+                Ok(fresh) => {
+                    if !fresh {
+                        warn!("approval {rid}: replayed TOTP code ({})", c.label);
+                    }
+                    fresh
+                }
+                Err(e) => {
+                    warn!("recording TOTP use: {e:#}");
+                    false
+                }
+            });
+            let Some((cred, _)) = accepted else {
+                warn!("approval {rid}: wrong TOTP code");
+                app.assertion_failed(Some(rid), &client_ip);
+                respond_text(req, 403, "wrong or already used code");
+                return;
+            };
+            app.limiter
+                .success(client_ip.as_deref().unwrap_or_default());
+            let mut requests = app.requests.lock().unwrap();
+            match requests.get_mut(*rid) {
+                Some(r) if r.status == "pending" => {
+                    info!("request {rid} approved by TOTP ({})", cred.label);
+                    let credential = format!("totp:{}", cred.id);
+                    app.finish(rid, r, "approved", Some(credential), client_ip, None);
+                    app.persist(&requests);
+                    app.decided.notify_all();
+                    drop(requests);
+                    respond_json(req, 200, json!({"ok": true}));
+                }
+                _ => {
+                    drop(requests);
+                    respond_text(req, 404, "");
+                }
+            }
+        }
+        (Method::Post, ["approve", rid, "deny"]) => {
+            let reason = deny_reason(&mut req);
+            let mut requests = app.requests.lock().unwrap();
+            if let Some(r) = requests.get_mut(*rid) {
+                if r.status == "pending" {
+                    app.finish(rid, r, "denied", None, client_ip, reason);
+                }
+            }
+            app.persist(&requests);
+            app.decided.notify_all();
+            drop(requests);
+            respond_json(req, 200, json!({"ok": true}));
+        }
+
+        // ----- history dashboard (passkey sign-in, short session) -----
+        (Method::Get, ["history"]) => respond_page(req, &app, Page::History, &[]),
+        (Method::Post, ["history", "login", "options"]) => {
+            let Some(passkey) = app.passkey() else {
+                respond_text(req, 404, "");
+                return;
+            };
+            match app.webauthn.start_passkey_authentication(&[passkey]) {
+                Ok((rcr, state)) => {
+                    *app.login_state.lock().unwrap() = Some(state);
+                    respond_json(req, 200, serde_json::to_value(&rcr).unwrap());
+                }
+                Err(e) => {
+                    warn!("start history sign-in: {e}");
+                    respond_text(req, 500, "authentication start failed");
+                }
+            }
+        }
+        (Method::Post, ["history", "login", "verify"]) => {
+            let body = read_body(&mut req);
+            let (Some(passkey), Some(state)) =
+                (app.passkey(), app.login_state.lock().unwrap().take())
+            else {
+                respond_text(req, 400, "no sign-in in progress");
+                return;
+            };
+            let cred: PublicKeyCredential = match serde_json::from_str(&body) {
+                Ok(c) => c,
+                Err(e) => {
+                    app.assertion_failed(None, &client_ip);
+                    respond_text(req, 400, &format!("bad credential: {e}"));
+                    return;
+                }
+            };
+            match app.webauthn.finish_passkey_authentication(&cred, &state) {
+                Ok(result) if result.user_verified() => {
+                    app.limiter
+                        .success(client_ip.as_deref().unwrap_or_default());
+                    app.passkey_used(passkey, &result);
+                    let token = new_session_token();
+                    let mut sessions = app.sessions.lock().unwrap();
+                    let now = now_unix();
+                    sessions.retain(|_, s| s.expires > now);
+                    sessions.insert(
+                        token.clone(),
+                        Session {
+                            expires: now + SESSION_SECS,
+                            credential: URL_SAFE_NO_PAD.encode(result.cred_id()),
+                        },
+                    );
+                    drop(sessions);
+                    info!(
+                        "history sign-in from {}",
+                        client_ip.as_deref().unwrap_or("?")
+                    );
+                    let cookie = format!(
+                        "{SESSION_COOKIE}={token}; Path=/history; Max-Age={SESSION_SECS}; \
+                         HttpOnly; Secure; SameSite=Strict"
+                    );
+                    let resp = Response::from_string(json!({"ok": true}).to_string())
+                        .with_header(header("Content-Type", "application/json"))
+                        .with_header(header("Set-Cookie", &cookie));
+                    let _ = req.respond(resp);
+                }
+                Ok(_) => {
+                    app.assertion_failed(None, &client_ip);
+                    respond_text(req, 403, "user verification required");
+                }
+                Err(e) => {
+                    warn!("finish history sign-in: {e}");
+                    app.assertion_failed(None, &client_ip);
+                    respond_text(req, 400, &format!("verification failed: {e}"));
+                }
+            }
+        }
+        (Method::Get, ["history", "data"]) => {
+            if session_credential(&app, &req).is_none() {
+                respond_text(req, 401, "");
+                return;
+            }
+            let pending: Vec<Value> = {
+                let requests = app.requests.lock().unwrap();
+                requests
+                    .iter()
+                    .filter(|(_, r)| r.status == "pending")
+                    .map(|(rid, r)| {
+                        json!({
+                            "rid": rid,
+                            "exe": r.exe,
+                            "path": r.path,
+                            "group": r.group,
+                            "title": r.title,
+                            "peer": r.peer,
+                            "exe_mismatch": r.exe_mismatch,
+                            "created_at": r.created_at,
+                            "wait_until": r.wait_until,
+                        })
+                    })
+                    .collect()
+            };
+            let recent = app.store.recent_history(HISTORY_SHOWN).unwrap_or_else(|e| {
+                warn!("reading history: {e:#}");
+                Vec::new()
+            });
+            respond_json(req, 200, json!({ "pending": pending, "recent": recent }));
+        }
+        (Method::Post, ["history", "deny", rid]) => {
+            let Some(credential) = session_credential(&app, &req) else {
+                respond_text(req, 401, "");
+                return;
+            };
+            let reason = deny_reason(&mut req);
+            let mut requests = app.requests.lock().unwrap();
+            match requests.get_mut(*rid) {
+                Some(r) if r.status == "pending" => {
+                    app.finish(rid, r, "denied", Some(credential), client_ip, reason);
+                    app.persist(&requests);
+                    app.decided.notify_all();
+                    drop(requests);
+                    info!("request {rid} denied from /history");
+                    respond_json(req, 200, json!({"ok": true}));
+                }
+                _ => {
+                    drop(requests);
+                    respond_text(req, 404, "");
+                }
+            }
+        }
+
+        _ => respond_text(req, 404, ""),
+    }
+}
+
+fn respond_metrics(req: tiny_http::Request, app: &App) {
+    let resp = Response::from_string(app.render_metrics()).with_header(header(
+        "Content-Type",
+        "text/plain; version=0.0.4; charset=utf-8",
+    ));
+    let _ = req.respond(resp);
+}
+
+/// The separate `listen.metrics` server: /metrics and nothing else.
+pub fn serve_metrics(server: Server, app: Arc<App>) {
+    for req in server.incoming_requests() {
+        if req.method() == &Method::Get && req.url().split('?').next() == Some("/metrics") {
+            respond_metrics(req, &app);
+        } else {
+            respond_text(req, 404, "");
+        }
+    }
+}
+
+/// Decisions listed on /history.
+const HISTORY_SHOWN: usize = 50;
+
+fn new_session_token() -> String {
+    let mut bytes = [0u8; 32];
+    use rand::RngCore;
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The credential behind a live `/history` session cookie, if any.
+fn session_credential(app: &App, req: &tiny_http::Request) -> Option<String> {
+    let cookies = req
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Cookie"))?
+        .value
+        .as_str();
+    let token = cookies
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == SESSION_COOKIE)?
+        .1;
+    let sessions = app.sessions.lock().unwrap();
+    sessions
+        .get(token)
+        .filter(|s| s.expires > now_unix())
+        .map(|s| s.credential.clone())
+}
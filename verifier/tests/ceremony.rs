@@ -0,0 +1,546 @@
+//! The WebAuthn ceremonies end to end: a software authenticator enrolls and
+//! approves against the real verifier while a control client waits on the
+//! socket, as the daemon does, and push goes to a local stand-in.
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::{Signer, Verifier as _};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::elliptic_curve::rand_core::OsRng;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use common::{
+    cli, ctrl_send, enroll_token, http, http_body, read_line, spawn_activated_with, tmp_dir,
+    Verifier,
+};
+
+const RP_ID: &str = "localhost";
+const ORIGIN: &str = "https://localhost";
+
+/// A platform authenticator in software: one P-256 credential, "none"
+/// attestation, and a sign counter the tests can rewind.
+struct SoftToken {
+    key: SigningKey,
+    cred_id: Vec<u8>,
+    counter: u32,
+    /// Whether the "biometric" passes (the UV flag).
+    verify_user: bool,
+}
+
+const UP: u8 = 0x01;
+const UV: u8 = 0x04;
+const AT: u8 = 0x40;
+
+impl SoftToken {
+    fn new() -> Self {
+        SoftToken {
+            key: SigningKey::random(&mut OsRng),
+            cred_id: (0..16).map(|_| rand::random()).collect(),
+            counter: 0,
+            verify_user: true,
+        }
+    }
+
+    fn auth_data(&self, extra_flags: u8) -> Vec<u8> {
+        let mut data = Sha256::digest(RP_ID).to_vec();
+        data.push(UP | if self.verify_user { UV } else { 0 } | extra_flags);
+        data.extend(self.counter.to_be_bytes());
+        data
+    }
+
+    fn client_data(kind: &str, options: &Value) -> Vec<u8> {
+        json!({
+            "type": kind,
+            "challenge": options["publicKey"]["challenge"],
+            "origin": ORIGIN,
+            "crossOrigin": false,
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    /// `navigator.credentials.create()` for the `/enroll/options` answer.
+    fn register(&self, options: &Value) -> Value {
+        assert_eq!(options["publicKey"]["rp"]["id"], RP_ID);
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = cbor_map(&[
+            (cbor_int(1), cbor_int(2)),  // kty: EC2
+            (cbor_int(3), cbor_int(-7)), // alg: ES256
+            (cbor_int(-1), cbor_int(1)), // crv: P-256
+            (cbor_int(-2), cbor_bytes(point.x().unwrap())),
+            (cbor_int(-3), cbor_bytes(point.y().unwrap())),
+        ]);
+        let mut auth_data = self.auth_data(AT);
+        auth_data.extend([0u8; 16]); // AAGUID
+        auth_data.extend((self.cred_id.len() as u16).to_be_bytes());
+        auth_data.extend(&self.cred_id);
+        auth_data.extend(cose_key);
+        let attestation = cbor_map(&[
+            (cbor_text("fmt"), cbor_text("none")),
+            (cbor_text("attStmt"), cbor_map(&[])),
+            (cbor_text("authData"), cbor_bytes(&auth_data)),
+        ]);
+        let id = b64u(&self.cred_id);
+        json!({
+            "id": id,
+            "rawId": id,
+            "type": "public-key",
+            "response": {
+                "attestationObject": b64u(&attestation),
+                "clientDataJSON": b64u(&Self::client_data("webauthn.create", options)),
+            },
+        })
+    }
+
+    /// `navigator.credentials.get()` for an `/options` answer; bumps the
+    /// counter first, as hardware does.
+    fn assert(&mut self, options: &Value) -> Value {
+        let allowed = options["publicKey"]["allowCredentials"].as_array().unwrap();
+        assert!(allowed.iter().any(|c| c["id"] == b64u(&self.cred_id)));
+        self.counter += 1;
+        let auth_data = self.auth_data(0);
+        let client_data = Self::client_data("webauthn.get", options);
+        let mut signed = auth_data.clone();
+        signed.extend(Sha256::digest(&client_data));
+        let signature: Signature = self.key.sign(&signed);
+        let id = b64u(&self.cred_id);
+        json!({
+            "id": id,
+            "rawId": id,
+            "type": "public-key",
+            "response": {
+                "authenticatorData": b64u(&auth_data),
+                "clientDataJSON": b64u(&client_data),
+                "signature": b64u(signature.to_der().as_bytes()),
+                "userHandle": null,
+            },
+        })
+    }
+}
+
+fn b64u(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+// Just enough CBOR for an attestation object: small maps, ints, strings.
+
+fn cbor_head(major: u8, n: usize) -> Vec<u8> {
+    match n {
+        0..=23 => vec![major << 5 | n as u8],
+        24..=0xff => vec![major << 5 | 24, n as u8],
+        _ => {
+            let mut head = vec![major << 5 | 25];
+            head.extend((n as u16).to_be_bytes());
+            head
+        }
+    }
+}
+
+fn cbor_int(n: i64) -> Vec<u8> {
+    match n {
+        0.. => cbor_head(0, n as usize),
+        _ => cbor_head(1, (-1 - n) as usize),
+    }
+}
+
+fn cbor_bytes(b: &[u8]) -> Vec<u8> {
+    let mut out = cbor_head(2, b.len());
+    out.extend(b);
+    out
+}
+
+fn cbor_text(s: &str) -> Vec<u8> {
+    let mut out = cbor_head(3, s.len());
+    out.extend(s.as_bytes());
+    out
+}
+
+fn cbor_map(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut out = cbor_head(5, entries.len());
+    for (k, v) in entries {
+        out.extend(k);
+        out.extend(v);
+    }
+    out
+}
+
+struct Setup {
+    dir: PathBuf,
+    sock: PathBuf,
+    web: SocketAddr,
+    _verifier: Verifier,
+}
+
+impl Setup {
+    fn start(tag: &str) -> Self {
+        let dir = tmp_dir(tag);
+        let sock = dir.join("ctrl.sock");
+        let ctrl = UnixListener::bind(&sock).unwrap();
+        let web = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = web.local_addr().unwrap();
+        let verifier = spawn_activated_with(
+            &dir,
+            vec![ctrl.as_raw_fd(), web.as_raw_fd()],
+            "ctrl:web",
+            // Every test here makes more calls than the default burst.
+            &[("AG_RATE_BURST", "100")],
+        );
+        Setup {
+            dir,
+            sock,
+            web: addr,
+            _verifier: verifier,
+        }
+    }
+
+    /// POST `body`; the status code and the parsed JSON body (Null if none).
+    fn post(&self, path: &str, body: &Value) -> (u16, Value) {
+        let body = if body.is_null() {
+            String::new()
+        } else {
+            body.to_string()
+        };
+        let resp = http_body(self.web, "POST", path, "", &body);
+        let status = resp[9..12].parse().unwrap();
+        let body = resp.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    fn enroll(&self, token: &SoftToken) {
+        let t = enroll_token(&self.dir, "passkey");
+        let (status, options) = self.post(&format!("/enroll/options?t={t}"), &Value::Null);
+        assert_eq!(status, 200, "{options}");
+        let answer = self.post(&format!("/enroll/verify?t={t}"), &token.register(&options));
+        assert_eq!(answer, (200, json!({"ok": true})));
+        // Spent.
+        let again = self.post(&format!("/enroll/options?t={t}"), &Value::Null);
+        assert_eq!(again.0, 404);
+    }
+
+    /// A control request as the daemon makes it; the connection and the id.
+    fn request(&self, timeout_secs: u64) -> (BufReader<UnixStream>, String) {
+        let mut client = ctrl_send(
+            &self.sock,
+            &json!({"exe": "game-mode", "path": "switch", "group": "login",
+                    "timeout_secs": timeout_secs}),
+        );
+        let rid = read_line(&mut client).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (client, rid)
+    }
+
+    /// The public decision key, as `game-mode setup` pins it.
+    fn decision_key(&self) -> VerifyingKey {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_access-gate-verifier"))
+            .arg("decision-key")
+            .env("AG_CONFIG", self.dir.join("absent.toml"))
+            .env("AG_RP_ID", RP_ID)
+            .env("AG_ORIGIN", ORIGIN)
+            .env("AG_DATA_DIR", &self.dir)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let point = URL_SAFE_NO_PAD
+            .decode(String::from_utf8(out.stdout).unwrap().trim())
+            .unwrap();
+        VerifyingKey::from_sec1_bytes(&point).unwrap()
+    }
+
+    /// Run the approve ceremony for `rid`; the verify call's status.
+    fn approve(&self, token: &mut SoftToken, rid: &str) -> u16 {
+        let (status, options) = self.post(&format!("/approve/{rid}/options"), &Value::Null);
+        assert_eq!(status, 200, "{options}");
+        let (status, _) = self.post(&format!("/approve/{rid}/verify"), &token.assert(&options));
+        status
+    }
+
+    /// The stored sign counter of the enrolled passkey.
+    fn stored_counter(&self) -> u64 {
+        let text = std::fs::read_to_string(self.dir.join("credential.json")).unwrap();
+        let passkey: Value = serde_json::from_str(&text).unwrap();
+        passkey["cred"]["counter"].as_u64().unwrap()
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Path, (lower-cased) headers and encrypted body of a delivered push.
+type Push = (String, HashMap<String, String>, Vec<u8>);
+
+/// A push service stand-in: answers 201 and hands over each push.
+fn push_service() -> (String, mpsc::Receiver<Push>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", server.server_addr().to_ip().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for mut req in server.incoming_requests() {
+            let headers = req
+                .headers()
+                .iter()
+                .map(|h| (h.field.to_string().to_lowercase(), h.value.to_string()))
+                .collect();
+            let mut body = Vec::new();
+            let _ = std::io::Read::read_to_end(req.as_reader(), &mut body);
+            let _ = tx.send((req.url().to_string(), headers, body));
+            let _ = req.respond(tiny_http::Response::empty(201));
+        }
+    });
+    (base, rx)
+}
+
+#[test]
+fn enroll_subscribe_and_approve() {
+    let t = Setup::start("e2e-approve");
+    let (push, pushes) = push_service();
+    let (subscription, _) = browser_subscription(&format!("{push}/push/phone"));
+    // Push setup and enrollment both need a token from the desktop.
+    assert_eq!(t.post("/push/subscribe", &subscription).0, 404);
+    assert_eq!(t.post("/enroll/options?t=guess", &Value::Null).0, 404);
+    let push_token = enroll_token(&t.dir, "push");
+    let page = http(t.web, "GET", &format!("/setup?t={push_token}"));
+    assert!(page.contains(&format!("'?t={push_token}'")), "{page}");
+    let subscribe = format!("/push/subscribe?t={push_token}");
+    assert_eq!(t.post(&subscribe, &subscription).0, 200);
+    assert_eq!(t.post(&subscribe, &subscription).0, 404);
+
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+    // One passkey only: enrollment is closed now, fresh token or not.
+    let again = enroll_token(&t.dir, "passkey");
+    let options = format!("/enroll/options?t={again}");
+    assert_eq!(t.post(&options, &Value::Null).0, 404);
+
+    let (mut client, rid) = t.request(60);
+    let (path, headers, _) = pushes.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(path, "/push/phone");
+    assert_eq!(headers["urgency"], "high");
+    assert!(headers["authorization"].starts_with("vapid "));
+
+    assert_eq!(t.approve(&mut token, &rid), 200);
+    assert_eq!(
+        read_line(&mut client).unwrap(),
+        json!({"status": "approved"})
+    );
+    assert_eq!(t.stored_counter(), 1);
+}
+
+#[test]
+fn assertion_without_user_verification_is_rejected() {
+    let t = Setup::start("e2e-uv");
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+    let (mut client, rid) = t.request(60);
+
+    token.verify_user = false;
+    assert_eq!(t.approve(&mut token, &rid), 400);
+    // Still open for a proper try once the failure lockout (2 s) passes.
+    thread::sleep(Duration::from_millis(2100));
+    token.verify_user = true;
+    assert_eq!(t.approve(&mut token, &rid), 200);
+    assert_eq!(read_line(&mut client).unwrap()["status"], "approved");
+}
+
+#[test]
+fn sign_counter_is_tracked_and_a_rewind_refused() {
+    let t = Setup::start("e2e-counter");
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+    for expected in 1..=2 {
+        let (mut client, rid) = t.request(60);
+        assert_eq!(t.approve(&mut token, &rid), 200);
+        assert_eq!(read_line(&mut client).unwrap()["status"], "approved");
+        assert_eq!(t.stored_counter(), expected);
+    }
+
+    // A cloned authenticator replaying an old counter.
+    token.counter = 0;
+    let (_client, rid) = t.request(60);
+    assert_eq!(t.approve(&mut token, &rid), 400);
+    assert_eq!(t.stored_counter(), 2);
+}
+
+#[test]
+fn expired_request_cannot_be_approved() {
+    let t = Setup::start("e2e-expiry");
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+    let (mut client, rid) = t.request(1);
+    let (status, options) = t.post(&format!("/approve/{rid}/options"), &Value::Null);
+    assert_eq!(status, 200);
+    assert_eq!(read_line(&mut client).unwrap()["status"], "timeout");
+    // The ceremony started in time, but the answer comes too late.
+    let late = t.post(&format!("/approve/{rid}/verify"), &token.assert(&options));
+    assert_eq!(late.0, 404);
+    assert_eq!(
+        t.post(&format!("/approve/{rid}/options"), &Value::Null).0,
+        404
+    );
+}
+
+#[test]
+fn deny_and_approve_race() {
+    let t = Setup::start("e2e-race");
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+
+    // Denied (on another device) while the fingerprint prompt is up.
+    let (mut client, rid) = t.request(60);
+    let (_, options) = t.post(&format!("/approve/{rid}/options"), &Value::Null);
+    let deny = json!({"reason": "not now"});
+    assert_eq!(t.post(&format!("/approve/{rid}/deny"), &deny).0, 200);
+    let late = t.post(&format!("/approve/{rid}/verify"), &token.assert(&options));
+    assert_eq!(late.0, 404);
+    assert_eq!(
+        read_line(&mut client).unwrap(),
+        json!({"status": "denied", "reason": "not now"})
+    );
+
+    // Approved first: a deny afterwards changes nothing.
+    let (mut client, rid) = t.request(60);
+    assert_eq!(t.approve(&mut token, &rid), 200);
+    assert_eq!(t.post(&format!("/approve/{rid}/deny"), &deny).0, 200);
+    assert_eq!(
+        read_line(&mut client).unwrap(),
+        json!({"status": "approved"})
+    );
+}
+
+#[test]
+fn decisions_are_signed_for_the_daemons_nonce() {
+    let t = Setup::start("e2e-signed");
+    let mut token = SoftToken::new();
+    t.enroll(&token);
+    let nonce = "bm9uY2Utbm9uY2Utbm9uY2U";
+    let mut client = ctrl_send(
+        &t.sock,
+        &json!({"exe": "game-mode", "path": "switch", "group": "login",
+                "timeout_secs": 60, "nonce": nonce}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert_eq!(t.approve(&mut token, &rid), 200);
+    let decision = read_line(&mut client).unwrap();
+    assert_eq!(decision["status"], "approved");
+
+    let expires = decision["expires"].as_u64().unwrap();
+    let sig = URL_SAFE_NO_PAD
+        .decode(decision["sig"].as_str().unwrap())
+        .unwrap();
+    let sig = Signature::from_slice(&sig).unwrap();
+    let signed = format!("access-gate-decision-v2\n{rid}\n{nonce}\napproved\n{expires}\n");
+    let key = t.decision_key();
+    assert!(key.verify(signed.as_bytes(), &sig).is_ok());
+    let replayed =
+        format!("access-gate-decision-v2\n{rid}\nAAAAAAAAAAAAAAAAAAAAAA\napproved\n{expires}\n");
+    assert!(key.verify(replayed.as_bytes(), &sig).is_err());
+
+    // A resume answers under the request's own nonce, not the caller's.
+    let mut resumed = ctrl_send(
+        &t.sock,
+        &json!({"resume": rid, "nonce": "AAAAAAAAAAAAAAAAAAAAAA"}),
+    );
+    let again = read_line(&mut resumed).unwrap();
+    let sig = URL_SAFE_NO_PAD
+        .decode(again["sig"].as_str().unwrap())
+        .unwrap();
+    let sig = Signature::from_slice(&sig).unwrap();
+    let expires = again["expires"].as_u64().unwrap();
+    let signed = format!("access-gate-decision-v2\n{rid}\n{nonce}\napproved\n{expires}\n");
+    assert!(key.verify(signed.as_bytes(), &sig).is_ok());
+
+    let mut bad = ctrl_send(
+        &t.sock,
+        &json!({"exe": "game-mode", "nonce": "x\napproved"}),
+    );
+    assert_eq!(read_line(&mut bad).unwrap(), json!({"error": "bad nonce"}));
+}
+
+/// A browser's push subscription to `endpoint`: its JSON and the key pair
+/// that decrypts what is pushed to it.
+fn browser_subscription(endpoint: &str) -> (Value, p256::SecretKey) {
+    let key = p256::SecretKey::random(&mut OsRng);
+    let subscription = json!({
+        "endpoint": endpoint,
+        "keys": {
+            "p256dh": b64u(key.public_key().to_encoded_point(false).as_bytes()),
+            "auth": b64u(&[7u8; 16]),
+        },
+    });
+    (subscription, key)
+}
+
+/// The `k=` (public VAPID key) of a push's Authorization header.
+fn vapid_key_of(headers: &HashMap<String, String>) -> &str {
+    headers["authorization"].split("k=").nth(1).unwrap()
+}
+
+#[test]
+fn vapid_rotation_moves_the_subscription() {
+    let t = Setup::start("e2e-vapid");
+    let (push, pushes) = push_service();
+    let (subscription, browser_key) = browser_subscription(&format!("{push}/push/phone"));
+    let push_token = enroll_token(&t.dir, "push");
+    let subscribe = format!("/push/subscribe?t={push_token}");
+    assert_eq!(t.post(&subscribe, &subscription).0, 200);
+
+    let status = cli(&t.dir, &["vapid", "status"]);
+    let old = status.split_whitespace().nth(2).unwrap().to_string();
+    assert!(cli(&t.dir, &["vapid", "rotate"]).contains("re-subscribe"));
+    let (path, headers, body) = pushes.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(path, "/push/phone");
+    assert_eq!(vapid_key_of(&headers), old);
+    let components = ece::EcKeyComponents::new(
+        browser_key.to_bytes().to_vec(),
+        browser_key
+            .public_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec(),
+    );
+    let message: Value =
+        serde_json::from_slice(&ece::decrypt(&components, &[7u8; 16], &body).unwrap()).unwrap();
+    assert_eq!(message["type"], "resubscribe");
+    let next = message["key"].as_str().unwrap();
+    assert_ne!(next, old);
+    let status = cli(&t.dir, &["vapid", "status"]);
+    assert!(status.contains(&format!("Current key: {old}")), "{status}");
+    assert!(status.contains(&format!("Rotating to {next}")), "{status}");
+
+    // The service worker's answer, under the next key.
+    let link = message["url"].as_str().unwrap();
+    let link = link.strip_prefix(ORIGIN).unwrap();
+    let (renewed, _) = browser_subscription(&format!("{push}/push/renewed"));
+    assert_eq!(t.post(link, &renewed).0, 200);
+    assert_eq!(t.post(link, &renewed).0, 404);
+    let status = cli(&t.dir, &["vapid", "status"]);
+    assert!(status.contains(&format!("Current key: {next}")), "{status}");
+    assert!(!status.contains("Rotating"), "{status}");
+
+    // Approvals now go to the new subscription, signed with the new key.
+    let (_client, _rid) = t.request(60);
+    let (path, headers, _) = pushes.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(path, "/push/renewed");
+    assert_eq!(vapid_key_of(&headers), next);
+}
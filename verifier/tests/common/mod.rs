@@ -0,0 +1,160 @@
+//! Shared helpers for the verifier's process-level tests.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use serde_json::Value;
+
+pub struct Verifier(pub Child);
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+pub fn tmp_dir(tag: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ag-act-{tag}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Start the verifier with `fds` passed as 3, 4, ... under `names`.
+pub fn spawn_activated(data_dir: &PathBuf, fds: Vec<i32>, names: &str) -> Verifier {
+    spawn_activated_with(data_dir, fds, names, &[])
+}
+
+/// The uid the verifier takes X-Forwarded-For from: the test's own.
+pub fn proxy_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+/// `spawn_activated` with extra environment on top of the defaults.
+pub fn spawn_activated_with(
+    data_dir: &PathBuf,
+    fds: Vec<i32>,
+    names: &str,
+    env: &[(&str, &str)],
+) -> Verifier {
+    let count = fds.len();
+    let mut cmd = Command::new("/bin/sh");
+    // LISTEN_PID must be the verifier's pid: set it in the shell that execs it.
+    cmd.args([
+        "-c",
+        "LISTEN_PID=$$ exec \"$0\"",
+        env!("CARGO_BIN_EXE_access-gate-verifier"),
+    ])
+    .env("LISTEN_FDS", count.to_string())
+    .env("LISTEN_FDNAMES", names)
+    .env("AG_CONFIG", data_dir.join("absent.toml"))
+    .env("AG_RP_ID", "localhost")
+    .env("AG_ORIGIN", "https://localhost")
+    .env("AG_DATA_DIR", data_dir)
+    // Must not be used: the activated sockets win.
+    .env("AG_CTRL_SOCKET", data_dir.join("unused.sock"))
+    .env("AG_WEB_LISTEN", "127.0.0.1:1")
+    // The tests play `tailscale serve`: their X-Forwarded-For counts.
+    .env("AG_PROXY_UID", proxy_uid().to_string())
+    .envs(env.iter().copied())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null());
+    unsafe {
+        cmd.pre_exec(move || {
+            // Park the sources above the target range first so dup2 can't
+            // clobber one that already sits at 3 or 4.
+            let high: Vec<i32> = fds
+                .iter()
+                .map(|&fd| libc::fcntl(fd, libc::F_DUPFD, 64))
+                .collect();
+            for (i, fd) in high.into_iter().enumerate() {
+                if fd < 0 || libc::dup2(fd, 3 + i as i32) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                libc::close(fd);
+            }
+            Ok(())
+        });
+    }
+    Verifier(cmd.spawn().expect("spawn verifier"))
+}
+
+/// Write one control line and return the connection for reading answers.
+pub fn ctrl_send(sock: &PathBuf, line: &Value) -> BufReader<UnixStream> {
+    let mut client = UnixStream::connect(sock).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(20)))
+        .unwrap();
+    client.write_all(format!("{line}\n").as_bytes()).unwrap();
+    BufReader::new(client)
+}
+
+/// Next JSON line, or `None` once the verifier hung up.
+pub fn read_line(reader: &mut BufReader<UnixStream>) -> Option<Value> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(n) if n > 0 => Some(serde_json::from_str(&line).unwrap()),
+        _ => None,
+    }
+}
+
+/// Minimal HTTP/1.1 request; returns the raw response.
+pub fn http(addr: SocketAddr, method: &str, path: &str) -> String {
+    http_with(addr, method, path, "")
+}
+
+/// `http` with extra header lines (each ending in `\r\n`).
+pub fn http_with(addr: SocketAddr, method: &str, path: &str, headers: &str) -> String {
+    http_body(addr, method, path, headers, "")
+}
+
+/// `http_with` plus a request body.
+pub fn http_body(addr: SocketAddr, method: &str, path: &str, headers: &str, body: &str) -> String {
+    let mut s = TcpStream::connect(addr).unwrap();
+    s.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+    write!(
+        s,
+        "{method} {path} HTTP/1.1\r\nHost: x\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut resp = String::new();
+    s.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+/// Run an `access-gate-verifier` subcommand on `data_dir`; its stdout.
+pub fn cli(data_dir: &PathBuf, args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_access-gate-verifier"))
+        .args(args)
+        .env("AG_CONFIG", data_dir.join("absent.toml"))
+        .env("AG_RP_ID", "localhost")
+        .env("AG_ORIGIN", "https://localhost")
+        .env("AG_DATA_DIR", data_dir)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// `access-gate-verifier enroll-token <purpose>` for `data_dir`: the token
+/// from the printed link.
+pub fn enroll_token(data_dir: &PathBuf, purpose: &str) -> String {
+    let stdout = cli(data_dir, &["enroll-token", purpose]);
+    let link = stdout
+        .lines()
+        .find(|l| l.starts_with("https://localhost/"))
+        .unwrap();
+    link.split_once("?t=").unwrap().1.to_string()
+}
@@ -0,0 +1,132 @@
+//! The approve page's event stream follows the request: decided on another
+//! device, or cancelled because the control client hung up.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+
+use common::{ctrl_send, http, read_line, spawn_activated, tmp_dir, Verifier};
+use serde_json::{json, Value};
+
+struct Setup {
+    dir: std::path::PathBuf,
+    sock: std::path::PathBuf,
+    web: SocketAddr,
+    _verifier: Verifier,
+}
+
+fn start(tag: &str) -> Setup {
+    let dir = tmp_dir(tag);
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let verifier = spawn_activated(&dir, vec![ctrl.as_raw_fd(), web.as_raw_fd()], "ctrl:web");
+    Setup {
+        dir,
+        sock,
+        web: addr,
+        _verifier: verifier,
+    }
+}
+
+/// Open the event stream and skip the response head.
+fn events(addr: SocketAddr, rid: &str) -> BufReader<TcpStream> {
+    let mut s = TcpStream::connect(addr).unwrap();
+    s.set_read_timeout(Some(Duration::from_secs(20))).unwrap();
+    write!(s, "GET /approve/{rid}/events HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let mut reader = BufReader::new(s);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            return reader;
+        }
+    }
+}
+
+/// Next `data:` payload (keepalive comments skipped); `None` once the
+/// response ends (the zero-length chunk).
+fn next_event(reader: &mut BufReader<TcpStream>) -> Option<Value> {
+    loop {
+        let mut size = String::new();
+        reader.read_line(&mut size).unwrap();
+        let size = usize::from_str_radix(size.trim(), 16).unwrap();
+        let mut chunk = vec![0u8; size + 2];
+        reader.read_exact(&mut chunk).unwrap();
+        if size == 0 {
+            return None;
+        }
+        let chunk = String::from_utf8(chunk).unwrap();
+        if let Some(data) = chunk.strip_prefix("data: ") {
+            return Some(serde_json::from_str(data.trim_end()).unwrap());
+        }
+    }
+}
+
+#[test]
+fn stream_reports_decision_made_elsewhere() {
+    let t = start("sse-deny");
+    let mut client = ctrl_send(
+        &t.sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 60}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut stream = events(t.web, &rid);
+    let first = next_event(&mut stream).unwrap();
+    assert_eq!(first["status"], "pending");
+    let remaining = first["remaining"].as_u64().unwrap();
+    assert!((58..=60).contains(&remaining), "{first}");
+
+    http(t.web, "POST", &format!("/approve/{rid}/deny"));
+    assert_eq!(next_event(&mut stream).unwrap()["status"], "denied");
+    assert!(
+        next_event(&mut stream).is_none(),
+        "stream ends once decided"
+    );
+    assert_eq!(read_line(&mut client).unwrap()["status"], "denied");
+
+    std::fs::remove_dir_all(&t.dir).unwrap();
+}
+
+#[test]
+fn hanging_up_the_control_client_cancels() {
+    let t = start("sse-cancel");
+    let mut client = ctrl_send(
+        &t.sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 60}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut stream = events(t.web, &rid);
+    assert_eq!(next_event(&mut stream).unwrap()["status"], "pending");
+    drop(client);
+    assert_eq!(next_event(&mut stream).unwrap()["status"], "cancelled");
+    // Too late to approve.
+    let resp = http(t.web, "POST", &format!("/approve/{rid}/options"));
+    assert!(resp.starts_with("HTTP/1.1 404"), "{resp}");
+
+    std::fs::remove_dir_all(&t.dir).unwrap();
+}
+
+#[test]
+fn unknown_request_has_no_stream() {
+    let t = start("sse-404");
+    let resp = http(t.web, "GET", "/approve/nope/events");
+    assert!(resp.starts_with("HTTP/1.1 404"), "{resp}");
+    std::fs::remove_dir_all(&t.dir).unwrap();
+}
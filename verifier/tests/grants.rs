@@ -0,0 +1,92 @@
+//! A grant answers matching requests straight away, is logged as such, and
+//! is managed from the CLI.
+
+mod common;
+
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+
+use common::{ctrl_send, read_line, spawn_activated_with, tmp_dir};
+use serde_json::json;
+
+fn cli(dir: &std::path::Path, args: &[&str]) -> (bool, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_access-gate-verifier"))
+        .args(args)
+        .env("AG_CONFIG", dir.join("absent.toml"))
+        .env("AG_RP_ID", "localhost")
+        .env("AG_ORIGIN", "https://localhost")
+        .env("AG_DATA_DIR", dir)
+        .output()
+        .unwrap();
+    (
+        out.status.success(),
+        String::from_utf8_lossy(&out.stdout).into_owned(),
+    )
+}
+
+#[test]
+fn grant_approves_matching_requests_until_revoked() {
+    let dir = tmp_dir("grants");
+    let far = now_unix() + 3600;
+    std::fs::write(
+        dir.join("grants.json"),
+        json!([{"id": "g1", "group": "login", "exe": "game-mode", "credential": "Y3JlZA",
+                "client_ip": "100.64.0.7", "created_at": 0, "expires_at": far}])
+        .to_string(),
+    )
+    .unwrap();
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    // The test stands in for the daemon, so its claim can't be checked.
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![ctrl.as_raw_fd()],
+        "ctrl",
+        &[("AG_EXE_PATHS", "")],
+    );
+    let request =
+        |exe: &str| json!({"exe": exe, "path": "p", "group": "login", "timeout_secs": 30});
+
+    let mut client = ctrl_send(&sock, &request("game-mode"));
+    assert!(read_line(&mut client).unwrap()["id"].is_string());
+    let decision = read_line(&mut client).unwrap();
+    assert_eq!(decision, json!({"status": "approved", "reason": "grant"}));
+
+    // Other executables still need the phone.
+    let mut other = ctrl_send(&sock, &request("steam"));
+    read_line(&mut other).unwrap();
+
+    let log = std::fs::read_to_string(dir.join("history.jsonl")).unwrap();
+    let first: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+    assert_eq!(first["reason"], "grant g1");
+    assert_eq!(first["credential"], "Y3JlZA");
+
+    let (ok, listed) = cli(&dir, &["grants", "list"]);
+    assert!(
+        ok && listed.contains("g1  login/game-mode  60 min left"),
+        "{listed}"
+    );
+    assert!(!cli(&dir, &["grants", "revoke", "nope"]).0);
+    assert!(cli(&dir, &["grants", "revoke", "g1"]).0);
+    assert!(cli(&dir, &["grants", "list"])
+        .1
+        .contains("No active grants."));
+
+    let mut client = ctrl_send(&sock, &request("game-mode"));
+    read_line(&mut client).unwrap();
+    client
+        .get_ref()
+        .set_read_timeout(Some(std::time::Duration::from_millis(300)))
+        .unwrap();
+    assert!(read_line(&mut client).is_none(), "still pending");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,80 @@
+//! Every finished request lands in history.jsonl with who asked, who
+//! decided and how; the dashboard itself stays behind a passkey sign-in.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{ctrl_send, http, http_body, http_with, read_line, spawn_activated, tmp_dir};
+use serde_json::{json, Value};
+
+fn history(dir: &std::path::Path) -> Vec<Value> {
+    std::fs::read_to_string(dir.join("history.jsonl"))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect()
+}
+
+#[test]
+fn decisions_are_logged_and_dashboard_needs_sign_in() {
+    let dir = tmp_dir("history");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated(&dir, vec![ctrl.as_raw_fd(), web.as_raw_fd()], "ctrl:web");
+
+    // Denied on the phone, behind a proxy, with a reason.
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "game-mode", "path": "p", "group": "login",
+                "title": "Enter game mode?", "timeout_secs": 30}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    http_body(
+        addr,
+        "POST",
+        &format!("/approve/{rid}/deny"),
+        "X-Forwarded-For: 100.64.0.7\r\n",
+        &json!({"reason": " Homework\u{1b}[2J\nfirst "}).to_string(),
+    );
+    assert_eq!(
+        read_line(&mut client).unwrap(),
+        json!({"status": "denied", "reason": "Homework[2J first"})
+    );
+
+    // Nobody answers this one.
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 1}),
+    );
+    read_line(&mut client).unwrap();
+    assert_eq!(read_line(&mut client).unwrap()["status"], "timeout");
+
+    let log = history(&dir);
+    assert_eq!(log.len(), 2, "{log:?}");
+    assert_eq!(log[0]["rid"], rid.as_str());
+    assert_eq!(log[0]["outcome"], "denied");
+    assert_eq!(log[0]["title"], "Enter game mode?");
+    assert_eq!(log[0]["client_ip"], "100.64.0.7");
+    assert_eq!(log[0]["reason"], "Homework[2J first");
+    assert_eq!(log[0]["peer"]["uid"], unsafe { libc::getuid() });
+    assert_eq!(log[0]["peer"]["pid"], std::process::id());
+    assert_eq!(log[1]["outcome"], "timeout");
+
+    assert!(http(addr, "GET", "/history").starts_with("HTTP/1.1 200"));
+    let data = http(addr, "GET", "/history/data");
+    assert!(data.starts_with("HTTP/1.1 401"), "{data}");
+    let forged = http_with(addr, "GET", "/history/data", "Cookie: ag_history=guess\r\n");
+    assert!(forged.starts_with("HTTP/1.1 401"), "{forged}");
+    let deny = http(addr, "POST", &format!("/history/deny/{rid}"));
+    assert!(deny.starts_with("HTTP/1.1 401"), "{deny}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
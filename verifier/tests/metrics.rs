@@ -0,0 +1,78 @@
+//! /metrics follows requests through the control and web planes, and moves
+//! to its own listener when `listen.metrics` is set.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{ctrl_send, http, read_line, spawn_activated, spawn_activated_with, tmp_dir};
+use serde_json::json;
+
+fn has_line(body: &str, line: &str) -> bool {
+    body.lines().any(|l| l == line)
+}
+
+#[test]
+fn counts_requests_on_the_web_plane() {
+    let dir = tmp_dir("metrics");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated(&dir, vec![ctrl.as_raw_fd(), web.as_raw_fd()], "ctrl:web");
+
+    let mut pending = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 30}),
+    );
+    read_line(&mut pending).unwrap();
+    let mut denied = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 30}),
+    );
+    let rid = read_line(&mut denied).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    http(addr, "POST", &format!("/approve/{rid}/deny"));
+    assert_eq!(read_line(&mut denied).unwrap()["status"], "denied");
+
+    let resp = http(addr, "GET", "/metrics");
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(resp.contains("text/plain; version=0.0.4"), "{resp}");
+    for line in [
+        "ag_requests_created_total 2",
+        "ag_requests_decided_total{status=\"denied\"} 1",
+        "ag_pending_requests 1",
+        "ag_enrolled_credentials 0",
+        "ag_approval_latency_seconds_count 0",
+    ] {
+        assert!(has_line(&resp, line), "missing {line:?} in\n{resp}");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn separate_listener_takes_metrics_off_the_web_plane() {
+    let dir = tmp_dir("metrics-sep");
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (web_addr, metrics_addr) = (web.local_addr().unwrap(), metrics.local_addr().unwrap());
+    // The activated socket wins over the configured address.
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![web.as_raw_fd(), metrics.as_raw_fd()],
+        "web:metrics",
+        &[("AG_METRICS_LISTEN", "127.0.0.1:1")],
+    );
+
+    let resp = http(metrics_addr, "GET", "/metrics");
+    assert!(has_line(&resp, "ag_requests_created_total 0"), "{resp}");
+    assert!(http(metrics_addr, "GET", "/history").starts_with("HTTP/1.1 404"));
+    assert!(http(web_addr, "GET", "/metrics").starts_with("HTTP/1.1 404"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,95 @@
+//! Pages follow the phone's Accept-Language and show the request's context
+//! escaped, and a broken template in the pages directory stops the verifier
+//! at startup.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use common::{ctrl_send, http, http_with, read_line, spawn_activated, tmp_dir};
+use serde_json::json;
+
+#[test]
+fn approve_page_speaks_the_phones_language() {
+    let dir = tmp_dir("pages");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated(&dir, vec![ctrl.as_raw_fd(), web.as_raw_fd()], "ctrl:web");
+
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "game-mode", "path": "switch", "group": "login", "timeout_secs": 30,
+                "context": {"hostname": "<b>den</b>", "vt": 1, "since_last_session": 7300}}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let path = format!("/approve/{rid}");
+
+    let page = http(addr, "GET", &path);
+    assert!(page.contains("<html lang=en>"), "{page}");
+    assert!(page.contains("<h2 id=hd>Access request</h2>"), "{page}");
+    assert!(
+        page.contains("<b>Computer:</b> &lt;b&gt;den&lt;/b&gt;<br><b>VT:</b> 1"),
+        "{page}"
+    );
+    assert!(page.contains("<b>Last session:</b> 2 h ago"), "{page}");
+    let page = http_with(
+        addr,
+        "GET",
+        &path,
+        "Accept-Language: de-AT,de;q=0.9,en;q=0.5\r\n",
+    );
+    assert!(page.contains("<html lang=de>"), "{page}");
+    assert!(page.contains("<h2 id=hd>Zugriffsanfrage</h2>"), "{page}");
+    assert!(page.contains("<b>Letzte Sitzung:</b> vor 2 Std."), "{page}");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn broken_template_fails_at_startup() {
+    let dir = tmp_dir("pages-broken");
+    std::fs::create_dir_all(dir.join("pages")).unwrap();
+    std::fs::write(
+        dir.join("pages/approve.html"),
+        "<h1>__t.approve_heading__</h1>",
+    )
+    .unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_access-gate-verifier"))
+        .env("AG_CONFIG", dir.join("absent.toml"))
+        .env("AG_RP_ID", "localhost")
+        .env("AG_ORIGIN", "https://localhost")
+        .env("AG_DATA_DIR", &dir)
+        .env("AG_CTRL_SOCKET", dir.join("ctrl.sock"))
+        .env("AG_WEB_LISTEN", "127.0.0.1:0")
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            let _ = child.kill();
+            panic!("verifier started with a broken template");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(!status.success());
+    let mut stderr = String::new();
+    std::io::Read::read_to_string(&mut child.stderr.take().unwrap(), &mut stderr).unwrap();
+    assert!(
+        stderr.contains("approve.html") && stderr.contains("needs __RID__"),
+        "{stderr}"
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,82 @@
+//! The control socket knows who is calling: requests carry the caller's
+//! verified binary, a claim that doesn't match it is flagged, and
+//! `policy.uids` limits which groups each uid may ask for.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{ctrl_send, http, read_line, spawn_activated_with, tmp_dir};
+use serde_json::{json, Value};
+
+#[test]
+fn caller_is_verified_flagged_and_limited() {
+    let dir = tmp_dir("peer");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let uid = unsafe { libc::getuid() };
+    let me = std::env::current_exe().unwrap();
+    let me = me.to_str().unwrap();
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![ctrl.as_raw_fd(), web.as_raw_fd()],
+        "ctrl:web",
+        &[
+            ("AG_UID_GROUPS", &format!("{uid}=login")),
+            (
+                "AG_EXE_PATHS",
+                &format!("game-mode=/usr/bin/game-mode,tester={me}"),
+            ),
+        ],
+    );
+
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "tester", "path": "p", "group": "sudo", "timeout_secs": 30}),
+    );
+    let refused = read_line(&mut client).unwrap();
+    assert!(
+        refused["error"].as_str().unwrap().contains("sudo"),
+        "{refused}"
+    );
+
+    // Truthful: shown, not flagged.
+    let mut honest = ctrl_send(
+        &sock,
+        &json!({"exe": "tester", "path": "p", "group": "login", "timeout_secs": 30}),
+    );
+    let rid = read_line(&mut honest).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let page = http(addr, "GET", &format!("/approve/{rid}"));
+    assert!(page.contains(&format!("{me} (uid {uid}")), "{page}");
+    assert!(!page.contains("<b>Warning:</b>"), "{page}");
+
+    // Claims to be the daemon.
+    let mut impostor = ctrl_send(
+        &sock,
+        &json!({"exe": "game-mode", "path": "p", "group": "login", "timeout_secs": 30}),
+    );
+    let fake = read_line(&mut impostor).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let page = http(addr, "GET", &format!("/approve/{fake}"));
+    assert!(page.contains("<b>Warning:</b>"), "{page}");
+
+    http(addr, "POST", &format!("/approve/{fake}/deny"));
+    assert_eq!(read_line(&mut impostor).unwrap()["status"], "denied");
+    let text = std::fs::read_to_string(dir.join("history.jsonl")).unwrap();
+    let record: Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+    assert_eq!(record["exe_mismatch"], true);
+    assert_eq!(record["peer"]["exe"], me);
+    assert_eq!(record["peer"]["gid"], unsafe { libc::getgid() });
+
+    drop(honest);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,88 @@
+//! The web plane throttles each client address (as forwarded by tailscale
+//! serve) on its own; scrapes are exempt, and nobody but the proxy picks the
+//! address.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+
+use common::{http, http_with, proxy_uid, spawn_activated_with, tmp_dir};
+
+#[test]
+fn clients_are_throttled_per_forwarded_address() {
+    let dir = tmp_dir("ratelimit");
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![web.as_raw_fd()],
+        "web",
+        &[("AG_RATE_BURST", "3"), ("AG_RATE_PER_MIN", "1")],
+    );
+    let phone = "X-Forwarded-For: 100.64.0.7\r\n";
+
+    for _ in 0..3 {
+        assert!(http_with(addr, "GET", "/", phone).starts_with("HTTP/1.1 200"));
+    }
+    let limited = http_with(addr, "POST", "/approve/guess/options", phone);
+    assert!(limited.starts_with("HTTP/1.1 429"), "{limited}");
+    assert!(limited.contains("Retry-After: "), "{limited}");
+
+    let other = http_with(addr, "GET", "/", "X-Forwarded-For: 100.64.0.8\r\n");
+    assert!(other.starts_with("HTTP/1.1 200"), "{other}");
+    assert!(http(addr, "GET", "/metrics").starts_with("HTTP/1.1 200"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rotating_the_client_part_of_the_header_does_not_reset_the_budget() {
+    let dir = tmp_dir("ratelimit-rotate");
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![web.as_raw_fd()],
+        "web",
+        &[("AG_RATE_BURST", "3"), ("AG_RATE_PER_MIN", "1")],
+    );
+    // The browser makes up the first entries; the proxy appends the last.
+    let spoofed = |n: u32| format!("X-Forwarded-For: 10.0.0.{n}, 100.64.0.7\r\n");
+
+    for n in 0..3 {
+        assert!(http_with(addr, "GET", "/", &spoofed(n)).starts_with("HTTP/1.1 200"));
+    }
+    let limited = http_with(addr, "GET", "/", &spoofed(3));
+    assert!(limited.starts_with("HTTP/1.1 429"), "{limited}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn forwarded_for_from_anyone_but_the_proxy_is_ignored() {
+    let dir = tmp_dir("ratelimit-local");
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let not_us = (proxy_uid() + 1).to_string();
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![web.as_raw_fd()],
+        "web",
+        &[
+            ("AG_RATE_BURST", "3"),
+            ("AG_RATE_PER_MIN", "1"),
+            ("AG_PROXY_UID", &not_us),
+        ],
+    );
+    // A local process claiming a fresh address per request: all loopback.
+    let spoofed = |n: u32| format!("X-Forwarded-For: 100.64.0.{n}\r\n");
+
+    for n in 0..3 {
+        assert!(http_with(addr, "GET", "/", &spoofed(n)).starts_with("HTTP/1.1 200"));
+    }
+    let limited = http_with(addr, "GET", "/", &spoofed(3));
+    assert!(limited.starts_with("HTTP/1.1 429"), "{limited}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
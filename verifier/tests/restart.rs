@@ -0,0 +1,53 @@
+//! A verifier killed mid-request comes back with the request journaled: the
+//! control client resumes by id and still gets the phone's decision.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{ctrl_send, http, read_line, spawn_activated, tmp_dir};
+use serde_json::json;
+
+#[test]
+fn pending_request_survives_a_crash() {
+    let dir = tmp_dir("restart");
+    let sock = dir.join("ctrl.sock");
+    // Held for both runs, like the .socket units do.
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let web_addr = web.local_addr().unwrap();
+    let fds = vec![ctrl.as_raw_fd(), web.as_raw_fd()];
+
+    let mut first = spawn_activated(&dir, fds.clone(), "ctrl:web");
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 60}),
+    );
+    let rid = read_line(&mut client).unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    assert!(dir.join("pending.json").exists());
+
+    first.0.kill().unwrap();
+    first.0.wait().unwrap();
+    assert!(read_line(&mut client).is_none(), "connection must drop");
+
+    let _second = spawn_activated(&dir, fds, "ctrl:web");
+    let mut resumed = ctrl_send(&sock, &json!({ "resume": rid }));
+    // The restored request still renders and can be decided.
+    let page = http(web_addr, "GET", &format!("/approve/{rid}"));
+    assert!(page.starts_with("HTTP/1.1 200"), "{page}");
+    http(web_addr, "POST", &format!("/approve/{rid}/deny"));
+    assert_eq!(read_line(&mut resumed).unwrap()["status"], "denied");
+
+    // Resuming is idempotent; an id the verifier never saw is "unknown".
+    let mut again = ctrl_send(&sock, &json!({ "resume": rid }));
+    assert_eq!(read_line(&mut again).unwrap()["status"], "denied");
+    let mut bogus = ctrl_send(&sock, &json!({ "resume": "nope" }));
+    assert_eq!(read_line(&mut bogus).unwrap()["status"], "unknown");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
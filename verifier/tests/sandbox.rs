@@ -0,0 +1,46 @@
+//! The serving verifier runs under its seccomp filter unless
+//! `sandbox.seccomp` is off (Landlock leaves no trace in /proc; see the
+//! unit tests in `sandbox.rs`).
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{http, spawn_activated_with, tmp_dir};
+
+fn proc_status(pid: u32, key: &str) -> String {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap();
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix(key))
+        .unwrap()
+        .trim()
+        .to_string()
+}
+
+#[test]
+fn verifier_confines_itself_unless_told_not_to() {
+    for (seccomp, expect) in [("on", "2"), ("off", "0")] {
+        let dir = tmp_dir(&format!("sandbox-{seccomp}"));
+        let ctrl = UnixListener::bind(dir.join("ctrl.sock")).unwrap();
+        let web = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = web.local_addr().unwrap();
+        let verifier = spawn_activated_with(
+            &dir,
+            vec![ctrl.as_raw_fd(), web.as_raw_fd()],
+            "ctrl:web",
+            &[("AG_SANDBOX_SECCOMP", seccomp)],
+        );
+        // Answering means the sandbox is up.
+        assert!(http(addr, "GET", "/").starts_with("HTTP/1.1 200"));
+        let pid = verifier.0.id();
+        assert_eq!(proc_status(pid, "Seccomp:"), expect, "seccomp {seccomp}");
+        if seccomp == "on" {
+            assert_eq!(proc_status(pid, "NoNewPrivs:"), "1");
+        }
+        drop(verifier);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,109 @@
+//! Socket activation end to end: the listeners are created before the
+//! verifier runs and handed over the way systemd does it (fds from 3,
+//! LISTEN_FDS / LISTEN_FDNAMES, LISTEN_PID = the verifier's own pid), with a
+//! control request already waiting in the backlog, also across a restart
+//! of the verifier behind sockets that stay open.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+
+use common::{ctrl_send, http, read_line, spawn_activated, tmp_dir};
+use serde_json::json;
+
+#[test]
+fn queued_request_is_answered_on_activated_sockets() {
+    let dir = tmp_dir("queue");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let web_addr = web.local_addr().unwrap();
+
+    // The daemon connects while the verifier is "restarting".
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 1}),
+    );
+
+    let _verifier = spawn_activated(&dir, vec![ctrl.as_raw_fd(), web.as_raw_fd()], "ctrl:web");
+    drop((ctrl, web));
+
+    let ack = read_line(&mut client).unwrap();
+    assert!(ack["id"].is_string(), "{ack}");
+
+    let resp = http(web_addr, "GET", "/");
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(!dir.join("unused.sock").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unnamed_fds_are_matched_by_family() {
+    let dir = tmp_dir("unnamed");
+    let sock = dir.join("ctrl.sock");
+    // Reverse order, default names: family decides.
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let _verifier = spawn_activated(
+        &dir,
+        vec![web.as_raw_fd(), ctrl.as_raw_fd()],
+        "access-gate-verifier-web.socket:access-gate-verifier-ctrl.socket",
+    );
+    drop((ctrl, web));
+
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 1}),
+    );
+    let ack = read_line(&mut client).unwrap();
+    assert!(ack["id"].is_string(), "{ack}");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn request_queued_during_a_restart_is_answered() {
+    let dir = tmp_dir("restart-backlog");
+    let sock = dir.join("ctrl.sock");
+    // Held here the way systemd holds them for the socket units.
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let web_addr = web.local_addr().unwrap();
+    let fds = || vec![ctrl.as_raw_fd(), web.as_raw_fd()];
+
+    let first = spawn_activated(&dir, fds(), "ctrl:web");
+    assert!(http(web_addr, "GET", "/").starts_with("HTTP/1.1 200"));
+    drop(first);
+
+    // Nobody accepts now; the request sits in the backlog.
+    let mut client = ctrl_send(
+        &sock,
+        &json!({"exe": "t", "path": "p", "group": "login", "timeout_secs": 1}),
+    );
+    let _second = spawn_activated(&dir, fds(), "ctrl:web");
+    let ack = read_line(&mut client).unwrap();
+    assert!(ack["id"].is_string(), "{ack}");
+    assert!(sock.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `PartOf=` the service would restart (close) the sockets with it and drop
+/// whatever waits in their backlog.
+#[test]
+fn socket_units_outlive_service_restarts() {
+    let dist = concat!(env!("CARGO_MANIFEST_DIR"), "/../dist");
+    for unit in [
+        "access-gate-verifier-ctrl.socket",
+        "access-gate-verifier-web.socket",
+    ] {
+        let text = std::fs::read_to_string(format!("{dist}/{unit}")).unwrap();
+        assert!(
+            !text.lines().any(|l| l.trim_start().starts_with("PartOf=")),
+            "{unit} is PartOf= the service"
+        );
+    }
+}
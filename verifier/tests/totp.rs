@@ -0,0 +1,133 @@
+//! An authenticator app enrolled on `/totp` approves requests in
+//! `policy.totp_groups` with a code, once per code.
+
+mod common;
+
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use access_gate_verifier::totp::{base32_decode, hotp, Algorithm, STEP};
+use common::{ctrl_send, enroll_token, http, http_body, read_line, spawn_activated_with, tmp_dir};
+use serde_json::{json, Value};
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn code(secret: &[u8], step: u64) -> String {
+    format!("{:06}", hotp(Algorithm::Sha1, secret, step, 6))
+}
+
+fn status(resp: &str) -> u16 {
+    resp[9..12].parse().unwrap()
+}
+
+#[test]
+fn enrolled_code_approves_once() {
+    let dir = tmp_dir("totp");
+    let sock = dir.join("ctrl.sock");
+    let ctrl = UnixListener::bind(&sock).unwrap();
+    let web = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = web.local_addr().unwrap();
+    let _verifier = spawn_activated_with(
+        &dir,
+        vec![ctrl.as_raw_fd(), web.as_raw_fd()],
+        "ctrl:web",
+        &[("AG_TOTP_GROUPS", "login"), ("AG_RATE_BURST", "100")],
+    );
+    let post =
+        |path: &str, body: &Value| status(&http_body(addr, "POST", path, "", &body.to_string()));
+
+    assert_eq!(status(&http(addr, "GET", "/totp")), 403);
+    let t = enroll_token(&dir, "totp");
+    let page = http(addr, "GET", &format!("/totp?t={t}"));
+    assert!(page.contains("<svg"), "{page}");
+    let key = page
+        .split("<code style=\"word-break:break-all\">")
+        .nth(1)
+        .and_then(|rest| rest.split('<').next())
+        .unwrap();
+    let secret = base32_decode(key).unwrap();
+
+    // Stay clear of a step boundary so "now" means the same step throughout.
+    if now_unix() % STEP > STEP - 10 {
+        std::thread::sleep(Duration::from_secs(10));
+    }
+    // A wrong code backs the client off like a failed assertion.
+    let backoff = || std::thread::sleep(Duration::from_secs(2));
+    let step = now_unix() / STEP;
+    let enroll = |c: &str| json!({"label": "Kid's tablet", "code": c});
+    let verify = format!("/totp/verify?t={t}");
+    assert_eq!(post(&verify, &enroll("000000")), 400);
+    backoff();
+    // One step behind is within the drift window.
+    assert_eq!(post(&verify, &enroll(&code(&secret, step - 1))), 200);
+    // The token is spent.
+    assert_eq!(post(&verify, &enroll(&code(&secret, step))), 404);
+
+    let request = |group: &str| {
+        let mut client = ctrl_send(
+            &sock,
+            &json!({"exe": "game-mode", "path": "switch", "group": group,
+                    "timeout_secs": 60}),
+        );
+        let rid = read_line(&mut client).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (client, rid)
+    };
+
+    // Other groups still need the passkey.
+    let (_sudo, rid) = request("sudo");
+    assert!(http(addr, "GET", &format!("/approve/{rid}")).contains("TOTP=false"));
+    let approve = |rid: &str, c: &str| post(&format!("/approve/{rid}/totp"), &json!({"code": c}));
+    assert_eq!(approve(&rid, &code(&secret, step)), 404);
+
+    let (mut client, rid) = request("login");
+    assert!(http(addr, "GET", &format!("/approve/{rid}")).contains("TOTP=true"));
+    // The enrolling code, or anything older, is spent.
+    assert_eq!(approve(&rid, &code(&secret, step - 1)), 403);
+    backoff();
+    assert_eq!(approve(&rid, &code(&secret, step)), 200);
+    assert_eq!(read_line(&mut client).unwrap()["status"], "approved");
+
+    let (_again, rid) = request("login");
+    assert_eq!(approve(&rid, &code(&secret, step)), 403);
+
+    let log = std::fs::read_to_string(dir.join("history.jsonl")).unwrap();
+    let approved: Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+    assert!(approved["credential"]
+        .as_str()
+        .unwrap()
+        .starts_with("totp:"));
+
+    let cli = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_access-gate-verifier"))
+            .args(args)
+            .env("AG_CONFIG", dir.join("absent.toml"))
+            .env("AG_RP_ID", "localhost")
+            .env("AG_ORIGIN", "https://localhost")
+            .env("AG_DATA_DIR", &dir)
+            .output()
+            .unwrap();
+        (
+            out.status.success(),
+            String::from_utf8_lossy(&out.stdout).into_owned(),
+        )
+    };
+    let (ok, listed) = cli(&["totp", "list"]);
+    assert!(ok && listed.contains("Kid's tablet"), "{listed}");
+    let id = listed.split_whitespace().next().unwrap();
+    assert!(cli(&["totp", "remove", id]).0);
+    assert!(cli(&["totp", "list"])
+        .1
+        .contains("No authenticators enrolled."));
+    let _ = std::fs::remove_dir_all(&dir);
+}